@@ -1,4 +1,4 @@
-use crate::error::InputSpan;
+use crate::{error::InputSpan, types};
 
 pub use crate::ast::{Expr::*, Item::*, PrimitiveType::*, Type::*};
 
@@ -14,6 +14,10 @@ pub enum Item {
     FunctionItem(FunctionDeclaration),
     StructItem(StructDeclaration),
     LetItem(LetStatement),
+    ModItem(ModDeclaration),
+    UseItem(UseDeclaration),
+    // An item that could not be parsed
+    ErrorItem(InputSpan),
 }
 
 impl Item {
@@ -22,6 +26,9 @@ impl Item {
             FunctionItem(x) => x.span,
             StructItem(x) => x.span,
             LetItem(x) => x.span,
+            ModItem(x) => x.span,
+            UseItem(x) => x.span,
+            ErrorItem(span) => *span,
         }
     }
 }
@@ -33,7 +40,6 @@ pub enum PrimitiveType {
     CharType,
     BoolType,
     AnyType,
-    BottomType,
 }
 
 #[derive(Debug, Hash, Clone, PartialEq, Eq)]
@@ -41,19 +47,29 @@ pub enum Type {
     Primitive(PrimitiveType),
     Pointer(Box<Type>),
     StaticArrayType(Box<Type>, i32),
-    DerefType(Box<Type>),
-    FieldRefType(Box<Type>, String),
+    // The name of a struct, which may be a path into another module such as `geo::Point`
     UserType(String),
-    VariableType(String),
 }
 
 #[derive(Debug, Clone)]
 pub struct Expression {
     pub expr: Box<Expr>,
-    pub rtype: Type,
+    /// The type of the expression, which is filled in by the semantic pass
+    pub rtype: Option<types::Type>,
     pub span: InputSpan,
 }
 
+impl Expression {
+    /// Create an expression that hasn't been type checked yet
+    pub fn new(expr: Expr, span: InputSpan) -> Expression {
+        Expression { expr: Box::new(expr), rtype: None, span }
+    }
+
+    pub fn rtype(&self) -> &types::Type {
+        self.rtype.as_ref().expect("ICE: expression has not been type checked")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Expr {
     // Control flow
@@ -67,22 +83,33 @@ pub enum Expr {
     // Variables
     LetExpr(LetStatement),
     AssignExpr(Assignment),
+    // A variable name, or a path to an item in another module such as `geo::ORIGIN`
     VariableExpr(String),
     StructInitExpr(StructInit),
 
     LitNumExpr(i32),
     LitCharExpr(char),
+    LitBoolExpr(bool),
     LitStringExpr(String),
+    NullExpr,
     StaticArrayExpr(StaticArray),
 
     FieldRefExpr(FieldRef),
     ArrayIndexExpr(ArrayIndex),
     RefExpr(Expression),
     DerefExpr(Expression),
-    CastExpr(Expression),
+    CastExpr(Expression, Type),
+    // Memory for a value of a type allocated from the heap, e.g. `new Node`
+    NewExpr(Type),
 
-    AsmOpExpr(String),
+    // Operators
+    BinaryExpr(BinaryExpression),
+    UnaryExpr(UnaryExpression),
+
+    AsmOpExpr(AsmBlock),
     EmptyExpr,
+    // An expression that could not be parsed
+    ErrorExpr,
 }
 
 #[derive(Debug, Clone)]
@@ -92,10 +119,11 @@ pub struct Block {
 }
 
 impl Block {
-    pub fn rtype(&self) -> Type {
+    /// The type of the value of the block, which is the value of its last statement
+    pub fn rtype(&self) -> types::Type {
         match self.statements.last() {
-            Some(stmt) => stmt.rtype.clone(),
-            None => Primitive(UnitType),
+            Some(stmt) => stmt.rtype().clone(),
+            None => types::UNIT_TYPE,
         }
     }
 }
@@ -126,7 +154,26 @@ pub struct ForLoopStatement {
 #[derive(Debug, Clone)]
 pub struct StructDeclaration {
     pub name: String,
-    pub fields: Vec<(String, Type)>,
+    /// The name and type of each field, along with the span of its declaration
+    pub fields: Vec<(String, Type, InputSpan)>,
+    /// Whether the fields are laid out without padding, set with `#[packed]`
+    pub packed: bool,
+    pub span: InputSpan,
+}
+
+/// A module, which is read from the file `<name>.pcp` next to the file that declares it
+#[derive(Debug)]
+pub struct ModDeclaration {
+    pub name: String,
+    /// The items in the module, which are filled in after the module's file is parsed
+    pub items: Vec<Item>,
+    pub span: InputSpan,
+}
+
+/// Makes an item from another module available by its last name, e.g. `use geo::area;`
+#[derive(Debug, Clone)]
+pub struct UseDeclaration {
+    pub path: String,
     pub span: InputSpan,
 }
 
@@ -136,9 +183,52 @@ pub struct FunctionDeclaration {
     pub params: Vec<(String, Type)>,
     pub rtype: Type,
     pub body: Block,
+    /// Whether all of the arguments are passed on the stack, set with `#[stack_args]`
+    pub stack_args: bool,
     pub span: InputSpan,
 }
 
+/// A block of inline assembly, e.g. `asm clobbers(r6) { "        lw      r6,{x}" }`
+#[derive(Debug, Clone)]
+pub struct AsmBlock {
+    /// The lines of assembly along with the span of the string each one was written in. Lines
+    /// can refer to variables as `{name}` and to the register holding the result as `{result}`.
+    pub lines: Vec<(String, InputSpan)>,
+    /// The registers other than the scratch registers that the assembly modifies
+    pub clobbers: Vec<(String, InputSpan)>,
+}
+
+/// A piece of a line of inline assembly
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AsmPart<'a> {
+    Text(&'a str),
+    /// An operand written as `{name}`, along with the column of the `{` in the line
+    Operand(&'a str, usize),
+}
+
+/// Split a line of inline assembly into text and operands. If an operand is not closed, the column
+/// of its `{` is returned as the error.
+pub fn asm_parts(line: &str) -> Result<Vec<AsmPart<'_>>, usize> {
+    let mut parts = vec![];
+    let mut rest = line;
+    while let Some(open) = rest.find('{') {
+        let column = line[..line.len() - rest.len() + open].chars().count();
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => return Err(column),
+        };
+        if open != 0 {
+            parts.push(AsmPart::Text(&rest[..open]));
+        }
+        parts.push(AsmPart::Operand(&rest[open + 1..close], column));
+        rest = &rest[close + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(AsmPart::Text(rest));
+    }
+    Ok(parts)
+}
+
 #[derive(Debug, Clone)]
 pub struct FunctionCall {
     pub name: String,
@@ -149,7 +239,8 @@ pub struct FunctionCall {
 #[derive(Debug, Clone)]
 pub struct LetStatement {
     pub name: String,
-    pub var_type: Type,
+    /// The type of the variable, or `None` if it is the type of the assigned value
+    pub var_type: Option<Type>,
     pub assignment: Option<Assignment>,
     pub is_const: bool,
     pub span: InputSpan,
@@ -188,3 +279,60 @@ pub struct ArrayIndex {
     pub target: Expression,
     pub span: InputSpan,
 }
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinaryOp {
+    // Arithmetic
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+
+    // Comparison
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+
+    // Logical
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    /// Returns the binding power of the operator, higher values bind more tightly.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq | BinaryOp::NotEq => 3,
+            BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 6,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryExpression {
+    pub op: BinaryOp,
+    pub lhs: Expression,
+    pub rhs: Expression,
+    pub span: InputSpan,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnaryExpression {
+    pub op: UnaryOp,
+    pub operand: Expression,
+    pub span: InputSpan,
+}
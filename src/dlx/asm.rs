@@ -3,26 +3,33 @@ pub use self::Value::*;
 
 use std::fmt;
 
+use crate::dlx::{
+    console,
+    syntax::{Expr, Operand, Operation, SyntaxError},
+};
+
 pub type RegId = usize;
 pub type _SpecialRegId = usize;
-pub type _TrapId = usize;
+pub type TrapId = usize;
 pub type LabelId = String;
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Const(i16),
-    _Unknown(LabelId),
+    /// A value that is not known until the program is assembled, e.g. `label+4`
+    Unknown(LabelId),
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Const(val) => write!(f, "{}", val),
-            _Unknown(ref label) => write!(f, "{}", label.clone()),
+            Unknown(ref label) => write!(f, "{}", label.clone()),
         }
     }
 }
 
-#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     // Memory transfer instructions
     Load8(RegId, Value, RegId),
@@ -48,10 +55,15 @@ pub enum Instruction {
     AddSignedValue(RegId, RegId, i16),
     AddUnsigned(RegId, RegId, RegId),
     AddUnsignedValue(RegId, RegId, u16),
+    AddUnsignedLabel(RegId, RegId, LabelId),
     SubSigned(RegId, RegId, RegId),
     SubSignedValue(RegId, RegId, i16),
     SubUnsigned(RegId, RegId, RegId),
     SubUnsignedValue(RegId, RegId, u16),
+    Mult(RegId, RegId, RegId),
+    MultUnsigned(RegId, RegId, RegId),
+    Div(RegId, RegId, RegId),
+    DivUnsigned(RegId, RegId, RegId),
     //ClearReg(RegId),
     //NegateReg(RegId),
     //Move(RegId, RegId),
@@ -92,12 +104,12 @@ pub enum Instruction {
     Xor(RegId, RegId, RegId),
     XorValue(RegId, RegId, u16),
 
-    // LShift(RegId, RegId, RegId),
+    LShift(RegId, RegId, RegId),
     LShiftValue(RegId, RegId, u16),
-    // RShiftSign(RegId, RegId, RegId),
-    // RShiftSignValue(RegId, RegId, u16),
-    // RShiftZero(RegId, RegId, RegId),
-    // RShiftZeroValue(RegId, RegId, u16),
+    RShiftSign(RegId, RegId, RegId),
+    RShiftSignValue(RegId, RegId, u16),
+    RShiftZero(RegId, RegId, RegId),
+    RShiftZeroValue(RegId, RegId, u16),
     // LShiftSign(RegId, RegId, RegId),
     // LShiftSignValue(RegId, RegId, u16),
     // LShiftZero(RegId, RegId, RegId),
@@ -108,7 +120,7 @@ pub enum Instruction {
     // StoreSpecial(SpecialRegId, RegId),
     // LoadSpecial(RegId, SpecialRegId),
     Nop,
-    // Trap(TrapId),
+    Trap(TrapId),
     // ReturnFromException,
 
     // Assembler Directives
@@ -123,6 +135,325 @@ pub enum Instruction {
     RawAsm(String),
 }
 
+impl Instruction {
+    /// Get the register written by the instruction, and the registers it reads
+    pub fn registers_mut(&mut self) -> (Option<&mut RegId>, Vec<&mut RegId>) {
+        match self {
+            Load8(d, _, s)
+            | Load8u(d, _, s)
+            | Load16(d, _, s)
+            | Load16u(d, _, s)
+            | Load32(d, _, s) => (Some(d), vec![s]),
+            Store8(_, a, b) | Store16(_, a, b) | Store32(_, a, b) => (None, vec![a, b]),
+
+            JumpIfZero(s, _) | JumpIfNotZero(s, _) | JumpStoreR(s) | JumpR(s) => (None, vec![s]),
+
+            LoadHighImmediate(d, _) => (Some(d), vec![]),
+
+            AddSigned(d, a, b)
+            | AddUnsigned(d, a, b)
+            | SubSigned(d, a, b)
+            | SubUnsigned(d, a, b)
+            | Mult(d, a, b)
+            | MultUnsigned(d, a, b)
+            | Div(d, a, b)
+            | DivUnsigned(d, a, b)
+            | SetEq(d, a, b)
+            | SetEqUnsigned(d, a, b)
+            | SetNotEq(d, a, b)
+            | SetNotEqUnsigned(d, a, b)
+            | SetGt(d, a, b)
+            | SetGtUnsigned(d, a, b)
+            | SetGtEq(d, a, b)
+            | SetGtEqUnsigned(d, a, b)
+            | SetLt(d, a, b)
+            | SetLtUnsigned(d, a, b)
+            | SetLtEq(d, a, b)
+            | SetLtEqUnsigned(d, a, b)
+            | And(d, a, b)
+            | Or(d, a, b)
+            | Xor(d, a, b)
+            | LShift(d, a, b)
+            | RShiftSign(d, a, b)
+            | RShiftZero(d, a, b) => (Some(d), vec![a, b]),
+
+            AddSignedValue(d, s, _)
+            | SubSignedValue(d, s, _)
+            | SetEqSignedValue(d, s, _)
+            | SetNotEqSignedValue(d, s, _)
+            | SetGtSignedValue(d, s, _)
+            | SetGtEqSignedValue(d, s, _)
+            | SetLtSignedValue(d, s, _)
+            | SetLtEqSignedValue(d, s, _) => (Some(d), vec![s]),
+
+            AddUnsignedValue(d, s, _)
+            | SubUnsignedValue(d, s, _)
+            | SetEqUnsignedValue(d, s, _)
+            | SetNotEqUnsignedValue(d, s, _)
+            | SetGtUnsignedValue(d, s, _)
+            | SetGtEqUnsignedValue(d, s, _)
+            | SetLtUnsignedValue(d, s, _)
+            | SetLtEqUnsignedValue(d, s, _)
+            | AndValue(d, s, _)
+            | OrValue(d, s, _)
+            | XorValue(d, s, _)
+            | LShiftValue(d, s, _)
+            | RShiftSignValue(d, s, _)
+            | RShiftZeroValue(d, s, _)
+            | AddUnsignedLabel(d, s, _) => (Some(d), vec![s]),
+
+            // Registers used by raw assembly are unknown to the compiler
+            Jump(..)
+            | JumpStore(..)
+            | Trap(..)
+            | Halt
+            | Nop
+            | Label(..)
+            | AllocateBytes(..)
+            | AllocateHalfWords(..)
+            | AllocateWords(..)
+            | AllocateSpace(..)
+            | AllocateAscii(..)
+            | Align(..)
+            | RawAsm(..) => (None, vec![]),
+        }
+    }
+
+    /// Convert an instruction or directive parsed from assembly text. Directives that have no
+    /// corresponding instruction give `None`, so that they can be kept as text.
+    pub fn from_operation(operation: &Operation) -> Result<Option<Instruction>, SyntaxError> {
+        use crate::dlx::syntax::Operand::*;
+
+        let mnemonic = operation.mnemonic.as_str();
+        let operands = operation.operands.as_slice();
+        if mnemonic.starts_with('.') {
+            return Ok(directive(mnemonic, operands));
+        }
+        let invalid = || format!("invalid operands for `{}`", mnemonic);
+
+        // Loads and stores, where an address without a base register is relative to r0
+        let address = |operand: &Operand| match operand {
+            Memory(offset, base) => Ok((value(offset)?, *base)),
+            Immediate(address) => Ok((value(address)?, 0)),
+            _ => Err(invalid()),
+        };
+        let load = |f: fn(RegId, Value, RegId) -> Instruction| match operands {
+            [Register(rd), operand] => {
+                let (offset, base) = address(operand)?;
+                Ok(f(*rd, offset, base))
+            }
+            _ => Err(invalid()),
+        };
+        let store = |f: fn(Value, RegId, RegId) -> Instruction| match operands {
+            [operand, Register(rs)] => {
+                let (offset, base) = address(operand)?;
+                Ok(f(offset, base, *rs))
+            }
+            _ => Err(invalid()),
+        };
+
+        // Arithmetic and logic operations
+        let alu = |f: fn(RegId, RegId, RegId) -> Instruction| match operands {
+            [Register(rd), Register(rs1), Register(rs2)] => Ok(f(*rd, *rs1, *rs2)),
+            _ => Err(invalid()),
+        };
+        let alu_imm = || match operands {
+            [Register(rd), Register(rs1), Immediate(value)] => Ok((*rd, *rs1, value)),
+            // Short form where the destination is also the source
+            [Register(rd), Immediate(value)] => Ok((*rd, *rd, value)),
+            _ => Err(invalid()),
+        };
+        let alu_signed = |f: fn(RegId, RegId, i16) -> Instruction| {
+            let (rd, rs1, value) = alu_imm()?;
+            Ok(f(rd, rs1, signed(value)?))
+        };
+        let alu_unsigned = |f: fn(RegId, RegId, u16) -> Instruction| {
+            let (rd, rs1, value) = alu_imm()?;
+            Ok(f(rd, rs1, unsigned(value)?))
+        };
+
+        // Control flow
+        let branch = |f: fn(RegId, LabelId) -> Instruction| match operands {
+            [Register(rs), Immediate(target)] => Ok(f(*rs, target.to_string())),
+            _ => Err(invalid()),
+        };
+        let jump = |f: fn(LabelId) -> Instruction| match operands {
+            [Immediate(target)] => Ok(f(target.to_string())),
+            _ => Err(invalid()),
+        };
+        let jump_reg = |f: fn(RegId) -> Instruction| match operands {
+            [Register(rs)] => Ok(f(*rs)),
+            _ => Err(invalid()),
+        };
+        let no_operands = |instruction| match operands {
+            [] => Ok(instruction),
+            _ => Err(invalid()),
+        };
+
+        let instruction = match mnemonic {
+            "lb" => load(Load8),
+            "lbu" => load(Load8u),
+            "lh" => load(Load16),
+            "lhu" => load(Load16u),
+            "lw" => load(Load32),
+            "sb" => store(Store8),
+            "sh" => store(Store16),
+            "sw" => store(Store32),
+
+            "beqz" | "bf" => branch(JumpIfZero),
+            "bnez" | "bt" => branch(JumpIfNotZero),
+            "j" => jump(Jump),
+            "jal" => jump(JumpStore),
+            "jr" => jump_reg(JumpR),
+            "jalr" => jump_reg(JumpStoreR),
+
+            "lhi" => match operands {
+                [Register(rd), Immediate(value)] => {
+                    unsigned(value).map(|value| LoadHighImmediate(*rd, value))
+                }
+                _ => Err(invalid()),
+            },
+
+            "add" => alu(AddSigned),
+            "addu" => alu(AddUnsigned),
+            "sub" => alu(SubSigned),
+            "subu" => alu(SubUnsigned),
+            "mult" => alu(Mult),
+            "multu" => alu(MultUnsigned),
+            "div" => alu(Div),
+            "divu" => alu(DivUnsigned),
+            "and" => alu(And),
+            "or" => alu(Or),
+            "xor" => alu(Xor),
+            "sll" => alu(LShift),
+            "srl" => alu(RShiftZero),
+            "sra" => alu(RShiftSign),
+            "seq" => alu(SetEq),
+            "sne" => alu(SetNotEq),
+            "slt" => alu(SetLt),
+            "sgt" => alu(SetGt),
+            "sle" => alu(SetLtEq),
+            "sge" => alu(SetGtEq),
+            "sequ" => alu(SetEqUnsigned),
+            "sneu" => alu(SetNotEqUnsigned),
+            "sltu" => alu(SetLtUnsigned),
+            "sgtu" => alu(SetGtUnsigned),
+            "sleu" => alu(SetLtEqUnsigned),
+            "sgeu" => alu(SetGtEqUnsigned),
+
+            "addi" => alu_signed(AddSignedValue),
+            // The address of a label can be added to a register
+            "addui" => match alu_imm() {
+                Ok((rd, rs1, label @ Expr::Symbol(..))) if number(label).is_err() => {
+                    Ok(AddUnsignedLabel(rd, rs1, label.to_string()))
+                }
+                _ => alu_unsigned(AddUnsignedValue),
+            },
+            "subi" => alu_signed(SubSignedValue),
+            "subui" => alu_unsigned(SubUnsignedValue),
+            "andi" => alu_unsigned(AndValue),
+            "ori" => alu_unsigned(OrValue),
+            "xori" => alu_unsigned(XorValue),
+            "slli" => alu_unsigned(LShiftValue),
+            "srli" => alu_unsigned(RShiftZeroValue),
+            "srai" => alu_unsigned(RShiftSignValue),
+            "seqi" => alu_signed(SetEqSignedValue),
+            "snei" => alu_signed(SetNotEqSignedValue),
+            "slti" => alu_signed(SetLtSignedValue),
+            "sgti" => alu_signed(SetGtSignedValue),
+            "slei" => alu_signed(SetLtEqSignedValue),
+            "sgei" => alu_signed(SetGtEqSignedValue),
+            "sequi" => alu_unsigned(SetEqUnsignedValue),
+            "sneui" => alu_unsigned(SetNotEqUnsignedValue),
+            "sltui" => alu_unsigned(SetLtUnsignedValue),
+            "sgtui" => alu_unsigned(SetGtUnsignedValue),
+            "sleui" => alu_unsigned(SetLtEqUnsignedValue),
+            "sgeui" => alu_unsigned(SetGtEqUnsignedValue),
+
+            "trap" => match operands {
+                [Immediate(id)] => number(id).and_then(|value| match value {
+                    0..=0x03FF_FFFF => Ok(Trap(value as TrapId)),
+                    _ => Err(format!("trap number `{}` out of range", id)),
+                }),
+                _ => Err(invalid()),
+            },
+            "halt" => no_operands(Halt),
+            "nop" => no_operands(Nop),
+
+            unknown => {
+                let message = format!("unknown instruction `{}`", unknown);
+                return Err(SyntaxError { message, columns: operation.mnemonic_columns.clone() });
+            }
+        };
+
+        // Other errors are in the operands, unless there aren't any
+        let columns = match operation.operand_columns.is_empty() {
+            true => operation.mnemonic_columns.clone(),
+            false => operation.operand_columns.clone(),
+        };
+        instruction.map(Some).map_err(|message| SyntaxError { message, columns })
+    }
+}
+
+/// Convert a directive to an instruction, if the operands are ones that the instruction can hold
+fn directive(mnemonic: &str, operands: &[Operand]) -> Option<Instruction> {
+    let numbers: Option<Vec<i32>> = operands
+        .iter()
+        .map(|operand| match operand {
+            Operand::Immediate(Expr::Number(value)) => Some(*value),
+            _ => None,
+        })
+        .collect();
+
+    match (mnemonic, operands) {
+        (".ascii", [Operand::Text(text)]) => Some(AllocateAscii(text.clone())),
+        (".space", [Operand::Immediate(Expr::Number(size))]) => {
+            Some(AllocateSpace(u32::try_from(*size).ok()?))
+        }
+        (".align", [Operand::Immediate(Expr::Number(n))]) => Some(Align(i16::try_from(*n).ok()?)),
+        (".word", _) => Some(AllocateWords(numbers?)),
+        (".half", _) => {
+            let values = numbers?.into_iter().map(i16::try_from).collect::<Result<_, _>>();
+            Some(AllocateHalfWords(values.ok()?))
+        }
+        (".byte", _) => {
+            let values = numbers?.into_iter().map(i8::try_from).collect::<Result<_, _>>();
+            Some(AllocateBytes(values.ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Get the value of a number, or of one of the symbols of the console
+fn number(expr: &Expr) -> Result<i32, String> {
+    match expr {
+        Expr::Number(value) => Ok(*value),
+        Expr::Symbol(name, offset) => match console::SYMBOLS.iter().find(|(x, _)| x == name) {
+            Some((_, value)) => Ok(value.wrapping_add(*offset)),
+            None => Err(format!("expected a number, found label `{}`", expr)),
+        },
+    }
+}
+
+fn signed(expr: &Expr) -> Result<i16, String> {
+    let value = number(expr)?;
+    i16::try_from(value).map_err(|_| format!("immediate value `{}` ({}) out of range", expr, value))
+}
+
+fn unsigned(expr: &Expr) -> Result<u16, String> {
+    let value = number(expr)?;
+    u16::try_from(value).map_err(|_| format!("immediate value `{}` ({}) out of range", expr, value))
+}
+
+/// Get the offset of a load or store, which may be an address that is not known until the
+/// program is assembled
+fn value(expr: &Expr) -> Result<Value, String> {
+    match expr {
+        Expr::Number(..) => Ok(Const(signed(expr)?)),
+        Expr::Symbol(..) => Ok(Unknown(expr.to_string())),
+    }
+}
+
 impl std::fmt::Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -147,10 +478,15 @@ impl std::fmt::Display for Instruction {
             AddSignedValue(j, i, s) => write!(f, "addi    r{},r{},{}", j, i, s),
             AddUnsigned(k, i, j) => write!(f, "addu    r{},r{},r{}", k, i, j),
             AddUnsignedValue(j, i, u) => write!(f, "addui   r{},r{},{}", j, i, u),
+            AddUnsignedLabel(j, i, label) => write!(f, "addui   r{},r{},{}", j, i, label),
             SubSigned(k, i, j) => write!(f, "sub     r{},r{},r{}", k, i, j),
             SubSignedValue(j, i, s) => write!(f, "subi    r{},r{},{}", j, i, s),
             SubUnsigned(k, i, j) => write!(f, "subu    r{},r{},r{}", k, i, j),
             SubUnsignedValue(j, i, u) => write!(f, "subui   r{},r{},{}", j, i, u),
+            Mult(k, i, j) => write!(f, "mult    r{},r{},r{}", k, i, j),
+            MultUnsigned(k, i, j) => write!(f, "multu   r{},r{},r{}", k, i, j),
+            Div(k, i, j) => write!(f, "div     r{},r{},r{}", k, i, j),
+            DivUnsigned(k, i, j) => write!(f, "divu    r{},r{},r{}", k, i, j),
 
             SetEq(k, i, j) => write!(f, "seq     r{},r{},r{}", k, i, j),
             SetEqSignedValue(j, i, s) => write!(f, "seqi    r{},r{},{}", j, i, s),
@@ -184,9 +520,15 @@ impl std::fmt::Display for Instruction {
             Xor(k, i, j) => write!(f, "xor     r{},r{},r{}", k, i, j),
             XorValue(j, i, u) => write!(f, "xori    r{},r{},{}", j, i, u),
 
+            LShift(k, i, j) => write!(f, "sll     r{},r{},r{}", k, i, j),
             LShiftValue(j, i, u) => write!(f, "slli    r{},r{},{}", j, i, u),
+            RShiftSign(k, i, j) => write!(f, "sra     r{},r{},r{}", k, i, j),
+            RShiftSignValue(j, i, u) => write!(f, "srai    r{},r{},{}", j, i, u),
+            RShiftZero(k, i, j) => write!(f, "srl     r{},r{},r{}", k, i, j),
+            RShiftZeroValue(j, i, u) => write!(f, "srli    r{},r{},{}", j, i, u),
 
             Halt => f.write_str("halt"),
+            Trap(id) => write!(f, "trap    {}", id),
             Nop => f.write_str("nop"),
 
             Label(name) => f.write_str(name),
@@ -0,0 +1,622 @@
+use std::{collections::HashMap, fmt};
+
+use crate::dlx::{
+    asm::Instruction,
+    encoding::*,
+    syntax::{self, Expr, Operand, Operation},
+};
+
+/// The address that the code segment is placed at. The data segments are placed after the code.
+const CODE_BASE: u32 = 0;
+
+/// The number of data bytes in each Intel HEX record
+const HEX_RECORD_SIZE: usize = 16;
+
+/// An error encountered while assembling a program
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: String,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line.trim() {
+            "" => f.write_str(&self.message),
+            line => write!(f, "{}: `{}`", self.message, line),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Code,
+    ConstData,
+    Data,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Segment::Code => f.write_str("code"),
+            Segment::ConstData => f.write_str("constdata"),
+            Segment::Data => f.write_str("data"),
+        }
+    }
+}
+
+/// A symbol defined by a program
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    /// The segment containing the symbol, or `None` for symbols defined by `.equ`
+    pub segment: Option<Segment>,
+}
+
+/// An assembled program
+pub struct Image {
+    /// The address of the first byte of the image
+    pub base: u32,
+    pub bytes: Vec<u8>,
+    /// The address of the first instruction to execute
+    pub start: u32,
+    /// All of the symbols in the program, sorted by value
+    pub symbols: Vec<Symbol>,
+}
+
+impl Image {
+    /// The address immediately after the end of the image
+    pub fn end(&self) -> u32 {
+        self.base + self.bytes.len() as u32
+    }
+
+    /// Get the image in Intel HEX format
+    pub fn to_intel_hex(&self) -> String {
+        let mut output = String::new();
+
+        let mut upper = 0;
+        for (i, chunk) in self.bytes.chunks(HEX_RECORD_SIZE).enumerate() {
+            let addr = self.base + (i * HEX_RECORD_SIZE) as u32;
+
+            // Records only contain the low 16 bits of the address, so an extended linear address
+            // record is required whenever the upper 16 bits change
+            if addr >> 16 != upper {
+                upper = addr >> 16;
+                output.push_str(&hex_record(0x04, 0, &(upper as u16).to_be_bytes()));
+            }
+            output.push_str(&hex_record(0x00, addr as u16, chunk));
+        }
+
+        output.push_str(&hex_record(0x05, 0, &self.start.to_be_bytes()));
+        output.push_str(&hex_record(0x01, 0, &[]));
+        output
+    }
+
+    /// Get a listing of the symbols in the image
+    pub fn symbol_table(&self) -> String {
+        let mut output = String::new();
+        for symbol in &self.symbols {
+            let segment = match symbol.segment {
+                Some(segment) => segment.to_string(),
+                None => "abs".to_string(),
+            };
+            output.push_str(&format!("{:08x} {:<9} {}\n", symbol.value, segment, symbol.name));
+        }
+        output
+    }
+}
+
+fn hex_record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+
+    // The checksum is the two's complement of the sum of all of the other bytes in the record
+    let checksum = bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)).wrapping_neg();
+    bytes.push(checksum);
+
+    let mut record = ":".to_string();
+    for byte in bytes {
+        record.push_str(&format!("{:02X}", byte));
+    }
+    record.push('\n');
+    record
+}
+
+/// Assemble a program into a binary image
+pub fn assemble(program: &[Instruction]) -> Result<Image, AsmError> {
+    // Convert the program to assembly text so that instructions generated by the compiler and
+    // instructions from inline assembly are handled in exactly the same way.
+    let mut text = vec![];
+    for instruction in program {
+        match instruction {
+            Instruction::Label(name) => text.push(name.clone()),
+            Instruction::RawAsm(data) => text.extend(data.lines().map(String::from)),
+            other => text.push(format!("        {}", other)),
+        }
+    }
+
+    let mut lines = vec![];
+    for line in &text {
+        let parsed = syntax::parse_line(line)
+            .map_err(|error| AsmError { line: line.clone(), message: error.message })?;
+        lines.push((line.as_str(), parsed));
+    }
+
+    Assembler::default().assemble(&lines)
+}
+
+/// An item placed in a segment during the first pass of assembly
+enum Item<'a> {
+    Instruction(&'a Operation),
+    Data(u32, &'a [Operand]),
+    Bytes(Vec<u8>),
+}
+
+struct Placed<'a> {
+    segment: Segment,
+    offset: u32,
+    item: Item<'a>,
+    line: &'a str,
+}
+
+#[derive(Default)]
+struct Assembler<'a> {
+    items: Vec<Placed<'a>>,
+    labels: Vec<(String, Segment, u32, &'a str)>,
+    equates: Vec<(String, i32, &'a str)>,
+    sizes: HashMap<Segment, u32>,
+    start: Option<(Expr, &'a str)>,
+}
+
+impl<'a> Assembler<'a> {
+    fn assemble(mut self, lines: &'a [(&'a str, syntax::Line)]) -> Result<Image, AsmError> {
+        // First pass: determine the location of every label and item
+        let mut segment = Segment::Code;
+        for (line, parsed) in lines {
+            let error = |message: String| AsmError { line: line.to_string(), message };
+
+            // Equates define a symbol with a constant value instead of an address
+            if let Some(operation) = parsed.operation.as_ref().filter(|x| x.mnemonic == ".equ") {
+                match (&parsed.label, operation.operands.as_slice()) {
+                    (Some(name), [Operand::Immediate(Expr::Number(value))]) => {
+                        self.equates.push((name.clone(), *value, line))
+                    }
+                    _ => return Err(error("expected `<name> .equ <value>`".to_string())),
+                }
+                continue;
+            }
+
+            if let Some(label) = &parsed.label {
+                let offset = self.offset(segment);
+                self.labels.push((label.clone(), segment, offset, line));
+            }
+            let operation = match &parsed.operation {
+                Some(operation) => operation,
+                None => continue,
+            };
+
+            match operation.mnemonic.as_str() {
+                ".seg" => {
+                    segment = match operation.operands.as_slice() {
+                        [Operand::Immediate(Expr::Symbol(name, 0))] => match name.as_str() {
+                            "code" | "text" => Segment::Code,
+                            "data" => Segment::Data,
+                            "constdata" => Segment::ConstData,
+                            other => return Err(error(format!("unknown segment `{}`", other))),
+                        },
+                        _ => return Err(error("expected segment name".to_string())),
+                    };
+                }
+                ".start" => match operation.operands.as_slice() {
+                    [Operand::Immediate(expr)] => self.start = Some((expr.clone(), line)),
+                    _ => return Err(error("expected start address".to_string())),
+                },
+                ".space" => match operation.operands.as_slice() {
+                    [Operand::Immediate(Expr::Number(n))] if *n >= 0 => {
+                        self.place(segment, Item::Bytes(vec![0; *n as usize]), line)
+                    }
+                    _ => return Err(error("expected size".to_string())),
+                },
+                ".align" => match operation.operands.as_slice() {
+                    [Operand::Immediate(Expr::Number(n))] if (0..16).contains(n) => {
+                        self.align(segment, 1 << n)
+                    }
+                    _ => return Err(error("expected alignment".to_string())),
+                },
+                ".ascii" | ".asciiz" => {
+                    let mut bytes = vec![];
+                    for operand in &operation.operands {
+                        match operand {
+                            Operand::Text(text) => {
+                                bytes.extend(syntax::unescape(text).map_err(error)?)
+                            }
+                            _ => return Err(error("expected string".to_string())),
+                        }
+                        if operation.mnemonic == ".asciiz" {
+                            bytes.push(0);
+                        }
+                    }
+                    self.place(segment, Item::Bytes(bytes), line);
+                }
+                ".byte" => self.place(segment, Item::Data(1, &operation.operands), line),
+                ".half" => {
+                    self.align(segment, 2);
+                    self.place(segment, Item::Data(2, &operation.operands), line)
+                }
+                ".word" => {
+                    self.align(segment, 4);
+                    self.place(segment, Item::Data(4, &operation.operands), line)
+                }
+                directive if directive.starts_with('.') => {
+                    return Err(error(format!("unknown directive `{}`", directive)))
+                }
+                _ => {
+                    self.align(segment, 4);
+                    self.place(segment, Item::Instruction(operation), line)
+                }
+            }
+        }
+
+        // Determine the base address of each of the segments
+        let mut bases = HashMap::new();
+        let mut next_base = CODE_BASE;
+        for segment in [Segment::Code, Segment::ConstData, Segment::Data] {
+            bases.insert(segment, next_base);
+            next_base = align_to(next_base + self.offset(segment), 8);
+        }
+
+        let mut symbols = vec![];
+        let mut values = HashMap::new();
+        let labels = self.labels.iter().map(|(name, segment, offset, line)| {
+            (name, bases[segment] + offset, Some(*segment), line)
+        });
+        let equates =
+            self.equates.iter().map(|(name, value, line)| (name, *value as u32, None, line));
+        for (name, value, segment, line) in labels.chain(equates) {
+            if values.insert(name.clone(), value).is_some() {
+                let message = format!("label `{}` defined multiple times", name);
+                return Err(AsmError { line: line.to_string(), message });
+            }
+            symbols.push(Symbol { name: name.clone(), value, segment });
+        }
+        symbols.sort_by_key(|x| x.value);
+
+        // Second pass: encode everything into the image
+        let mut bytes = vec![0; (next_base - CODE_BASE) as usize];
+        for placed in &self.items {
+            let error = |message: String| AsmError { line: placed.line.to_string(), message };
+            let addr = bases[&placed.segment] + placed.offset;
+            let start = (addr - CODE_BASE) as usize;
+            match &placed.item {
+                Item::Instruction(operation) => {
+                    let word = encode(operation, addr, &values).map_err(error)?;
+                    bytes[start..start + 4].copy_from_slice(&word.to_be_bytes());
+                }
+                Item::Data(width, operands) => {
+                    let width = *width as usize;
+                    for (i, operand) in operands.iter().enumerate() {
+                        let value = match operand {
+                            Operand::Immediate(expr) => resolve(expr, &values).map_err(error)?,
+                            _ => return Err(error("expected value".to_string())),
+                        };
+                        // DLX is big endian, so take the least significant bytes from the end
+                        let offset = start + i * width;
+                        bytes[offset..offset + width]
+                            .copy_from_slice(&value.to_be_bytes()[4 - width..]);
+                    }
+                }
+                Item::Bytes(data) => bytes[start..start + data.len()].copy_from_slice(data),
+            }
+        }
+
+        let start = match &self.start {
+            Some((expr, line)) => resolve(expr, &values)
+                .map_err(|message| AsmError { line: line.to_string(), message })?
+                as u32,
+            None => bases[&Segment::Code],
+        };
+
+        Ok(Image { base: CODE_BASE, bytes, start, symbols })
+    }
+
+    fn offset(&self, segment: Segment) -> u32 {
+        self.sizes.get(&segment).cloned().unwrap_or(0)
+    }
+
+    fn align(&mut self, segment: Segment, alignment: u32) {
+        let offset = self.offset(segment);
+        self.sizes.insert(segment, align_to(offset, alignment));
+    }
+
+    fn place(&mut self, segment: Segment, item: Item<'a>, line: &'a str) {
+        let offset = self.offset(segment);
+        let size = match &item {
+            Item::Instruction(..) => 4,
+            Item::Data(width, operands) => width * operands.len() as u32,
+            Item::Bytes(bytes) => bytes.len() as u32,
+        };
+        self.sizes.insert(segment, offset + size);
+        self.items.push(Placed { segment, offset, item, line });
+    }
+}
+
+fn align_to(value: u32, alignment: u32) -> u32 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn resolve(expr: &Expr, symbols: &HashMap<String, u32>) -> Result<i32, String> {
+    match expr {
+        Expr::Number(value) => Ok(*value),
+        Expr::Symbol(name, offset) => match symbols.get(name) {
+            Some(&addr) => Ok((addr as i32).wrapping_add(*offset)),
+            None => Err(format!("undefined symbol `{}`", name)),
+        },
+    }
+}
+
+/// Resolve an immediate value checking that it fits in 16 bits
+fn immediate(expr: &Expr, symbols: &HashMap<String, u32>, signed: bool) -> Result<u32, String> {
+    let value = resolve(expr, symbols)?;
+    match signed {
+        true if (i16::MIN as i32..=i16::MAX as i32).contains(&value) => Ok(value as u32),
+        false if (0..=u16::MAX as i32).contains(&value) => Ok(value as u32),
+        _ => Err(format!("immediate value `{}` ({}) out of range", expr, value)),
+    }
+}
+
+/// Encode a single instruction located at `addr`
+fn encode(operation: &Operation, addr: u32, symbols: &HashMap<String, u32>) -> Result<u32, String> {
+    use self::Operand::*;
+
+    let mnemonic = operation.mnemonic.as_str();
+    let operands = operation.operands.as_slice();
+    let invalid = || format!("invalid operands for `{}`", mnemonic);
+
+    // Loads and stores
+    let mem_offset = |expr: &Expr| immediate(expr, symbols, true);
+    let load = |opcode| match operands {
+        [Register(rd), Memory(offset, base)] => Ok(i_type(opcode, *base, *rd, mem_offset(offset)?)),
+        [Register(rd), Immediate(addr)] => Ok(i_type(opcode, 0, *rd, mem_offset(addr)?)),
+        _ => Err(invalid()),
+    };
+    let store = |opcode| match operands {
+        [Memory(offset, base), Register(rs)] => Ok(i_type(opcode, *base, *rs, mem_offset(offset)?)),
+        [Immediate(addr), Register(rs)] => Ok(i_type(opcode, 0, *rs, mem_offset(addr)?)),
+        _ => Err(invalid()),
+    };
+
+    // Arithmetic and logic operations
+    let alu = |opcode, func| match operands {
+        [Register(rd), Register(rs1), Register(rs2)] => Ok(r_type(opcode, func, *rs1, *rs2, *rd)),
+        _ => Err(invalid()),
+    };
+    let alu_imm = |opcode, signed| {
+        let (rd, rs1, value) = match operands {
+            [Register(rd), Register(rs1), Immediate(value)] => (*rd, *rs1, value),
+            // Short form where the destination is also the source
+            [Register(rd), Immediate(value)] => (*rd, *rd, value),
+            _ => return Err(invalid()),
+        };
+        Ok(i_type(opcode, rs1, rd, immediate(value, symbols, signed)?))
+    };
+
+    // Control flow, where the target is relative to the next instruction
+    let relative = |expr: &Expr, range: std::ops::RangeInclusive<i32>| {
+        let offset = resolve(expr, symbols)?.wrapping_sub(addr as i32 + 4);
+        match range.contains(&offset) {
+            true => Ok(offset as u32),
+            false => Err(format!("branch target `{}` out of range", expr)),
+        }
+    };
+    let branch = |opcode| match operands {
+        [Register(rs), Immediate(dest)] => {
+            Ok(i_type(opcode, *rs, 0, relative(dest, i16::MIN as i32..=i16::MAX as i32)?))
+        }
+        _ => Err(invalid()),
+    };
+    let jump = |opcode| match operands {
+        [Immediate(dest)] => Ok(j_type(opcode, relative(dest, JUMP_RANGE)?)),
+        _ => Err(invalid()),
+    };
+    let jump_reg = |opcode| match operands {
+        [Register(rs)] => Ok(i_type(opcode, *rs, 0, 0)),
+        _ => Err(invalid()),
+    };
+    let no_operands = |word| match operands {
+        [] => Ok(word),
+        _ => Err(invalid()),
+    };
+
+    match mnemonic {
+        "lb" => load(OP_LB),
+        "lbu" => load(OP_LBU),
+        "lh" => load(OP_LH),
+        "lhu" => load(OP_LHU),
+        "lw" => load(OP_LW),
+        "sb" => store(OP_SB),
+        "sh" => store(OP_SH),
+        "sw" => store(OP_SW),
+
+        "beqz" | "bf" => branch(OP_BEQZ),
+        "bnez" | "bt" => branch(OP_BNEZ),
+        "j" => jump(OP_J),
+        "jal" => jump(OP_JAL),
+        "jr" => jump_reg(OP_JR),
+        "jalr" => jump_reg(OP_JALR),
+
+        "lhi" => match operands {
+            [Register(rd), Immediate(value)] => {
+                Ok(i_type(OP_LHI, 0, *rd, immediate(value, symbols, false)?))
+            }
+            _ => Err(invalid()),
+        },
+
+        "add" => alu(OP_SPECIAL, FN_ADD),
+        "addu" => alu(OP_SPECIAL, FN_ADDU),
+        "sub" => alu(OP_SPECIAL, FN_SUB),
+        "subu" => alu(OP_SPECIAL, FN_SUBU),
+        "mult" => alu(OP_ARITH, FN_MULT),
+        "multu" => alu(OP_ARITH, FN_MULTU),
+        "div" => alu(OP_ARITH, FN_DIV),
+        "divu" => alu(OP_ARITH, FN_DIVU),
+        "and" => alu(OP_SPECIAL, FN_AND),
+        "or" => alu(OP_SPECIAL, FN_OR),
+        "xor" => alu(OP_SPECIAL, FN_XOR),
+        "sll" => alu(OP_SPECIAL, FN_SLL),
+        "srl" => alu(OP_SPECIAL, FN_SRL),
+        "sra" => alu(OP_SPECIAL, FN_SRA),
+        "seq" => alu(OP_SPECIAL, FN_SEQ),
+        "sne" => alu(OP_SPECIAL, FN_SNE),
+        "slt" => alu(OP_SPECIAL, FN_SLT),
+        "sgt" => alu(OP_SPECIAL, FN_SGT),
+        "sle" => alu(OP_SPECIAL, FN_SLE),
+        "sge" => alu(OP_SPECIAL, FN_SGE),
+        "sequ" => alu(OP_SPECIAL, FN_SEQU),
+        "sneu" => alu(OP_SPECIAL, FN_SNEU),
+        "sltu" => alu(OP_SPECIAL, FN_SLTU),
+        "sgtu" => alu(OP_SPECIAL, FN_SGTU),
+        "sleu" => alu(OP_SPECIAL, FN_SLEU),
+        "sgeu" => alu(OP_SPECIAL, FN_SGEU),
+
+        "addi" => alu_imm(OP_ADDI, true),
+        "addui" => alu_imm(OP_ADDUI, false),
+        "subi" => alu_imm(OP_SUBI, true),
+        "subui" => alu_imm(OP_SUBUI, false),
+        "andi" => alu_imm(OP_ANDI, false),
+        "ori" => alu_imm(OP_ORI, false),
+        "xori" => alu_imm(OP_XORI, false),
+        "slli" => alu_imm(OP_SLLI, false),
+        "srli" => alu_imm(OP_SRLI, false),
+        "srai" => alu_imm(OP_SRAI, false),
+        "seqi" => alu_imm(OP_SEQI, true),
+        "snei" => alu_imm(OP_SNEI, true),
+        "slti" => alu_imm(OP_SLTI, true),
+        "sgti" => alu_imm(OP_SGTI, true),
+        "slei" => alu_imm(OP_SLEI, true),
+        "sgei" => alu_imm(OP_SGEI, true),
+        "sequi" => alu_imm(OP_SEQUI, false),
+        "sneui" => alu_imm(OP_SNEUI, false),
+        "sltui" => alu_imm(OP_SLTUI, false),
+        "sgtui" => alu_imm(OP_SGTUI, false),
+        "sleui" => alu_imm(OP_SLEUI, false),
+        "sgeui" => alu_imm(OP_SGEUI, false),
+
+        "trap" => match operands {
+            [Immediate(id)] => match resolve(id, symbols)? {
+                id @ 0..=0x03FF_FFFF => Ok(j_type(OP_TRAP, id as u32)),
+                _ => Err(format!("trap number `{}` out of range", id)),
+            },
+            _ => Err(invalid()),
+        },
+        // `halt` is an alias for `trap 0`
+        "halt" => no_operands(j_type(OP_TRAP, 0)),
+        "nop" => no_operands(r_type(OP_SPECIAL, FN_NOP, 0, 0, 0)),
+
+        unknown => Err(format!("unknown instruction `{}`", unknown)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_text(text: &str) -> Result<Image, AsmError> {
+        assemble(&[Instruction::RawAsm(text.to_string())])
+    }
+
+    fn assemble_error(text: &str) -> String {
+        match assemble_text(text) {
+            Ok(..) => panic!("`{}` assembled without an error", text),
+            Err(error) => error.message,
+        }
+    }
+
+    fn word(image: &Image, addr: usize) -> u32 {
+        u32::from_be_bytes(image.bytes[addr..addr + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn encodes_instructions_of_each_format() {
+        let image =
+            assemble_text("        addi    r1,r2,5\n        add     r3,r1,r2\n        halt")
+                .unwrap();
+        assert_eq!(word(&image, 0), 0x2041_0005);
+        assert_eq!(word(&image, 4), 0x0022_1820);
+        assert_eq!(word(&image, 8), 0x4400_0000);
+    }
+
+    #[test]
+    fn encodes_branches_relative_to_the_next_instruction() {
+        let text = "\
+loop    addi    r1,r1,1
+        bnez    r1,loop
+        j       end
+        nop
+end     jal     loop";
+        let image = assemble_text(text).unwrap();
+        assert_eq!(word(&image, 4), i_type(OP_BNEZ, 1, 0, -8_i32 as u32));
+        assert_eq!(word(&image, 8), j_type(OP_J, 4));
+        assert_eq!(word(&image, 16), j_type(OP_JAL, -20_i32 as u32));
+    }
+
+    #[test]
+    fn rejects_targets_out_of_range() {
+        assert_eq!(
+            assemble_error("far     .equ    40000\n        beqz    r1,far"),
+            "branch target `far` out of range"
+        );
+
+        assert_eq!(
+            assemble_error("far     .equ    0x4000000\n        j       far"),
+            "branch target `far` out of range"
+        );
+
+        assert_eq!(
+            assemble_error("        addui   r1,r0,70000"),
+            "immediate value `70000` (70000) out of range"
+        );
+    }
+
+    #[test]
+    fn places_data_after_the_code() {
+        let text = "\
+size    .equ    300
+        .seg    data
+values  .word   size,-1
+        .half   7
+        .byte   1,2
+        .ascii  \"hi\"
+        .seg    code
+        addui   r1,r0,values+4";
+        let image = assemble_text(text).unwrap();
+
+        // The data segment starts at the next multiple of 8 after the code
+        assert_eq!(word(&image, 0), i_type(OP_ADDUI, 0, 1, 12));
+        assert_eq!(word(&image, 8), 300);
+        assert_eq!(word(&image, 12), 0xFFFF_FFFF);
+        assert_eq!(image.bytes[16..22], [0, 7, 1, 2, b'h', b'i']);
+
+        let symbol = |name: &str| {
+            let symbol = image.symbols.iter().find(|x| x.name == name).unwrap();
+            (symbol.value, symbol.segment)
+        };
+        assert_eq!(symbol("size"), (300, None));
+        assert_eq!(symbol("values"), (8, Some(Segment::Data)));
+    }
+
+    #[test]
+    fn writes_intel_hex_records() {
+        // Each segment is padded to a multiple of 8 bytes
+        let image = assemble_text("        halt").unwrap();
+        let expected = ":080000004400000000000000B4\n:0400000500000000F7\n:00000001FF\n";
+        assert_eq!(image.to_intel_hex(), expected);
+
+        // Addresses above 64 KiB need an extended linear address record
+        let image = Image { base: 0x1_0000, bytes: vec![0xAB], start: 0x1_0000, symbols: vec![] };
+        let expected = ":020000040001F9\n:01000000AB54\n:0400000500010000F6\n:00000001FF\n";
+        assert_eq!(image.to_intel_hex(), expected);
+    }
+}
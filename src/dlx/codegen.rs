@@ -1,362 +1,397 @@
-use std::collections::{
-    hash_map::Entry::{Occupied, Vacant},
-    HashMap,
-};
+use std::collections::HashMap;
 
 use crate::{
-    ast,
     dlx::asm::{self, Instruction, LabelId, RegId},
-    dlx::types::{self, Type, TypeTable},
-    error::{InputSpan, Logger},
+    dlx::console,
+    dlx::regalloc::{self, FIRST_VIRTUAL_REG},
+    dlx::syntax::{parse_line, parse_register, Expr},
+    error::{Diagnostic, FatalError, Logger},
+    ir::{self, Address, Argument, BlockId, Operand},
+    types,
 };
 
-use self::{Ident::*, IdentId::*, Location::*};
-
-const UNIT_TYPE: Type = types::Normal(0);
-const INT_TYPE: Type = types::Normal(1);
-const CHAR_TYPE: Type = types::Normal(2);
-const BOOL_TYPE: Type = types::Normal(3);
-
 // Special register that is always 0
 const ZERO_REG: RegId = 0;
 // Frame pointer register
-const FRAME_POINTER: RegId = 30;
+pub const FRAME_POINTER: RegId = 30;
 // Stack pointer register
-const STACK_POINTER: RegId = 14;
+pub const STACK_POINTER: RegId = 14;
 // Heap pointer register
-const _HEAP_POINTER: RegId = 15;
+pub const HEAP_POINTER: RegId = 15;
 // Return address register (set by jal)
-const RETURN_REG: RegId = 31;
-// Register used for storing the results of computations
-const RESULT_REG: RegId = 1;
-// Register used for temporary values
-const TEMP_REG: RegId = 2;
-// Register used for storing addresses
-const ADDR_REG: RegId = 3;
-// Register use for copying values
-const COPY_REG: RegId = 4;
+pub const RETURN_REG: RegId = 31;
+// Register used for return values and the results of inline assembly
+pub const RESULT_REG: RegId = 1;
+// Registers that the first word sized arguments of a call are passed in
+pub const ARG_REGS: [RegId; 4] = [2, 3, 4, 5];
+
+// Offsets from the frame pointer are signed 16 bit immediates, so a stack frame can't be larger than
+// this
+pub const MAX_FRAME_SIZE: u32 = 0x8000;
 
 const DATA_SEGMENT: &str = "        .seg    data";
 const CONST_DATA_SEGMENT: &str = "        .seg    constdata";
 const CODE_SEGMENT: &str = "        .seg    code";
 
-const PROGRAM_START: &str = "
-; Allocate some dynamic memory for the program to use
+/// Settings for the code that starts the program
+#[derive(Clone)]
+pub struct Runtime {
+    /// The number of bytes reserved for the stack
+    pub stack_size: u32,
+    /// The number of bytes reserved for the heap
+    pub heap_size: u32,
+    /// The function called once the stack and heap have been set up
+    pub entry: String,
+    /// The address or label to start execution at, instead of the start of the runtime code
+    pub start: Option<String>,
+    /// A function called with the value returned by the entry function before the machine halts
+    pub exit_hook: Option<String>,
+}
+
+impl Default for Runtime {
+    fn default() -> Runtime {
+        Runtime {
+            stack_size: 800,
+            heap_size: 800,
+            entry: "main".to_string(),
+            start: None,
+            exit_hook: None,
+        }
+    }
+}
+
+/// The labels defined by the program start code
+pub const RUNTIME_LABELS: &[&str] = &["prgsrt", "stack", "heap", "heapend"];
+
+/// The stack and heap must take up less than this many bytes in total. The start code loads their
+/// addresses with a 16 bit immediate, so they have to fit in the first 64 KiB of memory along with
+/// the rest of the program.
+pub const MAX_RUNTIME_MEMORY: u32 = 0x10000;
+
+/// Generate the code that sets up the stack and heap then calls the entry function
+fn program_start(runtime: &Runtime, hook_convention: ir::Convention) -> String {
+    let mut code = format!(
+        "
+; Allocate some dynamic memory for the program to use, starting on word boundaries
         .seg    data
-stack   .space  800
-heap    .space  800
+        .align  2
+stack   .space  {}
+        .align  2
+heap    .space  {}
+heapend                                 ; The end of the heap, which allocations can't pass
 
 ; Manually start the program
         .seg    code
-        .start  prgsrt
+        .start  {}
 
 prgsrt  addui   r14,r0,stack            ; Give the program a stack
         addui   r15,r0,heap             ; Give the program a heap
-        jal     main                    ; Jump to the program entry point
-        halt                            ; Stop the machine
-";
-
-pub struct Function {
-    ast: ast::FunctionDeclaration,
-    arg_types: Vec<Type>,
-    rtype: Type,
-    location: LabelId,
-}
-
-impl Function {
-    fn new(
-        ast: ast::FunctionDeclaration,
-        type_table: &TypeTable,
-        scope: &Scope,
-        location: LabelId,
-    ) -> Function {
-        let arg_types = ast.params.iter().map(|p| type_table.resolve_type(scope, &p.1)).collect();
-        let rtype = type_table.resolve_type(scope, &ast.rtype);
-        Function { ast, arg_types, rtype, location }
+        jal     {:<24}; Jump to the program entry point
+",
+        runtime.stack_size,
+        runtime.heap_size,
+        runtime.start.as_deref().unwrap_or("prgsrt"),
+        runtime.entry,
+    );
+
+    if let Some(hook) = &runtime.exit_hook {
+        // The result of the entry function is saved on the stack, then restored so that it is
+        // still available once the machine has halted. Hooks that take their arguments on the
+        // stack read it from there.
+        if hook_convention == ir::Convention::Register {
+            code.push_str(
+                "        addu    r2,r0,r1                ; Pass the result to the exit hook\n",
+            );
+        }
+        code.push_str(&format!(
+            "        sw      0(r14),r1               ; Save the result
+        addui   r14,r14,4
+        jal     {}
+        subui   r14,r14,4
+        lw      r1,0(r14)               ; Restore the result
+",
+            hook
+        ));
     }
-}
-
-#[derive(Clone, Debug)]
-enum Location {
-    Label(LabelId),
-    Offset(i16),
-    Register(RegId),
-}
 
-pub struct Variable {
-    ast: ast::LetStatement,
-    rtype: Type,
-    is_const: bool,
-    location: Location,
+    code.push_str("        halt                            ; Stop the machine\n");
+    code
 }
 
-impl Variable {
-    fn new(ast: ast::LetStatement, rtype: Type, location: Location, is_const: bool) -> Variable {
-        Variable { ast, rtype, is_const, location }
+/// Generate equates for the addresses of the memory mapped console registers
+fn console_equates() -> String {
+    let mut equates = "\n; Memory mapped console registers".to_string();
+    for (name, value) in console::SYMBOLS {
+        equates.push_str(&format!("\n{:<8}.equ    {}", name, value));
     }
+    equates
 }
 
-#[derive(Eq, PartialEq, Hash)]
-pub enum IdentId {
-    FnIdentId(usize),
-    VarIdentId(usize),
-}
+/// Generate code for a program. The program start code is only included if a runtime is given,
+/// otherwise the output can be linked with other code.
+pub fn codegen(
+    program: &ir::Program,
+    logger: &Logger,
+    runtime: Option<&Runtime>,
+) -> Vec<Instruction> {
+    if let Some(runtime) = runtime {
+        check_runtime(program, runtime, logger);
+    }
 
-pub enum Ident<'a> {
-    FnIdent(&'a Function),
-    VarIdent(&'a Variable),
-}
+    let mut data = CodeData {
+        logger,
+        instructions: vec![],
+        conventions: program
+            .functions
+            .iter()
+            .map(|function| (function.name.clone(), function.convention))
+            .collect(),
+        label_count: 0,
+        const_mem: false,
+        next_vreg: FIRST_VIRTUAL_REG,
+        frame: FrameLayout::default(),
+        clobbers: vec![],
+    };
 
-impl<'a> Ident<'a> {
-    pub fn rtype(&self) -> Type {
-        match *self {
-            FnIdent(func) => func.rtype.clone(),
-            VarIdent(var) => var.rtype.clone(),
-        }
+    data.instructions.push(asm::RawAsm(DATA_SEGMENT.to_string()));
+    // Compile global variables
+    for global in &program.globals {
+        data.compile_global_var(global);
     }
 
-    fn unwrap_var(self) -> &'a Variable {
-        match self {
-            FnIdent(..) => panic!("ICE attempted to unwrap function when attempting to get var"),
-            VarIdent(var) => var,
-        }
+    if let Some(runtime) = runtime {
+        data.instructions.push(asm::RawAsm(console_equates()));
+        let hook = runtime.exit_hook.as_ref().and_then(|hook| data.conventions.get(hook));
+        let hook_convention = hook.copied().unwrap_or(ir::Convention::Register);
+        data.instructions.push(asm::RawAsm(program_start(runtime, hook_convention)));
+    }
+    else {
+        data.instructions.push(asm::RawAsm(CODE_SEGMENT.to_string()));
+    }
+    // Compile global functions
+    for function in &program.functions {
+        data.compile_function(function);
     }
-}
 
-pub struct Scope<'a> {
-    functions: Vec<Function>,
-    vars: Vec<Variable>,
-    next_offset: i16,
-    ident_table: HashMap<String, IdentId>,
-    loop_ends: Vec<LabelId>,
-    end_label: LabelId,
-    parent: Option<&'a Scope<'a>>,
+    data.instructions
 }
 
-impl<'a> Scope<'a> {
-    pub fn new(end_label: LabelId) -> Scope<'a> {
-        Scope {
-            functions: vec![],
-            vars: vec![],
-            // Note: first avalible offset is 8, (the first 8 bytes store the frame pointer of prev
-            // stack frame, and return location).
-            next_offset: 8,
-            ident_table: HashMap::new(),
-            loop_ends: vec![],
-            end_label,
-            parent: None,
+/// Check that the stack and heap fit in the memory that the start code can address, and that the
+/// functions called by the program start code exist and have the right signatures
+fn check_runtime(program: &ir::Program, runtime: &Runtime, logger: &Logger) {
+    let total = runtime.stack_size as u64 + runtime.heap_size as u64;
+    if total >= MAX_RUNTIME_MEMORY as u64 {
+        let diagnostic =
+            Diagnostic::error("the stack and heap are too large".to_string(), program.span)
+                .code("E0514")
+                .note(format!(
+                "the stack and heap take up {} bytes, but must take up less than {} bytes in total",
+                total, MAX_RUNTIME_MEMORY
+            ))
+                .help("use a smaller stack or heap size".to_string());
+        logger.report(diagnostic);
+    }
+
+    let entry = match program.function(&runtime.entry) {
+        Some(function) => function,
+        None => {
+            let diagnostic = Diagnostic::error(
+                format!("program has no `{}` function", runtime.entry),
+                program.span,
+            )
+            .code("E0501")
+            .note(format!(
+                "the program start code calls `{}` after setting up the stack",
+                runtime.entry
+            ));
+            logger.report(diagnostic);
+            FatalError::raise();
         }
-    }
-
-    fn new_with_parent(parent: &'a Scope<'a>, end_label: LabelId) -> Scope<'a> {
-        let mut scope = Scope::new(end_label);
-        scope.parent = Some(parent);
-        scope
-    }
-
-    /// Add an identifier to the scope
-    fn add_ident(&mut self, ident_name: String, ident: IdentId, _span: InputSpan) {
-        match self.ident_table.entry(ident_name) {
-            Vacant(entry) => {
-                entry.insert(ident);
+    };
+    if !entry.params.is_empty() {
+        let diagnostic = Diagnostic::error(
+            format!("the entry function `{}` cannot take parameters", runtime.entry),
+            entry.span,
+        )
+        .code("E0506");
+        logger.report(diagnostic);
+    }
+
+    if let Some(hook) = &runtime.exit_hook {
+        match program.function(hook) {
+            Some(function) if takes_single_int(function) => {}
+            Some(function) => {
+                let diagnostic = Diagnostic::error(
+                    format!("the exit hook `{}` must take a single `int` parameter", hook),
+                    function.span,
+                )
+                .code("E0506")
+                .note("the hook is passed the value returned by the entry function".to_string());
+                logger.report(diagnostic);
             }
-            // This identifier shadows an existing one. Variable shadowing is not supported.
-            Occupied(..) => panic!("IDENT_SHADOW_ERROR, TODO: improve error message"),
-        }
-    }
-
-    /// Get the identifier corresponding to an identifier name.
-    pub fn get_ident(&self, ident_name: &String, span: InputSpan) -> Ident {
-        match self.ident_table.get(ident_name) {
-            Some(&FnIdentId(id)) => FnIdent(&self.functions[id]),
-            Some(&VarIdentId(id)) => VarIdent(&self.vars[id]),
             None => {
-                // If the identifier was not found in this scope, check the parent scope.
-                match self.parent {
-                    Some(parent) => parent.get_ident(ident_name, span),
-                    None => {
-                        // Reached the top level scope, but still could not find the identifier
-                        // therefore it doesn't not exist at this location.
-
-                        panic!(
-                            "IDENT_NOT_FOUND_ERROR, ({}), TODO: improve error message {:?}",
-                            ident_name, span,
-                        );
-                    }
-                }
+                let diagnostic =
+                    Diagnostic::error(format!("program has no `{}` function", hook), program.span)
+                        .code("E0501")
+                        .note(format!("`{}` is used as the exit hook", hook));
+                logger.report(diagnostic);
             }
         }
     }
 }
 
-pub fn codegen<'a>(
-    program: ast::Program,
-    logger: &'a Logger<'a>,
-    add_prog_start: bool,
-) -> Vec<Instruction> {
-    let mut global = Scope::new("exit".to_string());
-    let mut data = CodeData {
-        instructions: vec![],
-        type_table: types::typegen(&program),
-        label_count: 0,
-        logger,
-        add_to_address: false,
-        const_mem: false,
-    };
+fn takes_single_int(function: &ir::Function) -> bool {
+    matches!(function.params[..], [ir::Parameter { ty: Some(ir::Type::Int), .. }])
+}
 
-    // Parse globals
-    for item in program.items {
-        match item {
-            ast::FunctionItem(fn_item) => {
-                let id = FnIdentId(global.functions.len());
-                let name = fn_item.name.clone();
-                let label = name.clone();
-                global.add_ident(name, id, fn_item.span);
-                let function = Function::new(fn_item, &data.type_table, &global, label);
-                global.functions.push(function);
+/// The offsets of the slots and parameters of the current function from the frame pointer
+#[derive(Default)]
+struct FrameLayout {
+    slots: Vec<i16>,
+    /// The offset of each parameter that is kept in memory
+    params: Vec<i16>,
+    /// The register each parameter is passed in, or `None` if it is passed on the stack
+    param_regs: Vec<Option<RegId>>,
+    /// The number of bytes used by the frame pointer, return address, slots and parameters
+    size: u16,
+}
+
+impl FrameLayout {
+    /// Lay out the stack frame of a function, or give the number of bytes it needs if that is more
+    /// than `MAX_FRAME_SIZE`
+    fn new(function: &ir::Function) -> Result<FrameLayout, u32> {
+        // Note: the first 8 bytes store the frame pointer of prev stack frame, and return location
+        let mut size: u32 = 8;
+        let mut slots = Vec::with_capacity(function.slots.len());
+        for &slot_size in &function.slots {
+            slots.push(size);
+            size += slot_size as u32;
+        }
+
+        let param_types: Vec<Option<ir::Type>> = function.params.iter().map(|x| x.ty).collect();
+        let param_regs = arg_registers(function.convention, &param_types);
+
+        // The params passed on the stack are stored in negative offset before the frame pointer
+        // with the last param stored at FRAME_POINTER[-1]. Params passed in registers that must be
+        // kept in memory are stored after the slots.
+        let mut next_param_addr = 0;
+        let mut params = vec![0; function.params.len()];
+        for (i, param) in function.params.iter().enumerate().rev() {
+            if param_regs[i].is_none() {
+                next_param_addr -= param.size as i16;
+                params[i] = next_param_addr;
             }
-            ast::LetItem(let_item) => {
-                let id = VarIdentId(global.vars.len());
-                let name = let_item.name.clone();
-                let label = name.clone();
-                global.add_ident(name, id, let_item.span);
-                let rtype = data.resolve_type(&global, &let_item.var_type);
-                let is_const = let_item.is_const;
-                global.vars.push(Variable::new(let_item, rtype, Label(label), is_const));
+        }
+        for (i, param) in function.params.iter().enumerate() {
+            if param_regs[i].is_some() && param.home.is_none() {
+                params[i] = size as i16;
+                size += 4;
             }
-
-            // Handled by type gen
-            ast::StructItem(..) => {}
         }
-    }
-
-    data.instructions.push(asm::RawAsm(DATA_SEGMENT.to_string()));
-    // Compile global variables
-    for i in 0..global.vars.len() {
-        data.compile_global_var(&global, i);
-    }
 
-    if add_prog_start {
-        data.instructions.push(asm::RawAsm(PROGRAM_START.to_string()));
-    }
-    else {
-        data.instructions.push(asm::RawAsm(CODE_SEGMENT.to_string()));
-    }
-    // Compile global functions
-    for i in 0..global.functions.len() {
-        data.compile_global_fn(&global, i);
+        // Every offset in the frame is below its size, so they all fit in an `i16` if it does
+        if size > MAX_FRAME_SIZE {
+            return Err(size);
+        }
+        let slots = slots.into_iter().map(|offset| offset as i16).collect();
+        Ok(FrameLayout { slots, params, param_regs, size: size as u16 })
     }
+}
 
-    data.instructions
+/// Choose the register that each argument of a call is passed in. Word sized arguments are passed
+/// in the argument registers in order until they run out, and everything else is passed on the
+/// stack.
+fn arg_registers(convention: ir::Convention, types: &[Option<ir::Type>]) -> Vec<Option<RegId>> {
+    let mut free = ARG_REGS.iter().copied();
+    types
+        .iter()
+        .map(|ty| match (convention, ty) {
+            (ir::Convention::Register, Some(..)) => free.next(),
+            _ => None,
+        })
+        .collect()
 }
 
 struct CodeData<'a> {
+    logger: &'a Logger,
     instructions: Vec<Instruction>,
-    type_table: TypeTable,
+    /// How each function in the program takes its arguments
+    conventions: HashMap<String, ir::Convention>,
     label_count: usize,
-    logger: &'a Logger<'a>,
-    add_to_address: bool,
     const_mem: bool,
+    next_vreg: RegId,
+    frame: FrameLayout,
+    /// The registers modified by the inline assembly in the current function
+    clobbers: Vec<RegId>,
 }
 
 impl<'a> CodeData<'a> {
-    /// Codegen experienced a fatal error which must kill the program
-    fn fatal_error(&self) -> ! {
-        panic!();
-    }
-
     /// Generating a unique label id
     fn next_unique_id(&mut self) -> usize {
         self.label_count += 1;
         self.label_count - 1
     }
 
+    /// Create a label for the compiler's own use. Labels contain a `$`, which names in a program
+    /// can't, so that they can't clash with the labels of functions and globals.
     fn anon_label(&mut self) -> LabelId {
-        format!("a{}", self.next_unique_id())
+        format!("L${}", self.next_unique_id())
+    }
+
+    /// Create a new virtual register, which is assigned a machine register by the allocator
+    fn new_vreg(&mut self) -> RegId {
+        self.next_vreg += 1;
+        self.next_vreg - 1
+    }
+
+    /// Move the value of one register to another
+    fn move_reg(&mut self, to: RegId, from: RegId) {
+        if to != from {
+            self.instructions.push(asm::AddUnsigned(to, from, ZERO_REG));
+        }
     }
 
     /// Compile a global variable
-    fn compile_global_var(&mut self, scope: &Scope, var_id: usize) {
-        let is_const = scope.vars[var_id].is_const;
-        if is_const != self.const_mem {
-            if is_const {
+    fn compile_global_var(&mut self, global: &ir::Global) {
+        if global.is_const != self.const_mem {
+            if global.is_const {
                 self.instructions.push(asm::RawAsm(CONST_DATA_SEGMENT.to_string()));
             }
             else {
                 self.instructions.push(asm::RawAsm(DATA_SEGMENT.to_string()));
             }
-            self.const_mem = is_const;
+            self.const_mem = global.is_const;
         }
 
-        // Add the variable's label
-        let label = match scope.vars[var_id].location {
-            Label(ref s) => s.clone(),
-            ref other => panic!("ICE: Location of global var is not a label, was {:?}", other),
-        };
-        self.instructions.push(asm::Label(label));
-
-        // Allocate and initialize the variable
-        let rtype = self.resolve_type(scope, &scope.vars[var_id].ast.var_type);
-        match scope.vars[var_id].ast.assignment {
-            // Initialized variables
-            Some(ref expr) => {
-                let rhs_expr: &ast::Expr = match *expr.rhs.expr {
-                    ast::CastExpr(ref inner) => &inner.expr,
-                    ref other => other,
-                };
-
-                match *rhs_expr {
-                    ast::LitNumExpr(value) => {
-                        self.instructions.push(asm::AllocateWords(vec![value as i32]));
-                    }
-
-                    ast::StaticArrayExpr(ref inner) => {
-                        match *inner.elements[0].expr {
-                            // Array of integers
-                            ast::LitNumExpr(..) => {
-                                let mut unwrapped = vec![];
-                                for element in &inner.elements {
-                                    match *element.expr {
-                                        ast::LitNumExpr(n) => unwrapped.push(n as i32),
-                                        ref _invalid => panic!("Array has not literal value"),
-                                    }
-                                }
-                                self.instructions.push(asm::AllocateWords(unwrapped));
-                            }
-                            ref invalid => {
-                                panic!("Unable to statically resolve expression: `{:?}`", invalid)
-                            }
-                        }
-                    }
-
-                    ast::LitStringExpr(ref value) => {
-                        self.instructions.push(asm::AllocateAscii(value.clone()));
-                        if value.len() % 4 != 0 {
-                            self.instructions.push(asm::Align(2));
-                        }
-                    }
-
-                    // TODO: Handle other types of static data
-                    ref invalid => {
-                        println!("{:?}", invalid);
-                        unimplemented!();
-                    }
-                }
+        // Add the variable's label, then allocate and initialize the variable
+        self.instructions.push(asm::Label(global.name.clone()));
+        match global.init {
+            ir::Initializer::Words(ref values) => {
+                self.instructions.push(asm::AllocateWords(values.clone()))
             }
-            // Uninitialized variables
-            None => {
-                let size = self.size_of(&rtype) as u32;
-                self.instructions.push(asm::AllocateSpace(size));
+            ir::Initializer::Ascii(ref value) => {
+                // Note: the length of the string in the source doesn't match the number of bytes
+                // allocated when there are escape characters, so always realign
+                self.instructions.push(asm::AllocateAscii(value.clone()));
+                self.instructions.push(asm::Align(2));
             }
+            ir::Initializer::Zeroed(size) => self.instructions.push(asm::AllocateSpace(size)),
         }
     }
 
-    /// Compile a global function.
-    fn compile_global_fn(&mut self, scope: &Scope, fn_id: usize) {
+    /// Compile a function.
+    fn compile_function(&mut self, function: &ir::Function) {
         // Add the functions label
-        let label = scope.functions[fn_id].location.clone();
-        let span = scope.functions[fn_id].ast.span;
-        self.instructions.push(asm::Label(label));
+        let fn_start = self.instructions.len();
+        self.instructions.push(asm::Label(function.name.clone()));
+
+        // Each temporary is given its own virtual register, and any extra registers needed are
+        // numbered after them
+        self.next_vreg = FIRST_VIRTUAL_REG + function.temps.len();
+        self.frame = match FrameLayout::new(function) {
+            Ok(frame) => frame,
+            Err(size) => return self.frame_too_large(function, size),
+        };
 
         // Store caller's frame pointer and set current frame pointer
         self.instructions.push(asm::Store32(asm::Const(0), STACK_POINTER, FRAME_POINTER));
@@ -365,47 +400,45 @@ impl<'a> CodeData<'a> {
         // Store return location (this should be done by caller)
         self.instructions.push(asm::Store32(asm::Const(4), FRAME_POINTER, RETURN_REG));
 
-        // Create a local scope for this function
-        let mut local = Scope::new_with_parent(scope, self.anon_label());
-
-        // Register function parameters as local variables
-        // The input params are stored in negative offset before the frame pointer with the last
-        // param stored at FRAME_POINTER[-1]
-        let mut next_param_addr = 0_i32;
-        for &(ref name, ref var_type) in scope.functions[fn_id].ast.params.iter().rev() {
-            let var_ast = ast::LetStatement {
-                name: name.clone(),
-                var_type: var_type.clone(),
-                assignment: None,
-                is_const: false,
-                span,
-            };
-            let rtype = self.resolve_type(scope, &var_ast.var_type);
-            next_param_addr -= self.size_of(&rtype) as i32;
-            let var = Variable::new(var_ast, rtype, Offset(next_param_addr as i16), false);
-
-            let id = VarIdentId(local.vars.len());
-            local.add_ident(name.clone(), id, span);
-            local.vars.push(var);
-        }
-
         // Reserve stack space for the function:
-        // Note: since the stack space required is unknown at this point the total memory required
-        // for the function is unknown, so the instruction is set to Nop, and changed after the
-        // function has been fully compiled. The variable reserve_stack_index keeps track of the
-        // index to the value we need to change.
+        // Note: the stack space required is unknown until registers have been allocated, so the
+        // instruction is set to Nop, and changed by the allocator.
         let reserve_stack_index = self.instructions.len();
         self.instructions.push(asm::Nop);
 
-        // Compile the body of the function
-        self.compile_block(&mut local, &scope.functions[fn_id].ast.body);
+        // Move the parameters to where they are kept in this function
+        for (i, param) in function.params.iter().enumerate() {
+            match (self.frame.param_regs[i], param.home, param.ty) {
+                (Some(reg), Some(home), _) => self.move_reg(vreg(home), reg),
+                (Some(reg), None, Some(ty)) => {
+                    self.store(ty, self.frame.params[i], FRAME_POINTER, reg)
+                }
+                (None, Some(home), Some(ty)) => {
+                    self.load(ty, vreg(home), self.frame.params[i], FRAME_POINTER)
+                }
+                _ => {}
+            }
+        }
 
-        // Now set the amount of stack space to allocate
-        let frame_size = local.next_offset;
-        self.instructions[reserve_stack_index] =
-            asm::AddUnsignedValue(STACK_POINTER, STACK_POINTER, frame_size as u16);
+        // Compile the blocks in order, so that jumps to the next block can be left out
+        let labels: Vec<LabelId> = function.blocks.iter().map(|_| self.anon_label()).collect();
+        let end_label = self.anon_label();
+        let targets: Vec<BlockId> = (0..function.blocks.len())
+            .flat_map(|id| jump_targets(id, &function.blocks[id].terminator))
+            .collect();
+        for (id, block) in function.blocks.iter().enumerate() {
+            if targets.contains(&id) {
+                self.instructions.push(asm::Label(labels[id].clone()));
+            }
+            for instruction in &block.instructions {
+                self.compile_instruction(instruction);
+            }
+            let is_last = id + 1 == function.blocks.len();
+            self.compile_terminator(id, &block.terminator, &labels, &end_label, is_last);
+        }
 
-        self.instructions.push(asm::Label(local.end_label.clone()));
+        let epilogue_index = self.instructions.len();
+        self.instructions.push(asm::Label(end_label));
 
         // Remove this stack frame, and return to the previous one
         self.instructions.push(asm::Load32(RETURN_REG, asm::Const(4), FRAME_POINTER));
@@ -415,700 +448,462 @@ impl<'a> CodeData<'a> {
 
         // Insert a new line to make the output nicer to read
         self.instructions.push(asm::RawAsm("".to_string()));
-    }
 
-    fn compile_block(&mut self, scope: &mut Scope, block: &ast::Block) {
-        for statement in &block.statements {
-            self.compile_expression(scope, statement);
-        }
-    }
-
-    fn compile_expression(&mut self, scope: &mut Scope, expression: &ast::Expression) {
-        let span = expression.span;
-        match *expression.expr {
-            ast::RefExpr(ref inner) => {
-                let valid_address = self.compile_address(scope, inner);
-                if !valid_address {
-                    self.logger.report_error(
-                        "Cannot take reference to target expression".to_string(),
-                        span,
-                    );
-                    self.fatal_error();
+        // Now that the code is complete, assign registers to the function. This also sets the
+        // amount of stack space to allocate.
+        let frame = regalloc::Frame {
+            reserve_index: reserve_stack_index - fn_start,
+            epilogue_index: epilogue_index - fn_start,
+            size: self.frame.size,
+            clobbers: std::mem::take(&mut self.clobbers),
+        };
+        let code = self.instructions.split_off(fn_start);
+        match regalloc::allocate(code, frame) {
+            Ok(code) => self.instructions.extend(code),
+            Err(size) => self.frame_too_large(function, size),
+        }
+    }
+
+    /// Report a function whose stack frame needs `size` bytes, which is more than the offsets from
+    /// the frame pointer can reach
+    fn frame_too_large(&self, function: &ir::Function, size: u32) {
+        let diagnostic = Diagnostic::error(
+            format!("the stack frame of `{}` is too large", function.name),
+            function.span,
+        )
+        .code("E0513")
+        .note(format!(
+            "the frame takes up {} bytes, but can take up at most {} bytes",
+            size, MAX_FRAME_SIZE
+        ))
+        .help("allocate large arrays with `new`, or make them global".to_string());
+        self.logger.report(diagnostic);
+    }
+
+    fn compile_terminator(
+        &mut self,
+        id: BlockId,
+        terminator: &ir::Terminator,
+        labels: &[LabelId],
+        end_label: &LabelId,
+        is_last: bool,
+    ) {
+        let next = id + 1;
+        match *terminator {
+            ir::Branch { cond: ir::Temp(cond), then_block, else_block }
+                if then_block != else_block =>
+            {
+                if else_block == next {
+                    self.instructions
+                        .push(asm::JumpIfNotZero(vreg(cond), labels[then_block].clone()));
+                    return;
+                }
+                self.instructions.push(asm::JumpIfZero(vreg(cond), labels[else_block].clone()));
+                if then_block != next {
+                    self.instructions.push(asm::Jump(labels[then_block].clone()));
                 }
             }
-            ast::DerefExpr(ref inner) => {
-                // Check that we can dereference the expression
-                let inner_type = self.resolve_type(scope, &inner.rtype);
-                match inner_type {
-                    types::Pointer(..) => {
-                        // Evaluate the inner expression
-                        self.compile_expression(scope, inner);
-
-                        // Then dereference it
-                        self.load_var(inner_type.deref(), &Register(RESULT_REG));
-                    }
-                    types::StaticArray(..) => {
-                        // Evaluate the inner expression
-                        self.compile_expression(scope, inner);
-                        // Since this is a static array we don't need to dereference it
-                    }
-                    _invalid => {
-                        self.logger.report_error(
-                            format!("type `{:?}` cannot be dereferenced", "FIXME"),
-                            span,
-                        );
-                        self.fatal_error();
-                    }
+            ir::Return(value) => {
+                if let Some(value) = value {
+                    self.operand_to(RESULT_REG, value);
+                }
+                // The last block falls through to the end of the function
+                if !is_last {
+                    self.instructions.push(asm::Jump(end_label.clone()));
                 }
             }
-            ast::FieldRefExpr(ref inner) => {
-                self.compile_field_ref(scope, inner);
-                let inner_type = self.resolve_type(scope, &expression.rtype);
-                self.load_var(&inner_type, &Register(RESULT_REG));
+            _ => {
+                let target = match *terminator {
+                    ir::Branch { cond: ir::Const(0), else_block, .. } => else_block,
+                    ir::Branch { then_block, .. } | ir::Jump(then_block) => then_block,
+                    ir::Return(..) => unreachable!(),
+                };
+                if target != next {
+                    self.instructions.push(asm::Jump(labels[target].clone()));
+                }
             }
-            ast::ArrayIndexExpr(ref inner) => {
-                self.compile_array_index(scope, inner);
-                let inner_type = self.resolve_type(scope, &expression.rtype);
-                self.load_var(&inner_type, &Register(RESULT_REG));
+        }
+    }
+
+    fn compile_instruction(&mut self, instruction: &ir::Instruction) {
+        match *instruction {
+            ir::Copy { dst, src } => self.operand_to(vreg(dst), src),
+            ir::Binary { op, dst, lhs, rhs } => self.compile_binary(op, vreg(dst), lhs, rhs),
+            ir::Unary { op: ir::UnaryOp::Neg, dst, src } => {
+                let src = self.operand(src);
+                self.instructions.push(asm::SubSigned(vreg(dst), ZERO_REG, src));
+            }
+            ir::Unary { op: ir::UnaryOp::Not, dst, src } => {
+                let src = self.operand(src);
+                self.instructions.push(asm::SetEqSignedValue(vreg(dst), src, 0));
             }
-            ast::IfExpr(ref inner) => self.compile_if(scope, inner),
-            ast::ForLoopExpr(ref inner) => self.compile_for(scope, inner),
-            ast::LoopExpr(ref inner) => self.compile_loop(scope, inner),
-            ast::CallExpr(ref inner) => self.compile_call(scope, inner),
-            ast::Break => match scope.loop_ends.last() {
-                Some(label) => self.instructions.push(asm::Jump(label.clone())),
-                None => {
-                    self.logger.report_error("`break` outside of loop".to_string(), span);
-                    self.fatal_error();
+            ir::AddressOf { dst, ref src } => {
+                let (base, offset) = self.address(src);
+                match offset {
+                    0 => self.move_reg(vreg(dst), base),
+                    offset => self.instructions.push(asm::AddSignedValue(vreg(dst), base, offset)),
                 }
-            },
-            ast::Return(ref inner) => {
-                self.compile_expression(scope, inner);
-                let return_label = scope.end_label.clone();
-                self.instructions.push(asm::Jump(return_label));
             }
-            ast::LetExpr(ref inner) => self.compile_let(scope, inner),
-            ast::AssignExpr(ref inner) => self.compile_assign(scope, inner),
-            ast::VariableExpr(ref name) => {
-                let var = scope.get_ident(name, span).unwrap_var();
-                self.load_var(&var.rtype, &var.location);
+            ir::Load { dst, ty, ref src, align } if align < ty.size() => {
+                let (base, offset) = self.address(src);
+                self.load_unaligned(vreg(dst), offset, base);
             }
-            ast::StaticArrayExpr(ref inner) => self.compile_static_array(scope, inner),
-            ast::LitStringExpr(ref inner) => {
-                // Convert the string into a byte array
-                // FIXME: this should happen in the lexer/parser
-                let mut bytes = vec![];
-
-                // The remaining characters left to parse in this expression
-                let mut rem: &str = inner;
-
-                while let Some(mut next) = rem.chars().next() {
-                    rem = &rem[next.len_utf8()..];
-                    if next == '\\' {
-                        let escaped = rem.chars().next().expect("ICE, end of string was escaped");
-                        rem = &rem[escaped.len_utf8()..];
-
-                        match escaped {
-                            'n' => next = '\n',
-                            '0' => next = '\0',
-                            invalid => panic!("Invalid escape char: {}", invalid),
-                        }
-                    }
-
-                    let expression = ast::Expression {
-                        expr: Box::new(ast::LitCharExpr(next)),
-                        rtype: ast::Primitive(ast::CharType),
-                        span,
-                    };
-                    bytes.push(expression);
-                }
-
-                let static_array = ast::StaticArray { elements: bytes, span };
-                self.compile_static_array(scope, &static_array);
+            ir::Load { dst, ty, ref src, .. } => {
+                let (base, offset) = self.address(src);
+                self.load(ty, vreg(dst), offset, base);
             }
-            ast::StructInitExpr(ref inner) => self.compile_struct_init(scope, inner),
-            ast::LitNumExpr(value) => {
-                self.instructions.push(asm::AddSignedValue(RESULT_REG, ZERO_REG, value as i16));
+            ir::Store { ref dst, ty, src, align } if align < ty.size() => {
+                let value = self.operand(src);
+                let (base, offset) = self.address(dst);
+                self.store_unaligned(offset, base, value);
             }
-            ast::LitCharExpr(value) => {
-                self.instructions.push(asm::AddSignedValue(RESULT_REG, ZERO_REG, value as i16));
+            ir::Store { ref dst, ty, src, .. } => {
+                let value = self.operand(src);
+                let (base, offset) = self.address(dst);
+                self.store(ty, offset, base, value);
             }
-            ast::AsmOpExpr(ref inner) => {
-                self.instructions.push(asm::RawAsm(inner.clone()));
+            ir::CopyMemory { ref dst, ref src, size, align } => {
+                let (src, src_offset) = self.address(src);
+                let (dst, dst_offset) = self.address(dst);
+                self.copy_memory(dst, dst_offset, src, src_offset, size, align);
             }
-            ast::CastExpr(ref inner) => self.compile_expression(scope, inner),
-            ast::EmptyExpr => {}
-        }
-    }
-
-    fn compile_field_ref(&mut self, scope: &mut Scope, field_ref: &ast::FieldRef) {
-        self.compile_expression(scope, &field_ref.target);
-
-        let target_type = self.resolve_type(scope, &field_ref.target.rtype);
-        let target_base_type = self.type_table.base_type(&target_type);
+            ir::Call { dst, ref function, ref args } => {
+                // Write the arguments that aren't passed in registers to the stack, where they
+                // become the parameters of the called function
+                let convention = self.conventions[function];
+                let arg_types: Vec<Option<ir::Type>> = args
+                    .iter()
+                    .map(|arg| match *arg {
+                        Argument::Value(_, ty) => Some(ty),
+                        Argument::Memory(..) => None,
+                    })
+                    .collect();
+                let arg_regs = arg_registers(convention, &arg_types);
+                let mut stack_offset = 0;
+                for (arg, reg) in args.iter().zip(&arg_regs) {
+                    match *arg {
+                        Argument::Value(..) if reg.is_some() => {}
+                        Argument::Value(value, ty) => {
+                            let value = self.operand(value);
+                            self.store(ty, stack_offset, STACK_POINTER, value);
+                            stack_offset += 4;
+                        }
+                        Argument::Memory(address, size, align) => {
+                            // Arguments are padded to a whole number of words
+                            let address = self.operand(address);
+                            self.copy_memory(STACK_POINTER, stack_offset, address, 0, size, align);
+                            stack_offset += types::align(size) as i16;
+                        }
+                    }
+                }
 
-        let (field_offset, _) = self.find_field(target_base_type, &field_ref.field, field_ref.span);
+                if stack_offset != 0 {
+                    self.instructions.push(asm::AddUnsignedValue(
+                        STACK_POINTER,
+                        STACK_POINTER,
+                        stack_offset as u16,
+                    ));
+                }
 
-        // Add the offset to the target address
-        self.instructions.push(asm::AddUnsignedValue(RESULT_REG, RESULT_REG, field_offset));
-    }
+                // The argument registers are set last, directly before the call, so that they
+                // aren't needed for anything else while they hold the arguments
+                for (arg, reg) in args.iter().zip(&arg_regs) {
+                    if let (&Argument::Value(value, _), &Some(reg)) = (arg, reg) {
+                        self.operand_to(reg, value);
+                    }
+                }
+                self.instructions.push(asm::JumpStore(function.clone()));
+                // Restore the stack
+                if stack_offset != 0 {
+                    self.instructions.push(asm::SubUnsignedValue(
+                        STACK_POINTER,
+                        STACK_POINTER,
+                        stack_offset as u16,
+                    ));
+                }
 
-    fn find_field(
-        &self,
-        target_type: &types::BaseType,
-        field: &String,
-        span: InputSpan,
-    ) -> (u16, types::Type) {
-        match *target_type {
-            types::Composite(ref inner) => match inner.fields.get(field) {
-                Some(&(offset, ref type_)) => (offset, type_.clone()),
-                None => {
-                    self.logger.report_error(
-                        format!("type `{:?}` has no field {:?}", target_type, field),
-                        span,
-                    );
-                    self.fatal_error();
+                if let Some(dst) = dst {
+                    self.move_reg(vreg(dst), RESULT_REG);
                 }
-            },
-            ref invalid => {
-                self.logger
-                    .report_error(format!("type `{:?}` has no field {:?}", invalid, field), span);
-                self.fatal_error();
             }
-        }
-    }
-
-    fn compile_array_index(&mut self, scope: &mut Scope, index_expr: &ast::ArrayIndex) {
-        // Check that the type that we are indexing can be indexed
-        let target_type = self.resolve_type(scope, &index_expr.target.rtype);
-        match target_type {
-            types::Pointer(..) | types::StaticArray(..) => {}
-            _invalid => {
-                self.logger.report_error(
-                    format!("type `{:?}` cannot be dereferenced", "FIXME"),
-                    index_expr.span,
-                );
-                self.fatal_error();
+            ir::Asm { dst, ref code, ref clobbers } => {
+                // The assembly has been checked, so it can only fail to parse because of a bug
+                for line in self.expand_asm(code).lines() {
+                    let parsed = parse_line(line).expect("ICE: invalid inline assembly");
+                    let operation = match parsed.operation {
+                        Some(operation) => operation,
+                        None => {
+                            self.instructions.extend(parsed.label.map(asm::Label));
+                            continue;
+                        }
+                    };
+                    match Instruction::from_operation(&operation) {
+                        Ok(Some(instruction)) => {
+                            self.instructions.extend(parsed.label.map(asm::Label));
+                            self.instructions.push(instruction);
+                        }
+                        // Directives that the compiler doesn't use are kept as they were written
+                        Ok(None) => self.instructions.push(asm::RawAsm(line.to_string())),
+                        Err(error) => panic!("ICE: invalid inline assembly: {}", error.message),
+                    }
+                }
+                self.move_reg(vreg(dst), RESULT_REG);
+                for clobber in clobbers {
+                    let reg = parse_register(clobber).expect("ICE: invalid clobber");
+                    if !self.clobbers.contains(&reg) {
+                        self.clobbers.push(reg);
+                    }
+                }
             }
         }
-
-        // Evaluate the index
-        self.compile_expression(scope, &index_expr.index);
-
-        // Check that we are indexing with the correct type
-        let index_type = self.resolve_type(scope, &index_expr.index.rtype);
-        self.check_type(&index_type, &INT_TYPE, index_expr.index.span);
-
-        // Multiply by the size of the target type
-        let type_size = self.unaligned_size_of(target_type.deref());
-        self.multiply_by(type_size as usize);
-
-        // Either replace or add to the current address
-        if self.add_to_address {
-            self.instructions.push(asm::AddUnsigned(ADDR_REG, RESULT_REG, ADDR_REG));
-        }
-        else {
-            self.instructions.push(asm::AddUnsigned(ADDR_REG, RESULT_REG, ZERO_REG));
-        }
-
-        // Evaluate the target address
-        self.add_to_address = true;
-        self.compile_expression(scope, &index_expr.target);
-        self.add_to_address = false;
-
-        // Add the index to the target address
-        self.instructions.push(asm::AddUnsigned(RESULT_REG, RESULT_REG, ADDR_REG));
     }
 
-    fn compile_if(&mut self, scope: &mut Scope, if_statement: &ast::IfStatement) {
-        self.compile_expression(scope, &if_statement.condition);
-
-        // Check that the expression returns a boolean type
-        let cond_type = self.resolve_type(scope, &if_statement.condition.rtype);
-        self.check_type(&cond_type, &BOOL_TYPE, if_statement.span);
-
-        let else_label = self.anon_label();
-        let end_label = match if_statement.else_block {
-            Some(..) => self.anon_label(),
-            // If there is no else block, then the end label is equal to the else label
-            None => else_label.clone(),
-        };
-
-        self.instructions.push(asm::JumpIfZero(RESULT_REG, else_label.clone()));
-
-        // Compile the then block
-        self.compile_block(scope, &if_statement.body);
-        let then_rtype = self.resolve_type(scope, &if_statement.body.rtype());
-
-        match if_statement.else_block {
-            Some(ref block) => {
-                // If there is an else block we need to add a jump from the then block to the
-                // end label, and add a label for the else part
-                self.instructions.push(asm::Jump(end_label.clone()));
-                self.instructions.push(asm::Label(else_label));
-                // Then compile the else block
-                self.compile_block(scope, block);
-
-                // Check that both sides return the same type
-                let else_rtype = self.resolve_type(scope, &block.rtype());
-                self.check_type(&else_rtype, &then_rtype, block.span);
-            }
-            None => {
-                // If the else block was left unspecified, then the if statement must return the
-                // unit type
-                self.check_type(&then_rtype, &UNIT_TYPE, if_statement.span);
+    /// Get the text of a block of inline assembly, replacing its operands with their locations.
+    /// Labels defined in the block are given a unique suffix, so that they can't clash with labels
+    /// elsewhere in the program.
+    fn expand_asm(&mut self, code: &[ir::AsmPart]) -> String {
+        let mut text = String::new();
+        for part in code {
+            match part {
+                ir::AsmPart::Text(value) => text.push_str(value),
+                ir::AsmPart::ResultReg => text.push_str(&format!("r{}", RESULT_REG)),
+                ir::AsmPart::Operand(address) => {
+                    let offset = match address.base {
+                        ir::Slot(slot) => self.frame.slots[slot],
+                        ir::Param(index) => self.frame.params[index],
+                        ir::Global(ref label) => {
+                            let symbol = Expr::Symbol(label.clone(), address.offset);
+                            text.push_str(&symbol.to_string());
+                            continue;
+                        }
+                        ir::TempBase(..) => panic!("ICE: asm operand in a temporary"),
+                    };
+                    let offset = offset as i32 + address.offset;
+                    text.push_str(&format!("{}(r{})", offset, FRAME_POINTER));
+                }
             }
         }
 
-        // Add the end label
-        self.instructions.push(asm::Label(end_label));
-    }
-
-    /// Compile a for loop:
-    /// Note: We directly compile for loops instead of de-sugaring them into a normal loop with an
-    /// if break, for efficiency.
-    fn compile_for(&mut self, scope: &mut Scope, for_statement: &ast::ForLoopStatement) {
-        let var_size = self.size_of(&INT_TYPE) as u16;
-
-        // Create a fake ast so the we can refer to the variable inside the loop
-        let loop_var_name = for_statement.loop_var.clone();
-        let loop_var_ast = ast::LetStatement {
-            name: loop_var_name.clone(),
-            var_type: ast::Primitive(ast::IntType),
-            assignment: None,
-            is_const: false,
-            span: for_statement.span,
-        };
-        let loop_var_type = self.resolve_type(scope, &ast::Primitive(ast::IntType));
-        let loop_var = Variable::new(loop_var_ast, loop_var_type, Offset(scope.next_offset), false);
-        scope.next_offset += var_size as i16 * 2;
-
-        let loop_var_offset = scope.next_offset - 8;
-        let end_var_offset = scope.next_offset - 4;
-
-        let id = VarIdentId(scope.vars.len());
-        scope.add_ident(loop_var_name, id, for_statement.span);
-        scope.vars.push(loop_var);
-
-        // Compile the expression for the range end. The end range is written first, so that we have
-        // the loop var ready in the RESULT_REG
-        self.compile_expression(scope, &for_statement.end);
-        // Write the end expression to the stack
-        self.instructions.push(asm::Store32(asm::Const(end_var_offset), FRAME_POINTER, RESULT_REG));
-
-        // Check that the end expression has the correct type
-        let end_type = self.resolve_type(scope, &for_statement.end.rtype);
-        self.check_type(&end_type, &INT_TYPE, for_statement.end.span);
-
-        // Compile the expression for the range start
-        self.compile_expression(scope, &for_statement.start);
-        // Write the start expression to the stack
-        self.instructions.push(asm::Store32(
-            asm::Const(loop_var_offset),
-            FRAME_POINTER,
-            RESULT_REG,
-        ));
-
-        // Check that the start expression has the correct type
-        let start_type = self.resolve_type(scope, &for_statement.start.rtype);
-        self.check_type(&start_type, &INT_TYPE, for_statement.start.span);
-
-        let start_label = self.anon_label();
-        let cond_label = self.anon_label();
-        let end_label = self.anon_label();
-        scope.loop_ends.push(end_label);
-
-        // Check the condition before we start by jumping to the condition label
-        self.instructions.push(asm::Jump(cond_label.clone()));
-
-        // Compile the main body of the loop
-        self.instructions.push(asm::Label(start_label.clone()));
-        self.compile_block(scope, &for_statement.body);
-
-        // Increment the loop variable
-        self.instructions.push(asm::Load32(RESULT_REG, asm::Const(loop_var_offset), FRAME_POINTER));
-        self.instructions.push(asm::AddUnsignedValue(RESULT_REG, RESULT_REG, 1));
-        self.instructions.push(asm::Store32(
-            asm::Const(loop_var_offset),
-            FRAME_POINTER,
-            RESULT_REG,
-        ));
-
-        // Jump back to the start if we haven't finished the loop.
-        // Note: The loop variable will be already loaded into RESULT_REG
-        self.instructions.push(asm::Label(cond_label));
-        self.instructions.push(asm::Load32(TEMP_REG, asm::Const(end_var_offset), FRAME_POINTER));
-        self.instructions.push(asm::SetLt(RESULT_REG, RESULT_REG, TEMP_REG));
-        self.instructions.push(asm::JumpIfNotZero(RESULT_REG, start_label));
-
-        // Add end label
-        let end_label = scope.loop_ends.pop().expect("ICE: Missing label after loop");
-        self.instructions.push(asm::Label(end_label));
-
-        // Check that the body of the loop returns the correct type
-        let body_rtype = self.resolve_type(scope, &for_statement.body.rtype());
-        self.check_type(&body_rtype, &UNIT_TYPE, for_statement.span);
-    }
-
-    fn compile_loop(&mut self, scope: &mut Scope, loop_statement: &ast::LoopStatement) {
-        let start_label = self.anon_label();
-        self.instructions.push(asm::Label(start_label.clone()));
-
-        // Add the end label to the loop ends vector, so that it can be used by breaks
-        let end_label = self.anon_label();
-        scope.loop_ends.push(end_label);
-
-        self.compile_block(scope, &loop_statement.body);
-
-        // Add jump to start
-        self.instructions.push(asm::Jump(start_label));
-
-        // Add end label
-        let end_label = scope.loop_ends.pop().expect("ICE: Missing label after loop");
-        self.instructions.push(asm::Label(end_label));
-
-        // Check that the body of the loop returns the correct type
-        let body_rtype = self.resolve_type(scope, &loop_statement.body.rtype());
-        self.check_type(&body_rtype, &UNIT_TYPE, loop_statement.span);
-    }
-
-    fn compile_call(&mut self, scope: &mut Scope, call: &ast::FunctionCall) {
-        let mut call_args = vec![];
-        // Keep track of the offset of the stack, so that we can restore it later.
-        let mut stack_offset = 0;
-        for arg in &call.args {
-            let arg_type = self.resolve_type(scope, &arg.rtype);
-            let arg_size = self.size_of(&arg_type);
-
-            // Compile the expression
-            self.compile_expression(scope, arg);
-            // Write the result of the expression to the stack
-            self.copy_var(&arg_type, RESULT_REG, STACK_POINTER);
-            // Increment the stack
-            self.instructions.push(asm::AddUnsignedValue(
-                STACK_POINTER,
-                STACK_POINTER,
-                arg_size as u16,
-            ));
-            stack_offset += arg_size as i16;
-
-            call_args.push(arg_type);
-        }
-
-        // Get the function corresponding to the call
-        let function = match scope.get_ident(&call.name, call.span) {
-            FnIdent(ident) => ident,
-            VarIdent(..) => panic!("ERROR_EXPECTED_FUNCTION_FOUND_VAR, TODO: Improve this error"),
+        // Labels start at the beginning of a line
+        let id = self.next_unique_id();
+        let renames: HashMap<&str, String> = text
+            .lines()
+            .filter_map(|line| line.split(char::is_whitespace).next())
+            .filter(|label| !label.is_empty() && !label.starts_with(';'))
+            .map(|label| (label, format!("{}${}", label, id)))
+            .collect();
+        if renames.is_empty() {
+            return text;
+        }
+
+        let is_symbol = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$';
+        let mut renamed = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find(is_symbol) {
+            renamed.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find(|c| !is_symbol(c)).unwrap_or(rest.len());
+            let symbol = &rest[..end];
+            renamed.push_str(renames.get(symbol).map_or(symbol, String::as_str));
+            rest = &rest[end..];
+        }
+        renamed.push_str(rest);
+        renamed
+    }
+
+    fn compile_binary(&mut self, op: ir::BinaryOp, dst: RegId, lhs: Operand, rhs: Operand) {
+        use crate::ir::BinaryOp::*;
+
+        // Put constants on the right hand side where possible, so that the immediate form of the
+        // instruction can be used
+        let (op, lhs, rhs) = match (lhs, rhs) {
+            (ir::Const(..), ir::Temp(..)) => match op {
+                Add | Mul | Eq | NotEq => (op, rhs, lhs),
+                Lt => (Gt, rhs, lhs),
+                LtEq => (GtEq, rhs, lhs),
+                Gt => (Lt, rhs, lhs),
+                GtEq => (LtEq, rhs, lhs),
+                Sub | Div | Rem => (op, lhs, rhs),
+            },
+            _ => (op, lhs, rhs),
         };
 
-        // Check that the call args match the function args
-        if call_args.len() != function.arg_types.len() {
-            panic!("INCORRECT NUMBER OF ARGUMENTS");
-        }
-        for (call_arg, fn_arg) in call_args.iter().zip(function.arg_types.iter()) {
-            self.check_type(call_arg, fn_arg, call.span);
-        }
-
-        // Make the call
-        self.instructions.push(asm::JumpStore(function.location.clone()));
+        if let ir::Const(value) = rhs {
+            if let Ok(value) = i16::try_from(value) {
+                let instruction: Option<fn(RegId, RegId, i16) -> Instruction> = match op {
+                    Add => Some(asm::AddSignedValue),
+                    Sub => Some(asm::SubSignedValue),
+                    Eq => Some(asm::SetEqSignedValue),
+                    NotEq => Some(asm::SetNotEqSignedValue),
+                    Lt => Some(asm::SetLtSignedValue),
+                    LtEq => Some(asm::SetLtEqSignedValue),
+                    Gt => Some(asm::SetGtSignedValue),
+                    GtEq => Some(asm::SetGtEqSignedValue),
+                    Mul | Div | Rem => None,
+                };
+                if let Some(instruction) = instruction {
+                    let lhs = self.operand(lhs);
+                    self.instructions.push(instruction(dst, lhs, value));
+                    return;
+                }
 
-        // Restore the stack
-        if stack_offset != 0 {
-            self.instructions.push(asm::SubUnsignedValue(
-                STACK_POINTER,
-                STACK_POINTER,
-                stack_offset as u16,
-            ));
+                // Multiplications by a power of 2 can be done with a shift
+                if op == Mul && value > 0 && value & (value - 1) == 0 {
+                    let lhs = self.operand(lhs);
+                    match value.trailing_zeros() {
+                        0 => self.move_reg(dst, lhs),
+                        shift => self.instructions.push(asm::LShiftValue(dst, lhs, shift as u16)),
+                    }
+                    return;
+                }
+            }
         }
-    }
 
-    fn compile_let(&mut self, scope: &mut Scope, let_statement: &ast::LetStatement) {
-        // Register this variable
-        let id = VarIdentId(scope.vars.len());
-        scope.add_ident(let_statement.name.clone(), id, let_statement.span);
-
-        let rtype = self.resolve_type(scope, &let_statement.var_type);
-
-        let var = Variable::new(
-            let_statement.clone(),
-            rtype.clone(),
-            Offset(scope.next_offset),
-            let_statement.is_const,
-        );
-        scope.next_offset += self.size_of(&rtype) as i16;
-        scope.vars.push(var);
-
-        // Compile optional assignment
-        if let Some(assignment) = &let_statement.assignment {
-            self.compile_assign(scope, assignment)
+        let lhs = self.operand(lhs);
+        let rhs = self.operand(rhs);
+        match op {
+            Add => self.instructions.push(asm::AddSigned(dst, lhs, rhs)),
+            Sub => self.instructions.push(asm::SubSigned(dst, lhs, rhs)),
+            Mul => self.instructions.push(asm::Mult(dst, lhs, rhs)),
+            Div => self.instructions.push(asm::Div(dst, lhs, rhs)),
+            Rem => {
+                // DLX has no remainder instruction so compute: lhs - (lhs / rhs) * rhs
+                let product = self.new_vreg();
+                self.instructions.push(asm::Div(product, lhs, rhs));
+                self.instructions.push(asm::Mult(product, product, rhs));
+                self.instructions.push(asm::SubSigned(dst, lhs, product));
+            }
+            Eq => self.instructions.push(asm::SetEq(dst, lhs, rhs)),
+            NotEq => self.instructions.push(asm::SetNotEq(dst, lhs, rhs)),
+            Lt => self.instructions.push(asm::SetLt(dst, lhs, rhs)),
+            LtEq => self.instructions.push(asm::SetLtEq(dst, lhs, rhs)),
+            Gt => self.instructions.push(asm::SetGt(dst, lhs, rhs)),
+            GtEq => self.instructions.push(asm::SetGtEq(dst, lhs, rhs)),
+        }
+    }
+
+    /// Get a register holding the value of an operand
+    fn operand(&mut self, operand: Operand) -> RegId {
+        match operand {
+            ir::Temp(temp) => vreg(temp),
+            ir::Const(0) => ZERO_REG,
+            ir::Const(value) => {
+                let reg = self.new_vreg();
+                self.load_const(reg, value);
+                reg
+            }
         }
     }
 
-    fn compile_assign(&mut self, scope: &mut Scope, assignment: &ast::Assignment) {
-        // Compile the rhs expression and store the result in the location found
-        self.compile_expression(scope, &assignment.rhs);
-        self.instructions.push(asm::AddSigned(TEMP_REG, RESULT_REG, ZERO_REG));
-
-        // Check that the rhs result matches the target
-        let target_type = self.resolve_type(scope, &assignment.target.rtype);
-        self.check_type(
-            &self.resolve_type(scope, &assignment.rhs.rtype),
-            &target_type,
-            assignment.span,
-        );
-
-        // Get the address of where we want to place the variable
-        let valid_address = self.compile_address(scope, &assignment.target);
-        if !valid_address {
-            self.logger.report_error(
-                "illegal left-hand side expression".to_string(),
-                assignment.target.span,
-            );
-            self.fatal_error();
-        };
-
-        // We now have the result of the rhs in TEMP_REG and the address we want to assign to in
-        // RESULT_REG. The type of the variable is required so that we know how to copy the data.
-        self.copy_var(&target_type, TEMP_REG, RESULT_REG);
-    }
-
-    fn compile_struct_init(&mut self, scope: &mut Scope, struct_init: &ast::StructInit) {
-        // Determine the type of the struct
-        let struct_type = self.resolve_type(scope, &ast::UserType(struct_init.type_name.clone()));
-        let struct_base_type = self.type_table.base_type(&struct_type).clone();
-        let struct_size = self.size_of(&struct_type);
-
-        // Reserve memory for the struct
-        self.instructions.push(asm::AddUnsignedValue(STACK_POINTER, STACK_POINTER, struct_size));
-
-        for &(ref field_name, ref expression) in &struct_init.field_init {
-            let (field_offset, field_type) =
-                self.find_field(&struct_base_type, field_name, struct_init.span);
-
-            self.compile_expression(scope, expression);
-
-            // Check that the types match
-            self.check_type(
-                &self.resolve_type(scope, &expression.rtype),
-                &field_type,
-                struct_init.span,
-            );
-
-            self.instructions.push(asm::AddSignedValue(
-                TEMP_REG,
-                STACK_POINTER,
-                (field_offset as i16) - (struct_size as i16),
-            ));
-            self.copy_var(&field_type, RESULT_REG, TEMP_REG);
+    /// Write the value of an operand to a register
+    fn operand_to(&mut self, reg: RegId, operand: Operand) {
+        match operand {
+            ir::Temp(temp) => self.move_reg(reg, vreg(temp)),
+            ir::Const(value) => self.load_const(reg, value),
         }
-
-        // Unreserve the memory from the struct
-        self.instructions.push(asm::AddSignedValue(
-            STACK_POINTER,
-            STACK_POINTER,
-            -(struct_size as i16),
-        ));
-        // Return a pointer to the struct
-        self.instructions.push(asm::AddUnsigned(RESULT_REG, STACK_POINTER, ZERO_REG));
     }
 
-    fn compile_static_array(&mut self, scope: &mut Scope, array: &ast::StaticArray) {
-        // Special case for a zero sized array
-        if array.elements.is_empty() {
-            self.instructions.push(asm::AddUnsigned(RESULT_REG, STACK_POINTER, ZERO_REG));
-            return;
-        }
-
-        // Compile the first element and add it to the start of the array.
-        // NOTE: this needs to be done first so that we can properly resolve the type of the array
-        // elements
-        self.compile_expression(scope, &array.elements[0]);
-        let element_type = self.resolve_type(scope, &array.elements[0].rtype);
-        // Using the unaligned size for arrays allows us to efficiently store strings as byte arrays
-        let element_size = self.unaligned_size_of(&element_type);
-        self.copy_var(&element_type, RESULT_REG, STACK_POINTER);
-
-        let mut offset = element_size as i16;
-        self.instructions.push(asm::AddUnsignedValue(STACK_POINTER, STACK_POINTER, element_size));
-
-        for element in array.elements.iter().skip(1) {
-            self.compile_expression(scope, element);
-            self.copy_var(&element_type, RESULT_REG, STACK_POINTER);
-            self.instructions.push(asm::AddUnsignedValue(
-                STACK_POINTER,
-                STACK_POINTER,
-                element_size,
-            ));
-            offset += element_size as i16;
-        }
-
-        // Ensure that the stack pointer is correctly aligned
-        if offset % 4 != 0 {
-            let pad = 4 - (offset % 4);
-            offset += pad;
-            self.instructions.push(asm::AddUnsignedValue(STACK_POINTER, STACK_POINTER, pad as u16));
+    fn load_const(&mut self, reg: RegId, value: i32) {
+        match i16::try_from(value) {
+            Ok(value) => self.instructions.push(asm::AddSignedValue(reg, ZERO_REG, value)),
+            Err(_) => {
+                // Larger constants are built from their upper and lower halves
+                self.instructions.push(asm::LoadHighImmediate(reg, (value >> 16) as u16));
+                self.instructions.push(asm::OrValue(reg, reg, value as u16));
+            }
         }
-
-        // Unreserve the memory from the array
-        self.instructions.push(asm::AddSignedValue(STACK_POINTER, STACK_POINTER, -offset));
-        // Return a pointer to the first element
-        self.instructions.push(asm::AddUnsigned(RESULT_REG, STACK_POINTER, ZERO_REG));
     }
 
-    fn copy_var(&mut self, var_type: &types::Type, from: asm::RegId, to: asm::RegId) {
-        match *var_type {
-            CHAR_TYPE => {
-                self.instructions.push(asm::Store8(asm::Const(0), to, from));
-            }
-
-            // These types are a single word in size
-            INT_TYPE | BOOL_TYPE | types::Pointer(..) => {
-                self.instructions.push(asm::Store32(asm::Const(0), to, from));
+    /// Get the register and offset used to access an address
+    fn address(&mut self, address: &Address) -> (RegId, i16) {
+        let (base, offset) = match address.base {
+            ir::TempBase(temp) => (vreg(temp), address.offset),
+            ir::Slot(slot) => (FRAME_POINTER, self.frame.slots[slot] as i32 + address.offset),
+            ir::Param(index) => (FRAME_POINTER, self.frame.params[index] as i32 + address.offset),
+            ir::Global(ref label) => {
+                let reg = self.new_vreg();
+                self.instructions.push(asm::AddUnsignedLabel(reg, ZERO_REG, label.clone()));
+                (reg, address.offset)
             }
+        };
 
-            // Other types cannot be stored in a single word, and since there is no easy way to do
-            // a memcopy in DLX we must manually copy all bytes. In this case, the from register
-            // will store the location of the first byte
-            ref other => {
-                // The copy register should never be either of the input registers
-                assert!(from != COPY_REG && to != COPY_REG);
-
-                // NOTE: types *must* be word aligned
-                let num_words = (self.size_of(other) / 4) as i16;
-                // Perform a load and store for each of the words
-                for i in 0..num_words {
-                    self.instructions.push(asm::Load32(COPY_REG, asm::Const(i * 4), from));
-                    self.instructions.push(asm::Store32(asm::Const(i * 4), to, COPY_REG));
-                }
+        match i16::try_from(offset) {
+            Ok(offset) => (base, offset),
+            Err(_) => {
+                // The offset doesn't fit in the instruction, so add it to the base instead
+                let reg = self.new_vreg();
+                self.load_const(reg, offset);
+                self.instructions.push(asm::AddUnsigned(reg, base, reg));
+                (reg, 0)
             }
         }
     }
 
-    fn compile_address(&mut self, scope: &mut Scope, expression: &ast::Expression) -> bool {
-        let span = expression.span;
-        match *expression.expr {
-            // Address of an ordinary variable
-            ast::VariableExpr(ref name) => {
-                let var = scope.get_ident(name, span).unwrap_var();
-                self.address_of(&var.location);
-            }
-
-            // Address of a dereference (aka don't dereference)
-            ast::DerefExpr(ref inner) => self.compile_expression(scope, inner),
-
-            // Address of an array index
-            ast::ArrayIndexExpr(ref inner) => self.compile_array_index(scope, inner),
-
-            // Address of a struct field
-            ast::FieldRefExpr(ref inner) => self.compile_field_ref(scope, inner),
-
-            // Nothing else has a proper address
-            _ => return false,
+    fn load(&mut self, ty: ir::Type, dst: RegId, offset: i16, base: RegId) {
+        match ty {
+            ir::Type::Char => self.instructions.push(asm::Load8(dst, asm::Const(offset), base)),
+            _ => self.instructions.push(asm::Load32(dst, asm::Const(offset), base)),
         }
-        true
     }
 
-    fn load_var(&mut self, var_type: &types::Type, location: &Location) {
-        match *var_type {
-            // These types are byte sized
-            CHAR_TYPE => {
-                match *location {
-                    Label(ref _label) => {
-                        // HACK to allow us to access some extra memory
-                        self.address_of(location);
-                        self.instructions.push(asm::Load8(RESULT_REG, asm::Const(0), RESULT_REG));
-                        //self.instructions.push(asm::Load8(RESULT_REG,
-                        // asm::Unknown(label.clone()),    ZERO_REG));
-                    }
-                    Offset(amount) => {
-                        self.instructions.push(asm::Load8(
-                            RESULT_REG,
-                            asm::Const(amount),
-                            FRAME_POINTER,
-                        ));
-                    }
-                    Register(id) => {
-                        self.instructions.push(asm::Load8(RESULT_REG, asm::Const(0), id));
-                    }
-                }
-            }
-
-            // These types are word sized
-            INT_TYPE | BOOL_TYPE | types::Pointer(..) => {
-                match *location {
-                    Label(ref _label) => {
-                        // HACK to allow us to access some extra memory
-                        self.address_of(location);
-                        self.instructions.push(asm::Load32(RESULT_REG, asm::Const(0), RESULT_REG));
-                        //self.instructions.push(asm::Load32(RESULT_REG,
-                        // asm::Unknown(label.clone()),    ZERO_REG));
-                    }
-                    Offset(amount) => {
-                        self.instructions.push(asm::Load32(
-                            RESULT_REG,
-                            asm::Const(amount),
-                            FRAME_POINTER,
-                        ));
-                    }
-                    Register(id) => {
-                        self.instructions.push(asm::Load32(RESULT_REG, asm::Const(0), id));
-                    }
-                }
-            }
-            // These types cannot be stored in registers so we load their address into the result
-            // reg
-            _ => self.address_of(location),
+    fn store(&mut self, ty: ir::Type, offset: i16, base: RegId, value: RegId) {
+        match ty {
+            ir::Type::Char => self.instructions.push(asm::Store8(asm::Const(offset), base, value)),
+            _ => self.instructions.push(asm::Store32(asm::Const(offset), base, value)),
         }
     }
 
-    fn address_of(&mut self, var_location: &Location) {
-        match *var_location {
-            Label(ref label) => {
-                let asm = format!("        addui   r{},r{},{}", RESULT_REG, ZERO_REG, label);
-                self.instructions.push(asm::RawAsm(asm));
-            }
-            Offset(offset) => {
-                self.instructions.push(asm::AddSignedValue(RESULT_REG, FRAME_POINTER, offset));
-            }
-            Register(id) => {
-                self.instructions.push(asm::AddUnsigned(RESULT_REG, id, ZERO_REG));
-            }
+    /// Read a word from an address that may not be aligned, a byte at a time. Memory is big
+    /// endian, so the first byte holds the most significant bits.
+    fn load_unaligned(&mut self, dst: RegId, offset: i16, base: RegId) {
+        let byte = self.new_vreg();
+        self.instructions.push(asm::Load8u(dst, asm::Const(offset), base));
+        for i in 1..4 {
+            self.instructions.push(asm::Load8u(byte, asm::Const(offset + i), base));
+            self.instructions.push(asm::LShiftValue(dst, dst, 8));
+            self.instructions.push(asm::Or(dst, dst, byte));
         }
     }
 
-    fn multiply_by(&mut self, num: usize) {
-        // Check if it is a power of 2
-        // TODO: Generate extra code for multiplications that are not a power of 2
-        if num & (num - 1) != 0 {
-            panic!("ICE, error multiplying by a number that is not a power of 2");
-        }
-
-        let lshift_amount = (num as f32).log2() as u16;
-        if lshift_amount != 0 {
-            self.instructions.push(asm::LShiftValue(RESULT_REG, RESULT_REG, lshift_amount));
+    /// Write a word to an address that may not be aligned, a byte at a time
+    fn store_unaligned(&mut self, offset: i16, base: RegId, value: RegId) {
+        let byte = self.new_vreg();
+        for i in 0..3 {
+            self.instructions.push(asm::RShiftZeroValue(byte, value, 24 - 8 * i as u16));
+            self.instructions.push(asm::Store8(asm::Const(offset + i), base, byte));
         }
+        self.instructions.push(asm::Store8(asm::Const(offset + 3), base, value));
     }
 
-    /// Check that a type is the same as the expected type or one path never returns
-    fn check_type(&self, input: &Type, expected: &Type, span: InputSpan) {
-        if input != &types::Bottom && expected != &types::Bottom && input != expected {
-            self.logger.report_error(
-                format!("incorrect type, expected: {:?}, found: {:?}", expected, input),
-                span,
-            );
-            self.fatal_error();
+    /// Copy a value that doesn't fit in a register. Since there is no easy way to do a memcopy in
+    /// DLX we must manually copy each word, or each byte of values that may not be word aligned.
+    /// Any tail that is smaller than a word is copied a byte at a time.
+    fn copy_memory(
+        &mut self,
+        dst: RegId,
+        dst_offset: i16,
+        src: RegId,
+        src_offset: i16,
+        size: u16,
+        align: u16,
+    ) {
+        let copy_reg = self.new_vreg();
+        let mut copied = 0;
+        while copied < size {
+            let ty = if align >= 4 && size - copied >= 4 { ir::Type::Int } else { ir::Type::Char };
+            self.load(ty, copy_reg, src_offset + copied as i16, src);
+            self.store(ty, dst_offset + copied as i16, dst, copy_reg);
+            copied += ty.size();
         }
     }
+}
 
-    fn size_of(&self, type_: &Type) -> u16 {
-        self.type_table.size_of(type_)
-    }
-
-    fn unaligned_size_of(&self, type_: &Type) -> u16 {
-        self.type_table.unaligned_size_of(type_)
-    }
+/// Get the blocks that a terminator jumps to, leaving out the block that follows it
+fn jump_targets(id: BlockId, terminator: &ir::Terminator) -> Vec<BlockId> {
+    terminator.successors().into_iter().filter(|&target| target != id + 1).collect()
+}
 
-    fn resolve_type(&self, scope: &Scope, ast_type: &ast::Type) -> Type {
-        self.type_table.resolve_type(scope, ast_type)
-    }
+/// Get the virtual register that holds a temporary
+fn vreg(temp: ir::TempId) -> RegId {
+    FIRST_VIRTUAL_REG + temp
 }
@@ -0,0 +1,113 @@
+use std::io::{self, BufRead, Read, Write};
+
+// The console registers are mapped to the top of the address space so that they can be accessed
+// with a negative offset from r0, e.g. `lw r1,KbdCtrl`.
+
+/// Keyboard control register, bit 0 is set when a key is ready to be read
+pub const KBD_CTRL: u32 = 0xFFFF_FF00;
+/// Keyboard data register, reading returns the next key
+pub const KBD_DATA: u32 = 0xFFFF_FF04;
+/// Display control register, bit 0 is set when the display is ready for the next character
+pub const DSP_CTRL: u32 = 0xFFFF_FF08;
+/// Display data register, writing outputs a character
+pub const DSP_DATA: u32 = 0xFFFF_FF0C;
+
+/// Mask for the ready bit of the keyboard control register
+pub const KBD_RDY: u32 = 1;
+/// Mask for the ready bit of the display control register
+pub const DSP_RDY: u32 = 1;
+
+/// The first address that is handled by the console
+pub const BASE: u32 = KBD_CTRL;
+
+/// The symbols that programs use to access the console
+pub const SYMBOLS: &[(&str, i32)] = &[
+    ("KbdCtrl", KBD_CTRL as i32),
+    ("KbdData", KBD_DATA as i32),
+    ("DspCtrl", DSP_CTRL as i32),
+    ("DspData", DSP_DATA as i32),
+    ("Kbd_Rdy", KBD_RDY as i32),
+    ("Dsp_Rdy", DSP_RDY as i32),
+];
+
+/// An error from a console register access
+pub enum ConsoleError {
+    /// The program attempted to wait for a key after all the input was consumed
+    EndOfInput,
+    Fault(String),
+}
+
+/// A memory mapped console consisting of a keyboard and a character display
+pub struct Console {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    next_key: Option<u8>,
+}
+
+impl Console {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Console {
+        Console { input, output, next_key: None }
+    }
+
+    /// Create a console that reads from stdin and writes to stdout
+    pub fn stdio() -> Console {
+        Console::new(Box::new(io::BufReader::new(io::stdin())), Box::new(io::stdout()))
+    }
+
+    pub fn read(&mut self, addr: u32) -> Result<u32, ConsoleError> {
+        match addr {
+            // Note: reading the control register blocks until there is input available, so the
+            // keyboard is always ready once the read completes.
+            KBD_CTRL => {
+                self.wait_for_key()?;
+                Ok(KBD_RDY)
+            }
+            KBD_DATA => {
+                let key = self.wait_for_key()?;
+                self.next_key = None;
+                Ok(key as u32)
+            }
+            // The display is always able to accept new characters
+            DSP_CTRL => Ok(DSP_RDY),
+            DSP_DATA => Ok(0),
+            _ => Err(ConsoleError::Fault(format!("no device register at 0x{:08x}", addr))),
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, value: u32) -> Result<(), ConsoleError> {
+        match addr {
+            DSP_DATA => {
+                self.output
+                    .write_all(&[value as u8])
+                    .map_err(|e| ConsoleError::Fault(format!("display error: {}", e)))?;
+                Ok(())
+            }
+            KBD_CTRL | KBD_DATA | DSP_CTRL => Ok(()),
+            _ => Err(ConsoleError::Fault(format!("no device register at 0x{:08x}", addr))),
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.output.flush();
+    }
+
+    /// Get the next key without consuming it, reading more input if required
+    fn wait_for_key(&mut self) -> Result<u8, ConsoleError> {
+        if let Some(key) = self.next_key {
+            return Ok(key);
+        }
+
+        // Make sure any prompts are visible before waiting for input
+        self.flush();
+
+        let mut buf = [0];
+        match self.input.read(&mut buf) {
+            Ok(0) => Err(ConsoleError::EndOfInput),
+            Ok(_) => {
+                self.next_key = Some(buf[0]);
+                Ok(buf[0])
+            }
+            Err(e) => Err(ConsoleError::Fault(format!("keyboard error: {}", e))),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::dlx::asm::RegId;
+
+// Instructions are 32 bits wide and use one of three formats:
+//
+//   I-type:  opcode (6) | rs1 (5) | rd (5)  | immediate (16)
+//   R-type:  opcode (6) | rs1 (5) | rs2 (5) | rd (5) | unused (5) | func (6)
+//   J-type:  opcode (6) | offset (26)
+//
+// Branch and jump offsets are relative to the address of the following instruction.
+
+// Opcodes
+pub const OP_SPECIAL: u32 = 0x00;
+pub const OP_ARITH: u32 = 0x01;
+pub const OP_J: u32 = 0x02;
+pub const OP_JAL: u32 = 0x03;
+pub const OP_BEQZ: u32 = 0x04;
+pub const OP_BNEZ: u32 = 0x05;
+pub const OP_ADDI: u32 = 0x08;
+pub const OP_ADDUI: u32 = 0x09;
+pub const OP_SUBI: u32 = 0x0A;
+pub const OP_SUBUI: u32 = 0x0B;
+pub const OP_ANDI: u32 = 0x0C;
+pub const OP_ORI: u32 = 0x0D;
+pub const OP_XORI: u32 = 0x0E;
+pub const OP_LHI: u32 = 0x0F;
+pub const OP_TRAP: u32 = 0x11;
+pub const OP_JR: u32 = 0x12;
+pub const OP_JALR: u32 = 0x13;
+pub const OP_SLLI: u32 = 0x14;
+pub const OP_SRLI: u32 = 0x16;
+pub const OP_SRAI: u32 = 0x17;
+pub const OP_SEQI: u32 = 0x18;
+pub const OP_SNEI: u32 = 0x19;
+pub const OP_SLTI: u32 = 0x1A;
+pub const OP_SGTI: u32 = 0x1B;
+pub const OP_SLEI: u32 = 0x1C;
+pub const OP_SGEI: u32 = 0x1D;
+pub const OP_LB: u32 = 0x20;
+pub const OP_LH: u32 = 0x21;
+pub const OP_LW: u32 = 0x23;
+pub const OP_LBU: u32 = 0x24;
+pub const OP_LHU: u32 = 0x25;
+pub const OP_SB: u32 = 0x28;
+pub const OP_SH: u32 = 0x29;
+pub const OP_SW: u32 = 0x2B;
+pub const OP_SEQUI: u32 = 0x30;
+pub const OP_SNEUI: u32 = 0x31;
+pub const OP_SLTUI: u32 = 0x32;
+pub const OP_SGTUI: u32 = 0x33;
+pub const OP_SLEUI: u32 = 0x34;
+pub const OP_SGEUI: u32 = 0x35;
+
+// Function codes for `OP_SPECIAL` instructions
+pub const FN_NOP: u32 = 0x00;
+pub const FN_SLL: u32 = 0x04;
+pub const FN_SRL: u32 = 0x06;
+pub const FN_SRA: u32 = 0x07;
+pub const FN_SEQU: u32 = 0x10;
+pub const FN_SNEU: u32 = 0x11;
+pub const FN_SLTU: u32 = 0x12;
+pub const FN_SGTU: u32 = 0x13;
+pub const FN_SLEU: u32 = 0x14;
+pub const FN_SGEU: u32 = 0x15;
+pub const FN_ADD: u32 = 0x20;
+pub const FN_ADDU: u32 = 0x21;
+pub const FN_SUB: u32 = 0x22;
+pub const FN_SUBU: u32 = 0x23;
+pub const FN_AND: u32 = 0x24;
+pub const FN_OR: u32 = 0x25;
+pub const FN_XOR: u32 = 0x26;
+pub const FN_SEQ: u32 = 0x28;
+pub const FN_SNE: u32 = 0x29;
+pub const FN_SLT: u32 = 0x2A;
+pub const FN_SGT: u32 = 0x2B;
+pub const FN_SLE: u32 = 0x2C;
+pub const FN_SGE: u32 = 0x2D;
+
+// Function codes for `OP_ARITH` instructions. In standard DLX these operate on the floating point
+// registers, however the compiler uses them with the general purpose registers.
+pub const FN_MULT: u32 = 0x0E;
+pub const FN_DIV: u32 = 0x0F;
+pub const FN_MULTU: u32 = 0x16;
+pub const FN_DIVU: u32 = 0x17;
+
+/// The maximum distance of a jump, in bytes
+pub const JUMP_RANGE: std::ops::RangeInclusive<i32> = -(1 << 25)..=(1 << 25) - 1;
+
+pub fn i_type(opcode: u32, rs1: RegId, rd: RegId, immediate: u32) -> u32 {
+    (opcode << 26) | ((rs1 as u32) << 21) | ((rd as u32) << 16) | (immediate & 0xFFFF)
+}
+
+pub fn r_type(opcode: u32, func: u32, rs1: RegId, rs2: RegId, rd: RegId) -> u32 {
+    (opcode << 26) | ((rs1 as u32) << 21) | ((rs2 as u32) << 16) | ((rd as u32) << 11) | func
+}
+
+pub fn j_type(opcode: u32, offset: u32) -> u32 {
+    (opcode << 26) | (offset & 0x03FF_FFFF)
+}
+
+/// The fields of an encoded instruction
+pub struct Fields {
+    pub opcode: u32,
+    pub rs1: RegId,
+    /// The second source register of an R-type instruction, or the destination of an I-type
+    pub rs2: RegId,
+    pub rd: RegId,
+    pub func: u32,
+    /// The sign extended immediate of an I-type instruction
+    pub immediate: i32,
+    /// The sign extended offset of a J-type instruction
+    pub offset: i32,
+}
+
+impl Fields {
+    pub fn decode(word: u32) -> Fields {
+        Fields {
+            opcode: word >> 26,
+            rs1: ((word >> 21) & 0x1F) as RegId,
+            rs2: ((word >> 16) & 0x1F) as RegId,
+            rd: ((word >> 11) & 0x1F) as RegId,
+            func: word & 0x3F,
+            immediate: word as u16 as i16 as i32,
+            // Shift the offset to the top of the word then back down to sign extend it
+            offset: ((word << 6) as i32) >> 6,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_each_format() {
+        // addi r1,r2,5
+        assert_eq!(i_type(OP_ADDI, 2, 1, 5), 0x2041_0005);
+        // add r3,r1,r2
+        assert_eq!(r_type(OP_SPECIAL, FN_ADD, 1, 2, 3), 0x0022_1820);
+        // j +8
+        assert_eq!(j_type(OP_J, 8), 0x0800_0008);
+    }
+
+    #[test]
+    fn sign_extends_decoded_offsets() {
+        let fields = Fields::decode(i_type(OP_LW, 30, 1, -8_i32 as u32));
+        assert_eq!((fields.opcode, fields.rs1, fields.rs2, fields.immediate), (OP_LW, 30, 1, -8));
+
+        let fields = Fields::decode(j_type(OP_JAL, -12_i32 as u32));
+        assert_eq!((fields.opcode, fields.offset), (OP_JAL, -12));
+    }
+}
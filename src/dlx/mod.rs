@@ -1,4 +1,9 @@
-mod types;
-
 pub mod asm;
+pub mod assembler;
 pub mod codegen;
+pub mod console;
+pub mod encoding;
+pub mod peephole;
+pub mod regalloc;
+pub mod sim;
+pub mod syntax;
@@ -0,0 +1,289 @@
+use crate::dlx::asm::{self, Instruction, RegId};
+
+const ZERO_REG: RegId = 0;
+
+/// Remove redundant instructions from generated code by looking at short sequences of
+/// instructions. The code is scanned until nothing changes, since removing one instruction can
+/// make another one redundant.
+pub fn optimize(mut code: Vec<Instruction>) -> Vec<Instruction> {
+    loop {
+        let mut changed = false;
+        code = simplify(code, &mut changed);
+        propagate_moves(&mut code, &mut changed);
+        if !changed {
+            return code;
+        }
+    }
+}
+
+/// Remove or simplify instructions based on the instructions before them
+fn simplify(code: Vec<Instruction>, changed: &mut bool) -> Vec<Instruction> {
+    let mut output: Vec<Instruction> = Vec::with_capacity(code.len());
+    let mut reachable = true;
+
+    for instruction in code {
+        // Code after an unconditional jump can only be reached through a label
+        if !reachable && !starts_block(&instruction) {
+            *changed = true;
+            continue;
+        }
+        reachable = true;
+
+        // Adding zero to a register is either a move or does nothing
+        let instruction = match instruction {
+            asm::AddUnsignedValue(dst, src, 0)
+            | asm::AddSignedValue(dst, src, 0)
+            | asm::SubUnsignedValue(dst, src, 0)
+            | asm::SubSignedValue(dst, src, 0) => {
+                *changed = true;
+                asm::AddUnsigned(dst, src, ZERO_REG)
+            }
+            other => other,
+        };
+
+        match (&instruction, output.last()) {
+            // Moves of a register to itself
+            (asm::AddUnsigned(dst, src, ZERO_REG), _) if dst == src => {
+                *changed = true;
+                continue;
+            }
+            // Moves back to the register that was just copied
+            (asm::AddUnsigned(dst, src, ZERO_REG), Some(asm::AddUnsigned(a, b, ZERO_REG)))
+                if dst == b && src == a =>
+            {
+                *changed = true;
+                continue;
+            }
+            // Loads of a value that was just stored to the same place. Stores relative to r0 may
+            // be to the console, so reading them back can give a different value.
+            (
+                asm::Load32(dst, asm::Const(offset), base),
+                Some(asm::Store32(asm::Const(stored_offset), stored_base, value)),
+            ) if offset == stored_offset && base == stored_base && *base != ZERO_REG => {
+                *changed = true;
+                if dst != value {
+                    output.push(asm::AddUnsigned(*dst, *value, ZERO_REG));
+                }
+                continue;
+            }
+            // Jumps to a label that immediately follows them
+            (asm::Label(label), _) => {
+                let jump = output.iter().rposition(|x| !matches!(x, asm::Label(..)));
+                if let Some(index) = jump.filter(|&i| jump_target(&output[i]) == Some(label)) {
+                    output.remove(index);
+                    *changed = true;
+                }
+            }
+            _ => {}
+        }
+
+        reachable = !matches!(instruction, asm::Jump(..) | asm::JumpR(..) | asm::Halt);
+        output.push(instruction);
+    }
+
+    output
+}
+
+/// Replace a use of a register that was just copied from another register with the original
+/// register, when the copy isn't used again, e.g. `addu r2,r1,r0` followed by `sw 0(r14),r2`
+fn propagate_moves(code: &mut Vec<Instruction>, changed: &mut bool) {
+    let mut i = 0;
+    while i + 1 < code.len() {
+        let (dst, src) = match code[i] {
+            asm::AddUnsigned(dst, src, ZERO_REG) if dst != src => (dst, src),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let (def, uses) = registers(&code[i + 1]);
+        let redefined = def == Some(dst);
+        if !is_plain(&code[i + 1])
+            || !uses.contains(&dst)
+            || !(redefined || is_dead(&code[i + 2..], dst))
+        {
+            i += 1;
+            continue;
+        }
+
+        let (_, uses) = code[i + 1].registers_mut();
+        for reg in uses.into_iter().filter(|reg| **reg == dst) {
+            *reg = src;
+        }
+        code.remove(i);
+        *changed = true;
+    }
+}
+
+/// Check whether the value of a register is overwritten before it is read again. Only straight line
+/// code is considered, so the register is assumed to be used if control flow is reached first.
+fn is_dead(code: &[Instruction], reg: RegId) -> bool {
+    for instruction in code {
+        if !is_plain(instruction) {
+            return false;
+        }
+        let (def, uses) = registers(instruction);
+        if uses.contains(&reg) {
+            return false;
+        }
+        if def == Some(reg) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Get the register written by an instruction, and the registers it reads
+fn registers(instruction: &Instruction) -> (Option<RegId>, Vec<RegId>) {
+    let mut instruction = instruction.clone();
+    let (def, uses) = instruction.registers_mut();
+    (def.copied(), uses.into_iter().map(|reg| *reg).collect())
+}
+
+/// Check whether an instruction uses only the registers given by `registers_mut` and continues to
+/// the next instruction
+fn is_plain(instruction: &Instruction) -> bool {
+    !matches!(
+        instruction,
+        asm::JumpIfZero(..)
+            | asm::JumpIfNotZero(..)
+            | asm::Jump(..)
+            | asm::JumpStore(..)
+            | asm::JumpStoreR(..)
+            | asm::JumpR(..)
+            | asm::Trap(..)
+            | asm::Halt
+            | asm::Label(..)
+            | asm::RawAsm(..)
+    )
+}
+
+/// Check whether an instruction may be reached other than from the instruction before it
+fn starts_block(instruction: &Instruction) -> bool {
+    // Data and raw assembly may be reached through labels that the compiler doesn't know about
+    matches!(
+        instruction,
+        asm::Label(..)
+            | asm::RawAsm(..)
+            | asm::AllocateBytes(..)
+            | asm::AllocateHalfWords(..)
+            | asm::AllocateWords(..)
+            | asm::AllocateSpace(..)
+            | asm::AllocateAscii(..)
+            | asm::Align(..)
+    )
+}
+
+/// Get the label that a jump or branch goes to
+fn jump_target(instruction: &Instruction) -> Option<&String> {
+    match instruction {
+        asm::Jump(label) | asm::JumpIfZero(_, label) | asm::JumpIfNotZero(_, label) => Some(label),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dlx::asm::*;
+
+    fn label(name: &str) -> Instruction {
+        Label(name.to_string())
+    }
+
+    #[test]
+    fn removes_moves_to_the_same_register() {
+        let code = vec![AddUnsigned(1, 1, 0), AddUnsigned(2, 3, 0), JumpR(31)];
+        assert_eq!(optimize(code), vec![AddUnsigned(2, 3, 0), JumpR(31)]);
+    }
+
+    #[test]
+    fn removes_moves_back_to_the_source() {
+        let code = vec![AddUnsigned(2, 1, 0), AddUnsigned(1, 2, 0), JumpR(31)];
+        assert_eq!(optimize(code), vec![AddUnsigned(2, 1, 0), JumpR(31)]);
+    }
+
+    #[test]
+    fn propagates_moves_into_their_only_use() {
+        let code = vec![
+            AddUnsigned(6, 1, 0),
+            Store32(Const(8), 30, 6),
+            AddSignedValue(6, 0, 5),
+            JumpR(31),
+        ];
+        let expected = vec![Store32(Const(8), 30, 1), AddSignedValue(6, 0, 5), JumpR(31)];
+        assert_eq!(optimize(code), expected);
+    }
+
+    #[test]
+    fn keeps_moves_that_are_used_later() {
+        let code = vec![AddUnsigned(6, 1, 0), Store32(Const(8), 30, 6), JumpR(31)];
+        assert_eq!(optimize(code.clone()), code);
+
+        // Arguments are read by the call
+        let code = vec![AddUnsigned(2, 6, 0), AddUnsigned(3, 2, 0), JumpStore("f".to_string())];
+        assert_eq!(optimize(code.clone()), code);
+    }
+
+    #[test]
+    fn propagates_moves_into_an_instruction_that_overwrites_them() {
+        let code = vec![AddUnsigned(2, 1, 0), AddUnsignedValue(2, 2, 4), JumpR(31)];
+        assert_eq!(optimize(code), vec![AddUnsignedValue(2, 1, 4), JumpR(31)]);
+    }
+
+    #[test]
+    fn replaces_loads_of_stored_values() {
+        let code = vec![Store32(Const(8), 30, 6), Load32(6, Const(8), 30), JumpR(31)];
+        assert_eq!(optimize(code), vec![Store32(Const(8), 30, 6), JumpR(31)]);
+
+        let code = vec![Store32(Const(8), 30, 6), Load32(7, Const(8), 30), Halt];
+        let expected = vec![Store32(Const(8), 30, 6), AddUnsigned(7, 6, 0), Halt];
+        assert_eq!(optimize(code), expected);
+    }
+
+    #[test]
+    fn keeps_loads_of_other_locations() {
+        let code = vec![Store32(Const(8), 30, 6), Load32(6, Const(12), 30), JumpR(31)];
+        assert_eq!(optimize(code.clone()), code);
+
+        let code = vec![Store8(Const(8), 30, 6), Load8(6, Const(8), 30), JumpR(31)];
+        assert_eq!(optimize(code.clone()), code);
+
+        // The console registers can change between a store and a load
+        let code = vec![Store32(Const(-244), 0, 1), Load32(1, Const(-244), 0), JumpR(31)];
+        assert_eq!(optimize(code.clone()), code);
+    }
+
+    #[test]
+    fn removes_jumps_to_the_next_label() {
+        let code = vec![Jump("a1".to_string()), label("a1"), JumpR(31)];
+        assert_eq!(optimize(code), vec![label("a1"), JumpR(31)]);
+
+        let code = vec![JumpIfZero(1, "a2".to_string()), label("a1"), label("a2"), Halt];
+        assert_eq!(optimize(code), vec![label("a1"), label("a2"), Halt]);
+    }
+
+    #[test]
+    fn removes_zero_adds() {
+        let code = vec![AddUnsignedValue(14, 14, 0), SubSignedValue(2, 1, 0), Halt];
+        assert_eq!(optimize(code), vec![AddUnsigned(2, 1, 0), Halt]);
+    }
+
+    #[test]
+    fn removes_code_after_unconditional_jumps() {
+        let code = vec![
+            Jump("a1".to_string()),
+            AddUnsigned(2, 1, 0),
+            Halt,
+            label("a1"),
+            JumpR(31),
+            Nop,
+            RawAsm("".to_string()),
+        ];
+        assert_eq!(optimize(code), vec![label("a1"), JumpR(31), RawAsm("".to_string())]);
+
+        let code = vec![JumpR(31), Load32(1, Const(0), 2), AllocateWords(vec![1]), Halt];
+        assert_eq!(optimize(code), vec![JumpR(31), AllocateWords(vec![1]), Halt]);
+    }
+}
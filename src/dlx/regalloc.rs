@@ -0,0 +1,474 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::dlx::{
+    asm::{self, Instruction, LabelId, RegId},
+    codegen::{FRAME_POINTER, MAX_FRAME_SIZE, RETURN_REG, STACK_POINTER},
+};
+
+/// Registers numbered from here up are virtual registers, which are replaced by machine registers
+/// during allocation
+pub const FIRST_VIRTUAL_REG: RegId = 32;
+
+// Registers that virtual registers can be assigned to, other than the scratch registers. r14 and
+// r15 hold the stack and heap pointers. All of them are saved by the function that uses them
+// (callee-saved), so values stay in registers across calls.
+const ALLOCATABLE_REGS: [RegId; 20] =
+    [6, 7, 8, 9, 10, 11, 12, 13, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+
+// Registers that are overwritten by calls (caller-saved), since r1 holds the return value and the
+// arguments are passed in r2-r5, and that may be used by inline assembly. Values that are not live
+// across either can be kept in them without saving them.
+const SCRATCH_REGS: [RegId; 5] = [1, 2, 3, 4, 5];
+
+// Registers that spilled values are loaded into while they are being used
+const SPILL_REGS: [RegId; 2] = [28, 29];
+
+/// Check whether a register may hold a value of a function, so that it must be saved before
+/// anything else modifies it
+pub fn is_callee_saved(reg: RegId) -> bool {
+    ALLOCATABLE_REGS.contains(&reg) || SPILL_REGS.contains(&reg)
+}
+
+/// The stack frame of a function, which is extended to hold spilled and saved registers
+pub struct Frame {
+    /// The index of the instruction that reserves the stack space for the frame
+    pub reserve_index: usize,
+    /// The index of the label at the start of the function's epilogue
+    pub epilogue_index: usize,
+    /// The number of bytes already used by the frame
+    pub size: u16,
+    /// The registers modified by inline assembly, which can't be assigned to values
+    pub clobbers: Vec<RegId>,
+}
+
+/// The range of instructions that a virtual register must be kept alive for
+struct Interval {
+    reg: RegId,
+    start: usize,
+    end: usize,
+    /// Whether the value must survive a call or inline assembly
+    crosses_call: bool,
+}
+
+#[derive(Copy, Clone)]
+enum Allocation {
+    Register(RegId),
+    Spilled(i16),
+}
+
+/// Assign the virtual registers in the code of a function to machine registers, using linear scan
+/// allocation. Values that do not fit in the available registers are spilled to the stack frame.
+/// Fails with the size the frame would need if it grows past `MAX_FRAME_SIZE`.
+pub fn allocate(mut code: Vec<Instruction>, frame: Frame) -> Result<Vec<Instruction>, u32> {
+    let intervals = live_intervals(&mut code);
+    let mut allocations = linear_scan(intervals, &frame.clobbers);
+
+    // Spilled values are stored after the variables in the stack frame
+    let mut frame_size = frame.size as u32;
+    let mut spilled: Vec<_> = allocations
+        .iter_mut()
+        .filter_map(|(reg, allocation)| match allocation {
+            Allocation::Spilled(offset) => Some((*reg, offset)),
+            Allocation::Register(..) => None,
+        })
+        .collect();
+    spilled.sort_by_key(|(reg, _)| *reg);
+    for (_, offset) in &mut spilled {
+        **offset = frame_size as i16;
+        frame_size += 4;
+    }
+    let has_spills = !spilled.is_empty();
+
+    // The machine registers used by this function must be restored before it returns
+    let mut saved_regs: Vec<RegId> = allocations
+        .values()
+        .filter_map(|allocation| match allocation {
+            Allocation::Register(reg) if !SCRATCH_REGS.contains(reg) => Some(*reg),
+            Allocation::Register(..) | Allocation::Spilled(..) => None,
+        })
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if has_spills {
+        saved_regs.extend(SPILL_REGS);
+    }
+    // Registers modified by inline assembly are saved as well, unless they are scratch registers
+    for &reg in &frame.clobbers {
+        if !SCRATCH_REGS.contains(&reg) && reg != RETURN_REG && !saved_regs.contains(&reg) {
+            saved_regs.push(reg);
+        }
+    }
+    saved_regs.sort_unstable();
+    let save_offsets: Vec<(RegId, i16)> = saved_regs
+        .into_iter()
+        .map(|reg| {
+            frame_size += 4;
+            (reg, (frame_size - 4) as i16)
+        })
+        .collect();
+    if frame_size > MAX_FRAME_SIZE {
+        return Err(frame_size);
+    }
+    let frame_size = frame_size as u16;
+
+    let mut output = Vec::with_capacity(code.len());
+    for (i, mut instruction) in code.into_iter().enumerate() {
+        if i == frame.reserve_index {
+            output.push(asm::AddUnsignedValue(STACK_POINTER, STACK_POINTER, frame_size));
+            for &(reg, offset) in &save_offsets {
+                output.push(asm::Store32(asm::Const(offset), FRAME_POINTER, reg));
+            }
+            continue;
+        }
+        if i == frame.epilogue_index {
+            output.push(instruction);
+            for &(reg, offset) in &save_offsets {
+                output.push(asm::Load32(reg, asm::Const(offset), FRAME_POINTER));
+            }
+            continue;
+        }
+
+        let mut reloads = vec![];
+        let mut spill = None;
+        let (def, uses) = instruction.registers_mut();
+        let mut loaded: Vec<(RegId, RegId)> = vec![];
+        for reg in uses.into_iter().filter(|reg| **reg >= FIRST_VIRTUAL_REG) {
+            match allocations[&*reg] {
+                Allocation::Register(target) => *reg = target,
+                Allocation::Spilled(offset) => {
+                    // Load the value into a spill register, unless it has already been loaded
+                    let target = match loaded.iter().find(|(vreg, _)| *vreg == *reg) {
+                        Some(&(_, target)) => target,
+                        None => {
+                            let target = SPILL_REGS[loaded.len()];
+                            loaded.push((*reg, target));
+                            reloads.push(asm::Load32(target, asm::Const(offset), FRAME_POINTER));
+                            target
+                        }
+                    };
+                    *reg = target;
+                }
+            }
+        }
+        if let Some(reg) = def.filter(|reg| **reg >= FIRST_VIRTUAL_REG) {
+            match allocations[&*reg] {
+                Allocation::Register(target) => *reg = target,
+                Allocation::Spilled(offset) => {
+                    // The operands have been read by the time the result is written, so the first
+                    // spill register can always be reused
+                    *reg = SPILL_REGS[0];
+                    spill = Some(asm::Store32(asm::Const(offset), FRAME_POINTER, SPILL_REGS[0]));
+                }
+            }
+        }
+
+        // Moves between values that were assigned the same register are no longer needed
+        if let asm::AddUnsigned(to, from, 0) = &instruction {
+            if to == from && reloads.is_empty() && spill.is_none() {
+                continue;
+            }
+        }
+
+        output.extend(reloads);
+        output.push(instruction);
+        output.extend(spill);
+    }
+
+    Ok(output)
+}
+
+/// Find the range of instructions where each virtual register holds a value that may be used
+fn live_intervals(code: &mut [Instruction]) -> Vec<Interval> {
+    let labels: HashMap<LabelId, usize> = code
+        .iter()
+        .enumerate()
+        .filter_map(|(i, instruction)| match instruction {
+            asm::Label(label) => Some((label.clone(), i)),
+            _ => None,
+        })
+        .collect();
+
+    // The instructions that may be executed after each instruction
+    let successors: Vec<Vec<usize>> = code
+        .iter()
+        .enumerate()
+        .map(|(i, instruction)| {
+            let mut next: Vec<usize> = (i + 1..code.len()).take(1).collect();
+            match instruction {
+                asm::Jump(label) => labels.get(label).copied().into_iter().collect(),
+                asm::JumpIfZero(_, label) | asm::JumpIfNotZero(_, label) => {
+                    next.extend(labels.get(label));
+                    next
+                }
+                asm::JumpR(..) | asm::Halt => vec![],
+                _ => next,
+            }
+        })
+        .collect();
+
+    // The virtual registers written and read by each instruction
+    let mut defs = vec![];
+    let mut uses: Vec<Vec<RegId>> = vec![];
+    for instruction in code.iter_mut() {
+        let (def, used) = instruction.registers_mut();
+        defs.push(def.map(|reg| *reg).filter(|&reg| reg >= FIRST_VIRTUAL_REG));
+        uses.push(
+            used.into_iter().map(|reg| *reg).filter(|&reg| reg >= FIRST_VIRTUAL_REG).collect(),
+        );
+    }
+
+    // Find the registers that are live on entry to each instruction. Loops mean that this has to
+    // be repeated until nothing changes.
+    let mut live_in: Vec<HashSet<RegId>> = vec![HashSet::new(); code.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..code.len()).rev() {
+            let mut live: HashSet<RegId> =
+                successors[i].iter().flat_map(|&next| live_in[next].iter().copied()).collect();
+            if let Some(def) = defs[i] {
+                live.remove(&def);
+            }
+            live.extend(&uses[i]);
+
+            if live != live_in[i] {
+                live_in[i] = live;
+                changed = true;
+            }
+        }
+    }
+
+    // Since instructions are allocated in order, each register must be kept from the first to the
+    // last instruction that it is live at
+    let mut ranges: HashMap<RegId, (usize, usize)> = HashMap::new();
+    for (i, live) in live_in.iter().enumerate() {
+        for &reg in live.iter().chain(defs[i].iter()) {
+            let range = ranges.entry(reg).or_insert((i, i));
+            range.1 = i;
+        }
+    }
+
+    // Calls and inline assembly may overwrite the scratch registers. The scratch registers are also
+    // in use while arguments are moved into them before a call, while parameters are moved out of
+    // them at the start of a function, and by the instructions of inline assembly.
+    let calls: Vec<usize> = code
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(i, instruction)| {
+            let is_call = match instruction {
+                asm::JumpStore(..) | asm::JumpStoreR(..) | asm::Trap(..) | asm::RawAsm(..) => true,
+                other => {
+                    let (def, uses) = other.registers_mut();
+                    def.into_iter().chain(uses).any(|reg| SCRATCH_REGS.contains(reg))
+                }
+            };
+            is_call.then_some(i)
+        })
+        .collect();
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(reg, (start, end))| {
+            let crosses_call = calls.iter().any(|&call| start < call && call < end);
+            Interval { reg, start, end, crosses_call }
+        })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.reg));
+    intervals
+}
+
+/// Assign registers to live intervals in order of their start. When there are no registers left,
+/// the interval that ends last is spilled, since it would otherwise hold a register the longest.
+fn linear_scan(intervals: Vec<Interval>, clobbers: &[RegId]) -> HashMap<RegId, Allocation> {
+    let mut allocations = HashMap::new();
+    let mut free: Vec<RegId> =
+        ALLOCATABLE_REGS.iter().rev().copied().filter(|reg| !clobbers.contains(reg)).collect();
+    let mut free_scratch: Vec<RegId> = SCRATCH_REGS.iter().rev().copied().collect();
+    let mut active: Vec<Interval> = vec![];
+
+    for interval in intervals {
+        // Free the registers of intervals that have ended
+        active.retain(|other| {
+            if other.end < interval.start {
+                if let Some(&Allocation::Register(reg)) = allocations.get(&other.reg) {
+                    if SCRATCH_REGS.contains(&reg) {
+                        free_scratch.push(reg);
+                    }
+                    else {
+                        free.push(reg);
+                    }
+                }
+                return false;
+            }
+            true
+        });
+        // Prefer the lowest numbered registers, so that fewer registers need to be saved
+        free.sort_unstable_by(|a, b| b.cmp(a));
+        free_scratch.sort_unstable_by(|a, b| b.cmp(a));
+
+        let reg = if interval.crosses_call {
+            free.pop()
+        }
+        else {
+            free_scratch.pop().or_else(|| free.pop())
+        };
+        match reg {
+            Some(reg) => {
+                allocations.insert(interval.reg, Allocation::Register(reg));
+                active.push(interval);
+            }
+            None => {
+                // Only values in saved registers can give their register to this interval. There
+                // may be none when inline assembly clobbers every saved register.
+                let last = active
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, other)| match allocations[&other.reg] {
+                        Allocation::Register(reg) => !SCRATCH_REGS.contains(&reg),
+                        Allocation::Spilled(..) => false,
+                    })
+                    .max_by_key(|(_, other)| other.end);
+                match last {
+                    Some((index, last)) if last.end > interval.end => {
+                        let reg = allocations[&last.reg];
+                        allocations.insert(last.reg, Allocation::Spilled(0));
+                        allocations.insert(interval.reg, reg);
+                        active[index] = interval;
+                    }
+                    _ => {
+                        allocations.insert(interval.reg, Allocation::Spilled(0));
+                    }
+                }
+            }
+        }
+    }
+
+    allocations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dlx::{
+        asm::*,
+        sim::{Machine, Status},
+    };
+
+    // The start of the code of each test, which sets up the stack and frame pointers
+    const SETUP: usize = 2;
+
+    fn interval(reg: RegId, start: usize, end: usize) -> Interval {
+        Interval { reg, start, end, crosses_call: true }
+    }
+
+    /// Allocate the registers of the body of a function, which is followed by a subroutine that
+    /// overwrites every scratch register
+    fn allocate_body(body: Vec<Instruction>, clobbers: Vec<RegId>) -> Vec<Instruction> {
+        let mut code = vec![
+            AddUnsignedValue(FRAME_POINTER, 0, 0x8000),
+            AddUnsigned(STACK_POINTER, FRAME_POINTER, 0),
+            Nop,
+        ];
+        code.extend(body);
+        let epilogue_index = code.len();
+        code.push(Label("end".to_string()));
+        code.push(Halt);
+        code.push(Label("clobber".to_string()));
+        code.extend(SCRATCH_REGS.iter().map(|&reg| AddUnsignedValue(reg, 0, 99)));
+        code.push(JumpR(RETURN_REG));
+
+        let frame = Frame { reserve_index: SETUP, epilogue_index, size: 8, clobbers };
+        allocate(code, frame).ok().unwrap()
+    }
+
+    /// Run allocated code in the simulator, giving the value left in `r1`
+    fn run(code: &[Instruction]) -> u32 {
+        let mut machine = Machine::load(code).unwrap();
+        assert_eq!(machine.run(10_000), Status::Halted);
+        machine.regs[1]
+    }
+
+    #[test]
+    fn spills_when_every_saved_register_is_clobbered() {
+        let intervals = vec![interval(32, 0, 4), interval(33, 1, 3)];
+        let allocations = linear_scan(intervals, &ALLOCATABLE_REGS);
+        assert!(matches!(allocations[&32], Allocation::Spilled(..)));
+        assert!(matches!(allocations[&33], Allocation::Spilled(..)));
+    }
+
+    #[test]
+    fn spills_values_when_registers_run_out() {
+        // Keep 30 values live at once, which is more than the registers available
+        let values = FIRST_VIRTUAL_REG..FIRST_VIRTUAL_REG + 30;
+        let sum = FIRST_VIRTUAL_REG + 30;
+        let mut body: Vec<_> =
+            values.clone().map(|reg| AddUnsignedValue(reg, 0, reg as u16 - 31)).collect();
+        body.push(AddUnsigned(sum, 0, 0));
+        body.extend(values.map(|reg| AddUnsigned(sum, sum, reg)));
+        body.push(AddUnsigned(1, sum, 0));
+
+        let code = allocate_body(body, vec![]);
+        assert!(code
+            .iter()
+            .any(|x| matches!(x, Store32(_, FRAME_POINTER, reg) if SPILL_REGS.contains(reg))));
+        assert_eq!(run(&code), (1..=30).sum::<u32>());
+    }
+
+    #[test]
+    fn keeps_values_across_calls() {
+        let body = vec![
+            AddUnsignedValue(32, 0, 5),
+            AddUnsignedValue(33, 0, 6),
+            JumpStore("clobber".to_string()),
+            AddUnsigned(1, 32, 33),
+        ];
+        assert_eq!(run(&allocate_body(body, vec![])), 11);
+    }
+
+    #[test]
+    fn keeps_values_live_around_loops() {
+        // `k` is last read at the top of the loop, but is still needed on the next iteration, so
+        // `t` can't be given its register
+        let (k, sum, i, t) = (32, 33, 34, 35);
+        let body = vec![
+            AddUnsignedValue(k, 0, 7),
+            AddUnsignedValue(sum, 0, 0),
+            AddUnsignedValue(i, 0, 10),
+            Label("loop".to_string()),
+            AddUnsigned(sum, sum, k),
+            AddUnsigned(t, i, i),
+            AddUnsigned(sum, sum, t),
+            SubUnsignedValue(i, i, 1),
+            JumpIfNotZero(i, "loop".to_string()),
+            AddUnsigned(1, sum, 0),
+        ];
+        assert_eq!(run(&allocate_body(body, vec![])), 7 * 10 + 2 * 55);
+    }
+
+    #[test]
+    fn saves_the_registers_it_uses() {
+        let body = vec![
+            AddUnsignedValue(32, 0, 5),
+            JumpStore("clobber".to_string()),
+            AddUnsigned(1, 32, 0),
+        ];
+        let code = allocate_body(body, vec![16]);
+
+        // The value is kept in `r6` across the call, and `r16` is clobbered by inline assembly
+        let prologue = [
+            AddUnsignedValue(STACK_POINTER, STACK_POINTER, 16),
+            Store32(Const(8), FRAME_POINTER, 6),
+            Store32(Const(12), FRAME_POINTER, 16),
+            AddUnsignedValue(6, 0, 5),
+        ];
+        assert_eq!(code[SETUP..SETUP + 4], prologue[..]);
+        let epilogue = [
+            Label("end".to_string()),
+            Load32(6, Const(8), FRAME_POINTER),
+            Load32(16, Const(12), FRAME_POINTER),
+            Halt,
+        ];
+        assert_eq!(code[SETUP + 6..SETUP + 10], epilogue[..]);
+        assert_eq!(run(&code), 5);
+    }
+}
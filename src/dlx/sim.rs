@@ -0,0 +1,535 @@
+use std::fmt;
+
+use crate::dlx::{
+    asm::{Instruction, RegId},
+    assembler::{self, AsmError, Image},
+    console::{self, Console, ConsoleError},
+    encoding::*,
+};
+
+/// The size of the simulated memory in bytes. Since immediate values are 16 bits, all labels must
+/// be addressable with a 16 bit immediate.
+pub const MEMORY_SIZE: u32 = 0x10000;
+
+/// The default number of instructions to execute before assuming the program will never halt
+pub const DEFAULT_STEP_LIMIT: u64 = 50_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    fn bytes(self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum AluOp {
+    Add,
+    AddU,
+    Sub,
+    SubU,
+    Mult,
+    MultU,
+    Div,
+    DivU,
+    And,
+    Or,
+    Xor,
+    Sll,
+    Srl,
+    Sra,
+    Seq,
+    Sne,
+    Slt,
+    Sgt,
+    Sle,
+    Sge,
+    SeqU,
+    SneU,
+    SltU,
+    SgtU,
+    SleU,
+    SgeU,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Src {
+    Reg(RegId),
+    Imm(u32),
+}
+
+/// A decoded instruction ready to be executed
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Load { width: Width, signed: bool, rd: RegId, base: RegId, offset: i32 },
+    Store { width: Width, base: RegId, offset: i32, rs: RegId },
+    Alu { op: AluOp, rd: RegId, rs1: RegId, src2: Src },
+    LoadHigh { rd: RegId, value: u32 },
+    Branch { if_zero: bool, rs: RegId, offset: i32 },
+    Jump { link: bool, offset: i32 },
+    JumpReg { link: bool, rs: RegId },
+    Trap(u32),
+    Nop,
+}
+
+/// The reason that the simulation stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Halted,
+    StepLimit,
+    EndOfInput,
+    Fault { pc: u32, message: String },
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Halted => f.write_str("halted"),
+            Status::StepLimit => f.write_str("stopped: instruction limit reached"),
+            Status::EndOfInput => f.write_str("stopped: end of keyboard input"),
+            Status::Fault { pc, message } => write!(f, "fault at 0x{:08x}: {}", pc, message),
+        }
+    }
+}
+
+/// An exception raised while executing an instruction
+enum Exception {
+    EndOfInput,
+    Fault(String),
+}
+
+impl From<String> for Exception {
+    fn from(message: String) -> Exception {
+        Exception::Fault(message)
+    }
+}
+
+impl From<ConsoleError> for Exception {
+    fn from(error: ConsoleError) -> Exception {
+        match error {
+            ConsoleError::EndOfInput => Exception::EndOfInput,
+            ConsoleError::Fault(message) => Exception::Fault(message),
+        }
+    }
+}
+
+/// A simulated DLX machine
+pub struct Machine {
+    pub regs: [u32; 32],
+    pub pc: u32,
+    pub steps: u64,
+    memory: Vec<u8>,
+    ops: Vec<Option<Op>>,
+    console: Option<Console>,
+}
+
+impl Machine {
+    /// Assemble a program and load it into a new machine
+    pub fn load(program: &[Instruction]) -> Result<Machine, AsmError> {
+        let image = assembler::assemble(program)?;
+        if image.end() > MEMORY_SIZE {
+            let message = format!("program requires {} bytes of memory", image.end());
+            return Err(AsmError { line: String::new(), message });
+        }
+        Ok(Machine::from_image(&image))
+    }
+
+    /// Create a new machine with an assembled image loaded into memory
+    pub fn from_image(image: &Image) -> Machine {
+        let mut memory = vec![0; MEMORY_SIZE as usize];
+        let base = image.base as usize;
+        memory[base..base + image.bytes.len()].copy_from_slice(&image.bytes);
+
+        // Decode everything up front instead of on every step, memory writes keep this updated
+        let ops =
+            memory.chunks(4).map(|x| decode(u32::from_be_bytes(x.try_into().unwrap()))).collect();
+
+        Machine { regs: [0; 32], pc: image.start, steps: 0, memory, ops, console: None }
+    }
+
+    /// Attach a console to the machine's memory mapped device registers
+    pub fn attach_console(&mut self, console: Console) {
+        self.console = Some(console);
+    }
+
+    /// Run the machine until it halts, faults or `step_limit` instructions have been executed
+    pub fn run(&mut self, step_limit: u64) -> Status {
+        let mut status = Status::StepLimit;
+        while self.steps < step_limit {
+            let pc = self.pc;
+            match self.step() {
+                Ok(false) => {}
+                Ok(true) => status = Status::Halted,
+                Err(Exception::EndOfInput) => status = Status::EndOfInput,
+                Err(Exception::Fault(message)) => status = Status::Fault { pc, message },
+            }
+            if status != Status::StepLimit {
+                break;
+            }
+        }
+
+        if let Some(console) = &mut self.console {
+            console.flush();
+        }
+        status
+    }
+
+    /// Execute a single instruction, returning true if the machine halted
+    fn step(&mut self) -> Result<bool, Exception> {
+        if !self.pc.is_multiple_of(4) {
+            return Err("misaligned program counter".to_string().into());
+        }
+        let op = match self.ops.get((self.pc / 4) as usize) {
+            Some(Some(op)) => *op,
+            _ => return Err("no instruction at address".to_string().into()),
+        };
+
+        self.steps += 1;
+        let next_pc = self.pc.wrapping_add(4);
+        self.pc = next_pc;
+
+        match op {
+            Op::Load { width, signed, rd, base, offset } => {
+                let addr = self.regs[base].wrapping_add(offset as u32);
+                let value = self.read(addr, width)?;
+                let value = match (signed, width) {
+                    (true, Width::Byte) => value as u8 as i8 as i32 as u32,
+                    (true, Width::Half) => value as u16 as i16 as i32 as u32,
+                    _ => value,
+                };
+                self.set_reg(rd, value);
+            }
+            Op::Store { width, base, offset, rs } => {
+                let addr = self.regs[base].wrapping_add(offset as u32);
+                self.write(addr, width, self.regs[rs])?;
+            }
+            Op::Alu { op, rd, rs1, src2 } => {
+                let a = self.regs[rs1];
+                let b = match src2 {
+                    Src::Reg(reg) => self.regs[reg],
+                    Src::Imm(value) => value,
+                };
+                let value = alu(op, a, b)?;
+                self.set_reg(rd, value);
+            }
+            Op::LoadHigh { rd, value } => self.set_reg(rd, value << 16),
+            Op::Branch { if_zero, rs, offset } => {
+                if (self.regs[rs] == 0) == if_zero {
+                    self.pc = next_pc.wrapping_add(offset as u32);
+                }
+            }
+            Op::Jump { link, offset } => {
+                if link {
+                    self.set_reg(31, next_pc);
+                }
+                self.pc = next_pc.wrapping_add(offset as u32);
+            }
+            Op::JumpReg { link, rs } => {
+                let target = self.regs[rs];
+                if link {
+                    self.set_reg(31, next_pc);
+                }
+                self.pc = target;
+            }
+            Op::Trap(0) => {
+                // Leave the program counter at the halt instruction
+                self.pc = next_pc.wrapping_sub(4);
+                return Ok(true);
+            }
+            Op::Trap(id) => return Err(format!("unsupported trap `{}`", id).into()),
+            Op::Nop => {}
+        }
+
+        Ok(false)
+    }
+
+    /// Read a value from memory
+    fn read(&mut self, addr: u32, width: Width) -> Result<u32, Exception> {
+        if addr >= console::BASE {
+            return Ok(self.device(addr, width)?.read(addr)?);
+        }
+        let bytes = self.mem_range(addr, width)?;
+        Ok(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u32))
+    }
+
+    /// Write a value to memory
+    fn write(&mut self, addr: u32, width: Width, value: u32) -> Result<(), Exception> {
+        if addr >= console::BASE {
+            return Ok(self.device(addr, width)?.write(addr, value)?);
+        }
+        Ok(self.write_memory(addr, width, value)?)
+    }
+
+    /// Write a value to main memory
+    fn write_memory(&mut self, addr: u32, width: Width, value: u32) -> Result<(), String> {
+        // Check that the access is valid before modifying memory
+        let len = self.mem_range(addr, width)?.len();
+
+        // DLX is big endian, so the most significant byte is stored first
+        let start = addr as usize;
+        for i in 0..len {
+            self.memory[start + i] = (value >> (8 * (len - 1 - i))) as u8;
+        }
+
+        // Keep the decoded instruction up to date with the contents of memory
+        let word_start = start & !3;
+        let word = u32::from_be_bytes(self.memory[word_start..word_start + 4].try_into().unwrap());
+        self.ops[word_start / 4] = decode(word);
+        Ok(())
+    }
+
+    /// Get the device that handles an address. Device registers must be accessed as words.
+    fn device(&mut self, addr: u32, width: Width) -> Result<&mut Console, String> {
+        if width != Width::Word {
+            return Err(format!("device register at 0x{:08x} must be accessed as a word", addr));
+        }
+        match &mut self.console {
+            Some(console) => Ok(console),
+            None => Err(format!("no device attached at 0x{:08x}", addr)),
+        }
+    }
+
+    fn mem_range(&self, addr: u32, width: Width) -> Result<&[u8], String> {
+        if !addr.is_multiple_of(width.bytes()) {
+            return Err(format!("misaligned memory access at 0x{:08x}", addr));
+        }
+        let start = addr as usize;
+        let end = start + width.bytes() as usize;
+        if end > self.memory.len() {
+            return Err(format!("memory access out of bounds at 0x{:08x}", addr));
+        }
+        Ok(&self.memory[start..end])
+    }
+
+    fn set_reg(&mut self, reg: RegId, value: u32) {
+        // Register 0 is always 0
+        if reg != 0 {
+            self.regs[reg] = value;
+        }
+    }
+}
+
+fn alu(op: AluOp, a: u32, b: u32) -> Result<u32, String> {
+    let (sa, sb) = (a as i32, b as i32);
+    let value = match op {
+        AluOp::Add | AluOp::AddU => a.wrapping_add(b),
+        AluOp::Sub | AluOp::SubU => a.wrapping_sub(b),
+        AluOp::Mult => sa.wrapping_mul(sb) as u32,
+        AluOp::MultU => a.wrapping_mul(b),
+        AluOp::Div => match sb {
+            0 => return Err("division by zero".to_string()),
+            _ => sa.wrapping_div(sb) as u32,
+        },
+        AluOp::DivU => match b {
+            0 => return Err("division by zero".to_string()),
+            _ => a / b,
+        },
+        AluOp::And => a & b,
+        AluOp::Or => a | b,
+        AluOp::Xor => a ^ b,
+        AluOp::Sll => a << (b & 0x1f),
+        AluOp::Srl => a >> (b & 0x1f),
+        AluOp::Sra => (sa >> (b & 0x1f)) as u32,
+        AluOp::Seq | AluOp::SeqU => (a == b) as u32,
+        AluOp::Sne | AluOp::SneU => (a != b) as u32,
+        AluOp::Slt => (sa < sb) as u32,
+        AluOp::Sgt => (sa > sb) as u32,
+        AluOp::Sle => (sa <= sb) as u32,
+        AluOp::Sge => (sa >= sb) as u32,
+        AluOp::SltU => (a < b) as u32,
+        AluOp::SgtU => (a > b) as u32,
+        AluOp::SleU => (a <= b) as u32,
+        AluOp::SgeU => (a >= b) as u32,
+    };
+    Ok(value)
+}
+
+/// Decode an instruction word, returning `None` if it is not a valid instruction
+fn decode(word: u32) -> Option<Op> {
+    let Fields { opcode, rs1, rs2, rd, func, immediate, offset } = Fields::decode(word);
+
+    let load =
+        |width, signed| Some(Op::Load { width, signed, rd: rs2, base: rs1, offset: immediate });
+    let store = |width| Some(Op::Store { width, base: rs1, offset: immediate, rs: rs2 });
+    let alu = |op| Some(Op::Alu { op, rd, rs1, src2: Src::Reg(rs2) });
+    let alu_imm = |op, signed| {
+        let value = match signed {
+            true => immediate as u32,
+            false => immediate as u16 as u32,
+        };
+        Some(Op::Alu { op, rd: rs2, rs1, src2: Src::Imm(value) })
+    };
+
+    match opcode {
+        OP_SPECIAL => match func {
+            FN_NOP if word == 0 => Some(Op::Nop),
+            FN_ADD => alu(AluOp::Add),
+            FN_ADDU => alu(AluOp::AddU),
+            FN_SUB => alu(AluOp::Sub),
+            FN_SUBU => alu(AluOp::SubU),
+            FN_AND => alu(AluOp::And),
+            FN_OR => alu(AluOp::Or),
+            FN_XOR => alu(AluOp::Xor),
+            FN_SLL => alu(AluOp::Sll),
+            FN_SRL => alu(AluOp::Srl),
+            FN_SRA => alu(AluOp::Sra),
+            FN_SEQ => alu(AluOp::Seq),
+            FN_SNE => alu(AluOp::Sne),
+            FN_SLT => alu(AluOp::Slt),
+            FN_SGT => alu(AluOp::Sgt),
+            FN_SLE => alu(AluOp::Sle),
+            FN_SGE => alu(AluOp::Sge),
+            FN_SEQU => alu(AluOp::SeqU),
+            FN_SNEU => alu(AluOp::SneU),
+            FN_SLTU => alu(AluOp::SltU),
+            FN_SGTU => alu(AluOp::SgtU),
+            FN_SLEU => alu(AluOp::SleU),
+            FN_SGEU => alu(AluOp::SgeU),
+            _ => None,
+        },
+        OP_ARITH => match func {
+            FN_MULT => alu(AluOp::Mult),
+            FN_MULTU => alu(AluOp::MultU),
+            FN_DIV => alu(AluOp::Div),
+            FN_DIVU => alu(AluOp::DivU),
+            _ => None,
+        },
+
+        OP_LB => load(Width::Byte, true),
+        OP_LBU => load(Width::Byte, false),
+        OP_LH => load(Width::Half, true),
+        OP_LHU => load(Width::Half, false),
+        OP_LW => load(Width::Word, true),
+        OP_SB => store(Width::Byte),
+        OP_SH => store(Width::Half),
+        OP_SW => store(Width::Word),
+
+        OP_BEQZ => Some(Op::Branch { if_zero: true, rs: rs1, offset: immediate }),
+        OP_BNEZ => Some(Op::Branch { if_zero: false, rs: rs1, offset: immediate }),
+        OP_J => Some(Op::Jump { link: false, offset }),
+        OP_JAL => Some(Op::Jump { link: true, offset }),
+        OP_JR => Some(Op::JumpReg { link: false, rs: rs1 }),
+        OP_JALR => Some(Op::JumpReg { link: true, rs: rs1 }),
+
+        OP_LHI => Some(Op::LoadHigh { rd: rs2, value: immediate as u16 as u32 }),
+
+        OP_ADDI => alu_imm(AluOp::Add, true),
+        OP_ADDUI => alu_imm(AluOp::AddU, false),
+        OP_SUBI => alu_imm(AluOp::Sub, true),
+        OP_SUBUI => alu_imm(AluOp::SubU, false),
+        OP_ANDI => alu_imm(AluOp::And, false),
+        OP_ORI => alu_imm(AluOp::Or, false),
+        OP_XORI => alu_imm(AluOp::Xor, false),
+        OP_SLLI => alu_imm(AluOp::Sll, false),
+        OP_SRLI => alu_imm(AluOp::Srl, false),
+        OP_SRAI => alu_imm(AluOp::Sra, false),
+        OP_SEQI => alu_imm(AluOp::Seq, true),
+        OP_SNEI => alu_imm(AluOp::Sne, true),
+        OP_SLTI => alu_imm(AluOp::Slt, true),
+        OP_SGTI => alu_imm(AluOp::Sgt, true),
+        OP_SLEI => alu_imm(AluOp::Sle, true),
+        OP_SGEI => alu_imm(AluOp::Sge, true),
+        OP_SEQUI => alu_imm(AluOp::SeqU, false),
+        OP_SNEUI => alu_imm(AluOp::SneU, false),
+        OP_SLTUI => alu_imm(AluOp::SltU, false),
+        OP_SGTUI => alu_imm(AluOp::SgtU, false),
+        OP_SLEUI => alu_imm(AluOp::SleU, false),
+        OP_SGEUI => alu_imm(AluOp::SgeU, false),
+
+        OP_TRAP => Some(Op::Trap(offset as u32)),
+
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dlx::asm::*;
+
+    fn run(code: Vec<Instruction>) -> (Machine, Status) {
+        let mut machine = Machine::load(&code).unwrap();
+        let status = machine.run(1000);
+        (machine, status)
+    }
+
+    #[test]
+    fn executes_arithmetic() {
+        let code = vec![
+            AddSignedValue(1, 0, 6),
+            AddSignedValue(2, 0, -7),
+            Mult(3, 1, 2),
+            SubSignedValue(4, 3, 2),
+            Div(5, 3, 1),
+            AddSignedValue(0, 0, 1),
+            Halt,
+        ];
+        let (machine, status) = run(code);
+        assert_eq!(status, Status::Halted);
+        assert_eq!(machine.regs[3] as i32, -42);
+        assert_eq!(machine.regs[4] as i32, -44);
+        assert_eq!(machine.regs[5] as i32, -7);
+        // r0 is always zero
+        assert_eq!(machine.regs[0], 0);
+    }
+
+    #[test]
+    fn loads_values_that_were_stored() {
+        let code = vec![
+            AddSignedValue(1, 0, -2),
+            Store32(Const(0x100), 0, 1),
+            Load32(2, Const(0x100), 0),
+            Load8(3, Const(0x103), 0),
+            Load8u(4, Const(0x103), 0),
+            Store8(Const(0x104), 0, 1),
+            Load32(5, Const(0x104), 0),
+            Halt,
+        ];
+        let (machine, _) = run(code);
+        assert_eq!(machine.regs[2] as i32, -2);
+        assert_eq!(machine.regs[3] as i32, -2);
+        assert_eq!(machine.regs[4], 0xFE);
+        // Memory is big endian
+        assert_eq!(machine.regs[5], 0xFE00_0000);
+    }
+
+    #[test]
+    fn branches_until_the_condition_fails() {
+        let code = vec![
+            AddSignedValue(1, 0, 4),
+            Label("loop".to_string()),
+            AddUnsigned(2, 2, 1),
+            SubSignedValue(1, 1, 1),
+            JumpIfNotZero(1, "loop".to_string()),
+            Halt,
+        ];
+        let (machine, _) = run(code);
+        assert_eq!(machine.regs[2], 10);
+        assert_eq!(machine.steps, 1 + 4 * 3 + 1);
+    }
+
+    #[test]
+    fn stops_at_halt() {
+        let code = vec![Halt, AddSignedValue(1, 0, 1)];
+        let (machine, status) = run(code);
+        assert_eq!(status, Status::Halted);
+        assert_eq!((machine.pc, machine.steps, machine.regs[1]), (0, 1, 0));
+    }
+
+    #[test]
+    fn faults_on_accesses_outside_of_memory() {
+        let code = vec![LoadHighImmediate(1, 2), Load32(2, Const(0), 1), Halt];
+        let (_, status) = run(code);
+        let message = "memory access out of bounds at 0x00020000".to_string();
+        assert_eq!(status, Status::Fault { pc: 4, message });
+    }
+}
@@ -0,0 +1,285 @@
+use std::{fmt, ops::Range};
+
+use crate::dlx::asm::RegId;
+
+/// A single line of assembly
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub label: Option<String>,
+    pub operation: Option<Operation>,
+}
+
+/// An instruction or assembler directive along with its operands
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub mnemonic: String,
+    pub operands: Vec<Operand>,
+    /// The byte ranges of the mnemonic and of the operands in the line
+    pub mnemonic_columns: Range<usize>,
+    pub operand_columns: Range<usize>,
+}
+
+/// An error in a line of assembly, along with the byte range of the line that it refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub columns: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// A general purpose register, e.g. `r1`
+    Register(RegId),
+    /// An immediate value, e.g. `-4`, `16#5F` or `label`
+    Immediate(Expr),
+    /// A memory reference, e.g. `-4(r30)` or `label`
+    Memory(Expr, RegId),
+    /// A string literal with escape sequences left unprocessed
+    Text(String),
+    /// A variable of the program written as `{name}`, which is only allowed in inline assembly
+    Variable(String),
+}
+
+/// A value that may depend on the address of a symbol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i32),
+    Symbol(String, i32),
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{}", value),
+            Expr::Symbol(name, 0) => f.write_str(name),
+            Expr::Symbol(name, offset) => write!(f, "{}{:+}", name, offset),
+        }
+    }
+}
+
+/// Parse a single line of assembly.
+/// Labels start at the beginning of a line, instructions and directives are indented, operands
+/// are separated by commas and comments start with `;`.
+pub fn parse_line(line: &str) -> Result<Line, SyntaxError> {
+    let line = strip_comment(line);
+    let error = |message: String, part: &str| SyntaxError { message, columns: columns(line, part) };
+
+    // Labels must start at the beginning of the line
+    let (label, rest) = match line.chars().next() {
+        Some(c) if !c.is_whitespace() => {
+            let end = line.find(char::is_whitespace).unwrap_or(line.len());
+            let name = &line[..end];
+            if !is_symbol(name) {
+                return Err(error(format!("invalid label `{}`", name), name));
+            }
+            (Some(name.to_string()), &line[end..])
+        }
+        _ => (None, line),
+    };
+
+    let rest = rest.trim();
+    if rest.is_empty() {
+        return Ok(Line { label, operation: None });
+    }
+
+    let (mnemonic, operand_str) = match rest.find(char::is_whitespace) {
+        Some(end) => (&rest[..end], rest[end..].trim()),
+        None => (rest, &rest[rest.len()..]),
+    };
+
+    let mut operands = vec![];
+    for operand in split_operands(operand_str).map_err(|message| error(message, operand_str))? {
+        operands.push(parse_operand(operand).map_err(|message| error(message, operand))?);
+    }
+
+    let operation = Operation {
+        mnemonic: mnemonic.to_lowercase(),
+        operands,
+        mnemonic_columns: columns(line, mnemonic),
+        operand_columns: columns(line, operand_str),
+    };
+    Ok(Line { label, operation: Some(operation) })
+}
+
+/// Get the byte range of part of a line, which must be a slice of the line
+fn columns(line: &str, part: &str) -> Range<usize> {
+    let start = part.as_ptr() as usize - line.as_ptr() as usize;
+    start..start + part.len()
+}
+
+/// Parse a numeric literal. Numbers can be written in decimal, or in another base using either
+/// the `16#FF` or `0xFF` forms.
+pub fn parse_number(input: &str) -> Option<i32> {
+    let (negative, digits) = match input.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, input),
+    };
+
+    let value = if let Some((base, digits)) = digits.split_once('#') {
+        i64::from_str_radix(digits, base.parse().ok().filter(|b| (2..=36).contains(b))?).ok()?
+    }
+    else if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    }
+    else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<i64>().ok()?
+    }
+    else {
+        return None;
+    };
+
+    let value = if negative { -value } else { value };
+    // Allow both signed and unsigned 32-bit values
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return None;
+    }
+    Some(value as i32)
+}
+
+/// Process the escape sequences in a string literal
+pub fn unescape(input: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut chars = input.chars();
+    while let Some(next) = chars.next() {
+        let value = match next {
+            '\\' => match chars.next() {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some('0') => 0,
+                Some('\\') => b'\\',
+                Some('"') => b'"',
+                Some('\'') => b'\'',
+                Some(invalid) => return Err(format!("invalid escape sequence `\\{}`", invalid)),
+                None => return Err("string ends with an escape character".to_string()),
+            },
+            c if c.is_ascii() => c as u8,
+            invalid => return Err(format!("non-ascii character `{}` in string", invalid)),
+        };
+        bytes.push(value);
+    }
+    Ok(bytes)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_operands(input: &str) -> Result<Vec<&str>, String> {
+    let mut operands = vec![];
+    if input.is_empty() {
+        return Ok(operands);
+    }
+
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                operands.push(input[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if in_string {
+        return Err("unclosed string literal".to_string());
+    }
+    operands.push(input[start..].trim());
+
+    if operands.iter().any(|x| x.is_empty()) {
+        return Err("missing operand".to_string());
+    }
+    Ok(operands)
+}
+
+fn parse_operand(input: &str) -> Result<Operand, String> {
+    if let Some(text) = input.strip_prefix('"') {
+        return match text.strip_suffix('"') {
+            Some(text) => Ok(Operand::Text(text.to_string())),
+            None => Err(format!("invalid string literal `{}`", input)),
+        };
+    }
+
+    if let Some(reg) = parse_register(input) {
+        return Ok(Operand::Register(reg));
+    }
+
+    if let Some(name) = input.strip_prefix('{').and_then(|x| x.strip_suffix('}')) {
+        return Ok(Operand::Variable(name.to_string()));
+    }
+
+    // Memory reference of the form `offset(rX)`
+    if let Some(inner) = input.strip_suffix(')') {
+        let open = inner.find('(').ok_or_else(|| format!("invalid operand `{}`", input))?;
+        let base = parse_register(inner[open + 1..].trim())
+            .ok_or_else(|| format!("invalid base register in `{}`", input))?;
+        let offset = match inner[..open].trim() {
+            "" => Expr::Number(0),
+            offset => parse_expr(offset)?,
+        };
+        return Ok(Operand::Memory(offset, base));
+    }
+
+    Ok(Operand::Immediate(parse_expr(input)?))
+}
+
+/// Parse a register name of the form `rX` where X is between 0 and 31
+pub fn parse_register(input: &str) -> Option<RegId> {
+    let digits = input.strip_prefix('r').or_else(|| input.strip_prefix('R'))?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok().filter(|&reg| reg < 32)
+}
+
+fn parse_expr(input: &str) -> Result<Expr, String> {
+    if let Some(value) = parse_number(input) {
+        return Ok(Expr::Number(value));
+    }
+
+    // Character literals
+    if input.len() == 3 && input.starts_with('\'') && input.ends_with('\'') {
+        return Ok(Expr::Number(input.as_bytes()[1] as i32));
+    }
+
+    // Symbols with an optional constant offset, e.g. `label+4`
+    let split = input.rfind(['+', '-']).filter(|&i| i > 0);
+    let (name, offset) = match split {
+        Some(i) => {
+            let offset = parse_number(input[i..].trim_start_matches('+'))
+                .ok_or_else(|| format!("invalid offset in `{}`", input))?;
+            (input[..i].trim(), offset)
+        }
+        None => (input, 0),
+    };
+
+    if !is_symbol(name) {
+        return Err(format!("invalid operand `{}`", input));
+    }
+    Ok(Expr::Symbol(name.to_string(), offset))
+}
+
+fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
@@ -1,24 +1,38 @@
-use std::fmt;
+use std::{cell::RefCell, collections::BTreeSet, fmt, panic};
+
+/// Identifies one of the source files of a program, in the order that they were added to the
+/// logger
+pub type FileId = usize;
 
 #[derive(Debug, Copy, Clone)]
 pub struct InputSpan {
+    pub file: FileId,
     pub start: InputPos,
     pub end: InputPos,
 }
 
 impl fmt::Display for InputSpan {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f)
+        write!(f, "{}-{}", self.start, self.end)
     }
 }
 
 impl InputSpan {
-    pub fn new(start: InputPos, end: InputPos) -> InputSpan {
-        InputSpan { start, end }
+    pub fn new(file: FileId, start: InputPos, end: InputPos) -> InputSpan {
+        InputSpan { file, start, end }
     }
 
     pub fn invalid() -> InputSpan {
-        InputSpan { start: InputPos::start(), end: InputPos::start() }
+        InputSpan { file: 0, start: InputPos::start(), end: InputPos::start() }
+    }
+
+    /// Get the span of the columns from `start` to `end` of a span on a single line, counting from
+    /// the start of the span
+    pub fn columns(&self, start: usize, end: usize) -> InputSpan {
+        let line = self.start.line;
+        let start = InputPos { line, col: self.start.col + start };
+        let end = InputPos { line, col: self.start.col + end };
+        InputSpan { file: self.file, start, end }
     }
 }
 
@@ -34,31 +48,747 @@ impl InputPos {
     }
 }
 
-pub struct Logger<'a> {
-    lines: Vec<&'a str>,
+/// Positions are displayed as `line:column`, with both counting from 1
+impl fmt::Display for InputPos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col + 1)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn style(self) -> &'static str {
+        match self {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// A span of the input with a message describing it
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: InputSpan,
+    pub message: String,
+}
+
+/// A message about a problem in the input program
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A unique code identifying the kind of problem, e.g. `E0301`
+    pub code: Option<&'static str>,
+    pub message: String,
+    /// The location of the problem. The label message may be empty.
+    pub primary: Label,
+    /// Other locations that are related to the problem
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String, span: InputSpan) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code: None,
+            message,
+            primary: Label { span, message: String::new() },
+            secondary: vec![],
+            notes: vec![],
+            help: None,
+        }
+    }
+
+    pub fn error(message: String, span: InputSpan) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message, span)
+    }
+
+    pub fn warning(message: String, span: InputSpan) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message, span)
+    }
+
+    pub fn code(mut self, code: &'static str) -> Diagnostic {
+        self.code = Some(code);
+        self
+    }
+
+    /// Set the message shown alongside the primary span
+    pub fn label(mut self, message: String) -> Diagnostic {
+        self.primary.message = message;
+        self
+    }
+
+    pub fn secondary(mut self, span: InputSpan, message: String) -> Diagnostic {
+        self.secondary.push(Label { span, message });
+        self
+    }
+
+    pub fn note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn help(mut self, help: String) -> Diagnostic {
+        self.help = Some(help);
+        self
+    }
+}
+
+/// The payload used to unwind out of the compiler after a fatal error has been reported. Unlike a
+/// normal panic this does not print a message, since the problem is described by the diagnostics.
+pub struct FatalError;
+
+impl FatalError {
+    pub fn raise() -> ! {
+        panic::resume_unwind(Box::new(FatalError))
+    }
+}
+
+/// Run part of the compiler returning `None` if it was stopped by a fatal error
+pub fn catch_fatal<T>(f: impl FnOnce() -> T) -> Option<T> {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(value) => Some(value),
+        Err(payload) if payload.is::<FatalError>() => None,
+        // Any other panic is a bug in the compiler
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+// ANSI escape codes used when colour output is enabled
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+const RESET: &str = "\x1b[0m";
+
+/// The width of a tab character when displaying source lines
+const TAB_WIDTH: usize = 4;
+
+/// The number of lines of a multi-line span to show at either end before the rest is elided
+const MULTI_LINE_CONTEXT: usize = 2;
+
+pub struct Logger {
+    files: RefCell<Vec<SourceFile>>,
     print_span: bool,
+    color: bool,
+    diagnostics: RefCell<Vec<Diagnostic>>,
 }
 
-impl<'a> Logger<'a> {
-    pub fn new(input: &'a str, print_span: bool) -> Logger<'a> {
-        Logger { lines: input.lines().collect(), print_span }
+/// A source file of the program, kept so that diagnostics can show the lines they refer to
+struct SourceFile {
+    name: String,
+    lines: Vec<String>,
+    /// The byte offset of the start of each line
+    line_offsets: Vec<usize>,
+}
+
+impl SourceFile {
+    fn line(&self, line: usize) -> &str {
+        self.lines.get(line.wrapping_sub(1)).map_or("", String::as_str)
     }
 
-    pub fn report_error(&self, message: String, input_span: InputSpan) {
-        println!(
-            "unknown.pcp:{}:{}: {}:{} {}",
-            input_span.start.line,
-            input_span.start.col,
-            input_span.end.line,
-            input_span.end.col,
-            message
-        );
+    /// Get the byte offset of a position in the file
+    fn byte_offset(&self, pos: InputPos) -> usize {
+        let line_start = match self.line_offsets.get(pos.line.wrapping_sub(1)) {
+            Some(&offset) => offset,
+            None => return self.line_offsets.last().copied().unwrap_or(0),
+        };
+        // Carriage returns are not counted in the column, so skip over them here
+        let mut chars = self.line(pos.line).char_indices().filter(|&(_, c)| c != '\r');
+        match chars.nth(pos.col) {
+            Some((i, _)) => line_start + i,
+            None => line_start + self.line(pos.line).len(),
+        }
+    }
+}
+
+/// A label that has been clamped to the lines of the input
+struct Annotation<'d> {
+    start: InputPos,
+    end: InputPos,
+    message: &'d str,
+    primary: bool,
+}
+
+impl Logger {
+    pub fn new(print_span: bool) -> Logger {
+        Logger {
+            files: RefCell::new(vec![]),
+            print_span,
+            color: false,
+            diagnostics: RefCell::new(vec![]),
+        }
+    }
+
+    /// Enable or disable ANSI colour codes in the rendered output
+    pub fn with_color(mut self, color: bool) -> Logger {
+        self.color = color;
+        self
+    }
+
+    /// Add the source code `input`, read from `file`, returning the id used for spans in the file
+    pub fn add_file(&self, file: &str, input: &str) -> FileId {
+        let line_offsets =
+            std::iter::once(0).chain(input.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        let mut files = self.files.borrow_mut();
+        files.push(SourceFile {
+            name: file.to_string(),
+            lines: input.lines().map(String::from).collect(),
+            line_offsets,
+        });
+        files.len() - 1
+    }
+
+    pub fn report(&self, diagnostic: Diagnostic) {
+        self.diagnostics.borrow_mut().push(diagnostic);
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.error_count() != 0
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.diagnostics.borrow().iter().filter(|x| x.severity == severity).count()
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.borrow().clone()
+    }
 
-        if self.print_span {
-            for i in input_span.start.line..(input_span.end.line + 1) {
-                println!("{}", self.lines[i - 1]);
+    /// Render all of the reported diagnostics
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for diagnostic in self.diagnostics.borrow().iter() {
+            self.render_diagnostic(diagnostic, &mut output);
+        }
+
+        let warning = self.paint(YELLOW, "warning");
+        match self.count(Severity::Warning) {
+            0 => {}
+            1 => output.push_str(&format!("{}: 1 warning emitted\n", warning)),
+            n => output.push_str(&format!("{}: {} warnings emitted\n", warning, n)),
+        }
+
+        let error = self.paint(RED, "error");
+        match self.error_count() {
+            0 => {}
+            1 => output.push_str(&format!("{}: aborting due to previous error\n", error)),
+            n => output.push_str(&format!("{}: aborting due to {} previous errors\n", error, n)),
+        }
+        output
+    }
+
+    /// Render all of the reported diagnostics as JSON, with one object per line
+    pub fn render_json(&self) -> String {
+        let files = self.files.borrow();
+        let mut output = String::new();
+        for diagnostic in self.diagnostics.borrow().iter() {
+            let labels = std::iter::once((&diagnostic.primary, true))
+                .chain(diagnostic.secondary.iter().map(|x| (x, false)));
+            let spans: Vec<_> = labels
+                .map(|(label, primary)| span_json(&files[label.span.file], label, primary))
+                .collect();
+            let notes: Vec<_> = diagnostic.notes.iter().map(|x| json_string(x)).collect();
+
+            output.push_str(&format!(
+                "{{\"file\":{},\"severity\":\"{}\",\"code\":{},\"message\":{},\"spans\":[{}],\"notes\":[{}],\"help\":{}}}\n",
+                json_string(&files[diagnostic.primary.span.file].name),
+                diagnostic.severity,
+                diagnostic.code.map_or("null".to_string(), json_string),
+                json_string(&diagnostic.message),
+                spans.join(","),
+                notes.join(","),
+                diagnostic.help.as_deref().map_or("null".to_string(), json_string),
+            ));
+        }
+        output
+    }
+
+    /// Wrap `text` in an ANSI style if colour output is enabled
+    fn paint(&self, style: &str, text: &str) -> String {
+        match self.color {
+            true => format!("{}{}{}", style, text, RESET),
+            false => text.to_string(),
+        }
+    }
+
+    fn render_diagnostic(&self, diagnostic: &Diagnostic, output: &mut String) {
+        let kind = match diagnostic.code {
+            Some(code) => format!("{}[{}]", diagnostic.severity, code),
+            None => diagnostic.severity.to_string(),
+        };
+        output.push_str(&self.paint(diagnostic.severity.style(), &kind));
+        output.push_str(&self.paint(BOLD, &format!(": {}", diagnostic.message)));
+        output.push('\n');
+
+        // The file containing the primary label is shown first, followed by any other files that
+        // the labels refer to
+        let files = self.files.borrow();
+        let mut file_ids = vec![diagnostic.primary.span.file];
+        for label in &diagnostic.secondary {
+            if !file_ids.contains(&label.span.file) {
+                file_ids.push(label.span.file);
+            }
+        }
+        let sections: Vec<_> = file_ids
+            .iter()
+            .map(|&id| (&files[id], self.annotations(&files[id], id, diagnostic)))
+            .collect();
+
+        let width = sections
+            .iter()
+            .flat_map(|(_, annotations)| annotations.iter().map(|x| x.end.line))
+            .max()
+            .unwrap_or(1)
+            .to_string()
+            .len();
+        let gutter = " ".repeat(width);
+
+        for (i, (file, annotations)) in sections.iter().enumerate() {
+            let arrow = if i == 0 { "-->" } else { ":::" };
+            output.push_str(&format!(
+                "{}{} {}:{}\n",
+                gutter,
+                self.paint(BLUE, arrow),
+                file.name,
+                annotations[0].start
+            ));
+
+            if self.print_span {
+                self.render_snippet(file, annotations, diagnostic.severity, width, output);
+            }
+        }
+
+        let has_footer = !diagnostic.notes.is_empty() || diagnostic.help.is_some();
+        if self.print_span && has_footer {
+            output.push_str(&format!("{} {}\n", gutter, self.paint(BLUE, "|")));
+        }
+        for note in &diagnostic.notes {
+            output.push_str(&format!("{} {} {}\n", gutter, self.paint(BOLD, "= note:"), note));
+        }
+        if let Some(help) = &diagnostic.help {
+            output.push_str(&format!("{} {} {}\n", gutter, self.paint(CYAN, "= help:"), help));
+        }
+        output.push('\n');
+    }
+
+    /// Get the labels of a diagnostic in one file with their spans clamped to the file, primary
+    /// label first
+    fn annotations<'d>(
+        &self,
+        file: &SourceFile,
+        id: FileId,
+        diagnostic: &'d Diagnostic,
+    ) -> Vec<Annotation<'d>> {
+        let labels = std::iter::once((&diagnostic.primary, true))
+            .chain(diagnostic.secondary.iter().map(|x| (x, false)))
+            .filter(|(label, _)| label.span.file == id);
+
+        let mut annotations = vec![];
+        for (label, primary) in labels {
+            let start = label.span.start;
+            let mut end = label.span.end;
+            if (end.line, end.col) < (start.line, start.col) {
+                end = start;
+            }
+            // A span ending at the start of a line really ends at the end of the line before
+            if end.line > start.line && end.col == 0 {
+                end.line -= 1;
+                end.col = file.line(end.line).chars().count();
+            }
+            annotations.push(Annotation { start, end, message: &label.message, primary });
+        }
+        annotations
+    }
+
+    /// Render the source lines covered by the annotations. At most one multi-line annotation is
+    /// drawn in the margin, any others are only underlined on their first line.
+    fn render_snippet(
+        &self,
+        file: &SourceFile,
+        annotations: &[Annotation],
+        severity: Severity,
+        width: usize,
+        output: &mut String,
+    ) {
+        let multi_line = annotations.iter().position(|x| x.start.line != x.end.line);
+
+        // Determine which lines need to be displayed
+        let mut lines = BTreeSet::new();
+        for (i, annotation) in annotations.iter().enumerate() {
+            lines.insert(annotation.start.line);
+            if Some(i) == multi_line {
+                let (start, end) = (annotation.start.line, annotation.end.line);
+                lines.extend(start..=end.min(start + MULTI_LINE_CONTEXT - 1));
+                lines.extend(end.saturating_sub(MULTI_LINE_CONTEXT - 1).max(start)..=end);
             }
-            println!();
         }
+        // Don't elide a single line
+        let gaps: Vec<_> = lines.iter().zip(lines.iter().skip(1)).map(|(a, b)| (*a, *b)).collect();
+        for (a, b) in gaps {
+            if b == a + 2 {
+                lines.insert(a + 1);
+            }
+        }
+
+        let gutter = " ".repeat(width);
+        let bar = self.paint(BLUE, "|");
+        output.push_str(&format!("{} {}\n", gutter, bar));
+
+        let mark_style = |annotation: &Annotation| match annotation.primary {
+            true => (severity.style(), '^'),
+            false => (BLUE, '-'),
+        };
+
+        let mut previous = None;
+        for &line_no in &lines {
+            if previous.is_some_and(|previous| line_no > previous + 1) {
+                output.push_str(&format!("{}\n", self.paint(BLUE, "...")));
+            }
+            previous = Some(line_no);
+
+            let text = file.line(line_no);
+            let display_text = text.replace('\t', &" ".repeat(TAB_WIDTH));
+
+            // The margin used to draw multi-line spans
+            let (margin, row_margin) = match multi_line.map(|i| &annotations[i]) {
+                None => (String::new(), String::new()),
+                Some(multi) => {
+                    let first_col = text.chars().take_while(|c| c.is_whitespace()).count();
+                    let starts_line = multi.start.col <= first_col;
+                    let (style, _) = mark_style(multi);
+                    if line_no == multi.start.line && starts_line {
+                        (self.paint(style, "/ "), self.paint(style, "| "))
+                    }
+                    else if line_no > multi.start.line && line_no <= multi.end.line {
+                        (self.paint(style, "| "), self.paint(style, "| "))
+                    }
+                    else if line_no == multi.start.line {
+                        (String::from("  "), self.paint(style, "| "))
+                    }
+                    else {
+                        (String::from("  "), String::from("  "))
+                    }
+                }
+            };
+
+            let line_gutter = self.paint(BLUE, &format!("{:>width$} |", line_no, width = width));
+            output.push_str(format!("{} {}{}", line_gutter, margin, display_text).trim_end());
+            output.push('\n');
+
+            // Underline the single line annotations on this line
+            let mut single_line: Vec<_> = annotations
+                .iter()
+                .enumerate()
+                .filter(|&(i, x)| Some(i) != multi_line && x.start.line == line_no)
+                .map(|(_, x)| x)
+                .collect();
+            single_line.sort_by_key(|x| x.start.col);
+
+            for annotation in single_line {
+                let start = display_col(text, annotation.start.col);
+                let end = match annotation.end.line == line_no {
+                    true => display_col(text, annotation.end.col),
+                    false => display_text.len(),
+                };
+                let (style, mark) = mark_style(annotation);
+                let underline = mark.to_string().repeat(end.saturating_sub(start).max(1));
+                let label = format!("{} {}", underline, annotation.message);
+                output.push_str(&format!(
+                    "{} {} {}{}{}\n",
+                    gutter,
+                    bar,
+                    row_margin,
+                    " ".repeat(start),
+                    self.paint(style, label.trim_end())
+                ));
+            }
+
+            // Draw the start and end of the multi-line annotation
+            if let Some(multi) = multi_line.map(|i| &annotations[i]) {
+                let (style, mark) = mark_style(multi);
+                let first_col = text.chars().take_while(|c| c.is_whitespace()).count();
+                if line_no == multi.start.line && multi.start.col > first_col {
+                    let start = display_col(text, multi.start.col);
+                    let row = format!(" {}{}", "_".repeat(start + 2), mark);
+                    output.push_str(&format!("{} {}{}\n", gutter, bar, self.paint(style, &row)));
+                }
+                if line_no == multi.end.line {
+                    let end = display_col(text, multi.end.col).max(1);
+                    let row = format!("|{}{} {}", "_".repeat(end), mark, multi.message);
+                    let row = self.paint(style, row.trim_end());
+                    output.push_str(&format!("{} {} {}\n", gutter, bar, row));
+                }
+            }
+        }
+    }
+}
+
+fn span_json(file: &SourceFile, label: &Label, primary: bool) -> String {
+    let InputSpan { start, end, .. } = label.span;
+    let label = match label.message.is_empty() {
+        true => "null".to_string(),
+        false => json_string(&label.message),
+    };
+    format!(
+        "{{\"file\":{},\"byte_start\":{},\"byte_end\":{},\"line_start\":{},\"column_start\":{},\"line_end\":{},\"column_end\":{},\"is_primary\":{},\"label\":{}}}",
+        json_string(&file.name),
+        file.byte_offset(start),
+        file.byte_offset(end),
+        start.line,
+        start.col + 1,
+        end.line,
+        end.col + 1,
+        primary,
+        label
+    )
+}
+
+/// Quote and escape a string for use in JSON
+fn json_string(text: &str) -> String {
+    let mut output = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+/// Convert a column in a line of the input to a column in the displayed line
+fn display_col(text: &str, col: usize) -> usize {
+    let mut display = 0;
+    let mut chars = text.chars();
+    for _ in 0..col {
+        display += match chars.next() {
+            Some('\t') => TAB_WIDTH,
+            _ => 1,
+        };
+    }
+    display
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get a span from a line and column to another, with lines counting from 1 and columns from 0
+    fn span(file: FileId, start: (usize, usize), end: (usize, usize)) -> InputSpan {
+        let start = InputPos { line: start.0, col: start.1 };
+        let end = InputPos { line: end.0, col: end.1 };
+        InputSpan::new(file, start, end)
+    }
+
+    /// Render a single error without colours, leaving out the summary at the end
+    fn render(logger: &Logger, diagnostic: Diagnostic) -> String {
+        logger.report(diagnostic);
+        let output = logger.render();
+        output.trim_end_matches("error: aborting due to previous error\n").to_string()
+    }
+
+    /// Join the lines of the expected output of a diagnostic, which ends with a blank line
+    fn lines(lines: &[&str]) -> String {
+        lines.join("\n") + "\n\n"
+    }
+
+    // A function containing a block that spans several lines
+    const BLOCK: &str = concat!(
+        "fn main() -> int {\n",
+        "    let x = if (true) {\n",
+        "        1\n",
+        "        2\n",
+        "        3\n",
+        "        4\n",
+        "    };\n",
+        "    x\n",
+        "}\n",
+    );
+
+    #[test]
+    fn underlines_a_span_on_a_single_line() {
+        let logger = Logger::new(true);
+        let file = logger.add_file("main.pcp", "fn main() -> int {\n    x + 1\n}\n");
+        let diagnostic = Diagnostic::error(
+            "cannot find `x` in this scope".to_string(),
+            span(file, (2, 4), (2, 5)),
+        )
+        .code("E0301")
+        .label("not found in this scope".to_string())
+        .help("declare it with `let`".to_string());
+        let expected = lines(&[
+            "error[E0301]: cannot find `x` in this scope",
+            " --> main.pcp:2:5",
+            "  |",
+            "2 |     x + 1",
+            "  |     ^ not found in this scope",
+            "  |",
+            "  = help: declare it with `let`",
+        ]);
+        assert_eq!(render(&logger, diagnostic), expected);
+    }
+
+    #[test]
+    fn expands_tabs_before_underlining() {
+        let logger = Logger::new(true);
+        let file = logger.add_file("main.pcp", "fn main() -> int {\n\t\tfoo(\tbar)\n}\n");
+        let diagnostic = Diagnostic::error(
+            "cannot find `bar` in this scope".to_string(),
+            span(file, (2, 7), (2, 10)),
+        )
+        .label("not found".to_string());
+        let expected = lines(&[
+            "error: cannot find `bar` in this scope",
+            " --> main.pcp:2:8",
+            "  |",
+            "2 |         foo(    bar)",
+            "  |                 ^^^ not found",
+        ]);
+        assert_eq!(render(&logger, diagnostic), expected);
+    }
+
+    #[test]
+    fn draws_spans_over_several_lines_in_the_margin() {
+        // The middle of a long span is elided
+        let logger = Logger::new(true);
+        let file = logger.add_file("main.pcp", BLOCK);
+        let diagnostic =
+            Diagnostic::error("mismatched types".to_string(), span(file, (2, 12), (7, 5)))
+                .label("expected `int`, found `()`".to_string());
+        let expected = lines(&[
+            "error: mismatched types",
+            " --> main.pcp:2:13",
+            "  |",
+            "2 |       let x = if (true) {",
+            "  | ______________^",
+            "3 | |         1",
+            "...",
+            "6 | |         4",
+            "7 | |     };",
+            "  | |_____^ expected `int`, found `()`",
+        ]);
+        assert_eq!(render(&logger, diagnostic), expected);
+
+        // A span starting at the beginning of a line is drawn from the margin
+        let logger = Logger::new(true);
+        let file = logger.add_file("main.pcp", BLOCK);
+        let diagnostic =
+            Diagnostic::error("mismatched types".to_string(), span(file, (3, 8), (5, 9)))
+                .label("in this block".to_string());
+        let expected = lines(&[
+            "error: mismatched types",
+            " --> main.pcp:3:9",
+            "  |",
+            "3 | /         1",
+            "4 | |         2",
+            "5 | |         3",
+            "  | |_________^ in this block",
+        ]);
+        assert_eq!(render(&logger, diagnostic), expected);
+    }
+
+    #[test]
+    fn shows_secondary_labels_in_other_files() {
+        let logger = Logger::new(true);
+        let main =
+            logger.add_file("main.pcp", "mod geo;\nfn area() -> int { 0 }\nuse geo::area;\n");
+        let geo = logger.add_file("geo.pcp", "fn area() -> int {\n    1\n}\n");
+        let diagnostic = Diagnostic::error(
+            "`area` is defined multiple times".to_string(),
+            span(main, (3, 4), (3, 13)),
+        )
+        .label("`area` imported here".to_string())
+        .secondary(span(main, (2, 3), (2, 7)), "previous definition here".to_string())
+        .secondary(span(geo, (1, 3), (1, 7)), "imported item defined here".to_string())
+        .note("names must be unique in a module".to_string());
+        let expected = lines(&[
+            "error: `area` is defined multiple times",
+            " --> main.pcp:3:5",
+            "  |",
+            "2 | fn area() -> int { 0 }",
+            "  |    ---- previous definition here",
+            "3 | use geo::area;",
+            "  |     ^^^^^^^^^ `area` imported here",
+            " ::: geo.pcp:1:4",
+            "  |",
+            "1 | fn area() -> int {",
+            "  |    ---- imported item defined here",
+            "  |",
+            "  = note: names must be unique in a module",
+        ]);
+        assert_eq!(render(&logger, diagnostic), expected);
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\"\\n"), r#""say \"hi\"\\n""#);
+        assert_eq!(json_string("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(json_string("\0\x1b"), r#""\u0000\u001b""#);
+        assert_eq!(json_string("é ✓"), "\"é ✓\"");
+    }
+
+    #[test]
+    fn gives_byte_offsets_for_crlf_input() {
+        let logger = Logger::new(true);
+        let file = logger.add_file("main.pcp", "fn main() {\r\n    é + x\r\n}\r\n");
+        let files = logger.files.borrow();
+        let offset = |line, col| files[file].byte_offset(InputPos { line, col });
+        assert_eq!(offset(1, 0), 0);
+        assert_eq!(offset(2, 0), 13);
+        // `é` takes up two bytes
+        assert_eq!(offset(2, 6), 20);
+        // The end of a line comes before the carriage return
+        assert_eq!(offset(2, 9), 23);
+        assert_eq!(offset(3, 0), 25);
+    }
+
+    #[test]
+    fn renders_diagnostics_as_json() {
+        let logger = Logger::new(true);
+        let file = logger.add_file("main.pcp", "fn main() {\r\n    x\r\n}\r\n");
+        let diagnostic =
+            Diagnostic::error("cannot find `x`".to_string(), span(file, (2, 4), (2, 5)))
+                .code("E0301")
+                .label("not \"found\"".to_string())
+                .secondary(span(file, (1, 3), (1, 7)), String::new())
+                .note("a note".to_string());
+        logger.report(diagnostic);
+        let expected = concat!(
+            r#"{"file":"main.pcp","severity":"error","code":"E0301","message":"cannot find `x`","#,
+            r#""spans":[{"file":"main.pcp","byte_start":17,"byte_end":18,"line_start":2,"#,
+            r#""column_start":5,"line_end":2,"column_end":6,"is_primary":true,"#,
+            r#""label":"not \"found\""},{"file":"main.pcp","byte_start":3,"byte_end":7,"#,
+            r#""line_start":1,"column_start":4,"line_end":1,"column_end":8,"is_primary":false,"#,
+            r#""label":null}],"notes":["a note"],"help":null}"#,
+            "\n",
+        );
+        assert_eq!(logger.render_json(), expected);
     }
 }
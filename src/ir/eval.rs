@@ -0,0 +1,238 @@
+use crate::{
+    ast,
+    error::{Diagnostic, InputSpan},
+    sema,
+    types::{self, Type, TypeTable},
+};
+
+/// A value that is known when the program is compiled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Constant {
+    /// A value that fits in a word, such as an integer, character, boolean or null pointer
+    Word(i32),
+    /// The contents of a struct or array, laid out as they are in memory
+    Memory(Vec<u8>),
+}
+
+/// An expression that could not be evaluated when compiling
+pub enum EvalError {
+    /// An expression whose value is only known when the program runs, such as a function call
+    NotConstant(InputSpan),
+    DivideByZero(InputSpan),
+}
+
+impl EvalError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        match *self {
+            EvalError::NotConstant(span) => Diagnostic::error(
+                "global variables must be initialized with a constant value".to_string(),
+                span,
+            )
+            .code("E0502")
+            .label("not known at compile time".to_string())
+            .note(
+                "constant values are made of literals, arithmetic, casts, other constants, struct \
+                 literals and array literals"
+                    .to_string(),
+            ),
+            EvalError::DivideByZero(span) => {
+                Diagnostic::error("attempt to divide by zero".to_string(), span)
+                    .code("E0510")
+                    .label("the divisor of this constant value is zero".to_string())
+            }
+        }
+    }
+}
+
+/// Evaluates expressions that have been checked, folding them into constants
+pub struct Evaluator<'a> {
+    pub type_table: &'a TypeTable,
+    /// Finds the value of a constant variable from its name
+    pub lookup: &'a dyn Fn(&str) -> Option<Constant>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn evaluate(&self, expression: &ast::Expression) -> Result<Constant, EvalError> {
+        let span = expression.span;
+        let value = match *expression.expr {
+            ast::LitNumExpr(value) => Constant::Word(value),
+            ast::LitCharExpr(value) => Constant::Word(value as i32),
+            ast::LitBoolExpr(value) => Constant::Word(value as i32),
+            ast::NullExpr => Constant::Word(0),
+            ast::LitStringExpr(ref inner) => {
+                let string = sema::unescape(inner).expect("ICE: invalid escape sequence");
+                Constant::Memory(string.chars().map(|x| x as u8).collect())
+            }
+            ast::VariableExpr(ref name) => {
+                (self.lookup)(name).ok_or(EvalError::NotConstant(span))?
+            }
+            ast::CastExpr(ref inner, _) => self.evaluate(inner)?,
+            ast::BinaryExpr(ref inner) => self.evaluate_binary(inner)?,
+            ast::UnaryExpr(ref inner) => {
+                let operand = self.evaluate_word(&inner.operand)?;
+                Constant::Word(match inner.op {
+                    ast::UnaryOp::Neg => operand.wrapping_neg(),
+                    ast::UnaryOp::Not => (operand == 0) as i32,
+                })
+            }
+            ast::StaticArrayExpr(ref inner) => {
+                // Elements are stored without padding, in the same way as arrays built at run time
+                let mut bytes = vec![];
+                for element in &inner.elements {
+                    let value = self.evaluate(element)?;
+                    bytes.extend(self.to_bytes(&value, inner.elements[0].rtype()));
+                }
+                Constant::Memory(bytes)
+            }
+            ast::StructInitExpr(ref inner) => {
+                let struct_def = match self.type_table.base_type(expression.rtype()) {
+                    Some(types::Composite(inner)) => inner,
+                    _ => panic!("ICE: struct initializer resolved to a non-struct type"),
+                };
+                let mut bytes = vec![0; self.type_table.size_of(expression.rtype()) as usize];
+                for (field_name, value) in &inner.field_init {
+                    let field = struct_def.field(field_name).expect("ICE: unknown field");
+                    let value = self.to_bytes(&self.evaluate(value)?, &field.field_type);
+                    let offset = field.offset as usize;
+                    bytes[offset..offset + value.len()].copy_from_slice(&value);
+                }
+                Constant::Memory(bytes)
+            }
+            _ => return Err(EvalError::NotConstant(span)),
+        };
+
+        // Casts between words and aggregates reinterpret an address, which isn't known until the
+        // program runs
+        if matches!(value, Constant::Word(..)) != self.is_word(expression.rtype()) {
+            return Err(EvalError::NotConstant(span));
+        }
+        Ok(value)
+    }
+
+    fn evaluate_binary(&self, binary_expr: &ast::BinaryExpression) -> Result<Constant, EvalError> {
+        use crate::ast::BinaryOp::*;
+
+        // The right hand side of a logical operator is only evaluated if it is needed, as it is
+        // when the program runs
+        let lhs = self.evaluate_word(&binary_expr.lhs)?;
+        match binary_expr.op {
+            And if lhs == 0 => return Ok(Constant::Word(0)),
+            Or if lhs != 0 => return Ok(Constant::Word(1)),
+            _ => {}
+        }
+        let rhs = self.evaluate_word(&binary_expr.rhs)?;
+
+        // Arithmetic wraps on overflow, which matches the instructions used at run time
+        let value = match binary_expr.op {
+            Add => lhs.wrapping_add(rhs),
+            Sub => lhs.wrapping_sub(rhs),
+            Mul => lhs.wrapping_mul(rhs),
+            Div | Rem if rhs == 0 => return Err(EvalError::DivideByZero(binary_expr.span)),
+            Div => lhs.wrapping_div(rhs),
+            Rem => lhs.wrapping_rem(rhs),
+            Eq => (lhs == rhs) as i32,
+            NotEq => (lhs != rhs) as i32,
+            Lt => (lhs < rhs) as i32,
+            LtEq => (lhs <= rhs) as i32,
+            Gt => (lhs > rhs) as i32,
+            GtEq => (lhs >= rhs) as i32,
+            And | Or => (rhs != 0) as i32,
+        };
+        Ok(Constant::Word(value))
+    }
+
+    fn evaluate_word(&self, expression: &ast::Expression) -> Result<i32, EvalError> {
+        match self.evaluate(expression)? {
+            Constant::Word(value) => Ok(value),
+            Constant::Memory(..) => Err(EvalError::NotConstant(expression.span)),
+        }
+    }
+
+    /// Get the bytes that hold a constant of a type in memory. Memory is big endian, and characters
+    /// take up a single byte.
+    pub fn to_bytes(&self, value: &Constant, type_: &Type) -> Vec<u8> {
+        let size = self.type_table.unaligned_size_of(type_) as usize;
+        match *value {
+            Constant::Word(value) => value.to_be_bytes()[4 - size.min(4)..].to_vec(),
+            Constant::Memory(ref bytes) => {
+                let mut bytes = bytes.clone();
+                bytes.resize(size, 0);
+                bytes
+            }
+        }
+    }
+
+    /// Check whether values of a type are held in a word rather than in memory
+    fn is_word(&self, type_: &Type) -> bool {
+        match type_ {
+            types::StaticArray(..) => false,
+            types::Normal(..) => {
+                !matches!(self.type_table.base_type(type_), Some(types::Composite(..)))
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{ir, Compiler};
+
+    /// Compile a program, giving the initial value of each global or the codes of its errors
+    fn globals(source: &str) -> Result<Vec<(String, ir::Initializer)>, Vec<&'static str>> {
+        let session = Compiler { runtime: None, optimize: false }.session();
+        let program = session.parse(Path::new("main.pcp"), source).unwrap();
+        match session.lower(program) {
+            Some(program) => Ok(program.globals.into_iter().map(|x| (x.name, x.init)).collect()),
+            None => Err(session.diagnostics().iter().filter_map(|x| x.code).collect()),
+        }
+    }
+
+    /// Get the words that a global is initialized with
+    fn words(source: &str, name: &str) -> Vec<i32> {
+        let globals = globals(source).ok().unwrap();
+        match globals.into_iter().find(|(global, _)| global == name) {
+            Some((_, ir::Initializer::Words(words))) => words,
+            other => panic!("`{}` is not initialized with words: {:?}", name, other),
+        }
+    }
+
+    #[test]
+    fn folds_arithmetic() {
+        assert_eq!(words("const SIZE = 4 * 14;", "SIZE"), vec![56]);
+        assert_eq!(words("let x = -(7 - 10) % 2 + (1 < 2) as int;", "x"), vec![2]);
+    }
+
+    #[test]
+    fn uses_the_values_of_other_constants() {
+        let source = "const A = 3;\nconst B = A * 2;\nlet c = B + A;";
+        assert_eq!(words(source, "c"), vec![9]);
+    }
+
+    #[test]
+    fn lays_out_struct_literals_with_padding() {
+        let source = "struct S { c: char, x: int, d: char }\n\
+                      let s = S { c: 'A', x: 258, d: 'B' };";
+        assert_eq!(words(source, "s"), vec![0x41000000, 0x102, 0x42000000]);
+    }
+
+    #[test]
+    fn lays_out_char_arrays_a_byte_at_a_time() {
+        let source = "let a = ['a', 'b', 'c', 'd', 'e'];";
+        assert_eq!(words(source, "a"), vec![0x61626364, 0x65000000]);
+    }
+
+    #[test]
+    fn reports_division_by_zero() {
+        let source = "const ZERO = 0;\nlet x = 10 / ZERO;\nlet y = 10 % (2 - 2);";
+        assert_eq!(globals(source).err().unwrap(), vec!["E0510", "E0510"]);
+    }
+
+    #[test]
+    fn reports_values_that_are_not_constant() {
+        let source = "fn f() -> int { 1 }\nlet x = f();\nlet y = 1;\nlet z = y + 1;";
+        assert_eq!(globals(source).err().unwrap(), vec!["E0502", "E0502"]);
+    }
+}
@@ -0,0 +1,1052 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ast,
+    error::Logger,
+    ir::{
+        self,
+        eval::{self, EvalError, Evaluator},
+        Address, Argument, BlockId, Instruction, Operand, SlotId, TempId,
+    },
+    module::ALLOC_FN,
+    sema,
+    types::{self, Type, TypeTable, BOOL_TYPE, CHAR_TYPE, INT_TYPE, UNIT_TYPE},
+};
+
+use self::Location::*;
+
+#[derive(Clone, Debug)]
+enum Location {
+    // A global variable
+    Label(String),
+    // A variable stored in a slot of the stack frame
+    Frame(SlotId),
+    // A parameter that is kept where the caller put it
+    Param(usize),
+    // A variable that is kept in a temporary
+    Register(TempId),
+    // A constant whose value is known, which doesn't need to be stored anywhere
+    Constant(i32),
+}
+
+/// Lower a program that has been checked to IR
+pub fn lower(program: &ast::Program, type_table: TypeTable, logger: &Logger) -> ir::Program {
+    let mut data = LowerData {
+        type_table,
+        logger,
+        builder: Builder::default(),
+        locals: HashMap::new(),
+        loop_ends: vec![],
+        use_registers: false,
+        addressed_vars: HashSet::new(),
+        rtype: UNIT_TYPE,
+        return_address: None,
+        constants: HashMap::new(),
+    };
+
+    // Globals are lowered first, so that functions can use the values of constants declared after
+    // them
+    let mut globals = vec![];
+    for item in &program.items {
+        if let ast::LetItem(let_item) = item {
+            globals.push(data.lower_global_var(let_item));
+        }
+    }
+
+    let mut functions = vec![];
+    for item in &program.items {
+        match item {
+            ast::FunctionItem(fn_item) => functions.push(data.lower_global_fn(fn_item)),
+            ast::LetItem(..) => {}
+
+            // Handled by type gen
+            ast::StructItem(..) | ast::ErrorItem(..) => {}
+
+            // Modules are flattened into the items of the program when it is checked
+            ast::ModItem(..) | ast::UseItem(..) => {}
+        }
+    }
+    ir::Program { globals, functions, span: program.span }
+}
+
+/// Builds the blocks of the function that is currently being lowered
+#[derive(Default)]
+struct Builder {
+    temps: Vec<ir::Type>,
+    slots: Vec<u16>,
+    blocks: Vec<ir::Block>,
+    /// The blocks in the order that they were started, which is the order they are laid out in
+    order: Vec<BlockId>,
+    current: BlockId,
+}
+
+impl Builder {
+    fn new_temp(&mut self, ty: ir::Type) -> TempId {
+        self.temps.push(ty);
+        self.temps.len() - 1
+    }
+
+    fn new_slot(&mut self, size: u16) -> SlotId {
+        self.slots.push(size);
+        self.slots.len() - 1
+    }
+
+    /// Create a new block. The block is terminated by a return until `terminate` is called.
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push(ir::Block { instructions: vec![], terminator: ir::Return(None) });
+        self.blocks.len() - 1
+    }
+
+    /// Add instructions to the end of a block
+    fn switch_to(&mut self, block: BlockId) {
+        self.current = block;
+        self.order.push(block);
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.blocks[self.current].instructions.push(instruction);
+    }
+
+    fn terminate(&mut self, terminator: ir::Terminator) {
+        self.blocks[self.current].terminator = terminator;
+    }
+
+    /// End the current block, then start a new one for any code that follows it. The new block can
+    /// never be reached, but it keeps the code for unreachable statements valid.
+    fn terminate_and_continue(&mut self, terminator: ir::Terminator) {
+        self.terminate(terminator);
+        let next = self.new_block();
+        self.switch_to(next);
+    }
+
+    /// Reorder the blocks so that they are laid out in the order they were started
+    fn finish(&mut self) -> (Vec<ir::Type>, Vec<u16>, Vec<ir::Block>) {
+        let mut new_ids = vec![usize::MAX; self.blocks.len()];
+        for (new_id, &block) in self.order.iter().enumerate() {
+            new_ids[block] = new_id;
+        }
+
+        let mut blocks: Vec<(BlockId, ir::Block)> =
+            self.blocks.drain(..).enumerate().map(|(id, block)| (new_ids[id], block)).collect();
+        blocks.sort_by_key(|(id, _)| *id);
+        let mut blocks: Vec<ir::Block> = blocks.into_iter().map(|(_, block)| block).collect();
+        for block in &mut blocks {
+            block.terminator.map_targets(|target| new_ids[target]);
+        }
+
+        self.order.clear();
+        (std::mem::take(&mut self.temps), std::mem::take(&mut self.slots), blocks)
+    }
+}
+
+struct LowerData<'a> {
+    type_table: TypeTable,
+    logger: &'a Logger,
+    builder: Builder,
+    /// The locations of the variables in the current function
+    locals: HashMap<String, Location>,
+    /// The blocks following the loops around the current expression, which `break` jumps to
+    loop_ends: Vec<BlockId>,
+    /// Whether variables in the current function can be kept in temporaries
+    use_registers: bool,
+    /// The variables in the current function that have their address taken
+    addressed_vars: HashSet<String>,
+    /// The return type of the current function
+    rtype: Type,
+    /// The temporary holding the address that the current function writes its return value to, if
+    /// it returns an aggregate
+    return_address: Option<TempId>,
+    /// The values of the global constants, by label
+    constants: HashMap<String, eval::Constant>,
+}
+
+impl<'a> LowerData<'a> {
+    /// Check whether a local variable can be kept in a temporary. Only variables that fit in a
+    /// word and never have their address taken can be, and none are in functions containing
+    /// inline assembly since it may refer to variables by their offset in the stack frame.
+    fn fits_in_register(&self, name: &str, rtype: &Type) -> bool {
+        let word_sized = matches!(*rtype, INT_TYPE | BOOL_TYPE | types::Pointer(..));
+        word_sized && self.use_registers && !self.addressed_vars.contains(name)
+    }
+
+    /// Choose the location of a new local variable, reserving a slot in the stack frame if it can't
+    /// be kept in a temporary
+    fn local_location(&mut self, name: &str, rtype: &Type) -> Location {
+        match self.value_type(rtype) {
+            Some(ty) if self.fits_in_register(name, rtype) => Register(self.builder.new_temp(ty)),
+            _ => Frame(self.builder.new_slot(self.size_of(rtype))),
+        }
+    }
+
+    /// Get the location of a variable. Names that aren't local variables refer to globals.
+    fn location(&self, name: &String) -> Location {
+        self.locals.get(name).cloned().unwrap_or_else(|| Label(name.clone()))
+    }
+
+    /// Get the type of the temporary used to hold a value of a type, or `None` if values of the
+    /// type are kept in memory
+    fn value_type(&self, type_: &Type) -> Option<ir::Type> {
+        match *type_ {
+            INT_TYPE => Some(ir::Type::Int),
+            CHAR_TYPE => Some(ir::Type::Char),
+            BOOL_TYPE => Some(ir::Type::Bool),
+            types::Pointer(..) => Some(ir::Type::Pointer),
+            // Values of an unknown type (e.g. the result of inline assembly) are treated as words
+            types::Any | types::Bottom => Some(ir::Type::Int),
+            _ => None,
+        }
+    }
+
+    /// Check whether a value of a type is an aggregate, which is held in memory
+    fn is_aggregate(&self, type_: &Type) -> bool {
+        *type_ != UNIT_TYPE && self.value_type(type_).is_none()
+    }
+
+    /// Lower a global variable
+    fn lower_global_var(&mut self, let_item: &ast::LetStatement) -> ir::Global {
+        let init = self.global_initializer(let_item);
+        ir::Global { name: let_item.name.clone(), is_const: let_item.is_const, init }
+    }
+
+    /// Evaluate the initial value of a global variable, which is stored in the program's data
+    fn global_initializer(&mut self, let_item: &ast::LetStatement) -> ir::Initializer {
+        let assignment = match let_item.assignment {
+            Some(ref assignment) => assignment,
+            None => {
+                let rtype = self.resolve_type(let_item.var_type.as_ref().unwrap());
+                return ir::Initializer::Zeroed(self.size_of(&rtype) as u32);
+            }
+        };
+        let rtype = assignment.target.rtype();
+
+        let value = match self.evaluate(&assignment.rhs) {
+            Ok(value) => value,
+            Err(error) => {
+                self.logger.report(error.to_diagnostic());
+                return ir::Initializer::Zeroed(self.size_of(rtype) as u32);
+            }
+        };
+        if let_item.is_const {
+            let constant = match value {
+                eval::Constant::Word(value) => eval::Constant::Word(stored_value(value, rtype)),
+                ref other => other.clone(),
+            };
+            self.constants.insert(let_item.name.clone(), constant);
+        }
+
+        // Strings are kept as text, so that they can be read in the output
+        let rhs_expr: &ast::Expr = match *assignment.rhs.expr {
+            ast::CastExpr(ref inner, _) => &inner.expr,
+            ref other => other,
+        };
+        if let ast::LitStringExpr(ref value) = *rhs_expr {
+            return ir::Initializer::Ascii(value.clone());
+        }
+
+        let mut bytes = self.evaluator(&|_| None).to_bytes(&value, rtype);
+        bytes.resize(self.size_of(rtype) as usize, 0);
+        let words = bytes.chunks(4).map(|x| i32::from_be_bytes(x.try_into().unwrap())).collect();
+        ir::Initializer::Words(words)
+    }
+
+    /// Evaluate an expression when compiling. Constant local variables are found first, followed
+    /// by global constants.
+    fn evaluate(&self, expression: &ast::Expression) -> Result<eval::Constant, EvalError> {
+        let lookup = |name: &str| match self.locals.get(name) {
+            Some(&Constant(value)) => Some(eval::Constant::Word(value)),
+            Some(..) => None,
+            None => self.constants.get(name).cloned(),
+        };
+        self.evaluator(&lookup).evaluate(expression)
+    }
+
+    fn evaluator<'e>(
+        &'e self,
+        lookup: &'e dyn Fn(&str) -> Option<eval::Constant>,
+    ) -> Evaluator<'e> {
+        Evaluator { type_table: &self.type_table, lookup }
+    }
+
+    /// Lower a global function.
+    fn lower_global_fn(&mut self, function: &ast::FunctionDeclaration) -> ir::Function {
+        let span = function.span;
+
+        // Find the variables that must be kept in memory
+        let mut has_asm = false;
+        self.addressed_vars.clear();
+        for statement in &function.body.statements {
+            scan_expression(statement, &mut self.addressed_vars, &mut has_asm);
+        }
+        self.use_registers = !has_asm;
+        self.rtype = self.resolve_type(&function.rtype);
+        self.locals.clear();
+
+        let entry = self.builder.new_block();
+        self.builder.switch_to(entry);
+
+        // Aggregates are returned by copying them to memory provided by the caller, whose address
+        // is passed as a hidden first parameter
+        let mut params = vec![];
+        self.return_address = None;
+        if self.is_aggregate(&self.rtype) {
+            let home = self.builder.new_temp(ir::Type::Pointer);
+            params.push(ir::Parameter { ty: Some(ir::Type::Pointer), size: 4, home: Some(home) });
+            self.return_address = Some(home);
+        }
+
+        // Register function parameters as local variables
+        for (name, var_type) in &function.params {
+            let rtype = self.resolve_type(var_type);
+            let ty = self.value_type(&rtype);
+            let home = match ty {
+                Some(ty) if self.fits_in_register(name, &rtype) => Some(self.builder.new_temp(ty)),
+                _ => None,
+            };
+            self.locals.insert(name.clone(), home.map_or(Param(params.len()), Register));
+            params.push(ir::Parameter { ty, size: self.size_of(&rtype), home });
+        }
+
+        // Lower the body of the function, returning the value of the last statement
+        let value = self.lower_block(&function.body);
+        let align = function.body.statements.last().map_or(4, |x| self.value_align(x));
+        let terminator = self.return_terminator(value, align);
+        self.builder.terminate(terminator);
+
+        let (temps, slots, blocks) = self.builder.finish();
+        let convention =
+            if function.stack_args { ir::Convention::Stack } else { ir::Convention::Register };
+        let name = function.name.clone();
+        ir::Function { name, params, convention, temps, slots, blocks, span }
+    }
+
+    /// Get the terminator that returns a value from the current function. Aggregates are copied to
+    /// the memory provided by the caller, and its address is returned. `align` is the alignment of
+    /// the memory holding the value.
+    fn return_terminator(&mut self, value: Operand, align: u16) -> ir::Terminator {
+        if self.rtype == UNIT_TYPE {
+            return ir::Return(None);
+        }
+        match self.return_address {
+            Some(dst) => {
+                let size = self.unaligned_size_of(&self.rtype);
+                let src = self.indirect(value);
+                let address = Address::new(ir::TempBase(dst));
+                self.builder.emit(ir::CopyMemory { dst: address, src, size, align });
+                ir::Return(Some(ir::Temp(dst)))
+            }
+            None => ir::Return(Some(value)),
+        }
+    }
+
+    fn lower_block(&mut self, block: &ast::Block) -> Operand {
+        let mut value = ir::Const(0);
+        for statement in &block.statements {
+            value = self.lower_expression(statement);
+        }
+        value
+    }
+
+    /// Lower an expression, returning the operand that holds its value. Aggregates are represented
+    /// by their address, and expressions without a value return a constant.
+    fn lower_expression(&mut self, expression: &ast::Expression) -> Operand {
+        match *expression.expr {
+            ast::RefExpr(ref inner) => {
+                let address = self.lower_address(inner);
+                self.address_of(address)
+            }
+            ast::DerefExpr(ref inner) => {
+                // Evaluate the inner expression, then dereference it. The value of an array is the
+                // address of its first element, so arrays can be dereferenced in the same way.
+                let pointer = self.lower_expression(inner);
+                let address = self.indirect(pointer);
+                self.load_value(expression.rtype(), address, self.value_align(expression))
+            }
+            ast::FieldRefExpr(ref inner) => {
+                let address = self.lower_field_ref(inner);
+                self.load_value(expression.rtype(), address, self.value_align(expression))
+            }
+            ast::ArrayIndexExpr(ref inner) => {
+                let address = self.lower_array_index(inner);
+                self.load_value(expression.rtype(), address, self.value_align(expression))
+            }
+            ast::IfExpr(ref inner) => self.lower_if(inner),
+            ast::ForLoopExpr(ref inner) => self.lower_for(inner),
+            ast::LoopExpr(ref inner) => self.lower_loop(inner),
+            ast::CallExpr(ref inner) => self.lower_call(inner, expression.rtype()),
+            ast::Break => {
+                let end = *self.loop_ends.last().expect("ICE: `break` outside of a loop");
+                self.builder.terminate_and_continue(ir::Jump(end));
+                ir::Const(0)
+            }
+            ast::Return(ref inner) => {
+                let value = self.lower_expression(inner);
+                let terminator = self.return_terminator(value, self.value_align(inner));
+                self.builder.terminate_and_continue(terminator);
+                ir::Const(0)
+            }
+            ast::LetExpr(ref inner) => {
+                self.lower_let(inner);
+                ir::Const(0)
+            }
+            ast::AssignExpr(ref inner) => {
+                self.lower_assign(inner);
+                ir::Const(0)
+            }
+            ast::VariableExpr(ref name) => match self.location(name) {
+                // Copy the value so that later assignments to the variable don't change it
+                Register(temp) => {
+                    let ty = self.builder.temps[temp];
+                    self.copy(ty, ir::Temp(temp))
+                }
+                Constant(value) => ir::Const(value),
+                // Global constants that fit in a word are used directly instead of being loaded
+                Label(label) => match self.constants.get(&label) {
+                    Some(&eval::Constant::Word(value)) => ir::Const(value),
+                    _ => {
+                        let address = variable_address(&Label(label));
+                        self.load_value(expression.rtype(), address, self.value_align(expression))
+                    }
+                },
+                other => {
+                    let address = variable_address(&other);
+                    self.load_value(expression.rtype(), address, self.value_align(expression))
+                }
+            },
+            ast::StaticArrayExpr(ref inner) => self.lower_static_array(inner),
+            ast::LitStringExpr(ref inner) => {
+                // Store the characters of the string in a byte array
+                let string = sema::unescape(inner).expect("ICE: invalid escape sequence");
+                let slot = self.builder.new_slot(types::align(string.chars().count() as u16));
+                for (i, value) in string.chars().enumerate() {
+                    let address = Address { base: ir::Slot(slot), offset: i as i32 };
+                    let src = ir::Const(value as i32);
+                    let ty = ir::Type::Char;
+                    self.builder.emit(ir::Store { dst: address, ty, src, align: 1 });
+                }
+                self.address_of(Address::new(ir::Slot(slot)))
+            }
+            ast::StructInitExpr(ref inner) => self.lower_struct_init(inner, expression.rtype()),
+            ast::LitNumExpr(value) => ir::Const(value),
+            ast::LitCharExpr(value) => ir::Const(value as i32),
+            ast::LitBoolExpr(value) => ir::Const(value as i32),
+            ast::NullExpr => ir::Const(0),
+            ast::AsmOpExpr(ref inner) => self.lower_asm(inner),
+            ast::CastExpr(ref inner, _) => self.lower_expression(inner),
+            ast::NewExpr(..) => {
+                let size = self.size_of(expression.rtype().deref_type());
+                let dst = self.builder.new_temp(ir::Type::Pointer);
+                let args = vec![Argument::Value(ir::Const(size as i32), ir::Type::Int)];
+                let function = sema::mangle(ALLOC_FN);
+                self.builder.emit(ir::Call { dst: Some(dst), function, args });
+                ir::Temp(dst)
+            }
+            // Operators with constant operands are replaced with their result
+            ast::BinaryExpr(ref inner) => {
+                self.fold(expression).map_or_else(|| self.lower_binary(inner), ir::Const)
+            }
+            ast::UnaryExpr(ref inner) => {
+                self.fold(expression).map_or_else(|| self.lower_unary(inner), ir::Const)
+            }
+            ast::EmptyExpr => ir::Const(0),
+
+            // Programs with syntax errors are never lowered
+            ast::ErrorExpr => panic!("ICE: attempted to lower an invalid expression"),
+        }
+    }
+
+    fn lower_field_ref(&mut self, field_ref: &ast::FieldRef) -> Address {
+        let target = self.lower_expression(&field_ref.target);
+        let field_offset = match self.type_table.base_type(field_ref.target.rtype()) {
+            Some(types::Composite(inner)) => {
+                inner.field(&field_ref.field).expect("ICE: unknown field").offset
+            }
+            _ => panic!("ICE: field reference to a type without fields"),
+        };
+
+        // Add the offset to the target address
+        let mut address = self.indirect(target);
+        address.offset += field_offset as i32;
+        address
+    }
+
+    fn lower_array_index(&mut self, index_expr: &ast::ArrayIndex) -> Address {
+        // Evaluate the index, and multiply it by the size of the target type
+        let index = self.lower_expression(&index_expr.index);
+        let type_size = self.unaligned_size_of(index_expr.target.rtype().deref_type());
+        let offset = match (type_size, index) {
+            (1, index) => index,
+            (size, ir::Const(index)) => ir::Const(index.wrapping_mul(size as i32)),
+            (size, index) => {
+                self.binary(ir::BinaryOp::Mul, ir::Type::Int, index, ir::Const(size as i32))
+            }
+        };
+
+        // Evaluate the target address, and add the offset to it
+        let target = self.lower_expression(&index_expr.target);
+        let address = self.binary(ir::BinaryOp::Add, ir::Type::Pointer, target, offset);
+        self.indirect(address)
+    }
+
+    fn lower_if(&mut self, if_statement: &ast::IfStatement) -> Operand {
+        let condition = self.lower_expression(&if_statement.condition);
+
+        let then_block = self.builder.new_block();
+        let else_block = self.builder.new_block();
+        let end_block = match if_statement.else_block {
+            Some(..) => self.builder.new_block(),
+            // If there is no else block, then the end block is equal to the else block
+            None => else_block,
+        };
+        self.builder.terminate(ir::Branch { cond: condition, then_block, else_block });
+
+        // Lower the then block
+        self.builder.switch_to(then_block);
+        let then_value = self.lower_block(&if_statement.body);
+
+        // The value of the if statement is written to the same temporary by both blocks
+        let mut result = None;
+        self.assign_result(&mut result, &if_statement.body.rtype(), then_value);
+        self.builder.terminate(ir::Jump(end_block));
+
+        if let Some(ref block) = if_statement.else_block {
+            self.builder.switch_to(else_block);
+            let else_value = self.lower_block(block);
+            self.assign_result(&mut result, &block.rtype(), else_value);
+            self.builder.terminate(ir::Jump(end_block));
+        }
+
+        self.builder.switch_to(end_block);
+        result.map_or(ir::Const(0), ir::Temp)
+    }
+
+    /// Copy the value of one branch of an if statement to the temporary holding its result
+    fn assign_result(&mut self, result: &mut Option<TempId>, rtype: &Type, value: Operand) {
+        if *rtype == UNIT_TYPE || *rtype == types::Bottom {
+            return;
+        }
+        let ty = self.value_type(rtype).unwrap_or(ir::Type::Pointer);
+        let dst = *result.get_or_insert_with(|| self.builder.new_temp(ty));
+        self.builder.emit(ir::Copy { dst, src: value });
+    }
+
+    /// Lower a for loop:
+    /// Note: We directly lower for loops instead of de-sugaring them into a normal loop with an
+    /// if break, so that the condition is only checked at the end of the loop.
+    fn lower_for(&mut self, for_statement: &ast::ForLoopStatement) -> Operand {
+        let location = self.local_location(&for_statement.loop_var, &INT_TYPE);
+        self.locals.insert(for_statement.loop_var.clone(), location.clone());
+
+        // Lower the expression for the range end. The end of the range is evaluated first, and is
+        // kept for the rest of the loop.
+        let end = self.lower_expression(&for_statement.end);
+
+        // Lower the expression for the range start, and use it to initialize the loop variable
+        let start = self.lower_expression(&for_statement.start);
+        self.store_variable(&location, &INT_TYPE, start);
+
+        let body_block = self.builder.new_block();
+        let cond_block = self.builder.new_block();
+        let end_block = self.builder.new_block();
+        self.loop_ends.push(end_block);
+
+        // Check the condition before we start by jumping to the condition block
+        self.builder.terminate(ir::Jump(cond_block));
+
+        // Lower the main body of the loop
+        self.builder.switch_to(body_block);
+        self.lower_block(&for_statement.body);
+
+        // Increment the loop variable
+        let loop_var = self.load_variable(&location, &INT_TYPE);
+        let next = self.binary(ir::BinaryOp::Add, ir::Type::Int, loop_var, ir::Const(1));
+        self.store_variable(&location, &INT_TYPE, next);
+        self.builder.terminate(ir::Jump(cond_block));
+
+        // Jump back to the start if we haven't finished the loop
+        self.builder.switch_to(cond_block);
+        let loop_var = self.load_variable(&location, &INT_TYPE);
+        let cond = self.binary(ir::BinaryOp::Lt, ir::Type::Bool, loop_var, end);
+        self.builder.terminate(ir::Branch { cond, then_block: body_block, else_block: end_block });
+
+        let end_block = self.loop_ends.pop().expect("ICE: Missing block after loop");
+        self.builder.switch_to(end_block);
+        ir::Const(0)
+    }
+
+    fn lower_loop(&mut self, loop_statement: &ast::LoopStatement) -> Operand {
+        let start_block = self.builder.new_block();
+        self.builder.terminate(ir::Jump(start_block));
+        self.builder.switch_to(start_block);
+
+        // Add the end block to the loop ends vector, so that it can be used by breaks
+        let end_block = self.builder.new_block();
+        self.loop_ends.push(end_block);
+
+        self.lower_block(&loop_statement.body);
+
+        // Add jump to start
+        self.builder.terminate(ir::Jump(start_block));
+
+        let end_block = self.loop_ends.pop().expect("ICE: Missing block after loop");
+        self.builder.switch_to(end_block);
+        ir::Const(0)
+    }
+
+    fn lower_call(&mut self, call: &ast::FunctionCall, rtype: &Type) -> Operand {
+        let mut args = vec![];
+        for arg in &call.args {
+            let value = self.lower_expression(arg);
+            args.push(match self.value_type(arg.rtype()) {
+                Some(ty) => Argument::Value(value, ty),
+                None => {
+                    let size = self.unaligned_size_of(arg.rtype());
+                    Argument::Memory(value, size, self.value_align(arg))
+                }
+            });
+        }
+
+        // Aggregates are returned in a slot of this stack frame, whose address is passed as a
+        // hidden first argument
+        if self.is_aggregate(rtype) {
+            let slot = self.builder.new_slot(self.size_of(rtype));
+            let address = self.address_of(Address::new(ir::Slot(slot)));
+            args.insert(0, Argument::Value(address, ir::Type::Pointer));
+            self.builder.emit(ir::Call { dst: None, function: call.name.clone(), args });
+            return address;
+        }
+
+        // Make the call
+        let dst = match self.value_type(rtype) {
+            Some(ty) if *rtype != UNIT_TYPE => Some(self.builder.new_temp(ty)),
+            _ => None,
+        };
+        self.builder.emit(ir::Call { dst, function: call.name.clone(), args });
+        match dst {
+            Some(dst) => ir::Temp(dst),
+            None => ir::Const(0),
+        }
+    }
+
+    fn lower_binary(&mut self, binary_expr: &ast::BinaryExpression) -> Operand {
+        use crate::ast::BinaryOp::*;
+
+        let op = binary_expr.op;
+        if op == And || op == Or {
+            return self.lower_logical(binary_expr);
+        }
+
+        let lhs = self.lower_expression(&binary_expr.lhs);
+        let rhs = self.lower_expression(&binary_expr.rhs);
+
+        let (op, ty) = match op {
+            Add => (ir::BinaryOp::Add, ir::Type::Int),
+            Sub => (ir::BinaryOp::Sub, ir::Type::Int),
+            Mul => (ir::BinaryOp::Mul, ir::Type::Int),
+            Div => (ir::BinaryOp::Div, ir::Type::Int),
+            Rem => (ir::BinaryOp::Rem, ir::Type::Int),
+            Eq => (ir::BinaryOp::Eq, ir::Type::Bool),
+            NotEq => (ir::BinaryOp::NotEq, ir::Type::Bool),
+            Lt => (ir::BinaryOp::Lt, ir::Type::Bool),
+            LtEq => (ir::BinaryOp::LtEq, ir::Type::Bool),
+            Gt => (ir::BinaryOp::Gt, ir::Type::Bool),
+            GtEq => (ir::BinaryOp::GtEq, ir::Type::Bool),
+            And | Or => unreachable!(),
+        };
+        self.binary(op, ty, lhs, rhs)
+    }
+
+    /// Lower a short circuiting logical operator
+    fn lower_logical(&mut self, binary_expr: &ast::BinaryExpression) -> Operand {
+        let lhs = self.lower_expression(&binary_expr.lhs);
+        let result = self.builder.new_temp(ir::Type::Bool);
+        self.builder.emit(ir::Copy { dst: result, src: lhs });
+
+        // Skip the evaluation of the right hand side if the result is already known
+        let rhs_block = self.builder.new_block();
+        let end_block = self.builder.new_block();
+        let (then_block, else_block) = match binary_expr.op {
+            ast::BinaryOp::And => (rhs_block, end_block),
+            _ => (end_block, rhs_block),
+        };
+        self.builder.terminate(ir::Branch { cond: ir::Temp(result), then_block, else_block });
+
+        self.builder.switch_to(rhs_block);
+        let rhs = self.lower_expression(&binary_expr.rhs);
+        self.builder.emit(ir::Copy { dst: result, src: rhs });
+        self.builder.terminate(ir::Jump(end_block));
+
+        self.builder.switch_to(end_block);
+        ir::Temp(result)
+    }
+
+    /// Lower a block of inline assembly, replacing the names of its operands with their locations
+    fn lower_asm(&mut self, block: &ast::AsmBlock) -> Operand {
+        let mut code = vec![];
+        for (i, (line, _)) in block.lines.iter().enumerate() {
+            if i != 0 {
+                code.push(ir::AsmPart::Text("\n".to_string()));
+            }
+            let parts = ast::asm_parts(line).expect("ICE: invalid operand in inline assembly");
+            for part in parts {
+                code.push(match part {
+                    ast::AsmPart::Text(text) => ir::AsmPart::Text(text.to_string()),
+                    ast::AsmPart::Operand("result", _) => ir::AsmPart::ResultReg,
+                    ast::AsmPart::Operand(name, _) => {
+                        let base = match self.location(&name.to_string()) {
+                            Label(label) => ir::Global(label),
+                            Frame(slot) => ir::Slot(slot),
+                            Param(index) => ir::Param(index),
+                            // Functions containing inline assembly keep all variables in memory
+                            Register(..) | Constant(..) => {
+                                panic!("ICE: asm operand `{}` is not in memory", name)
+                            }
+                        };
+                        ir::AsmPart::Operand(Address::new(base))
+                    }
+                });
+            }
+        }
+
+        let clobbers = block.clobbers.iter().map(|(name, _)| name.clone()).collect();
+        let dst = self.builder.new_temp(ir::Type::Int);
+        self.builder.emit(ir::Asm { dst, code, clobbers });
+        ir::Temp(dst)
+    }
+
+    fn lower_unary(&mut self, unary_expr: &ast::UnaryExpression) -> Operand {
+        let operand = self.lower_expression(&unary_expr.operand);
+        let (op, ty) = match unary_expr.op {
+            ast::UnaryOp::Neg => (ir::UnaryOp::Neg, ir::Type::Int),
+            ast::UnaryOp::Not => (ir::UnaryOp::Not, ir::Type::Bool),
+        };
+        let dst = self.builder.new_temp(ty);
+        self.builder.emit(ir::Unary { op, dst, src: operand });
+        ir::Temp(dst)
+    }
+
+    fn lower_let(&mut self, let_statement: &ast::LetStatement) {
+        let rtype = match let_statement.assignment {
+            Some(ref assignment) => assignment.target.rtype().clone(),
+            None => self.resolve_type(let_statement.var_type.as_ref().unwrap()),
+        };
+
+        // Constants are replaced by their value wherever they are used, so they aren't stored
+        if let Some(value) = self.folded_value(let_statement, &rtype) {
+            self.locals.insert(let_statement.name.clone(), Constant(value));
+            return;
+        }
+
+        let location = self.local_location(&let_statement.name, &rtype);
+        self.locals.insert(let_statement.name.clone(), location);
+
+        // Lower optional assignment
+        if let Some(assignment) = &let_statement.assignment {
+            self.lower_assign(assignment)
+        }
+    }
+
+    /// Get the value of a constant local variable, if its uses can be replaced with its value.
+    /// Constants that have their address taken or are used by inline assembly must be stored.
+    fn folded_value(&self, let_statement: &ast::LetStatement, rtype: &Type) -> Option<i32> {
+        let assignment = let_statement.assignment.as_ref().filter(|_| let_statement.is_const)?;
+        if !self.use_registers || self.addressed_vars.contains(&let_statement.name) {
+            return None;
+        }
+        self.fold(&assignment.rhs).map(|value| stored_value(value, rtype))
+    }
+
+    /// Get the value of an expression that only depends on constants and fits in a word. Division
+    /// by zero is left to fail when the program runs.
+    fn fold(&self, expression: &ast::Expression) -> Option<i32> {
+        match self.evaluate(expression) {
+            Ok(eval::Constant::Word(value)) => Some(value),
+            Ok(eval::Constant::Memory(..)) | Err(..) => None,
+        }
+    }
+
+    fn lower_assign(&mut self, assignment: &ast::Assignment) {
+        // Lower the rhs expression and store the result in the location found
+        let value = self.lower_expression(&assignment.rhs);
+
+        // Get the address of where we want to place the variable
+        let address = match self.lower_assign_target(&assignment.target) {
+            Ok(temp) => {
+                // Variables kept in temporaries don't have an address, so they are assigned
+                // directly
+                self.builder.emit(ir::Copy { dst: temp, src: value });
+                return;
+            }
+            Err(address) => address,
+        };
+        let align = self.value_align(&assignment.target).min(self.value_align(&assignment.rhs));
+        self.store_value(assignment.target.rtype(), address, align, value);
+    }
+
+    /// Get the temporary or address that an assignment writes to
+    fn lower_assign_target(&mut self, target: &ast::Expression) -> Result<TempId, Address> {
+        if let ast::VariableExpr(ref name) = *target.expr {
+            if let Register(temp) = self.location(name) {
+                return Ok(temp);
+            }
+        }
+        Err(self.lower_address(target))
+    }
+
+    fn lower_struct_init(&mut self, struct_init: &ast::StructInit, struct_type: &Type) -> Operand {
+        let struct_def = match self.type_table.base_type(struct_type) {
+            Some(types::Composite(inner)) => inner.clone(),
+            _ => panic!("ICE: struct initializer resolved to a non-struct type"),
+        };
+
+        // Reserve memory for the struct
+        let slot = self.builder.new_slot(self.size_of(struct_type));
+
+        for (field_name, expression) in &struct_init.field_init {
+            let field = struct_def.field(field_name).expect("ICE: unknown field");
+            let value = self.lower_expression(expression);
+            let address = Address { base: ir::Slot(slot), offset: field.offset as i32 };
+            let align = if struct_def.packed { 1 } else { self.align_of(&field.field_type) };
+            self.store_value(&field.field_type, address, align, value);
+        }
+
+        // Return a pointer to the struct
+        self.address_of(Address::new(ir::Slot(slot)))
+    }
+
+    fn lower_static_array(&mut self, array: &ast::StaticArray) -> Operand {
+        let values: Vec<Operand> =
+            array.elements.iter().map(|x| self.lower_expression(x)).collect();
+
+        // Using the unaligned size for arrays allows us to efficiently store strings as byte arrays
+        let element_type = array.elements.first().map(|x| x.rtype());
+        let element_size = element_type.map_or(0, |x| self.unaligned_size_of(x));
+        let slot = self.builder.new_slot(types::align(element_size * values.len() as u16));
+        if let Some(element_type) = element_type {
+            for (i, value) in values.into_iter().enumerate() {
+                let address =
+                    Address { base: ir::Slot(slot), offset: i as i32 * element_size as i32 };
+                self.store_value(element_type, address, self.align_of(element_type), value);
+            }
+        }
+
+        // Return a pointer to the first element
+        self.address_of(Address::new(ir::Slot(slot)))
+    }
+
+    /// Get the address of an expression that refers to a location in memory
+    fn lower_address(&mut self, expression: &ast::Expression) -> Address {
+        match *expression.expr {
+            // Address of an ordinary variable
+            ast::VariableExpr(ref name) => variable_address(&self.location(name)),
+
+            // Address of a dereference (aka don't dereference)
+            ast::DerefExpr(ref inner) => {
+                let pointer = self.lower_expression(inner);
+                self.indirect(pointer)
+            }
+
+            // Address of an array index
+            ast::ArrayIndexExpr(ref inner) => self.lower_array_index(inner),
+
+            // Address of a struct field
+            ast::FieldRefExpr(ref inner) => self.lower_field_ref(inner),
+
+            // Nothing else has a proper address
+            _ => panic!("ICE: attempted to take the address of an expression that isn't a place"),
+        }
+    }
+
+    /// Read the value of a variable
+    fn load_variable(&mut self, location: &Location, var_type: &Type) -> Operand {
+        match *location {
+            Register(temp) => ir::Temp(temp),
+            ref other => {
+                self.load_value(var_type, variable_address(other), self.align_of(var_type))
+            }
+        }
+    }
+
+    /// Write the value of a variable
+    fn store_variable(&mut self, location: &Location, var_type: &Type, value: Operand) {
+        match *location {
+            Register(temp) => self.builder.emit(ir::Copy { dst: temp, src: value }),
+            ref other => {
+                let align = self.align_of(var_type);
+                self.store_value(var_type, variable_address(other), align, value)
+            }
+        }
+    }
+
+    /// Load a value from memory. Aggregates can't be held in temporaries, so their address is used
+    /// as their value instead.
+    fn load_value(&mut self, var_type: &Type, address: Address, align: u16) -> Operand {
+        match self.value_type(var_type) {
+            Some(ty) => {
+                let dst = self.builder.new_temp(ty);
+                self.builder.emit(ir::Load { dst, ty, src: address, align });
+                ir::Temp(dst)
+            }
+            None => self.address_of(address),
+        }
+    }
+
+    /// Store a value in memory, copying the contents of aggregates
+    fn store_value(&mut self, var_type: &Type, address: Address, align: u16, value: Operand) {
+        match self.value_type(var_type) {
+            Some(ty) => self.builder.emit(ir::Store { dst: address, ty, src: value, align }),
+            None => {
+                let size = self.unaligned_size_of(var_type);
+                let src = self.indirect(value);
+                self.builder.emit(ir::CopyMemory { dst: address, src, size, align });
+            }
+        }
+    }
+
+    /// Get the alignment of the memory holding the value of an expression. Variables and the
+    /// aggregates created by an expression are stored in whole words, while fields of packed structs
+    /// can be at any address.
+    fn value_align(&self, expression: &ast::Expression) -> u16 {
+        match *expression.expr {
+            ast::VariableExpr(..)
+            | ast::CallExpr(..)
+            | ast::LitStringExpr(..)
+            | ast::StaticArrayExpr(..)
+            | ast::StructInitExpr(..) => 4,
+            ast::FieldRefExpr(ref inner) if self.type_table.is_packed(inner.target.rtype()) => 1,
+            _ => match self.value_type(expression.rtype()) {
+                Some(ty) => ty.size(),
+                None => self.align_of(expression.rtype()),
+            },
+        }
+    }
+
+    fn address_of(&mut self, address: Address) -> Operand {
+        let dst = self.builder.new_temp(ir::Type::Pointer);
+        self.builder.emit(ir::AddressOf { dst, src: address });
+        ir::Temp(dst)
+    }
+
+    /// Get the address held by an operand
+    fn indirect(&mut self, pointer: Operand) -> Address {
+        let temp = match pointer {
+            ir::Temp(temp) => temp,
+            // Addresses must be in a temporary
+            constant => match self.copy(ir::Type::Pointer, constant) {
+                ir::Temp(temp) => temp,
+                ir::Const(..) => unreachable!(),
+            },
+        };
+        Address::new(ir::TempBase(temp))
+    }
+
+    fn copy(&mut self, ty: ir::Type, src: Operand) -> Operand {
+        let dst = self.builder.new_temp(ty);
+        self.builder.emit(ir::Copy { dst, src });
+        ir::Temp(dst)
+    }
+
+    fn binary(&mut self, op: ir::BinaryOp, ty: ir::Type, lhs: Operand, rhs: Operand) -> Operand {
+        let dst = self.builder.new_temp(ty);
+        self.builder.emit(ir::Binary { op, dst, lhs, rhs });
+        ir::Temp(dst)
+    }
+
+    fn size_of(&self, type_: &Type) -> u16 {
+        self.type_table.size_of(type_)
+    }
+
+    fn unaligned_size_of(&self, type_: &Type) -> u16 {
+        self.type_table.unaligned_size_of(type_)
+    }
+
+    fn align_of(&self, type_: &Type) -> u16 {
+        self.type_table.align_of(type_)
+    }
+
+    /// Resolve a type written in the program. The types have already been checked, so this can't
+    /// fail.
+    fn resolve_type(&self, ast_type: &ast::Type) -> Type {
+        match self.type_table.resolve_type(ast_type) {
+            Ok(type_) => type_,
+            Err(..) => panic!("ICE: unresolved type `{:?}` after checking", ast_type),
+        }
+    }
+}
+
+/// Get the value that a word has when it is read back from a variable of a type. Characters are
+/// stored in a single byte, so only the lowest byte is kept.
+fn stored_value(value: i32, rtype: &Type) -> i32 {
+    match *rtype {
+        CHAR_TYPE => value as i8 as i32,
+        _ => value,
+    }
+}
+
+/// Get the address of a variable that is stored in memory
+fn variable_address(location: &Location) -> Address {
+    match *location {
+        Label(ref label) => Address::new(ir::Global(label.clone())),
+        Frame(slot) => Address::new(ir::Slot(slot)),
+        Param(index) => Address::new(ir::Param(index)),
+        Register(..) => panic!("ICE: attempted to take the address of a register variable"),
+        Constant(..) => panic!("ICE: attempted to take the address of a folded constant"),
+    }
+}
+
+/// Find the variables in an expression that have their address taken, and whether it contains any
+/// inline assembly
+fn scan_expression(expression: &ast::Expression, addressed: &mut HashSet<String>, asm: &mut bool) {
+    let mut scan = |expression: &ast::Expression| scan_expression(expression, addressed, asm);
+    match *expression.expr {
+        ast::IfExpr(ref inner) => {
+            scan(&inner.condition);
+            inner.body.statements.iter().for_each(&mut scan);
+            inner.else_block.iter().flat_map(|x| &x.statements).for_each(scan);
+        }
+        ast::ForLoopExpr(ref inner) => {
+            scan(&inner.start);
+            scan(&inner.end);
+            inner.body.statements.iter().for_each(scan);
+        }
+        ast::LoopExpr(ref inner) => inner.body.statements.iter().for_each(scan),
+        ast::CallExpr(ref inner) => inner.args.iter().for_each(scan),
+        ast::LetExpr(ast::LetStatement { assignment: Some(ref inner), .. })
+        | ast::AssignExpr(ref inner) => {
+            scan(&inner.target);
+            scan(&inner.rhs);
+        }
+        ast::StructInitExpr(ref inner) => inner.field_init.iter().for_each(|x| scan(&x.1)),
+        ast::StaticArrayExpr(ref inner) => inner.elements.iter().for_each(scan),
+        ast::FieldRefExpr(ref inner) => scan(&inner.target),
+        ast::ArrayIndexExpr(ref inner) => {
+            scan(&inner.index);
+            scan(&inner.target);
+        }
+        ast::RefExpr(ref inner) => {
+            if let ast::VariableExpr(ref name) = *inner.expr {
+                addressed.insert(name.clone());
+            }
+            scan_expression(inner, addressed, asm);
+        }
+        ast::Return(ref inner) | ast::DerefExpr(ref inner) | ast::CastExpr(ref inner, _) => {
+            scan(inner)
+        }
+        ast::BinaryExpr(ref inner) => {
+            scan(&inner.lhs);
+            scan(&inner.rhs);
+        }
+        ast::UnaryExpr(ref inner) => scan(&inner.operand),
+        ast::AsmOpExpr(..) => *asm = true,
+        ast::LetExpr(..)
+        | ast::Break
+        | ast::VariableExpr(..)
+        | ast::LitNumExpr(..)
+        | ast::LitCharExpr(..)
+        | ast::LitBoolExpr(..)
+        | ast::LitStringExpr(..)
+        | ast::NullExpr
+        | ast::NewExpr(..)
+        | ast::EmptyExpr
+        | ast::ErrorExpr => {}
+    }
+}
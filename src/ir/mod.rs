@@ -0,0 +1,559 @@
+use std::fmt;
+
+use crate::error::InputSpan;
+
+pub use self::{Base::*, Instruction::*, Operand::*, Terminator::*};
+
+pub mod eval;
+pub mod lower;
+pub mod opt;
+
+pub type TempId = usize;
+pub type SlotId = usize;
+pub type BlockId = usize;
+
+// The IR is a three address code where values are held in an unlimited number of temporaries.
+// Aggregates (structs and arrays) are never held in temporaries, instead they are stored in memory
+// and referred to by their address.
+
+/// The type of a value that can be held in a temporary
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Char,
+    Bool,
+    Pointer,
+}
+
+impl Type {
+    /// The number of bytes taken up by a value of the type in memory
+    pub fn size(self) -> u16 {
+        match self {
+            Type::Char => 1,
+            _ => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Temp(TempId),
+    Const(i32),
+}
+
+/// The location that an address is relative to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Base {
+    /// The address held in a temporary
+    TempBase(TempId),
+    /// A slot in the stack frame of the function
+    Slot(SlotId),
+    /// A parameter of the function that is passed in memory
+    Param(usize),
+    /// A global variable
+    Global(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub base: Base,
+    pub offset: i32,
+}
+
+impl Address {
+    pub fn new(base: Base) -> Address {
+        Address { base, offset: 0 }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+/// A piece of the code of an inline assembly block
+#[derive(Clone, Debug)]
+pub enum AsmPart {
+    Text(String),
+    /// The location of a variable, written as `{name}` in the source
+    Operand(Address),
+    /// The register that the result is left in, written as `{result}` in the source
+    ResultReg,
+}
+
+/// An argument to a function call
+#[derive(Clone, Debug)]
+pub enum Argument {
+    /// A value that fits in a temporary
+    Value(Operand, Type),
+    /// An aggregate that is copied from the address given, along with its size and alignment in
+    /// bytes
+    Memory(Operand, u16, u16),
+}
+
+#[derive(Clone, Debug)]
+pub enum Instruction {
+    Copy {
+        dst: TempId,
+        src: Operand,
+    },
+    Binary {
+        op: BinaryOp,
+        dst: TempId,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Unary {
+        op: UnaryOp,
+        dst: TempId,
+        src: Operand,
+    },
+    AddressOf {
+        dst: TempId,
+        src: Address,
+    },
+    /// Read a value from memory. The address is a multiple of `align`, which is less than the size
+    /// of the value for fields of packed structs.
+    Load {
+        dst: TempId,
+        ty: Type,
+        src: Address,
+        align: u16,
+    },
+    Store {
+        dst: Address,
+        ty: Type,
+        src: Operand,
+        align: u16,
+    },
+    /// Copy `size` bytes between two addresses that are both a multiple of `align`
+    CopyMemory {
+        dst: Address,
+        src: Address,
+        size: u16,
+        align: u16,
+    },
+    Call {
+        dst: Option<TempId>,
+        function: String,
+        args: Vec<Argument>,
+    },
+    /// Inline assembly, which leaves its result in `dst`
+    Asm {
+        dst: TempId,
+        code: Vec<AsmPart>,
+        /// The registers that the code modifies other than the scratch registers
+        clobbers: Vec<String>,
+    },
+}
+
+impl Instruction {
+    /// Get the temporary written by the instruction
+    pub fn def(&self) -> Option<TempId> {
+        match *self {
+            Copy { dst, .. }
+            | Binary { dst, .. }
+            | Unary { dst, .. }
+            | AddressOf { dst, .. }
+            | Load { dst, .. }
+            | Asm { dst, .. } => Some(dst),
+            Call { dst, .. } => dst,
+            Store { .. } | CopyMemory { .. } => None,
+        }
+    }
+
+    /// Get a mutable reference to the temporary written by the instruction
+    pub fn def_mut(&mut self) -> Option<&mut TempId> {
+        match self {
+            Copy { dst, .. }
+            | Binary { dst, .. }
+            | Unary { dst, .. }
+            | AddressOf { dst, .. }
+            | Load { dst, .. }
+            | Asm { dst, .. } => Some(dst),
+            Call { dst, .. } => dst.as_mut(),
+            Store { .. } | CopyMemory { .. } => None,
+        }
+    }
+
+    /// Get the operands read by the instruction, not including the bases of addresses
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Copy { src, .. } | Unary { src, .. } | Store { src, .. } => vec![src],
+            Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Call { args, .. } => args
+                .iter_mut()
+                .map(|arg| match arg {
+                    Argument::Value(operand, _) | Argument::Memory(operand, ..) => operand,
+                })
+                .collect(),
+            AddressOf { .. } | Load { .. } | CopyMemory { .. } | Asm { .. } => vec![],
+        }
+    }
+
+    /// Get the temporaries read by the instruction as the bases of addresses
+    pub fn bases_mut(&mut self) -> Vec<&mut TempId> {
+        let addresses = match self {
+            Store { dst, .. } => vec![dst],
+            AddressOf { src, .. } | Load { src, .. } => vec![src],
+            CopyMemory { dst, src, .. } => vec![dst, src],
+            Copy { .. } | Binary { .. } | Unary { .. } | Call { .. } | Asm { .. } => vec![],
+        };
+        addresses
+            .into_iter()
+            .filter_map(|address| match &mut address.base {
+                TempBase(temp) => Some(temp),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Get the temporaries read by the instruction
+    pub fn uses(&self) -> Vec<TempId> {
+        fn operand(operand: &Operand) -> Option<TempId> {
+            match *operand {
+                Temp(temp) => Some(temp),
+                Const(..) => None,
+            }
+        }
+        fn base(address: &Address) -> Option<TempId> {
+            match address.base {
+                TempBase(temp) => Some(temp),
+                _ => None,
+            }
+        }
+
+        match self {
+            Copy { src, .. } | Unary { src, .. } => operand(src).into_iter().collect(),
+            Binary { lhs, rhs, .. } => operand(lhs).into_iter().chain(operand(rhs)).collect(),
+            Store { dst, src, .. } => operand(src).into_iter().chain(base(dst)).collect(),
+            AddressOf { src, .. } | Load { src, .. } => base(src).into_iter().collect(),
+            CopyMemory { dst, src, .. } => base(dst).into_iter().chain(base(src)).collect(),
+            Call { args, .. } => args
+                .iter()
+                .filter_map(|arg| match arg {
+                    Argument::Value(value, _) | Argument::Memory(value, ..) => operand(value),
+                })
+                .collect(),
+            Asm { .. } => vec![],
+        }
+    }
+
+    /// Returns true if the instruction has effects other than writing to its destination
+    pub fn has_side_effects(&self) -> bool {
+        match self {
+            Copy { .. } | Unary { .. } | AddressOf { .. } | Load { .. } => false,
+            // Division by zero stops the machine
+            Binary { op, .. } => matches!(op, BinaryOp::Div | BinaryOp::Rem),
+            Store { .. } | CopyMemory { .. } | Call { .. } | Asm { .. } => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch { cond: Operand, then_block: BlockId, else_block: BlockId },
+    Return(Option<Operand>),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match *self {
+            Jump(target) => vec![target],
+            Branch { then_block, else_block, .. } => vec![then_block, else_block],
+            Return(..) => vec![],
+        }
+    }
+
+    /// Change the blocks that the terminator jumps to
+    pub fn map_targets(&mut self, mut f: impl FnMut(BlockId) -> BlockId) {
+        match self {
+            Jump(target) => *target = f(*target),
+            Branch { then_block, else_block, .. } => {
+                *then_block = f(*then_block);
+                *else_block = f(*else_block);
+            }
+            Return(..) => {}
+        }
+    }
+
+    pub fn operand_mut(&mut self) -> Option<&mut Operand> {
+        match self {
+            Branch { cond, .. } => Some(cond),
+            Return(value) => value.as_mut(),
+            Jump(..) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+/// How the arguments of a function are passed to it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Convention {
+    /// The first few word sized arguments are passed in registers and the rest on the stack
+    Register,
+    /// All of the arguments are passed on the stack
+    Stack,
+}
+
+/// A parameter of a function
+#[derive(Clone, Debug)]
+pub struct Parameter {
+    /// The type of the parameter, or `None` if it is an aggregate
+    pub ty: Option<Type>,
+    pub size: u16,
+    /// The temporary the parameter is copied to on entry, or `None` if it is kept in memory
+    pub home: Option<TempId>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub name: String,
+    /// The parameters of the function. Functions that return an aggregate take the address to
+    /// write it to as an extra first parameter.
+    pub params: Vec<Parameter>,
+    pub convention: Convention,
+    /// The type of each temporary
+    pub temps: Vec<Type>,
+    /// The size of each slot in the stack frame
+    pub slots: Vec<u16>,
+    /// The blocks of the function, starting with the entry block
+    pub blocks: Vec<Block>,
+    pub span: InputSpan,
+}
+
+#[derive(Clone, Debug)]
+pub enum Initializer {
+    Words(Vec<i32>),
+    Ascii(String),
+    Zeroed(u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct Global {
+    pub name: String,
+    pub is_const: bool,
+    pub init: Initializer,
+}
+
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub globals: Vec<Global>,
+    pub functions: Vec<Function>,
+    pub span: InputSpan,
+}
+
+impl Program {
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => f.write_str("int"),
+            Type::Char => f.write_str("char"),
+            Type::Bool => f.write_str("bool"),
+            Type::Pointer => f.write_str("ptr"),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Temp(temp) => write!(f, "%{}", temp),
+            Const(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.base {
+            TempBase(temp) => write!(f, "[%{}", temp)?,
+            Slot(slot) => write!(f, "[s{}", slot)?,
+            Param(index) => write!(f, "[p{}", index)?,
+            Global(name) => write!(f, "[@{}", name)?,
+        }
+        match self.offset {
+            0 => f.write_str("]"),
+            offset if offset < 0 => write!(f, " - {}]", -offset),
+            offset => write!(f, " + {}]", offset),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Rem => "rem",
+            BinaryOp::Eq => "eq",
+            BinaryOp::NotEq => "ne",
+            BinaryOp::Lt => "lt",
+            BinaryOp::LtEq => "le",
+            BinaryOp::Gt => "gt",
+            BinaryOp::GtEq => "ge",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Argument::Value(operand, ty) => write!(f, "{} {}", ty, operand),
+            Argument::Memory(operand, size, align) => {
+                write!(f, "[{}; {}, align {}]", operand, size, align)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Copy { dst, src } => write!(f, "%{} = copy {}", dst, src),
+            Binary { op, dst, lhs, rhs } => write!(f, "%{} = {} {}, {}", dst, op, lhs, rhs),
+            Unary { op: UnaryOp::Neg, dst, src } => write!(f, "%{} = neg {}", dst, src),
+            Unary { op: UnaryOp::Not, dst, src } => write!(f, "%{} = not {}", dst, src),
+            AddressOf { dst, src } => write!(f, "%{} = addr {}", dst, src),
+            Load { dst, ty, src, align } => {
+                write!(f, "%{} = load {} {}", dst, ty, src)?;
+                write_align(f, *ty, *align)
+            }
+            Store { dst, ty, src, align } => {
+                write!(f, "store {} {}, {}", ty, dst, src)?;
+                write_align(f, *ty, *align)
+            }
+            CopyMemory { dst, src, size, align } => {
+                write!(f, "copy {}, {}, {}, align {}", dst, src, size, align)
+            }
+            Call { dst, function, args } => {
+                if let Some(dst) = dst {
+                    write!(f, "%{} = ", dst)?;
+                }
+                let args: Vec<String> = args.iter().map(Argument::to_string).collect();
+                write!(f, "call {}({})", function, args.join(", "))
+            }
+            Asm { dst, code, clobbers } => {
+                write!(f, "%{} = asm \"", dst)?;
+                for part in code {
+                    match part {
+                        AsmPart::Text(text) => write!(f, "{}", text.escape_debug())?,
+                        AsmPart::Operand(address) => write!(f, "{{{}}}", address)?,
+                        AsmPart::ResultReg => f.write_str("{result}")?,
+                    }
+                }
+                f.write_str("\"")?;
+                if !clobbers.is_empty() {
+                    write!(f, " clobbers({})", clobbers.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write the alignment of a load or store, if the value may not be aligned
+fn write_align(f: &mut fmt::Formatter, ty: Type, align: u16) -> fmt::Result {
+    if align < ty.size() {
+        write!(f, ", align {}", align)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Jump(target) => write!(f, "jump bb{}", target),
+            Branch { cond, then_block, else_block } => {
+                write!(f, "branch {}, bb{}, bb{}", cond, then_block, else_block)
+            }
+            Return(Some(value)) => write!(f, "ret {}", value),
+            Return(None) => f.write_str("ret"),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| match (param.ty, param.home) {
+                (Some(ty), Some(home)) => format!("%{}: {}", home, ty),
+                (Some(ty), None) => format!("p{}: {}", i, ty),
+                (None, _) => format!("p{}: [{}]", i, param.size),
+            })
+            .collect();
+        if self.convention == Convention::Stack {
+            writeln!(f, "#[stack_args]")?;
+        }
+        writeln!(f, "fn {}({}) {{", self.name, params.join(", "))?;
+        for (i, size) in self.slots.iter().enumerate() {
+            writeln!(f, "    s{}: [{}]", i, size)?;
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "bb{}:", i)?;
+            for instruction in &block.instructions {
+                writeln!(f, "    {}", instruction)?;
+            }
+            writeln!(f, "    {}", block.terminator)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for global in &self.globals {
+            let kind = if global.is_const { "const" } else { "global" };
+            match &global.init {
+                Initializer::Words(words) => {
+                    let words: Vec<String> = words.iter().map(i32::to_string).collect();
+                    writeln!(f, "{} @{} = words {}", kind, global.name, words.join(", "))?
+                }
+                Initializer::Ascii(text) => {
+                    writeln!(f, "{} @{} = ascii \"{}\"", kind, global.name, text)?
+                }
+                Initializer::Zeroed(size) => {
+                    writeln!(f, "{} @{} = zeroed {}", kind, global.name, size)?
+                }
+            }
+        }
+        for function in &self.functions {
+            writeln!(f)?;
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ir::{self, BlockId, Function, Operand, Program, TempId},
+    module::STD_MODULE,
+};
+
+// Lowering produces simple but wasteful code, for example every read of a variable copies its value
+// to a new temporary. These passes clean up the IR before it is passed to the code generator.
+
+/// Optimize every function in a program
+pub fn optimize(program: &mut Program) {
+    remove_unused_library_items(program);
+    for function in &mut program.functions {
+        remove_unreachable_blocks(function);
+        propagate_copies(function);
+        coalesce_copies(function);
+        remove_dead_code(function);
+        remove_unreachable_blocks(function);
+    }
+}
+
+/// Remove the functions and globals of the standard library that the program doesn't use, so that
+/// only the parts of the library a program needs are compiled
+fn remove_unused_library_items(program: &mut Program) {
+    // Items of the standard library have labels that start with the name of the module
+    let prefix = format!("{}.", STD_MODULE);
+    let is_library = |name: &str| name.starts_with(&prefix);
+
+    // Everything outside of the library is kept, along with anything that it refers to
+    let functions = program.functions.iter().map(|function| &function.name);
+    let globals = program.globals.iter().map(|global| &global.name);
+    let mut used: HashSet<String> =
+        functions.chain(globals).filter(|name| !is_library(name)).cloned().collect();
+    let mut queue: Vec<&Function> =
+        program.functions.iter().filter(|function| !is_library(&function.name)).collect();
+
+    while let Some(function) = queue.pop() {
+        for name in references(function) {
+            if is_library(&name) && used.insert(name.clone()) {
+                queue.extend(program.function(&name));
+            }
+        }
+    }
+
+    program.functions.retain(|function| used.contains(&function.name));
+    program.globals.retain(|global| used.contains(&global.name));
+}
+
+/// Get the names of the functions and globals that a function refers to
+fn references(function: &Function) -> Vec<String> {
+    let mut names = vec![];
+    for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+        let addresses = match instruction {
+            ir::Call { function, .. } => {
+                names.push(function.clone());
+                continue;
+            }
+            // Inline assembly can refer to any label, so every symbol in the code is included
+            ir::Asm { code, .. } => {
+                let is_symbol = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+                for part in code {
+                    match part {
+                        ir::AsmPart::Text(text) => {
+                            names.extend(text.split(|c| !is_symbol(c)).map(str::to_string))
+                        }
+                        ir::AsmPart::Operand(ir::Address { base: ir::Global(name), .. }) => {
+                            names.push(name.clone())
+                        }
+                        ir::AsmPart::Operand(..) | ir::AsmPart::ResultReg => {}
+                    }
+                }
+                continue;
+            }
+            ir::Store { dst, .. } => vec![dst],
+            ir::AddressOf { src, .. } | ir::Load { src, .. } => vec![src],
+            ir::CopyMemory { dst, src, .. } => vec![dst, src],
+            ir::Copy { .. } | ir::Binary { .. } | ir::Unary { .. } => vec![],
+        };
+        for address in addresses {
+            if let ir::Global(name) = &address.base {
+                names.push(name.clone());
+            }
+        }
+    }
+    names
+}
+
+/// Count the number of times each temporary is written, including the parameters that are copied
+/// to temporaries on entry
+fn count_defs(function: &Function) -> Vec<usize> {
+    let mut defs = vec![0; function.temps.len()];
+    for home in function.params.iter().filter_map(|param| param.home) {
+        defs[home] += 1;
+    }
+    for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+        if let Some(def) = instruction.def() {
+            defs[def] += 1;
+        }
+    }
+    defs
+}
+
+/// Count the number of times each temporary is read
+fn count_uses(function: &Function) -> Vec<usize> {
+    let mut uses = vec![0; function.temps.len()];
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            for temp in instruction.uses() {
+                uses[temp] += 1;
+            }
+        }
+        if let ir::Branch { cond: ir::Temp(temp), .. } | ir::Return(Some(ir::Temp(temp))) =
+            block.terminator
+        {
+            uses[temp] += 1;
+        }
+    }
+    uses
+}
+
+/// Replace the uses of temporaries that are copies of another operand with the operand itself. This
+/// is only done within a block, while the source of the copy is known to be unchanged.
+fn propagate_copies(function: &mut Function) {
+    let defs = count_defs(function);
+
+    for block in &mut function.blocks {
+        let mut copies: HashMap<TempId, Operand> = HashMap::new();
+        for instruction in &mut block.instructions {
+            for operand in instruction.operands_mut() {
+                if let ir::Temp(temp) = *operand {
+                    if let Some(&source) = copies.get(&temp) {
+                        *operand = source;
+                    }
+                }
+            }
+            for base in instruction.bases_mut() {
+                // Addresses must be held in a temporary, so constants can't be propagated here
+                if let Some(&ir::Temp(source)) = copies.get(base) {
+                    *base = source;
+                }
+            }
+
+            if let Some(def) = instruction.def() {
+                copies.retain(|_, source| *source != ir::Temp(def));
+            }
+            if let ir::Copy { dst, src } = *instruction {
+                if defs[dst] == 1 && src != ir::Temp(dst) {
+                    copies.insert(dst, src);
+                }
+            }
+        }
+
+        if let Some(operand) = block.terminator.operand_mut() {
+            if let ir::Temp(temp) = *operand {
+                if let Some(&source) = copies.get(&temp) {
+                    *operand = source;
+                }
+            }
+        }
+    }
+}
+
+/// Remove copies from temporaries that are only used once, by writing the value directly to the
+/// destination of the copy instead. This is only possible if the destination isn't read or written
+/// between the instruction that produces the value and the copy.
+fn coalesce_copies(function: &mut Function) {
+    let defs = count_defs(function);
+    let uses = count_uses(function);
+
+    for block in &mut function.blocks {
+        let mut removed = vec![false; block.instructions.len()];
+        for i in 0..block.instructions.len() {
+            let (dst, src) = match block.instructions[i] {
+                ir::Copy { dst, src: ir::Temp(src) } if dst != src => (dst, src),
+                _ => continue,
+            };
+            if defs[src] != 1 || uses[src] != 1 {
+                continue;
+            }
+
+            // Find the instruction that produces the value
+            let def =
+                (0..i).rev().find(|&j| !removed[j] && block.instructions[j].def() == Some(src));
+            let def = match def {
+                Some(def) => def,
+                None => continue,
+            };
+            let clobbered = (def + 1..i).filter(|&j| !removed[j]).any(|j| {
+                let instruction = &block.instructions[j];
+                instruction.def() == Some(dst) || instruction.uses().contains(&dst)
+            });
+            if clobbered {
+                continue;
+            }
+
+            if let Some(def) = block.instructions[def].def_mut() {
+                *def = dst;
+            }
+            removed[i] = true;
+        }
+
+        let mut removed = removed.into_iter();
+        block.instructions.retain(|_| !removed.next().unwrap());
+    }
+}
+
+/// Remove instructions that write to temporaries that are never read
+fn remove_dead_code(function: &mut Function) {
+    loop {
+        let uses = count_uses(function);
+        let mut changed = false;
+        for block in &mut function.blocks {
+            let length = block.instructions.len();
+            block.instructions.retain(|instruction| match instruction.def() {
+                Some(def) => uses[def] != 0 || instruction.has_side_effects(),
+                None => true,
+            });
+            changed |= block.instructions.len() != length;
+
+            // The result of a call doesn't need to be kept if it isn't used
+            for instruction in &mut block.instructions {
+                if let ir::Call { dst: dst @ Some(..), .. } = instruction {
+                    if uses[dst.unwrap()] == 0 {
+                        *dst = None;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // Parameters that are never read don't need to be loaded
+    let uses = count_uses(function);
+    for param in &mut function.params {
+        if param.home.is_some_and(|home| uses[home] == 0) {
+            param.home = None;
+        }
+    }
+}
+
+/// Remove blocks that can never be executed, such as code following a return
+fn remove_unreachable_blocks(function: &mut Function) {
+    // Branches on a constant always go the same way
+    for block in &mut function.blocks {
+        if let ir::Branch { cond: ir::Const(value), then_block, else_block } = block.terminator {
+            let target = if value != 0 { then_block } else { else_block };
+            block.terminator = ir::Jump(target);
+        }
+    }
+
+    // Jumps to empty blocks can go straight to the block that they jump to, which often leaves
+    // the empty block unreachable
+    let forward: Vec<Option<BlockId>> = function
+        .blocks
+        .iter()
+        .map(|block| match block.terminator {
+            ir::Jump(target) if block.instructions.is_empty() => Some(target),
+            _ => None,
+        })
+        .collect();
+    let resolve = |mut target: BlockId| {
+        // Limit the number of steps, since empty blocks may form an infinite loop
+        for _ in 0..forward.len() {
+            match forward[target] {
+                Some(next) => target = next,
+                None => break,
+            }
+        }
+        target
+    };
+    for block in &mut function.blocks {
+        block.terminator.map_targets(resolve);
+    }
+
+    let mut reachable = vec![false; function.blocks.len()];
+    let mut stack = vec![0];
+    while let Some(block) = stack.pop() {
+        if !reachable[block] {
+            reachable[block] = true;
+            stack.extend(function.blocks[block].terminator.successors());
+        }
+    }
+
+    // Keep the remaining blocks in the same order
+    let mut new_ids = vec![0; function.blocks.len()];
+    let mut next_id = 0;
+    for (id, &reachable) in reachable.iter().enumerate() {
+        new_ids[id] = next_id;
+        next_id += reachable as usize;
+    }
+
+    let blocks = std::mem::take(&mut function.blocks);
+    function.blocks = blocks
+        .into_iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| *reachable)
+        .map(|(mut block, _)| {
+            block.terminator.map_targets(|target| new_ids[target]);
+            block
+        })
+        .collect();
+}
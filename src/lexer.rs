@@ -1,4 +1,6 @@
-use crate::error::InputPos;
+use std::fmt;
+
+use crate::error::{Diagnostic, FileId, InputPos, InputSpan, Logger};
 pub use crate::lexer::TokenValue::*;
 
 #[derive(Eq, PartialEq, Clone, Debug)]
@@ -9,6 +11,7 @@ pub enum TokenValue {
     RightBrace,
     LeftBracket,
     RightBracket,
+    HashBracket,
 
     Let,
     Const,
@@ -17,16 +20,27 @@ pub enum TokenValue {
 
     Comma,
     Colon,
+    PathSep,
     SemiColon,
     Dot,
     Star,
     Amp,
 
     Equal,
+    NotEqual,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
     Plus,
     PlusEq,
     Minus,
     MinusEq,
+    Slash,
+    Percent,
+    Not,
+    AndAnd,
+    OrOr,
 
     Eof,
 
@@ -42,8 +56,11 @@ pub enum TokenValue {
     Else,
     Struct,
     As,
+    New,
     Fn,
     Asm,
+    Mod,
+    Use,
 
     Bool,
     Char,
@@ -60,20 +77,105 @@ pub enum TokenValue {
     Ident(String),
 }
 
+impl fmt::Display for TokenValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            LeftParen => "(",
+            RightParen => ")",
+            LeftBrace => "{",
+            RightBrace => "}",
+            LeftBracket => "[",
+            RightBracket => "]",
+            HashBracket => "#[",
+            Let => "let",
+            Const => "const",
+            Assignment => "=",
+            RightArrow => "->",
+            Comma => ",",
+            Colon => ":",
+            PathSep => "::",
+            SemiColon => ";",
+            Dot => ".",
+            Star => "*",
+            Amp => "&",
+            Equal => "==",
+            NotEqual => "!=",
+            Less => "<",
+            LessEq => "<=",
+            Greater => ">",
+            GreaterEq => ">=",
+            Plus => "+",
+            PlusEq => "+=",
+            Minus => "-",
+            MinusEq => "-=",
+            Slash => "/",
+            Percent => "%",
+            Not => "!",
+            AndAnd => "&&",
+            OrOr => "||",
+            Eof => return f.write_str("end of file"),
+            For => "for",
+            Range => "range",
+            In => "in",
+            While => "while",
+            Loop => "loop",
+            Break => "break",
+            Return => "return",
+            If => "if",
+            Else => "else",
+            Struct => "struct",
+            As => "as",
+            New => "new",
+            Fn => "fn",
+            Asm => "asm",
+            Mod => "mod",
+            Use => "use",
+            Bool => "bool",
+            Char => "char",
+            Int => "int",
+            _Uint => "uint",
+            Any => "any",
+            True => "true",
+            False => "false",
+            LitNum(value) => return write!(f, "`{}`", value),
+            LitChar(value) => return write!(f, "`'{}'`", value.escape_default()),
+            LitString(value) => return write!(f, "`\"{}\"`", value),
+            Null => "null",
+            Ident(name) => return write!(f, "identifier `{}`", name),
+        };
+        write!(f, "`{}`", text)
+    }
+}
+
 #[derive(Debug)]
 pub struct Token {
     pub value: TokenValue,
     pub pos: InputPos,
+    /// The position directly after the last character of the token
+    pub end: InputPos,
 }
 
 pub struct Lexer<'a> {
     remaining: &'a str,
+    file: FileId,
     pos: InputPos,
+    logger: &'a Logger,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(source: &str) -> Lexer {
-        Lexer { remaining: source, pos: InputPos::start() }
+    pub fn new(source: &'a str, file: FileId, logger: &'a Logger) -> Lexer<'a> {
+        Lexer { remaining: source, file, pos: InputPos::start(), logger }
+    }
+
+    /// The file that the tokens are read from
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    /// Report an error covering the next `len` characters of the input
+    fn error(&self, code: &'static str, message: String, len: usize) -> Diagnostic {
+        let end = InputPos { col: self.pos.col + len, line: self.pos.line };
+        Diagnostic::error(message, InputSpan::new(self.file, self.pos, end)).code(code)
     }
 
     fn bump(&mut self) {
@@ -101,8 +203,8 @@ impl<'a> Lexer<'a> {
                 // Match whitespace
                 c if c.is_whitespace() => self.bump(),
 
-                // Match comments
-                '#' => {
+                // Match comments, which don't include the start of an attribute
+                '#' if !self.remaining.starts_with("#[") => {
                     let comment_line = self.pos.line;
                     while self.pos.line == comment_line && !self.remaining.is_empty() {
                         self.bump();
@@ -128,6 +230,7 @@ impl<'a> Iterator for Lexer<'a> {
             return None;
         }
 
+        let start = self.pos;
         let mut token_len = 1;
         let token_val = match self.remaining.chars().next().unwrap() {
             '(' => LeftParen,
@@ -138,13 +241,70 @@ impl<'a> Iterator for Lexer<'a> {
 
             '[' => LeftBracket,
             ']' => RightBracket,
+            '#' => {
+                token_len += 1;
+                HashBracket
+            }
 
             ';' => SemiColon,
-            ':' => Colon,
+            ':' => match self.remaining.chars().nth(1) {
+                Some(':') => {
+                    token_len += 1;
+                    PathSep
+                }
+                _ => Colon,
+            },
             ',' => Comma,
             '.' => Dot,
             '*' => Star,
-            '&' => Amp,
+            '/' => Slash,
+            '%' => Percent,
+
+            '&' => match self.remaining.chars().nth(1) {
+                Some('&') => {
+                    token_len += 1;
+                    AndAnd
+                }
+                _ => Amp,
+            },
+
+            '|' => match self.remaining.chars().nth(1) {
+                Some('|') => {
+                    token_len += 1;
+                    OrOr
+                }
+                // Treat a single `|` as a logical or, so that the rest of the input can still be
+                // checked
+                _ => {
+                    let error = self.error("E0101", "invalid operator `|`".to_string(), 1);
+                    self.logger.report(error.help("use `||` for logical or".to_string()));
+                    OrOr
+                }
+            },
+
+            '!' => match self.remaining.chars().nth(1) {
+                Some('=') => {
+                    token_len += 1;
+                    NotEqual
+                }
+                _ => Not,
+            },
+
+            '<' => match self.remaining.chars().nth(1) {
+                Some('=') => {
+                    token_len += 1;
+                    LessEq
+                }
+                _ => Less,
+            },
+
+            '>' => match self.remaining.chars().nth(1) {
+                Some('=') => {
+                    token_len += 1;
+                    GreaterEq
+                }
+                _ => Greater,
+            },
 
             '=' => match self.remaining.chars().nth(1) {
                 Some('=') => {
@@ -179,34 +339,58 @@ impl<'a> Iterator for Lexer<'a> {
                 let number_str = &self.remaining[..token_len];
                 match number_str.parse() {
                     Ok(n) => LitNum(n),
-                    Err(e) => panic!("ICE: Invalid number: {}", e),
+                    Err(e) => {
+                        let message = format!("invalid number `{}`: {}", number_str, e);
+                        self.logger.report(self.error("E0102", message, token_len));
+                        LitNum(0)
+                    }
                 }
             }
 
             '"' => {
-                self.bump();
-                token_len = match self.remaining.find('"') {
+                token_len = match self.remaining[1..].find('"') {
                     Some(offset) => offset,
                     None => {
-                        panic!("Unclosed \" ");
+                        let error = self.error("E0103", "unclosed string literal".to_string(), 1);
+                        self.logger.report(error.label("string starts here".to_string()));
+
+                        // Treat the rest of the input as part of the string
+                        let rest = self.remaining[1..].to_string();
+                        while !self.remaining.is_empty() {
+                            self.bump();
+                        }
+                        return Some(Token { value: LitString(rest), pos: start, end: self.pos });
                     }
                 };
+                self.bump();
                 let result = LitString(self.remaining[..token_len].to_string());
                 token_len += 1;
                 result
             }
 
             '\'' => {
-                if len < 3 {
-                    panic!("Invalid char literal");
-                }
-                self.bump();
-                let result = LitChar(self.remaining.chars().next().unwrap());
-                self.bump();
-                if !self.remaining.starts_with('\'') {
-                    panic!("Unclosed '");
+                let mut chars = self.remaining.chars().skip(1);
+                match (chars.next(), chars.next()) {
+                    (Some(value), Some('\'')) if value != '\n' => {
+                        self.bump();
+                        self.bump();
+                        LitChar(value)
+                    }
+                    _ => {
+                        // Skip to the closing quote if there is one on the same line
+                        let line = self.remaining.lines().next().unwrap_or("");
+                        token_len = match line[1..].find('\'') {
+                            Some(offset) => offset + 2,
+                            None => 1,
+                        };
+
+                        let error =
+                            self.error("E0104", "invalid char literal".to_string(), token_len);
+                        let help = "char literals must contain exactly one character, e.g. `'a'`";
+                        self.logger.report(error.help(help.to_string()));
+                        LitChar('\0')
+                    }
                 }
-                result
             }
 
             _ => {
@@ -227,7 +411,10 @@ impl<'a> Iterator for Lexer<'a> {
                     "asm" => Asm,
                     "fn" => Fn,
                     "struct" => Struct,
+                    "mod" => Mod,
+                    "use" => Use,
                     "as" => As,
+                    "new" => New,
                     "true" => True,
                     "false" => False,
                     "null" => Null,
@@ -240,10 +427,13 @@ impl<'a> Iterator for Lexer<'a> {
             }
         };
 
-        let token = Token { value: token_val, pos: self.pos };
-        self.pos.col += token_len;
-        self.remaining = &self.remaining[token_len..];
-        Some(token)
+        // Bump over each character so that the position is updated correctly for tokens containing
+        // newlines or multi-byte characters
+        let remaining_len = self.remaining.len() - token_len;
+        while self.remaining.len() > remaining_len {
+            self.bump();
+        }
+        Some(Token { value: token_val, pos: start, end: self.pos })
     }
 }
 
@@ -251,7 +441,7 @@ impl<'a> Iterator for Lexer<'a> {
 fn scan_token(string: &str) -> usize {
     const TOKEN_BOUNDS: &[char] = &[
         ' ', '\t', '\n', '\r', '#', ':', ';', ',', '(', ')', '{', '}', '[', ']', '.', '*', '&',
-        '=', '+', '-', '"', '\'',
+        '=', '+', '-', '"', '\'', '/', '%', '!', '<', '>', '|',
     ];
 
     match string.find(TOKEN_BOUNDS) {
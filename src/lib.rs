@@ -0,0 +1,308 @@
+// Enum variants are glob imported throughout the compiler, so variant names include the kind of
+// the enum to avoid conflicts (e.g. `ast::IfExpr` and `ast::UserType`).
+#![allow(clippy::enum_variant_names)]
+
+use std::{fs, io, path::Path};
+
+pub use crate::{
+    dlx::{
+        asm::Instruction,
+        codegen::{codegen, Runtime},
+    },
+    error::{Diagnostic, Logger},
+    lexer::{Lexer, Token},
+    parser::parse,
+    types::typegen,
+};
+
+use crate::{
+    dlx::peephole,
+    error::FatalError,
+    ir::{lower::lower, opt::optimize},
+    module::{load_modules, load_std},
+    types::TypeTable,
+};
+
+pub mod ast;
+pub mod dlx;
+pub mod error;
+pub mod ir;
+pub mod lexer;
+pub mod module;
+pub mod parser;
+pub mod project;
+pub mod sema;
+pub mod types;
+
+/// The settings used to compile programs
+#[derive(Clone)]
+pub struct Compiler {
+    /// The settings for the program start code, or `None` if it should not be included
+    pub runtime: Option<Runtime>,
+    /// Whether the generated code is improved with the peephole optimiser
+    pub optimize: bool,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler { runtime: Some(Runtime::default()), optimize: false }
+    }
+}
+
+impl Compiler {
+    /// Start a session for compiling a single program
+    pub fn session(&self) -> Session {
+        Session { compiler: self.clone(), logger: Logger::new(true) }
+    }
+
+    /// Compile the source of a program. Diagnostics refer to the program as `path`, and the
+    /// modules it declares are read from the same directory.
+    pub fn compile_str(&self, path: &Path, source: &str) -> Output {
+        let session = self.session();
+        let code = session.compile(path, source);
+        Output { code, diagnostics: session.diagnostics() }
+    }
+
+    /// Read a program from a file and compile it
+    pub fn compile_path(&self, path: &Path) -> io::Result<Output> {
+        let source = fs::read_to_string(path)?;
+        Ok(self.compile_str(path, &source))
+    }
+}
+
+/// The result of compiling a program
+pub struct Output {
+    /// The generated code, or `None` if the program has errors
+    pub code: Option<Vec<Instruction>>,
+    /// The errors and warnings reported while compiling the program
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The compilation of a single program, which keeps its source files and diagnostics. Each stage
+/// of the compiler can be run separately, and returns `None` once an error has been reported.
+pub struct Session {
+    compiler: Compiler,
+    logger: Logger,
+}
+
+impl Session {
+    /// Enable or disable ANSI colour codes in the rendered diagnostics
+    pub fn with_color(mut self, color: bool) -> Session {
+        self.logger = self.logger.with_color(color);
+        self
+    }
+
+    /// The logger that diagnostics are reported to, which can render them for display
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.logger.diagnostics()
+    }
+
+    /// Run every stage of the compiler on a program
+    pub fn compile(&self, path: &Path, source: &str) -> Option<Vec<Instruction>> {
+        let program = self.parse(path, source)?;
+        let program = self.lower(program)?;
+        self.codegen(&program)
+    }
+
+    /// Split the source of a program into tokens
+    pub fn tokens(&self, path: &Path, source: &str) -> Option<Vec<Token>> {
+        let file = self.logger.add_file(&path.display().to_string(), source);
+        let tokens = Lexer::new(source, file, &self.logger).collect();
+        self.finish(Some(tokens))
+    }
+
+    /// Parse a program, along with the modules that it declares
+    pub fn parse(&self, path: &Path, source: &str) -> Option<ast::Program> {
+        let program = error::catch_fatal(|| {
+            let file = self.logger.add_file(&path.display().to_string(), source);
+            let mut program = parse(Lexer::new(source, file, &self.logger), &self.logger);
+            load_modules(&mut program, path, &self.logger);
+            program
+        });
+        self.finish(program)
+    }
+
+    /// Add the standard library to a parsed program and check it, giving the types it declares
+    pub fn check(&self, program: &mut ast::Program) -> Option<TypeTable> {
+        let type_table = error::catch_fatal(|| {
+            load_std(program, &self.logger);
+
+            // Don't attempt to generate code for a program with syntax errors
+            if self.logger.has_errors() {
+                FatalError::raise();
+            }
+            sema::check(program, &self.logger)
+        });
+        self.finish(type_table)
+    }
+
+    /// Check a parsed program, then lower it to optimised IR
+    pub fn lower(&self, mut program: ast::Program) -> Option<ir::Program> {
+        let type_table = self.check(&mut program)?;
+        let program = error::catch_fatal(|| {
+            let mut program = lower(&program, type_table, &self.logger);
+            optimize(&mut program);
+            program
+        });
+        self.finish(program)
+    }
+
+    /// Generate the instructions for a program that has been lowered
+    pub fn codegen(&self, program: &ir::Program) -> Option<Vec<Instruction>> {
+        let runtime = self.compiler.runtime.as_ref();
+        let code = error::catch_fatal(|| codegen(program, &self.logger, runtime));
+        match self.finish(code) {
+            Some(code) if self.compiler.optimize => Some(peephole::optimize(code)),
+            code => code,
+        }
+    }
+
+    /// Discard the output of a stage if it reported any errors
+    fn finish<T>(&self, output: Option<T>) -> Option<T> {
+        output.filter(|_| !self.logger.has_errors())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_a_program_from_a_string() {
+        let output =
+            Compiler::default().compile_str(Path::new("main.pcp"), "fn main() -> int { 42 }");
+        assert!(output.diagnostics.is_empty());
+        let code = output.code.unwrap();
+        assert!(code.contains(&dlx::asm::Label("main".to_string())));
+    }
+
+    #[test]
+    fn returns_diagnostics_for_invalid_programs() {
+        let output =
+            Compiler::default().compile_str(Path::new("main.pcp"), "fn main() -> int { x }");
+        assert!(output.code.is_none());
+        assert_eq!(output.diagnostics.len(), 1);
+        assert_eq!(output.diagnostics[0].code, Some("E0301"));
+    }
+
+    #[test]
+    fn rejects_labels_used_by_the_runtime() {
+        let source = "let heap = 3;\nlet r3 = 4;\nfn r2() -> int { r3 }\n\
+                      fn a1() -> int { if (true) { 1 } else { 2 } }\n\
+                      fn main() -> int { a1() + r2() }";
+        let output = Compiler::default().compile_str(Path::new("main.pcp"), source);
+        let codes: Vec<_> = output.diagnostics.iter().map(|x| x.code).collect();
+        assert_eq!(codes, vec![Some("E0308"); 3]);
+    }
+
+    #[test]
+    fn requires_an_allocator_for_new() {
+        let source = "fn std() {}\nfn main() -> int { let x = new int; *x }";
+        let output = Compiler::default().compile_str(Path::new("main.pcp"), source);
+        assert!(output.code.is_none());
+        assert_eq!(output.diagnostics[0].code, Some("E0511"));
+    }
+
+    #[test]
+    fn reuses_freed_memory_and_runs_out_of_heap() {
+        let source = "struct Pair { a: int, b: int }\n\
+                      fn main() -> int {\n\
+                          let first = new Pair;\n\
+                          first.a = 5;\n\
+                          first.b = 7;\n\
+                          free(first);\n\
+                          let second = new Pair;\n\
+                          let result = 0;\n\
+                          if (second == first) { result += 1; }\n\
+                          if (second.a == 0 && second.b == 0) { result += 2; }\n\
+                          if (alloc(64) == null) { result += 4; }\n\
+                          if (alloc(16) != null) { result += 8; }\n\
+                          free(second);\n\
+                          if (alloc(2147483647) == null) { result += 16; }\n\
+                          result\n\
+                      }";
+        let runtime = Runtime { heap_size: 48, ..Default::default() };
+        let compiler = Compiler { runtime: Some(runtime), optimize: false };
+        let output = compiler.compile_str(Path::new("main.pcp"), source);
+        assert!(output.diagnostics.is_empty());
+        let mut machine = dlx::sim::Machine::load(&output.code.unwrap()).ok().unwrap();
+        assert_eq!(machine.run(dlx::sim::DEFAULT_STEP_LIMIT), dlx::sim::Status::Halted);
+        assert_eq!(machine.regs[1], 31);
+    }
+
+    #[test]
+    fn reports_recursive_structs_and_duplicate_fields() {
+        let source = "struct A { b: B, x: int, x: int }\nstruct B { a: A }\nfn main() -> int { 0 }";
+        let output = Compiler::default().compile_str(Path::new("main.pcp"), source);
+        assert!(output.code.is_none());
+        let codes: Vec<_> = output.diagnostics.iter().map(|x| x.code).collect();
+        assert_eq!(codes, vec![Some("E0409"), Some("E0302")]);
+        assert_eq!(output.diagnostics[0].notes, vec!["`B` contains `A`, which contains `B`"]);
+    }
+
+    #[test]
+    fn rejects_types_that_are_too_large() {
+        let codes = |source: &str| {
+            let output = Compiler::default().compile_str(Path::new("main.pcp"), source);
+            output.diagnostics.iter().map(|x| x.code).collect::<Vec<_>>()
+        };
+        let source = "let a: [char, ..70000];\nlet b: [int, ..20000];\nfn main() -> int { 0 }";
+        assert_eq!(codes(source), vec![Some("E0410"), Some("E0410")]);
+        let source = "struct A { x: [int, ..10000], y: [int, ..10000] }\nfn main() -> int { 0 }";
+        assert_eq!(codes(source), vec![Some("E0410")]);
+    }
+
+    #[test]
+    fn rejects_stack_frames_that_are_too_large() {
+        let source = "fn small() -> int { let a: [int, ..8000]; a[7999] = 4; a[7999] }\n\
+                      fn large() -> int { let a: [int, ..5000]; let b: [int, ..5000]; \
+                      let c: [int, ..5000]; a[0] + b[0] + c[0] }\n\
+                      fn main() -> int { small() + large() }";
+        let output = Compiler::default().compile_str(Path::new("main.pcp"), source);
+        let codes: Vec<_> = output.diagnostics.iter().map(|x| x.code).collect();
+        assert_eq!(codes, vec![Some("E0513")]);
+        assert_eq!(output.diagnostics[0].message, "the stack frame of `large` is too large");
+    }
+
+    #[test]
+    fn checks_the_size_of_the_stack_and_heap() {
+        let source = "fn main() -> int { let p = new int; *p = 5; *p }";
+        let compile = |stack_size| {
+            let runtime = Runtime { stack_size, ..Default::default() };
+            Compiler { runtime: Some(runtime), optimize: false }
+                .compile_str(Path::new("main.pcp"), source)
+        };
+        let output = compile(100000);
+        assert!(output.code.is_none());
+        assert_eq!(output.diagnostics[0].code, Some("E0514"));
+
+        // The heap is still word aligned after a stack whose size isn't a multiple of 4
+        let mut machine = dlx::sim::Machine::load(&compile(801).code.unwrap()).ok().unwrap();
+        assert_eq!(machine.run(dlx::sim::DEFAULT_STEP_LIMIT), dlx::sim::Status::Halted);
+        assert_eq!(machine.regs[1], 5);
+    }
+
+    #[test]
+    fn reports_the_layout_of_the_program_without_the_standard_library() {
+        let session = Compiler::default().session();
+        let source = "struct P { x: int }\nfn main() -> int { let p = new P; p.x }";
+        let mut program = session.parse(Path::new("main.pcp"), source).unwrap();
+        let report = session.check(&mut program).unwrap().layout_report();
+        assert_eq!(report, "struct P (size 4, align 4)\n    offset 0    x: int (4 bytes)\n");
+    }
+
+    #[test]
+    fn runs_the_stages_of_a_session_separately() {
+        let session = Compiler { runtime: None, optimize: true }.session();
+        let path = Path::new("lib.pcp");
+        let program = session.parse(path, "fn double(x: int) -> int { x * 2 }").unwrap();
+        let program = session.lower(program).unwrap();
+        assert_eq!(program.functions.len(), 1);
+        assert!(session.codegen(&program).is_some());
+        assert!(session.diagnostics().is_empty());
+    }
+}
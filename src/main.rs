@@ -1,32 +1,333 @@
-use std::{cmp::min, fs::File, io::Read, iter};
+use std::{
+    cmp::min,
+    env, fs,
+    io::{self, IsTerminal, Write},
+    iter,
+    path::Path,
+    process,
+};
 
-use crate::{dlx::codegen::codegen, error::Logger, lexer::Lexer, parser::parse};
+use pchip::{
+    dlx::{self, assembler, codegen::MAX_RUNTIME_MEMORY, console::Console, sim},
+    module::STD_VERSION,
+    project::{Project, PROJECT_FILE},
+    Compiler, Instruction, Session,
+};
 
-mod ast;
-mod dlx;
-mod error;
-mod lexer;
-mod parser;
+const USAGE: &str = "\
+Usage: pchip [options] <file>               Compile a program
+       pchip run [options] <file>           Compile a program and run it in the simulator
+       pchip build [options] <file> <out>   Compile and assemble a program to a binary
+
+Options:
+    -o <path>               Write the output to <path> instead of stdout
+    --emit=<kind>           The kind of output to generate: asm (default), ir, ast, tokens, layout
+                            or bin
+    -O                      Remove redundant instructions from the generated code
+    --no-start              Don't include the program start code, e.g. for library code
+    --stack-size=<bytes>    The size of the stack (default: 800)
+    --heap-size=<bytes>     The size of the heap (default: 800)
+    --entry=<name>          The function called by the start code (default: main)
+    --start=<address>       The address or label the program starts at (default: prgsrt)
+    --exit-hook=<name>      A function called with the result of the entry function before halting
+    --project=<path>        Read the runtime settings from a project file (default: pchip.toml
+                            next to the input file, if it exists)
+    --error-format=<format> How to print errors: human (default) or json
+    -h, --help              Print this message
+    -V, --version           Print the version of the compiler";
+
+// Exit codes
+const EXIT_COMPILE_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO_ERROR: i32 = 3;
+const EXIT_PROGRAM_FAILED: i32 = 4;
+
+/// How diagnostics are written to stderr
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Command {
+    Compile,
+    Run,
+}
+
+/// The kind of output to generate
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Emit {
+    Tokens,
+    Ast,
+    Layout,
+    Ir,
+    Asm,
+    Bin,
+}
+
+struct Options {
+    command: Command,
+    input: String,
+    output: Option<String>,
+    emit: Emit,
+    compiler: Compiler,
+    error_format: ErrorFormat,
+}
+
+/// The result of compiling a program
+enum Output {
+    Text(String),
+    Code(Vec<Instruction>),
+}
 
 fn main() {
-    let args: Vec<_> = std::env::args().collect();
-    if args.len() != 2 {
-        println!("Invalid usage");
-        return;
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            eprintln!("For more information try `pchip --help`");
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let input = match fs::read_to_string(&options.input) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("error: could not read `{}`: {}", options.input, e);
+            process::exit(EXIT_IO_ERROR);
+        }
+    };
+
+    // Only use colour when writing diagnostics to a terminal, following https://no-color.org
+    let color = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let session = options.compiler.session().with_color(color);
+    let output = compile(&options, &input, &session);
+
+    let logger = session.logger();
+    match options.error_format {
+        ErrorFormat::Human if !logger.diagnostics().is_empty() => eprint!("{}", logger.render()),
+        ErrorFormat::Human => {}
+        ErrorFormat::Json => eprint!("{}", logger.render_json()),
+    }
+    let output = match output {
+        Some(output) => output,
+        None => process::exit(EXIT_COMPILE_ERROR),
+    };
+
+    match output {
+        Output::Code(code) if options.command == Command::Run => {
+            // Programs are always run with the start code
+            let entry = options.compiler.runtime.as_ref().map_or("main", |x| x.entry.as_str());
+            run(&code, entry)
+        }
+        Output::Code(code) if options.emit == Emit::Bin => {
+            // Binary output is never written to stdout
+            let path = match options.output {
+                Some(path) => path,
+                None => Path::new(&options.input).with_extension("bin").display().to_string(),
+            };
+            build(&code, &path)
+        }
+        Output::Code(code) => write_output(options.output.as_deref(), &asm_text(&code)),
+        Output::Text(text) => write_output(options.output.as_deref(), &text),
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        command: Command::Compile,
+        input: String::new(),
+        output: None,
+        emit: Emit::Asm,
+        compiler: Compiler::default(),
+        error_format: ErrorFormat::Human,
+    };
+    let mut positional = vec![];
+    let mut emit = None;
+    let mut no_start = false;
+    let mut project = None;
+    let (mut stack_size, mut heap_size) = (None, None);
+    let (mut entry, mut start, mut exit_hook) = (None, None, None);
+
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+            _ => (arg.as_str(), None),
+        };
+        let value = || value.ok_or_else(|| format!("`{}` requires a value", name));
+
+        match name {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "-V" | "--version" => {
+                println!("pchip {} (std {})", env!("CARGO_PKG_VERSION"), STD_VERSION);
+                process::exit(0);
+            }
+            "-o" => match args.next() {
+                Some(path) => options.output = Some(path),
+                None => return Err("`-o` requires a path".to_string()),
+            },
+            "--emit" => {
+                emit = Some(match value()? {
+                    "tokens" => Emit::Tokens,
+                    "ast" => Emit::Ast,
+                    "layout" => Emit::Layout,
+                    "ir" => Emit::Ir,
+                    "asm" => Emit::Asm,
+                    "bin" => Emit::Bin,
+                    invalid => return Err(format!("unknown output kind `{}`", invalid)),
+                })
+            }
+            "-O" => options.compiler.optimize = true,
+            "--no-start" => no_start = true,
+            "--stack-size" => stack_size = Some(parse_size(name, value()?)?),
+            "--heap-size" => heap_size = Some(parse_size(name, value()?)?),
+            "--entry" => entry = Some(value()?.to_string()),
+            "--start" => start = Some(value()?.to_string()),
+            "--exit-hook" => exit_hook = Some(value()?.to_string()),
+            "--project" => project = Some(value()?.to_string()),
+            "--error-format" => {
+                options.error_format = match value()? {
+                    "human" => ErrorFormat::Human,
+                    "json" => ErrorFormat::Json,
+                    invalid => return Err(format!("unknown error format `{}`", invalid)),
+                }
+            }
+            _ if name.starts_with('-') => return Err(format!("unknown option `{}`", name)),
+            _ => positional.push(arg),
+        }
+    }
+
+    // The first positional argument may be a subcommand
+    match positional.first().map(String::as_str) {
+        Some("run") => {
+            options.command = Command::Run;
+            positional.remove(0);
+        }
+        Some("build") if positional.len() == 3 => {
+            emit = emit.or(Some(Emit::Bin));
+            options.output = options.output.or(positional.pop());
+            positional.remove(0);
+        }
+        _ => {}
+    }
+
+    options.input = match positional.len() {
+        0 => return Err("no input file".to_string()),
+        1 => positional.remove(0),
+        _ => return Err(format!("unexpected argument `{}`", positional[1])),
+    };
+
+    if options.command == Command::Run {
+        if no_start {
+            return Err("`--no-start` cannot be used with `run`".to_string());
+        }
+        if emit.is_some() || options.output.is_some() {
+            return Err("`--emit` and `-o` cannot be used with `run`".to_string());
+        }
+    }
+    options.emit = emit.unwrap_or(Emit::Asm);
+
+    if no_start {
+        let runtime_options = [stack_size.is_some(), heap_size.is_some(), project.is_some()];
+        let runtime_names = [entry.is_some(), start.is_some(), exit_hook.is_some()];
+        if runtime_options.into_iter().chain(runtime_names).any(|x| x) {
+            return Err("runtime options cannot be used with `--no-start`".to_string());
+        }
+        options.compiler.runtime = None;
+        return Ok(options);
     }
-    let mut file = match File::open(args[1].clone()) {
-        Ok(f) => f,
-        Err(e) => panic!("Error opening file: {}", e),
+
+    // Settings given on the command line override the ones in the project file
+    let project = match project {
+        Some(path) => Some(load_project(Path::new(&path))?),
+        None => {
+            let path = Path::new(&options.input).with_file_name(PROJECT_FILE);
+            if path.is_file() {
+                Some(load_project(&path)?)
+            }
+            else {
+                None
+            }
+        }
     };
-    let mut input = String::new();
-    if let Err(e) = file.read_to_string(&mut input) {
-        panic!("Error reading file: {}", e);
+    let mut runtime = project.map(|x| x.runtime).unwrap_or_default();
+    runtime.stack_size = stack_size.unwrap_or(runtime.stack_size);
+    runtime.heap_size = heap_size.unwrap_or(runtime.heap_size);
+    runtime.entry = entry.unwrap_or(runtime.entry);
+    runtime.start = start.or(runtime.start);
+    runtime.exit_hook = exit_hook.or(runtime.exit_hook);
+    options.compiler.runtime = Some(runtime);
+
+    Ok(options)
+}
+
+fn parse_size(name: &str, value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(size) if size < MAX_RUNTIME_MEMORY => Ok(size),
+        Ok(_) => Err(format!(
+            "invalid value `{}` for `{}`, the stack and heap must take up less than {} bytes",
+            value, name, MAX_RUNTIME_MEMORY
+        )),
+        Err(_) => {
+            Err(format!("invalid value `{}` for `{}`, expected a number of bytes", value, name))
+        }
+    }
+}
+
+fn load_project(path: &Path) -> Result<Project, String> {
+    Project::load(path).map_err(|e| format!("could not read `{}`: {}", path.display(), e))
+}
+
+fn compile(options: &Options, input: &str, session: &Session) -> Option<Output> {
+    let path = Path::new(&options.input);
+    if options.emit == Emit::Tokens {
+        let mut tokens = String::new();
+        for token in session.tokens(path, input)? {
+            tokens.push_str(&format!("{:<8}{}\n", token.pos.to_string(), token.value));
+        }
+        return Some(Output::Text(tokens));
+    }
+
+    let mut program = session.parse(path, input)?;
+    if options.emit == Emit::Ast {
+        return Some(Output::Text(format!("{:#?}\n", program)));
+    }
+    if options.emit == Emit::Layout {
+        return Some(Output::Text(session.check(&mut program)?.layout_report()));
     }
+    let program = session.lower(program)?;
+    if options.emit == Emit::Ir {
+        return Some(Output::Text(program.to_string()));
+    }
+    session.codegen(&program).map(Output::Code)
+}
 
-    let logger = Logger::new(&input, true);
-    let program = parse(Lexer::new(&input), &logger);
-    let code = codegen(program, &logger, true);
+/// Write text to a file, or to stdout if no path is given
+fn write_output(path: Option<&str>, text: &str) {
+    match path {
+        Some(path) => {
+            if let Err(e) = fs::write(path, text) {
+                eprintln!("error: could not write `{}`: {}", path, e);
+                process::exit(EXIT_IO_ERROR);
+            }
+        }
+        None => {
+            // Stop quietly if stdout is closed early, e.g. when piped into `head`
+            if let Err(e) = io::stdout().write_all(text.as_bytes()) {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    eprintln!("error: could not write output: {}", e);
+                    process::exit(EXIT_IO_ERROR);
+                }
+            }
+        }
+    }
+}
 
+fn asm_text(code: &[Instruction]) -> String {
     let mut space = 0;
     let mut program_string = String::new();
     for inst in code {
@@ -36,7 +337,7 @@ fn main() {
                     program_string.push('\n');
                     space = 0;
                 }
-                program_string.push_str(&label);
+                program_string.push_str(label);
                 space += label.len();
             }
             dlx::asm::RawAsm(data) => {
@@ -44,12 +345,12 @@ fn main() {
                     program_string.push('\n');
                     space = 0;
                 }
-                program_string.push_str(&data);
+                program_string.push_str(data);
                 program_string.push('\n');
             }
             other => {
                 let data = other.to_string();
-                program_string.extend(iter::repeat(' ').take(8 - min(7, space)));
+                program_string.extend(iter::repeat_n(' ', 8 - min(7, space)));
                 program_string.push_str(&data);
                 program_string.push('\n');
                 space = 0;
@@ -57,5 +358,67 @@ fn main() {
         }
     }
 
-    println!("{}", program_string);
+    program_string.push('\n');
+    program_string
+}
+
+/// Assemble a program and write the image to `output`. The image is written in Intel HEX format if
+/// the output file has a `.hex` extension, otherwise it is written as a flat binary. The symbol
+/// table is written next to the image with a `.sym` extension.
+fn build(code: &[Instruction], output: &str) {
+    let image = match assembler::assemble(code) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("error: could not assemble program: {}", e);
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+    };
+
+    let output = Path::new(output);
+    let result = match output.extension() {
+        Some(ext) if ext == "hex" => fs::write(output, image.to_intel_hex()),
+        _ => fs::write(output, &image.bytes),
+    };
+    let result = result.and_then(|_| fs::write(output.with_extension("sym"), image.symbol_table()));
+    if let Err(e) = result {
+        eprintln!("error: could not write `{}`: {}", output.display(), e);
+        process::exit(EXIT_IO_ERROR);
+    }
+}
+
+/// Run a program in the simulator and report the final state of the machine
+fn run(code: &[Instruction], entry: &str) {
+    let mut machine = match sim::Machine::load(code) {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprintln!("error: could not load program: {}", e);
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+    };
+
+    machine.attach_console(Console::stdio());
+
+    let status = machine.run(sim::DEFAULT_STEP_LIMIT);
+    let mut report = format!("\nProgram {} after {} instructions\n", status, machine.steps);
+
+    report.push_str("Registers:\n");
+    for row in machine.regs.chunks(4).enumerate() {
+        let (row_index, values) = row;
+        for (i, value) in values.iter().enumerate() {
+            let reg = format!("r{}", row_index * 4 + i);
+            report.push_str(&format!("  {:>3} = 0x{:08x}", reg, value));
+        }
+        report.push('\n');
+    }
+
+    if status == sim::Status::Halted {
+        report.push_str(&format!("{} returned {}\n", entry, machine.regs[1] as i32));
+    }
+    write_output(None, &report);
+
+    match status {
+        // Running out of input is the normal way for a scripted interactive program to stop
+        sim::Status::Halted | sim::Status::EndOfInput => {}
+        _ => process::exit(EXIT_PROGRAM_FAILED),
+    }
 }
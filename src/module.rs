@@ -0,0 +1,155 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    ast,
+    error::{Diagnostic, InputSpan, Logger},
+    lexer::Lexer,
+    parser::parse,
+};
+
+/// The name of the module that the standard library is available as
+pub const STD_MODULE: &str = "std";
+
+/// The version of the standard library, which must match `VERSION` in `std/std.pcp`
+pub const STD_VERSION: u32 = 1;
+
+/// Functions of the standard library that can be called from any module by their name alone, unless
+/// the module or the root module defines something with the same name
+pub const PRELUDE: &[&str] = &["std::alloc::alloc", "std::alloc::free"];
+
+/// The function called by `new` expressions to allocate memory
+pub const ALLOC_FN: &str = "std::alloc::alloc";
+
+// The standard library is included in the compiler, and its files are given paths in a directory
+// that can't be confused with a real one
+const STD_DIR: &str = "<std>";
+const STD_FILES: &[(&str, &str)] = &[
+    ("std.pcp", include_str!("../std/std.pcp")),
+    ("str.pcp", include_str!("../std/str.pcp")),
+    ("mem.pcp", include_str!("../std/mem.pcp")),
+    ("fmt.pcp", include_str!("../std/fmt.pcp")),
+    ("io.pcp", include_str!("../std/io.pcp")),
+    ("alloc.pcp", include_str!("../std/alloc.pcp")),
+];
+
+/// Read and parse the files of the modules declared by a program, which is read from `path`. The
+/// items of each module are added to its declaration, so that the program forms a tree of modules.
+pub fn load_modules(program: &mut ast::Program, path: &Path, logger: &Logger) {
+    let mut loaded = HashSet::new();
+    loaded.insert(canonical_path(path));
+    load_items(&mut program.items, path, &mut loaded, logger);
+}
+
+/// Add the standard library to a program as the module `std`, unless the program already defines
+/// something with that name
+pub fn load_std(program: &mut ast::Program, logger: &Logger) {
+    let defines_std = program.items.iter().any(|item| match item {
+        ast::FunctionItem(x) => x.name == STD_MODULE,
+        ast::StructItem(x) => x.name == STD_MODULE,
+        ast::LetItem(x) => x.name == STD_MODULE,
+        ast::ModItem(x) => x.name == STD_MODULE,
+        ast::UseItem(x) => x.path.rsplit("::").next() == Some(STD_MODULE),
+        ast::ErrorItem(..) => false,
+    });
+    if defines_std {
+        return;
+    }
+
+    let module = ast::ModDeclaration {
+        name: STD_MODULE.to_string(),
+        items: vec![],
+        span: InputSpan::invalid(),
+    };
+    let mut items = [ast::ModItem(module)];
+    let path = Path::new(STD_DIR).join(STD_FILES[0].0);
+    load_items(&mut items, &path, &mut HashSet::new(), logger);
+    let [std] = items;
+    program.items.push(std);
+}
+
+/// Load the modules declared in a list of items. Modules are read from `<name>.pcp` in the same
+/// directory as the file that declares them.
+fn load_items(
+    items: &mut [ast::Item],
+    path: &Path,
+    loaded: &mut HashSet<PathBuf>,
+    logger: &Logger,
+) {
+    for item in items {
+        let module = match item {
+            ast::ModItem(module) => module,
+            _ => continue,
+        };
+        let module_path = path.with_file_name(format!("{}.pcp", module.name));
+        let file_name = module_path.display().to_string();
+
+        // Each file can only be loaded once, which also stops a module from including itself
+        if !loaded.insert(canonical_path(&module_path)) {
+            let diagnostic =
+                Diagnostic::error(format!("file `{}` is already a module", file_name), module.span)
+                    .code("E0307")
+                    .note("each file can only be declared as a module once".to_string())
+                    .help("use `use` to refer to the items of the existing module".to_string());
+            logger.report(diagnostic);
+            continue;
+        }
+
+        let input = match read_module(&module_path) {
+            Ok(input) => input,
+            Err(e) => {
+                let diagnostic =
+                    Diagnostic::error(format!("cannot find module `{}`", module.name), module.span)
+                        .code("E0306")
+                        .note(format!("could not read `{}`: {}", file_name, e));
+                logger.report(diagnostic);
+                continue;
+            }
+        };
+
+        let file = logger.add_file(&file_name, &input);
+        let mut program = parse(Lexer::new(&input, file, logger), logger);
+        load_items(&mut program.items, &module_path, loaded, logger);
+        module.items = program.items;
+    }
+}
+
+/// Read the source of a module, which may be part of the standard library
+fn read_module(path: &Path) -> io::Result<String> {
+    let name = match path.strip_prefix(STD_DIR) {
+        Ok(name) => name,
+        Err(_) => return fs::read_to_string(path),
+    };
+    match STD_FILES.iter().find(|(file, _)| Path::new(file) == name) {
+        Some((_, source)) => Ok(source.to_string()),
+        None => Err(io::ErrorKind::NotFound.into()),
+    }
+}
+
+/// Get a path that is the same for every way of referring to a file, if the file exists
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn std_version_matches_the_library() {
+        let logger = Logger::new(true);
+        let (name, source) = STD_FILES[0];
+        let program = parse(Lexer::new(source, logger.add_file(name, source), &logger), &logger);
+        let version = program.items.iter().find_map(|item| match item {
+            ast::LetItem(x) if x.name == "VERSION" => x.assignment.as_ref(),
+            _ => None,
+        });
+        match version.map(|x| &*x.rhs.expr) {
+            Some(ast::LitNumExpr(version)) => assert_eq!(*version as u32, STD_VERSION),
+            _ => panic!("`std::VERSION` is not a number"),
+        }
+    }
+}
@@ -1,24 +1,31 @@
 use crate::{
     ast,
-    error::{InputPos, InputSpan, Logger},
+    error::{self, Diagnostic, FatalError, FileId, InputPos, InputSpan, Logger},
     lexer::{self, Lexer},
 };
 
-pub fn parse<'a>(lexer: Lexer, logger: &'a Logger<'a>) -> ast::Program {
+/// The attributes that can be written before a function
+const FUNCTION_ATTRIBUTES: &[&str] = &["stack_args"];
+
+/// The attributes that can be written before a struct
+const STRUCT_ATTRIBUTES: &[&str] = &["packed"];
+
+pub fn parse(lexer: Lexer, logger: &Logger) -> ast::Program {
+    let file = lexer.file();
     let mut tokens: Vec<lexer::Token> = lexer.collect();
-    let end_token = lexer::Token {
-        value: lexer::Eof,
-        pos: tokens.last().map(|t| t.pos).unwrap_or_else(InputPos::start),
-    };
-    tokens.push(end_token);
-    let mut parser = Parser { tokens, logger, index: 0, fake_semicolon: false };
+    let end_pos = tokens.last().map(|t| t.end).unwrap_or_else(InputPos::start);
+    tokens.push(lexer::Token { value: lexer::Eof, pos: end_pos, end: end_pos });
+    let mut parser = Parser { tokens, file, logger, index: 0, last: 0, fake_semicolon: false };
     parser.parse()
 }
 
 struct Parser<'a> {
     tokens: Vec<lexer::Token>,
-    logger: &'a Logger<'a>,
+    file: FileId,
+    logger: &'a Logger,
     index: usize,
+    /// The index of the last token that was consumed
+    last: usize,
     fake_semicolon: bool,
 }
 
@@ -28,52 +35,177 @@ impl<'a> Parser<'a> {
     }
 
     fn next_token(&mut self) -> lexer::TokenValue {
-        self.index += 1;
-        self.tokens[self.index - 1].value.clone()
+        let token = self.peek();
+        self.bump();
+        token
     }
 
     fn bump(&mut self) {
-        self.index += 1;
+        self.last = self.index;
+        // The end of file token is never consumed so that parsing can continue after an error
+        if self.index + 1 < self.tokens.len() {
+            self.index += 1;
+        }
     }
 
     fn expect(&mut self, token: lexer::TokenValue) {
-        let span_start = self.current_pos();
-        let next = self.next_token();
+        let next = self.peek();
         if next != token {
-            self.logger.report_error(
-                format!("expected `{:?}` but found `{:?}`", token, next),
-                InputSpan::new(span_start, self.current_pos()),
-            );
-            self.fatal_error();
+            // The unexpected token is left in place so that it can be used to recover
+            self.unexpected(&token.to_string(), next, self.peek_span());
+        }
+        self.bump();
+    }
+
+    /// Report that an unexpected token was found
+    fn unexpected(&self, expected: &str, found: lexer::TokenValue, span: InputSpan) -> ! {
+        let diagnostic = Diagnostic::error(format!("expected {}, found {}", expected, found), span)
+            .code("E0201")
+            .label(format!("expected {}", expected));
+        self.logger.report(diagnostic);
+        self.fatal_error();
+    }
+
+    /// Check that an operator or an opening bracket is followed by an operand, since a `;` would
+    /// otherwise be parsed as an empty expression
+    fn expect_operand(&self) {
+        if self.peek() == lexer::SemiColon {
+            self.unexpected("an expression", lexer::SemiColon, self.peek_span());
         }
     }
 
     fn fatal_error(&self) -> ! {
-        panic!("");
+        FatalError::raise();
     }
 
     fn current_pos(&self) -> InputPos {
         self.tokens[self.index].pos
     }
 
+    /// The span from `start` to the end of the last token that was consumed
+    fn span_from(&self, start: InputPos) -> InputSpan {
+        let end = self.tokens[self.last].end;
+        match (end.line, end.col) < (start.line, start.col) {
+            // Nothing has been consumed since `start`
+            true => InputSpan::new(self.file, start, start),
+            false => InputSpan::new(self.file, start, end),
+        }
+    }
+
+    /// The span of the next token
+    fn peek_span(&self) -> InputSpan {
+        let token = &self.tokens[self.index];
+        InputSpan::new(self.file, token.pos, token.end)
+    }
+
+    /// The span of the last token that was consumed
+    fn last_span(&self) -> InputSpan {
+        let token = &self.tokens[self.last];
+        InputSpan::new(self.file, token.pos, token.end)
+    }
+
     fn parse(&mut self) -> ast::Program {
         let mut items = vec![];
         let span_start = self.current_pos();
         let mut span_end = self.current_pos();
 
-        while let Some(item) = self.try_parse_item() {
+        loop {
+            let item_start = self.current_pos();
+            let item = match error::catch_fatal(|| self.try_parse_item()) {
+                Some(Some(item)) => item,
+                Some(None) => break,
+                None => {
+                    self.recover_item();
+                    ast::ErrorItem(self.span_from(item_start))
+                }
+            };
             span_end = item.span().end;
             items.push(item);
         }
 
-        ast::Program { items, span: InputSpan::new(span_start, span_end) }
+        ast::Program { items, span: InputSpan::new(self.file, span_start, span_end) }
+    }
+
+    /// Skip tokens after a syntax error until the start of the next item. Blocks are skipped
+    /// entirely so that `let` statements inside a function body are not mistaken for items.
+    fn recover_item(&mut self) {
+        self.fake_semicolon = false;
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                lexer::Fn
+                | lexer::Struct
+                | lexer::Let
+                | lexer::Const
+                | lexer::Mod
+                | lexer::Use
+                | lexer::HashBracket
+                    if depth == 0 =>
+                {
+                    break
+                }
+                lexer::Eof => break,
+                lexer::LeftBrace => depth += 1,
+                lexer::RightBrace => depth = std::cmp::max(depth - 1, 0),
+                _ => {}
+            }
+            self.bump();
+        }
+    }
+
+    /// Skip tokens after a syntax error until the end of the current statement. Returns false if
+    /// the end of the enclosing block could not be found, in which case the error should be
+    /// handled by the enclosing item.
+    fn recover_statement(&mut self) -> bool {
+        self.fake_semicolon = false;
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                // A new item, or the end of the file, means the block was never closed
+                lexer::Fn
+                | lexer::Struct
+                | lexer::Mod
+                | lexer::Use
+                | lexer::HashBracket
+                | lexer::Eof => return false,
+
+                lexer::SemiColon if depth == 0 => {
+                    self.bump();
+                    return true;
+                }
+                lexer::Let | lexer::Const | lexer::RightBrace if depth == 0 => return true,
+
+                lexer::LeftBrace => depth += 1,
+                lexer::RightBrace => {
+                    depth -= 1;
+                    // A statement ending in a block (e.g. an `if` statement) does not need a
+                    // semicolon
+                    if depth == 0 {
+                        self.bump();
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+            self.bump();
+        }
     }
 
     fn try_parse_item(&mut self) -> Option<ast::Item> {
-        let span_start = self.current_pos();
+        let attributes = self.parse_attributes();
         let item = match self.next_token() {
-            lexer::Fn => ast::FunctionItem(self.parse_function()),
-            lexer::Struct => ast::StructItem(self.parse_struct_decl()),
+            lexer::Fn => {
+                self.check_attributes(&attributes, "functions", FUNCTION_ATTRIBUTES);
+                let mut function = self.parse_function();
+                function.stack_args = attributes.iter().any(|(name, _)| name == "stack_args");
+                return Some(ast::FunctionItem(function));
+            }
+            lexer::Struct => {
+                self.check_attributes(&attributes, "structs", STRUCT_ATTRIBUTES);
+                let mut struct_decl = self.parse_struct_decl();
+                struct_decl.packed = attributes.iter().any(|(name, _)| name == "packed");
+                return Some(ast::StructItem(struct_decl));
+            }
             lexer::Let => {
                 let item = ast::LetItem(self.parse_let(false));
                 self.expect(lexer::SemiColon);
@@ -84,21 +216,74 @@ impl<'a> Parser<'a> {
                 self.expect(lexer::SemiColon);
                 item
             }
+            lexer::Mod => ast::ModItem(self.parse_mod()),
+            lexer::Use => ast::UseItem(self.parse_use()),
 
-            lexer::Eof => return None,
+            lexer::Eof if attributes.is_empty() => return None,
 
             invalid => {
-                self.logger.report_error(
-                    format!("expected `<Item>` but found, `{:?}`", invalid),
-                    InputSpan::new(span_start, self.current_pos()),
-                );
-                self.fatal_error();
+                self.unexpected("an item", invalid, self.last_span());
             }
         };
 
+        // Only functions and structs can have attributes
+        self.check_attributes(&attributes, "this item", &[]);
         Some(item)
     }
 
+    /// Parse the attributes before an item, e.g. `#[stack_args]`
+    fn parse_attributes(&mut self) -> Vec<(String, InputSpan)> {
+        let mut attributes = vec![];
+        while self.peek() == lexer::HashBracket {
+            self.bump();
+            let span = self.peek_span();
+            let name = self.parse_name();
+            self.expect(lexer::RightBracket);
+            attributes.push((name, span));
+        }
+        attributes
+    }
+
+    /// Report any attributes that can't be used on an item
+    fn check_attributes(&self, attributes: &[(String, InputSpan)], item: &str, allowed: &[&str]) {
+        for (name, span) in attributes {
+            if allowed.contains(&name.as_str()) {
+                continue;
+            }
+            let allowed: Vec<String> = allowed.iter().map(|x| format!("`{}`", x)).collect();
+            let note = if allowed.is_empty() {
+                format!("{} cannot have attributes", item)
+            }
+            else {
+                format!("the attributes allowed on {} are: {}", item, allowed.join(", "))
+            };
+            let mut known = FUNCTION_ATTRIBUTES.iter().chain(STRUCT_ATTRIBUTES);
+            let message = if known.any(|x| x == name) {
+                format!("attribute `{}` cannot be used on {}", name, item)
+            }
+            else {
+                format!("unknown attribute `{}`", name)
+            };
+            let diagnostic = Diagnostic::error(message, *span).code("E0205").note(note);
+            self.logger.report(diagnostic);
+        }
+    }
+
+    fn parse_mod(&mut self) -> ast::ModDeclaration {
+        let span_start = self.current_pos();
+        let name = self.parse_name();
+        self.expect(lexer::SemiColon);
+        ast::ModDeclaration { name, items: vec![], span: self.span_from(span_start) }
+    }
+
+    fn parse_use(&mut self) -> ast::UseDeclaration {
+        let span_start = self.current_pos();
+        let name = self.parse_name();
+        let path = self.parse_path(name);
+        self.expect(lexer::SemiColon);
+        ast::UseDeclaration { path, span: self.span_from(span_start) }
+    }
+
     fn parse_function(&mut self) -> ast::FunctionDeclaration {
         let span_start = self.current_pos();
 
@@ -131,7 +316,6 @@ impl<'a> Parser<'a> {
             }
         }
 
-        let type_span_start = self.current_pos();
         // Read function return type
         let rtype = match self.peek() {
             lexer::LeftBrace => ast::Primitive(ast::UnitType),
@@ -142,25 +326,15 @@ impl<'a> Parser<'a> {
             }
 
             invalid => {
-                self.logger.report_error(
-                    format!("expected `{{` or `->` but found `{:?}`", invalid),
-                    InputSpan::new(type_span_start, self.current_pos()),
-                );
-                self.fatal_error();
+                self.unexpected("`{` or `->`", invalid, self.peek_span());
             }
         };
 
         // Read function body
         let body = self.parse_block();
 
-        let span_end = self.current_pos();
-        ast::FunctionDeclaration {
-            name,
-            params,
-            rtype,
-            body,
-            span: InputSpan::new(span_start, span_end),
-        }
+        let span = self.span_from(span_start);
+        ast::FunctionDeclaration { name, params, rtype, body, stack_args: false, span }
     }
 
     fn parse_struct_decl(&mut self) -> ast::StructDeclaration {
@@ -175,10 +349,11 @@ impl<'a> Parser<'a> {
             }
 
             // Read the field
+            let field_start = self.current_pos();
             let field_name = self.parse_name();
             self.expect(lexer::Colon);
             let field_type = self.parse_type();
-            fields.push((field_name, field_type));
+            fields.push((field_name, field_type, self.span_from(field_start)));
 
             // Check if there might be another field
             if self.peek() != lexer::Comma {
@@ -189,11 +364,7 @@ impl<'a> Parser<'a> {
 
         self.expect(lexer::RightBrace);
 
-        ast::StructDeclaration {
-            name,
-            fields,
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        ast::StructDeclaration { name, fields, packed: false, span: self.span_from(span_start) }
     }
 
     fn parse_let(&mut self, is_const: bool) -> ast::LetStatement {
@@ -203,75 +374,66 @@ impl<'a> Parser<'a> {
         let opt_assignment = match self.peek() {
             lexer::Assignment => {
                 self.bump();
+                self.expect_operand();
 
-                let target_span = InputSpan::new(span_start, self.current_pos());
+                let target_span = self.span_from(span_start);
                 let rhs = self.parse_expression();
 
-                let target = ast::Expression {
-                    expr: Box::new(ast::VariableExpr(name.clone())),
-                    // Note: We should check that this type matches the specified type
-                    rtype: rhs.rtype.clone(),
-                    span: target_span,
-                };
+                let target = ast::Expression::new(ast::VariableExpr(name.clone()), target_span);
 
-                Some(ast::Assignment {
-                    target,
-                    rhs,
-                    span: InputSpan::new(span_start, self.current_pos()),
-                })
+                Some(ast::Assignment { target, rhs, span: self.span_from(span_start) })
             }
             lexer::SemiColon => None,
             invalid => {
-                self.logger.report_error(
-                    format!("expected `=` or `;` but found `{:?}`", invalid),
-                    InputSpan::new(span_start, self.current_pos()),
-                );
-                self.fatal_error();
+                self.unexpected("`=` or `;`", invalid, self.peek_span());
             }
         };
 
         // If the type wasn't specified for this variable then there needs an assignment
-        let type_ = match (&opt_type, &opt_assignment) {
-            (&Some(ref t), _) => t.clone(),
-            (&None, &Some(ref assignment)) => assignment.rhs.rtype.clone(),
-            (&None, &None) => {
-                self.logger.report_error(
-                    format!("could not determine type for variable `{:?}`", name),
-                    InputSpan::new(span_start, self.current_pos()),
-                );
-                self.fatal_error();
-            }
-        };
+        if opt_type.is_none() && opt_assignment.is_none() {
+            let span = self.span_from(span_start);
+            let diagnostic =
+                Diagnostic::error(format!("cannot determine the type of `{}`", name), span)
+                    .code("E0202")
+                    .help(format!("add a type annotation, e.g. `let {}: int;`", name));
+            self.logger.report(diagnostic);
+            self.fatal_error();
+        }
 
         ast::LetStatement {
             name,
-            var_type: type_,
+            var_type: opt_type,
             assignment: opt_assignment,
             is_const,
-            span: InputSpan::new(span_start, self.current_pos()),
+            span: self.span_from(span_start),
         }
     }
 
     fn parse_name(&mut self) -> String {
-        let span_start = self.current_pos();
         match self.next_token() {
             lexer::Ident(name) => name,
 
             invalid => {
-                self.logger.report_error(
-                    format!("expected `<identifer>` but found `{:?}`", invalid),
-                    InputSpan::new(span_start, self.current_pos()),
-                );
-                self.fatal_error();
+                self.unexpected("an identifier", invalid, self.last_span());
             }
         }
     }
 
+    /// Parse the rest of a path starting with `first`, e.g. `geo::area`
+    fn parse_path(&mut self, first: String) -> String {
+        let mut path = first;
+        while self.peek() == lexer::PathSep {
+            self.bump();
+            path.push_str("::");
+            path.push_str(&self.parse_name());
+        }
+        path
+    }
+
     fn parse_type(&mut self) -> ast::Type {
-        let span_start = self.current_pos();
         match self.next_token() {
             // User defined types
-            lexer::Ident(name) => ast::UserType(name),
+            lexer::Ident(name) => ast::UserType(self.parse_path(name)),
 
             // Primitive types
             lexer::Int => ast::Primitive(ast::IntType),
@@ -291,11 +453,7 @@ impl<'a> Parser<'a> {
                 let size = match self.next_token() {
                     lexer::LitNum(n) => n,
                     invalid => {
-                        self.logger.report_error(
-                            format!("expected `<integer>` but found `{:?}`", invalid),
-                            InputSpan::new(span_start, self.current_pos()),
-                        );
-                        self.fatal_error();
+                        self.unexpected("an integer", invalid, self.last_span());
                     }
                 };
                 self.expect(lexer::RightBracket);
@@ -303,11 +461,7 @@ impl<'a> Parser<'a> {
             }
 
             invalid => {
-                self.logger.report_error(
-                    format!("expected `<Type>` but found `{:?}`", invalid),
-                    InputSpan::new(span_start, self.current_pos()),
-                );
-                self.fatal_error();
+                self.unexpected("a type", invalid, self.last_span());
             }
         }
     }
@@ -317,11 +471,11 @@ impl<'a> Parser<'a> {
         match self.parse_var_with_type() {
             (name, Some(t)) => (name, t),
             (name, None) => {
-                let span_end = self.current_pos();
-                self.logger.report_error(
-                    format!("expected `<Type>` for parameter `{:?}`", name),
-                    InputSpan::new(span_start, span_end),
-                );
+                let span = self.span_from(span_start);
+                let diagnostic = Diagnostic::error(format!("missing type for `{}`", name), span)
+                    .code("E0203")
+                    .help(format!("function parameters must have a type, e.g. `{}: int`", name));
+                self.logger.report(diagnostic);
                 self.fatal_error();
             }
         }
@@ -350,109 +504,216 @@ impl<'a> Parser<'a> {
                 break;
             }
             else {
-                let expression = self.parse_expression();
-                statements.push(expression);
-
-                if self.peek() != lexer::RightBrace && !self.fake_semicolon {
-                    self.expect(lexer::SemiColon);
+                let statement_start = self.current_pos();
+                let statement = error::catch_fatal(|| {
+                    let expression = self.parse_expression();
+                    if self.peek() != lexer::RightBrace && !self.fake_semicolon {
+                        self.expect(lexer::SemiColon);
+                    }
+                    self.fake_semicolon = false;
+                    expression
+                });
+
+                match statement {
+                    Some(expression) => statements.push(expression),
+                    None => {
+                        let recovered = self.recover_statement();
+                        let span = self.span_from(statement_start);
+                        statements.push(ast::Expression::new(ast::ErrorExpr, span));
+                        if !recovered {
+                            self.fatal_error();
+                        }
+                    }
                 }
-                self.fake_semicolon = false;
             }
         }
 
-        ast::Block { statements, span: InputSpan::new(span_start, self.current_pos()) }
+        ast::Block { statements, span: self.span_from(span_start) }
     }
 
     /// Parse an expression defined by the following grammar:
-    ///     Expression = *<Expression> | &<Expression> | -<Expression> |
-    ///                   <Variable> | <Call> | <LetStatement> | <IfStatement> | <WhileStatement>
-    ///                   <ForStatement> | <LoopStatement> | <AsmStatement> |
-    ///                   true | false | Number | break | return <Expression>
-    /// FIXME: Unfortunately this function does not work well with order of operations. At the
-    /// moment there are special hacks to ensure that assignment and dereferencing work well
-    /// together.
+    ///     Expression = <BinaryExpr> | <BinaryExpr> = <Expression> |
+    ///                  <BinaryExpr> += <Expression> | <BinaryExpr> -= <Expression>
+    ///     BinaryExpr = <CastExpr> | <BinaryExpr> <BinaryOp> <BinaryExpr>
+    ///     CastExpr   = <UnaryExpr> | <CastExpr> as <Type>
+    ///     UnaryExpr  = &<UnaryExpr> | -<UnaryExpr> | !<UnaryExpr> | *<Primary><Postfix>* |
+    ///                  <Primary><Postfix>*
+    ///     Postfix    = [<Expression>] | .<Ident>
+    ///     Primary    = <Variable> | <Call> | <LetStatement> | <IfStatement> | <WhileStatement>
+    ///                  <ForStatement> | <LoopStatement> | <AsmStatement> | (<Expression>) |
+    ///                  true | false | Number | break | return <Expression> | new <Type>
+    /// Binary operators are parsed using precedence climbing, see `ast::BinaryOp::precedence` for
+    /// the binding power of each of the operators.
     /// Plans:
-    ///  - Split the parsing up according to order of operations
-    ///  - Add infix operator support
     ///  - Allow block expressions
     fn parse_expression(&mut self) -> ast::Expression {
         let span_start = self.current_pos();
-        let expression = match self.next_token() {
-            lexer::Amp => {
-                let target = self.parse_expression();
-                let rtype = ast::Pointer(Box::new(target.rtype.clone()));
+        let expression = self.parse_binary(1);
+        self.parse_assignment(expression, span_start)
+    }
 
-                ast::Expression {
-                    expr: Box::new(ast::RefExpr(target)),
-                    rtype,
-                    span: InputSpan::new(span_start, self.current_pos()),
-                }
+    fn parse_assignment(
+        &mut self,
+        target: ast::Expression,
+        span_start: InputPos,
+    ) -> ast::Expression {
+        let op = match self.peek() {
+            _ if self.fake_semicolon => return target,
+            lexer::Assignment => None,
+            lexer::PlusEq => Some(ast::BinaryOp::Add),
+            lexer::MinusEq => Some(ast::BinaryOp::Sub),
+            _ => return target,
+        };
+        self.bump();
+
+        self.expect_operand();
+        let mut rhs = self.parse_expression();
+
+        // Compound assignments are desugared into an ordinary assignment, so `a += b` becomes
+        // `a = a + b`.
+        if let Some(op) = op {
+            let binary_expr = ast::BinaryExpression {
+                op,
+                lhs: target.clone(),
+                rhs,
+                span: self.span_from(span_start),
+            };
+            rhs = ast::Expression::new(ast::BinaryExpr(binary_expr), self.span_from(span_start));
+        }
+
+        let assignment = ast::Assignment { target, rhs, span: self.span_from(span_start) };
+        ast::Expression::new(ast::AssignExpr(assignment), self.span_from(span_start))
+    }
+
+    /// Parse a sequence of binary operators with a precedence greater than or equal to
+    /// `min_precedence`.
+    fn parse_binary(&mut self, min_precedence: u8) -> ast::Expression {
+        let span_start = self.current_pos();
+        let mut lhs = self.parse_cast();
+
+        loop {
+            // Statements such as `if` and `loop` are not allowed to be the left hand side of an
+            // operator without being wrapped in brackets.
+            if self.fake_semicolon {
+                break;
+            }
+
+            let op = match binary_op(&self.peek()) {
+                Some(op) if op.precedence() >= min_precedence => op,
+                _ => break,
+            };
+            self.bump();
+
+            // All binary operators are left associative
+            self.expect_operand();
+            let rhs = self.parse_binary(op.precedence() + 1);
+
+            let binary_expr =
+                ast::BinaryExpression { op, lhs, rhs, span: self.span_from(span_start) };
+            lhs = ast::Expression::new(ast::BinaryExpr(binary_expr), self.span_from(span_start));
+        }
+
+        lhs
+    }
+
+    fn parse_cast(&mut self) -> ast::Expression {
+        let span_start = self.current_pos();
+        let mut expression = self.parse_unary();
+
+        while self.peek() == lexer::As && !self.fake_semicolon {
+            self.bump();
+            let target_type = self.parse_type();
+            let span = self.span_from(span_start);
+            expression = ast::Expression::new(ast::CastExpr(expression, target_type), span);
+        }
+
+        expression
+    }
+
+    fn parse_unary(&mut self) -> ast::Expression {
+        let span_start = self.current_pos();
+        match self.peek() {
+            lexer::Amp => {
+                self.bump();
+                self.expect_operand();
+                let target = self.parse_unary();
+                ast::Expression::new(ast::RefExpr(target), self.span_from(span_start))
             }
             lexer::Star => {
-                // FIXME: You should be able to dereference an arbitrary expression not just an
-                // identifier.
-                let deref_target = match self.next_token() {
-                    lexer::Ident(name) => self.handle_ident(name, span_start),
-                    invalid => {
-                        self.logger.report_error(
-                            format!("expected `<Ident>` but found `{:?}`", invalid),
-                            InputSpan::new(span_start, self.current_pos()),
-                        );
-                        println!("ICE: FIXME, allow arbitrary expression dereferencing");
-                        self.fatal_error();
+                self.bump();
+                // Note: A dereference binds more tightly than field and array accesses, so
+                // `*a.b` is `(*a).b`
+                self.expect_operand();
+                let deref_target = self.parse_primary();
+                let expression =
+                    ast::Expression::new(ast::DerefExpr(deref_target), self.span_from(span_start));
+                self.parse_postfix(expression)
+            }
+            lexer::Minus => {
+                self.bump();
+                match self.peek() {
+                    // Negative literals are resolved immediately
+                    lexer::LitNum(value) => {
+                        self.bump();
+                        let expression = self.handle_num(-value, span_start);
+                        self.parse_postfix(expression)
                     }
-                };
-                let rtype = ast::DerefType(Box::new(deref_target.rtype.clone()));
-                ast::Expression {
-                    expr: Box::new(ast::DerefExpr(deref_target)),
-                    rtype,
-                    span: InputSpan::new(span_start, self.current_pos()),
+                    _ => self.parse_unary_op(ast::UnaryOp::Neg, span_start),
                 }
             }
-            lexer::Ident(name) => self.handle_ident(name, span_start),
+            lexer::Not => {
+                self.bump();
+                self.parse_unary_op(ast::UnaryOp::Not, span_start)
+            }
+            _ => {
+                let expression = self.parse_primary();
+                self.parse_postfix(expression)
+            }
+        }
+    }
+
+    fn parse_unary_op(&mut self, op: ast::UnaryOp, span_start: InputPos) -> ast::Expression {
+        self.expect_operand();
+        let operand = self.parse_unary();
+        let unary_expr = ast::UnaryExpression { op, operand, span: self.span_from(span_start) };
+        ast::Expression::new(ast::UnaryExpr(unary_expr), self.span_from(span_start))
+    }
+
+    fn parse_primary(&mut self) -> ast::Expression {
+        let span_start = self.current_pos();
+        match self.next_token() {
+            lexer::Ident(name) => {
+                let path = self.parse_path(name);
+                self.handle_ident(path, span_start)
+            }
             lexer::LitNum(value) => self.handle_num(value, span_start),
-            lexer::LitChar(value) => ast::Expression {
-                expr: Box::new(ast::LitCharExpr(value)),
-                rtype: ast::Primitive(ast::CharType),
-                span: InputSpan::new(span_start, self.current_pos()),
-            },
+            lexer::LitChar(value) => {
+                ast::Expression::new(ast::LitCharExpr(value), self.span_from(span_start))
+            }
             lexer::LitString(value) => {
-                let escape_hack = value.chars().filter(|&x| x == '\\').count() as i32;
-                let len = value.len() as i32 - escape_hack;
-                ast::Expression {
-                    expr: Box::new(ast::LitStringExpr(value)),
-                    rtype: ast::StaticArrayType(Box::new(ast::Primitive(ast::CharType)), len),
-                    span: InputSpan::new(span_start, self.current_pos()),
-                }
+                ast::Expression::new(ast::LitStringExpr(value), self.span_from(span_start))
             }
-            lexer::True => ast::Expression {
-                expr: Box::new(ast::LitNumExpr(1)),
-                rtype: ast::Primitive(ast::BoolType),
-                span: InputSpan::new(span_start, self.current_pos()),
-            },
-            lexer::False => ast::Expression {
-                expr: Box::new(ast::LitNumExpr(0)),
-                rtype: ast::Primitive(ast::BoolType),
-                span: InputSpan::new(span_start, self.current_pos()),
-            },
-            lexer::Null => ast::Expression {
-                expr: Box::new(ast::LitNumExpr(0)),
-                rtype: ast::Pointer(Box::new(ast::Primitive(ast::AnyType))),
-                span: InputSpan::new(span_start, self.current_pos()),
-            },
+            lexer::True => ast::Expression::new(ast::LitBoolExpr(true), self.span_from(span_start)),
+            lexer::False => {
+                ast::Expression::new(ast::LitBoolExpr(false), self.span_from(span_start))
+            }
+            lexer::Null => ast::Expression::new(ast::NullExpr, self.span_from(span_start)),
             lexer::LeftBracket => self.parse_static_array(span_start),
-            lexer::Minus => match self.peek() {
-                lexer::LitNum(value) => {
-                    self.bump();
-                    self.handle_num(-value, span_start)
-                }
-                _ => panic!("ICE: Cannot negate expression"),
-            },
-            lexer::Let => ast::Expression {
-                expr: Box::new(ast::LetExpr(self.parse_let(false))),
-                rtype: ast::Primitive(ast::UnitType),
-                span: InputSpan::new(span_start, self.current_pos()),
-            },
+            lexer::LeftParen => {
+                self.expect_operand();
+                let mut inner = self.parse_expression();
+                self.expect(lexer::RightParen);
+                inner.span = self.span_from(span_start);
+                inner
+            }
+            lexer::Let => {
+                let let_statement = self.parse_let(false);
+                ast::Expression::new(ast::LetExpr(let_statement), self.span_from(span_start))
+            }
+            lexer::Const => {
+                let let_statement = self.parse_let(true);
+                ast::Expression::new(ast::LetExpr(let_statement), self.span_from(span_start))
+            }
             lexer::If => self.parse_if(span_start),
             lexer::For => self.parse_for(span_start),
             lexer::While => self.parse_while(span_start),
@@ -460,68 +721,37 @@ impl<'a> Parser<'a> {
             lexer::Break => self.parse_break(span_start),
             lexer::Return => self.parse_return(span_start),
             lexer::Asm => self.parse_asm(span_start),
-            lexer::SemiColon => ast::Expression {
-                expr: Box::new(ast::EmptyExpr),
-                rtype: ast::Primitive(ast::UnitType),
-                span: InputSpan::new(span_start, self.current_pos()),
-            },
+            lexer::New => {
+                let allocated_type = self.parse_type();
+                ast::Expression::new(ast::NewExpr(allocated_type), self.span_from(span_start))
+            }
+            lexer::SemiColon => ast::Expression::new(ast::EmptyExpr, self.span_from(span_start)),
             invalid => {
-                self.logger.report_error(
-                    format!("expected `<Expression>` but found `{:?}`", invalid),
-                    InputSpan::new(span_start, self.current_pos()),
-                );
-                self.fatal_error();
+                self.unexpected("an expression", invalid, self.last_span());
             }
-        };
-
-        self.parse_expression_end(expression)
+        }
     }
 
-    fn parse_expression_end(&mut self, expression: ast::Expression) -> ast::Expression {
-        let span_start = self.current_pos();
-        match self.peek() {
-            lexer::As => {
-                self.bump();
-                ast::Expression {
-                    expr: Box::new(ast::CastExpr(expression)),
-                    rtype: self.parse_type(),
-                    span: InputSpan::new(span_start, self.current_pos()),
-                }
-            }
-
-            lexer::Assignment => {
-                self.bump();
-                let rhs = self.parse_expression();
-                let assignment = ast::Assignment {
-                    target: expression,
-                    rhs,
-                    span: InputSpan::new(span_start, self.current_pos()),
-                };
-                ast::Expression {
-                    expr: Box::new(ast::AssignExpr(assignment)),
-                    rtype: ast::Primitive(ast::UnitType),
-                    span: InputSpan::new(span_start, self.current_pos()),
-                }
-            }
+    fn parse_postfix(&mut self, expression: ast::Expression) -> ast::Expression {
+        let span_start = expression.span.start;
+        if self.fake_semicolon {
+            return expression;
+        }
 
+        match self.peek() {
             lexer::LeftBracket => {
                 self.bump();
 
                 let index = self.parse_expression();
                 self.expect(lexer::RightBracket);
 
-                let rtype = ast::DerefType(Box::new(expression.rtype.clone()));
-                let index_expr = ast::ArrayIndex {
-                    target: expression,
-                    index,
-                    span: InputSpan::new(span_start, self.current_pos()),
-                };
-                let new_expression = ast::Expression {
-                    expr: Box::new(ast::ArrayIndexExpr(index_expr)),
-                    rtype,
-                    span: InputSpan::new(span_start, self.current_pos()),
-                };
-                self.parse_expression_end(new_expression)
+                let index_expr =
+                    ast::ArrayIndex { target: expression, index, span: self.span_from(span_start) };
+                let new_expression = ast::Expression::new(
+                    ast::ArrayIndexExpr(index_expr),
+                    self.span_from(span_start),
+                );
+                self.parse_postfix(new_expression)
             }
 
             lexer::Dot => {
@@ -529,26 +759,18 @@ impl<'a> Parser<'a> {
 
                 let field_name = self.parse_name();
 
-                let rtype =
-                    ast::FieldRefType(Box::new(expression.rtype.clone()), field_name.clone());
                 let field_ref_expr = ast::FieldRef {
                     field: field_name,
                     target: expression,
-                    span: InputSpan::new(span_start, self.current_pos()),
-                };
-                let new_expression = ast::Expression {
-                    expr: Box::new(ast::FieldRefExpr(field_ref_expr)),
-                    rtype,
-                    span: InputSpan::new(span_start, self.current_pos()),
+                    span: self.span_from(span_start),
                 };
-                self.parse_expression_end(new_expression)
+                let new_expression = ast::Expression::new(
+                    ast::FieldRefExpr(field_ref_expr),
+                    self.span_from(span_start),
+                );
+                self.parse_postfix(new_expression)
             }
 
-            lexer::Plus => unimplemented!(),
-            lexer::Minus => unimplemented!(),
-            lexer::PlusEq => unimplemented!(),
-            lexer::MinusEq => unimplemented!(),
-
             _ => expression,
         }
     }
@@ -569,11 +791,7 @@ impl<'a> Parser<'a> {
             }
 
             // Otherwise it is just an ordinary variable
-            _ => ast::Expression {
-                expr: Box::new(ast::VariableExpr(name.clone())),
-                rtype: ast::VariableType(name),
-                span: InputSpan::new(span_start, self.current_pos()),
-            },
+            _ => ast::Expression::new(ast::VariableExpr(name), self.span_from(span_start)),
         }
     }
 
@@ -598,17 +816,8 @@ impl<'a> Parser<'a> {
             }
         }
 
-        let function_call = ast::FunctionCall {
-            name: name.clone(),
-            args,
-            span: InputSpan::new(span_start, self.current_pos()),
-        };
-
-        ast::Expression {
-            expr: Box::new(ast::CallExpr(function_call)),
-            rtype: ast::VariableType(name),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        let function_call = ast::FunctionCall { name, args, span: self.span_from(span_start) };
+        ast::Expression::new(ast::CallExpr(function_call), self.span_from(span_start))
     }
 
     fn parse_function_init(&mut self, name: String, span_start: InputPos) -> ast::Expression {
@@ -636,16 +845,11 @@ impl<'a> Parser<'a> {
         self.expect(lexer::RightBrace);
 
         let struct_init = ast::StructInit {
-            type_name: name.clone(),
+            type_name: name,
             field_init: fields,
-            span: InputSpan::new(span_start, self.current_pos()),
+            span: self.span_from(span_start),
         };
-
-        ast::Expression {
-            expr: Box::new(ast::StructInitExpr(struct_init)),
-            rtype: ast::UserType(name),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        ast::Expression::new(ast::StructInitExpr(struct_init), self.span_from(span_start))
     }
 
     fn parse_static_array(&mut self, span_start: InputPos) -> ast::Expression {
@@ -671,32 +875,19 @@ impl<'a> Parser<'a> {
 
         // 0 length arrays are invalid
         if elements.is_empty() {
-            self.logger.report_error(
-                "cannot define array of length 0".to_string(),
-                InputSpan::new(span_start, self.current_pos()),
-            );
+            let span = self.span_from(span_start);
+            let diagnostic = Diagnostic::error("cannot define array of length 0".to_string(), span)
+                .code("E0204");
+            self.logger.report(diagnostic);
             self.fatal_error();
         }
 
-        let element_type = elements[0].rtype.clone();
-        let length = elements.len();
-
-        let array_expr =
-            ast::StaticArray { elements, span: InputSpan::new(span_start, self.current_pos()) };
-
-        ast::Expression {
-            expr: Box::new(ast::StaticArrayExpr(array_expr)),
-            rtype: ast::StaticArrayType(Box::new(element_type), length as i32),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        let array_expr = ast::StaticArray { elements, span: self.span_from(span_start) };
+        ast::Expression::new(ast::StaticArrayExpr(array_expr), self.span_from(span_start))
     }
 
     fn handle_num(&mut self, val: i32, span_start: InputPos) -> ast::Expression {
-        ast::Expression {
-            expr: Box::new(ast::LitNumExpr(val)),
-            rtype: ast::Primitive(ast::IntType),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        ast::Expression::new(ast::LitNumExpr(val), self.span_from(span_start))
     }
 
     /// Parse an if statement defined by:
@@ -715,7 +906,7 @@ impl<'a> Parser<'a> {
                     let else_if_span_start = self.current_pos();
                     let inner_block = ast::Block {
                         statements: vec![self.parse_if(else_if_span_start)],
-                        span: InputSpan::new(else_if_span_start, self.current_pos()),
+                        span: self.span_from(else_if_span_start),
                     };
                     Some(inner_block)
                 }
@@ -731,21 +922,9 @@ impl<'a> Parser<'a> {
             self.fake_semicolon = true;
         }
 
-        let if_statement = ast::IfStatement {
-            condition,
-            body,
-            else_block,
-            span: InputSpan::new(span_start, self.current_pos()),
-        };
-
-        // The return type of the if statement is the return type of the body. We check that the
-        // the else part matches during compile time.
-        let rtype = if_statement.body.rtype();
-        ast::Expression {
-            expr: Box::new(ast::IfExpr(if_statement)),
-            rtype,
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        let if_statement =
+            ast::IfStatement { condition, body, else_block, span: self.span_from(span_start) };
+        ast::Expression::new(ast::IfExpr(if_statement), self.span_from(span_start))
     }
 
     /// Parse a for statement defined by:
@@ -770,19 +949,9 @@ impl<'a> Parser<'a> {
             self.fake_semicolon = true;
         }
 
-        let for_statement = ast::ForLoopStatement {
-            loop_var,
-            start,
-            end,
-            body,
-            span: InputSpan::new(span_start, self.current_pos()),
-        };
-
-        ast::Expression {
-            expr: Box::new(ast::ForLoopExpr(for_statement)),
-            rtype: ast::Primitive(ast::UnitType),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        let for_statement =
+            ast::ForLoopStatement { loop_var, start, end, body, span: self.span_from(span_start) };
+        ast::Expression::new(ast::ForLoopExpr(for_statement), self.span_from(span_start))
     }
 
     fn parse_loop(&mut self, span_start: InputPos) -> ast::Expression {
@@ -793,14 +962,8 @@ impl<'a> Parser<'a> {
             self.fake_semicolon = true;
         }
 
-        let loop_statement =
-            ast::LoopStatement { body, span: InputSpan::new(span_start, self.current_pos()) };
-
-        ast::Expression {
-            expr: Box::new(ast::LoopExpr(loop_statement)),
-            rtype: ast::Primitive(ast::BottomType),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        let loop_statement = ast::LoopStatement { body, span: self.span_from(span_start) };
+        ast::Expression::new(ast::LoopExpr(loop_statement), self.span_from(span_start))
     }
 
     fn parse_while(&mut self, span_start: InputPos) -> ast::Expression {
@@ -810,12 +973,8 @@ impl<'a> Parser<'a> {
         self.expect(lexer::RightParen);
 
         let break_body = ast::Block {
-            statements: vec![ast::Expression {
-                expr: Box::new(ast::Break),
-                rtype: ast::Primitive(ast::BottomType),
-                span: InputSpan::invalid(),
-            }],
-            span: InputSpan::new(span_start, self.current_pos()),
+            statements: vec![ast::Expression::new(ast::Break, InputSpan::invalid())],
+            span: self.span_from(span_start),
         };
 
         let loop_body = self.parse_block();
@@ -825,7 +984,7 @@ impl<'a> Parser<'a> {
             condition,
             body: loop_body,
             else_block: Some(break_body),
-            span: InputSpan::new(span_start, self.current_pos()),
+            span: self.span_from(span_start),
         };
 
         // Insert an implicit semicolon if there wasn't one at the end of the while statement
@@ -835,82 +994,165 @@ impl<'a> Parser<'a> {
 
         let loop_statement = ast::LoopStatement {
             body: ast::Block {
-                statements: vec![ast::Expression {
-                    expr: Box::new(ast::IfExpr(real_body)),
-                    rtype: ast::Primitive(ast::BottomType),
-                    span: InputSpan::invalid(),
-                }],
+                statements: vec![ast::Expression::new(
+                    ast::IfExpr(real_body),
+                    InputSpan::invalid(),
+                )],
                 span: body_span,
             },
-            span: InputSpan::new(span_start, self.current_pos()),
+            span: self.span_from(span_start),
         };
-
-        ast::Expression {
-            expr: Box::new(ast::LoopExpr(loop_statement)),
-            rtype: ast::Primitive(ast::BottomType),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        ast::Expression::new(ast::LoopExpr(loop_statement), self.span_from(span_start))
     }
 
     fn parse_break(&mut self, span_start: InputPos) -> ast::Expression {
-        ast::Expression {
-            expr: Box::new(ast::Break),
-            rtype: ast::Primitive(ast::BottomType),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        ast::Expression::new(ast::Break, self.span_from(span_start))
     }
 
     fn parse_return(&mut self, span_start: InputPos) -> ast::Expression {
         let expression = self.parse_expression();
-        ast::Expression {
-            expr: Box::new(ast::Return(expression)),
-            rtype: ast::Primitive(ast::BottomType),
-            span: InputSpan::new(span_start, self.current_pos()),
-        }
+        ast::Expression::new(ast::Return(expression), self.span_from(span_start))
     }
 
     fn parse_asm(&mut self, span_start: InputPos) -> ast::Expression {
+        // Registers modified by the assembly are listed before the block, e.g. `clobbers(r6, r7)`
+        let mut clobbers = vec![];
+        if self.peek() == lexer::Ident("clobbers".to_string()) {
+            self.bump();
+            self.expect(lexer::LeftParen);
+            while self.peek() != lexer::RightParen {
+                let span = self.peek_span();
+                clobbers.push((self.parse_name(), span));
+                if self.peek() != lexer::Comma {
+                    break;
+                }
+                self.bump();
+            }
+            self.expect(lexer::RightParen);
+        }
+
         self.expect(lexer::LeftBrace);
 
-        let mut code = String::new();
+        let mut lines = vec![];
         loop {
             match self.next_token() {
                 lexer::LitString(string) => {
-                    code.push_str(&string);
-                }
-                lexer::RightBrace => {
-                    code.pop();
-                    break;
+                    lines.push((string, self.last_span()));
                 }
+                lexer::RightBrace => break,
                 invalid => {
-                    self.logger.report_error(
-                        format!("expected `\"<string>\"` or `}}` but found, `{:?}`", invalid),
-                        InputSpan::new(span_start, self.current_pos()),
-                    );
-                    self.fatal_error();
+                    self.unexpected("a string or `}`", invalid, self.last_span());
                 }
             }
 
             match self.next_token() {
                 lexer::RightBrace => break,
-                lexer::Comma => {
-                    code.push('\n');
-                    continue;
-                }
+                lexer::Comma => continue,
                 invalid => {
-                    self.logger.report_error(
-                        format!("expected `,` or `}}` but found, `{:?}`", invalid),
-                        InputSpan::new(span_start, self.current_pos()),
-                    );
-                    self.fatal_error();
+                    self.unexpected("`,` or `}`", invalid, self.last_span());
                 }
             }
         }
 
-        ast::Expression {
-            expr: Box::new(ast::AsmOpExpr(code)),
-            rtype: ast::Primitive(ast::AnyType),
-            span: InputSpan::new(span_start, self.current_pos()),
+        let block = ast::AsmBlock { lines, clobbers };
+        ast::Expression::new(ast::AsmOpExpr(block), self.span_from(span_start))
+    }
+}
+
+/// Returns the binary operator corresponding to a token, if there is one
+fn binary_op(token: &lexer::TokenValue) -> Option<ast::BinaryOp> {
+    let op = match token {
+        lexer::Plus => ast::BinaryOp::Add,
+        lexer::Minus => ast::BinaryOp::Sub,
+        lexer::Star => ast::BinaryOp::Mul,
+        lexer::Slash => ast::BinaryOp::Div,
+        lexer::Percent => ast::BinaryOp::Rem,
+        lexer::Equal => ast::BinaryOp::Eq,
+        lexer::NotEqual => ast::BinaryOp::NotEq,
+        lexer::Less => ast::BinaryOp::Lt,
+        lexer::LessEq => ast::BinaryOp::LtEq,
+        lexer::Greater => ast::BinaryOp::Gt,
+        lexer::GreaterEq => ast::BinaryOp::GtEq,
+        lexer::AndAnd => ast::BinaryOp::And,
+        lexer::OrOr => ast::BinaryOp::Or,
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::Diagnostic, lexer::Lexer};
+
+    /// Parse a program, giving the diagnostics it reported
+    fn parse_errors(source: &str) -> Vec<Diagnostic> {
+        let logger = Logger::new(true);
+        parse(Lexer::new(source, logger.add_file("main.pcp", source), &logger), &logger);
+        logger.diagnostics()
+    }
+
+    /// Parse a single statement, and write it out with brackets around each operation
+    fn parse_statement(source: &str) -> String {
+        let logger = Logger::new(true);
+        let source = format!("fn main() {{ {}; }}", source);
+        let program =
+            parse(Lexer::new(&source, logger.add_file("main.pcp", &source), &logger), &logger);
+        assert!(!logger.has_errors());
+        match &program.items[..] {
+            [ast::FunctionItem(function)] => show(&function.body.statements[0]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn show(expression: &ast::Expression) -> String {
+        match &*expression.expr {
+            ast::BinaryExpr(x) => {
+                format!("({} {:?} {})", show(&x.lhs), x.op, show(&x.rhs))
+            }
+            ast::UnaryExpr(x) => format!("({:?} {})", x.op, show(&x.operand)),
+            ast::DerefExpr(x) => format!("(*{})", show(x)),
+            ast::FieldRefExpr(x) => format!("({}.{})", show(&x.target), x.field),
+            ast::AssignExpr(x) => format!("({} = {})", show(&x.target), show(&x.rhs)),
+            ast::VariableExpr(name) => name.clone(),
+            ast::LitNumExpr(value) => value.to_string(),
+            other => panic!("unexpected expression `{:?}`", other),
         }
     }
+
+    #[test]
+    fn binary_operators_are_left_associative() {
+        assert_eq!(parse_statement("a - b - c"), "((a Sub b) Sub c)");
+        assert_eq!(parse_statement("a / b * c"), "((a Div b) Mul c)");
+    }
+
+    #[test]
+    fn binary_operators_bind_by_precedence() {
+        assert_eq!(parse_statement("a + b * c"), "(a Add (b Mul c))");
+        assert_eq!(parse_statement("a * b + c"), "((a Mul b) Add c)");
+        assert_eq!(parse_statement("!a && b || c"), "(((Not a) And b) Or c)");
+        assert_eq!(parse_statement("a || b && c"), "(a Or (b And c))");
+        assert_eq!(parse_statement("a + 1 < b == c"), "(((a Add 1) Lt b) Eq c)");
+        assert_eq!(parse_statement("-a * b"), "((Neg a) Mul b)");
+    }
+
+    #[test]
+    fn field_accesses_apply_to_the_dereferenced_value() {
+        assert_eq!(parse_statement("*a.b"), "((*a).b)");
+        assert_eq!(parse_statement("*a.b + c"), "(((*a).b) Add c)");
+    }
+
+    #[test]
+    fn desugars_compound_assignment() {
+        assert_eq!(parse_statement("a += b * 2"), "(a = (a Add (b Mul 2)))");
+        assert_eq!(parse_statement("a.b -= 1"), "((a.b) = ((a.b) Sub 1))");
+    }
+
+    #[test]
+    fn reports_a_missing_initialiser_at_the_semicolon() {
+        let errors = parse_errors("fn main() {\n    let x: int = ;\n    x\n}");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "expected an expression, found `;`");
+        assert_eq!(errors[0].primary.span.start.line, 2);
+    }
 }
@@ -0,0 +1,143 @@
+use std::{fmt, fs, io, path::Path};
+
+use crate::dlx::codegen::{Runtime, MAX_RUNTIME_MEMORY};
+
+/// The name of the project file that is loaded from the directory of the input file
+pub const PROJECT_FILE: &str = "pchip.toml";
+
+// A project file contains settings that would otherwise be given on the command line, written as a
+// small subset of TOML:
+//
+//   [runtime]
+//   stack-size = 4000
+//   heap-size = 800
+//   entry = "main"
+//   start = "prgsrt"
+//   exit-hook = "on_exit"
+//
+// Comments start with `#`, and strings must be quoted.
+
+#[derive(Debug)]
+pub enum ProjectError {
+    Io(io::Error),
+    Syntax { line: usize, message: String },
+}
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProjectError::Io(e) => write!(f, "{}", e),
+            ProjectError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+/// The settings read from a project file
+#[derive(Default)]
+pub struct Project {
+    pub runtime: Runtime,
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Project, ProjectError> {
+        let source = fs::read_to_string(path).map_err(ProjectError::Io)?;
+        Project::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Project, ProjectError> {
+        let mut project = Project::default();
+        let mut section = String::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let error = |message: String| ProjectError::Syntax { line: i + 1, message };
+
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                section = name.trim().to_string();
+                if section != "runtime" {
+                    return Err(error(format!("unknown section `[{}]`", section)));
+                }
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), Value::parse(value.trim()).map_err(error)?),
+                None => return Err(error("expected `key = value`".to_string())),
+            };
+            if section.is_empty() {
+                return Err(error(format!("`{}` must be in a section, e.g. `[runtime]`", key)));
+            }
+
+            let runtime = &mut project.runtime;
+            match key {
+                "stack-size" => runtime.stack_size = value.size(key).map_err(error)?,
+                "heap-size" => runtime.heap_size = value.size(key).map_err(error)?,
+                "entry" => runtime.entry = value.string(key).map_err(error)?,
+                "start" => runtime.start = Some(value.string(key).map_err(error)?),
+                "exit-hook" => runtime.exit_hook = Some(value.string(key).map_err(error)?),
+                _ => return Err(error(format!("unknown key `{}`", key))),
+            }
+        }
+
+        Ok(project)
+    }
+}
+
+enum Value {
+    Integer(u32),
+    String(String),
+}
+
+impl Value {
+    fn parse(text: &str) -> Result<Value, String> {
+        if let Some(string) = text.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+            return Ok(Value::String(string.to_string()));
+        }
+        match text.parse() {
+            Ok(value) => Ok(Value::Integer(value)),
+            Err(_) => Err(format!("invalid value `{}`, expected an integer or a string", text)),
+        }
+    }
+
+    fn integer(self, key: &str) -> Result<u32, String> {
+        match self {
+            Value::Integer(value) => Ok(value),
+            Value::String(..) => Err(format!("`{}` must be an integer", key)),
+        }
+    }
+
+    /// Get a size in bytes of part of the memory set up by the start code
+    fn size(self, key: &str) -> Result<u32, String> {
+        match self.integer(key)? {
+            size if size < MAX_RUNTIME_MEMORY => Ok(size),
+            _ => Err(format!(
+                "`{}` is too large, the stack and heap must take up less than {} bytes",
+                key, MAX_RUNTIME_MEMORY
+            )),
+        }
+    }
+
+    fn string(self, key: &str) -> Result<String, String> {
+        match self {
+            Value::String(value) => Ok(value),
+            Value::Integer(..) => Err(format!("`{}` must be a string", key)),
+        }
+    }
+}
+
+/// Remove a comment from a line, ignoring `#` characters inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
@@ -0,0 +1,1219 @@
+use std::{
+    collections::{
+        hash_map::Entry::{Occupied, Vacant},
+        HashMap,
+    },
+    ops::Range,
+};
+
+use crate::{
+    ast,
+    dlx::{
+        asm::{Instruction, RegId},
+        codegen::{FRAME_POINTER, HEAP_POINTER, RESULT_REG, RUNTIME_LABELS, STACK_POINTER},
+        console, regalloc,
+        syntax::{parse_line, parse_register, Expr, Line, Operand, SyntaxError},
+    },
+    error::{Diagnostic, FatalError, InputSpan, Logger},
+    module::{ALLOC_FN, PRELUDE, STD_MODULE},
+    types::{self, Type, TypeError, TypeTable, BOOL_TYPE, CHAR_TYPE, INT_TYPE, UNIT_TYPE},
+};
+
+use self::{Ident::*, IdentId::*};
+
+/// The signature of a function
+pub struct Function {
+    params: Vec<Type>,
+    rtype: Type,
+    span: InputSpan,
+}
+
+pub struct Variable {
+    rtype: Type,
+    span: InputSpan,
+    /// Whether the variable was declared with `const`, so that it can't be assigned to
+    is_const: bool,
+}
+
+#[derive(Eq, PartialEq, Hash)]
+pub enum IdentId {
+    FnIdentId(usize),
+    VarIdentId(usize),
+}
+
+pub enum Ident<'a> {
+    FnIdent(&'a Function),
+    VarIdent(&'a Variable),
+}
+
+/// The variables and functions that can be referred to by name. The items of every module are
+/// kept in a single global scope under their full paths, while each function has a local scope.
+#[derive(Default)]
+pub struct Scope {
+    functions: Vec<Function>,
+    vars: Vec<Variable>,
+    ident_table: HashMap<String, IdentId>,
+}
+
+impl Scope {
+    fn add_fn(&mut self, name: String, function: Function, logger: &Logger) {
+        let id = FnIdentId(self.functions.len());
+        let span = function.span;
+        self.functions.push(function);
+        self.add_ident(name, id, span, logger);
+    }
+
+    fn add_var(&mut self, name: String, variable: Variable, logger: &Logger) {
+        let id = VarIdentId(self.vars.len());
+        let span = variable.span;
+        self.vars.push(variable);
+        self.add_ident(name, id, span, logger);
+    }
+
+    /// Add an identifier to the scope
+    fn add_ident(&mut self, ident_name: String, ident: IdentId, span: InputSpan, logger: &Logger) {
+        match self.ident_table.entry(ident_name) {
+            Vacant(entry) => {
+                entry.insert(ident);
+            }
+            // This identifier shadows an existing one. Variable shadowing is not supported.
+            Occupied(entry) => {
+                let previous = match *entry.get() {
+                    FnIdentId(id) => self.functions[id].span,
+                    VarIdentId(id) => self.vars[id].span,
+                };
+                let diagnostic = Diagnostic::error(
+                    format!("`{}` is defined multiple times in this scope", entry.key()),
+                    span,
+                )
+                .code("E0302")
+                .secondary(previous, format!("previous definition of `{}` here", entry.key()))
+                .note("shadowing of variables is not supported".to_string());
+                logger.report(diagnostic);
+            }
+        }
+    }
+
+    /// Get the identifier corresponding to an identifier name.
+    pub fn get_ident(&self, ident_name: &str) -> Option<Ident<'_>> {
+        match self.ident_table.get(ident_name) {
+            Some(&FnIdentId(id)) => Some(FnIdent(&self.functions[id])),
+            Some(&VarIdentId(id)) => Some(VarIdent(&self.vars[id])),
+            None => None,
+        }
+    }
+}
+
+/// A module of the program, and the names that can be used inside it
+struct Module {
+    /// The path of the module, e.g. `geo::shapes`, which is empty for the root module
+    path: String,
+    /// The items declared in the module
+    items: HashMap<String, InputSpan>,
+    /// The modules declared in the module
+    modules: HashMap<String, InputSpan>,
+    /// The items brought into the module with `use`, mapped to their full paths
+    imports: HashMap<String, (String, InputSpan)>,
+}
+
+impl Module {
+    fn new(path: String) -> Module {
+        Module { path, items: HashMap::new(), modules: HashMap::new(), imports: HashMap::new() }
+    }
+
+    /// Get the full path of an item declared in this module
+    fn item_path(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.to_string()
+        }
+        else {
+            format!("{}::{}", self.path, name)
+        }
+    }
+
+    /// Get the full path of a name used in this module
+    fn lookup(&self, name: &str) -> Option<String> {
+        if self.items.contains_key(name) || self.modules.contains_key(name) {
+            return Some(self.item_path(name));
+        }
+        self.imports.get(name).map(|(path, _)| path.clone())
+    }
+
+    /// Get the location of the first definition of a name in this module
+    fn definition(&self, name: &str) -> Option<InputSpan> {
+        let import = self.imports.get(name).map(|&(_, span)| span);
+        self.items.get(name).or_else(|| self.modules.get(name)).copied().or(import)
+    }
+}
+
+/// Get the full path of the item or module that a path refers to inside a module. The first name in
+/// the path is looked up in the module, then in the root module, and then in the prelude.
+fn resolve_path(modules: &[Module], module: usize, path: &str) -> Option<String> {
+    let mut names = path.split("::");
+    let first = names.next()?;
+    let mut full_path = modules[module]
+        .lookup(first)
+        .or_else(|| modules[0].lookup(first))
+        .or_else(|| prelude_path(modules, first))?;
+    for name in names {
+        let inner = modules.iter().find(|x| x.path == full_path)?;
+        full_path = inner.lookup(name)?;
+    }
+    Some(full_path)
+}
+
+/// Get the full path of an item of the prelude from its name, if the standard library is available
+fn prelude_path(modules: &[Module], name: &str) -> Option<String> {
+    let path = PRELUDE.iter().find(|path| path.rsplit("::").next() == Some(name))?;
+    resolve_path(modules, 0, path)
+}
+
+/// Replace the names of structs in a type with their full paths
+fn qualify_type(modules: &[Module], module: usize, ast_type: &mut ast::Type) {
+    match ast_type {
+        ast::Pointer(inner) | ast::StaticArrayType(inner, _) => {
+            qualify_type(modules, module, inner)
+        }
+        ast::UserType(name) => {
+            // Unknown names are left as they are, so that they can be reported as written
+            if let Some(path) = resolve_path(modules, module, name) {
+                *name = path;
+            }
+        }
+        ast::Primitive(..) => {}
+    }
+}
+
+/// Get the label used for a variable or function with a full path. Module names are separated with
+/// `.` instead of `::`, which can't appear in a name, so an item in a module can never have the same
+/// label as an item in the root module.
+pub fn mangle(path: &str) -> String {
+    path.replace("::", ".")
+}
+
+/// Check that an item doesn't have the same label as part of the runtime or a register. Items in
+/// the root module keep their own name as their label, so they could clash with the labels of the
+/// program start code or the console registers, or be read as a register by the assembler.
+fn check_label(path: &str, span: InputSpan, logger: &Logger) {
+    let mut reserved = RUNTIME_LABELS.iter().copied().chain(console::SYMBOLS.iter().map(|x| x.0));
+    let (message, label) = if reserved.any(|x| x == path) {
+        ("reserved by the runtime", "this name is already used as a label by the runtime")
+    }
+    else if parse_register(path).is_some() {
+        ("the name of a register", "this name would be read as a register by the assembler")
+    }
+    else {
+        return;
+    };
+    let diagnostic = Diagnostic::error(format!("`{}` is {}", path, message), span)
+        .code("E0308")
+        .label(label.to_string())
+        .note("items in the root module are given their own name as their label".to_string())
+        .help("rename it, or move it into a module".to_string());
+    logger.report(diagnostic);
+}
+
+/// Flatten a tree of modules into a list of items, along with the module that each item is in
+fn flatten(
+    items: Vec<ast::Item>,
+    module: usize,
+    modules: &mut Vec<Module>,
+    flat: &mut Vec<(usize, ast::Item)>,
+    logger: &Logger,
+) {
+    for item in items {
+        let declaration = match item {
+            ast::ModItem(declaration) => declaration,
+            item => {
+                flat.push((module, item));
+                continue;
+            }
+        };
+
+        if let Some(previous) = modules[module].modules.get(&declaration.name) {
+            let diagnostic = Diagnostic::error(
+                format!("module `{}` is defined multiple times", declaration.name),
+                declaration.span,
+            )
+            .code("E0302")
+            .secondary(*previous, format!("previous definition of `{}` here", declaration.name));
+            logger.report(diagnostic);
+            continue;
+        }
+        modules[module].modules.insert(declaration.name.clone(), declaration.span);
+
+        let id = modules.len();
+        modules.push(Module::new(modules[module].item_path(&declaration.name)));
+        flatten(declaration.items, id, modules, flat, logger);
+    }
+}
+
+/// Add the items imported with `use` to the modules that import them
+fn resolve_imports(items: &[(usize, ast::Item)], modules: &mut [Module], logger: &Logger) {
+    for (module, item) in items {
+        let declaration = match item {
+            ast::UseItem(declaration) => declaration,
+            _ => continue,
+        };
+        let path = match resolve_path(modules, *module, &declaration.path) {
+            Some(path) => path,
+            None => {
+                let diagnostic = Diagnostic::error(
+                    format!("unresolved import `{}`", declaration.path),
+                    declaration.span,
+                )
+                .code("E0301")
+                .label("no item or module with this path".to_string());
+                logger.report(diagnostic);
+                continue;
+            }
+        };
+
+        let name = declaration.path.rsplit("::").next().unwrap_or_default().to_string();
+        if let Some(previous) = modules[*module].definition(&name) {
+            let diagnostic = Diagnostic::error(
+                format!("`{}` is defined multiple times in this scope", name),
+                declaration.span,
+            )
+            .code("E0302")
+            .secondary(previous, format!("`{}` is also defined here", name));
+            logger.report(diagnostic);
+            continue;
+        }
+        modules[*module].imports.insert(name, (path, declaration.span));
+    }
+}
+
+/// Resolve the names and types used by a program, annotating every expression with its type. All
+/// errors are reported before compilation is aborted, so that they can be fixed together.
+///
+/// The modules of the program are flattened into a single list of items. Structs are renamed to
+/// their full paths (e.g. `geo::Point`), and variables and functions to their labels (e.g.
+/// `geo.area`), so that later passes don't need to know about modules.
+pub fn check(program: &mut ast::Program, logger: &Logger) -> TypeTable {
+    let mut modules = vec![Module::new(String::new())];
+    let mut items = vec![];
+    flatten(std::mem::take(&mut program.items), 0, &mut modules, &mut items, logger);
+
+    // Record the names declared by each module, so that items can be used before they are declared
+    for (module, item) in &items {
+        let (name, span) = match item {
+            ast::FunctionItem(x) => (&x.name, x.span),
+            ast::StructItem(x) => (&x.name, x.span),
+            ast::LetItem(x) => (&x.name, x.span),
+            ast::ModItem(..) | ast::UseItem(..) | ast::ErrorItem(..) => continue,
+        };
+        modules[*module].items.entry(name.clone()).or_insert(span);
+    }
+    resolve_imports(&items, &mut modules, logger);
+
+    // Structs are renamed before the type table is generated, since they are identified by name
+    let mut item_modules = vec![];
+    for (module, mut item) in items {
+        if let ast::StructItem(ref mut struct_item) = item {
+            struct_item.name = modules[module].item_path(&struct_item.name);
+            for (_, field_type, _) in &mut struct_item.fields {
+                qualify_type(&modules, module, field_type);
+            }
+        }
+        if let ast::UseItem(..) = item {
+            continue;
+        }
+        program.items.push(item);
+        item_modules.push(module);
+    }
+
+    let mut data = CheckData {
+        type_table: types::typegen(program, logger),
+        logger,
+        global: Scope::default(),
+        modules,
+        module: 0,
+        rtype: UNIT_TYPE,
+        loop_depth: 0,
+    };
+
+    // Declare the functions and global variables. Global variables can only refer to the items
+    // declared before them.
+    for (item, &module) in program.items.iter_mut().zip(&item_modules) {
+        data.module = module;
+        match item {
+            ast::FunctionItem(fn_item) => {
+                let span = fn_item.span;
+                let params =
+                    fn_item.params.iter_mut().map(|p| data.resolve_type(&mut p.1, span)).collect();
+                let rtype = data.resolve_type(&mut fn_item.rtype, span);
+                let path = data.modules[module].item_path(&fn_item.name);
+                check_label(&path, span, logger);
+                fn_item.name = mangle(&path);
+                data.global.add_fn(path, Function { params, rtype, span }, logger);
+            }
+            ast::LetItem(let_item) => {
+                let rtype = data.check_let_type(&mut Scope::default(), let_item);
+                let path = data.modules[module].item_path(&let_item.name);
+                check_label(&path, let_item.span, logger);
+                let_item.name = mangle(&path);
+                let variable = Variable { rtype, span: let_item.span, is_const: let_item.is_const };
+                data.global.add_var(path, variable, logger);
+            }
+
+            // Handled by type gen
+            ast::StructItem(..) | ast::ErrorItem(..) => {}
+
+            ast::ModItem(..) | ast::UseItem(..) => unreachable!(),
+        }
+    }
+
+    // Check the bodies of the functions, which can refer to any item
+    let mut id = 0;
+    for (item, &module) in program.items.iter_mut().zip(&item_modules) {
+        if let ast::FunctionItem(fn_item) = item {
+            data.module = module;
+            data.check_function(id, fn_item);
+            id += 1;
+        }
+    }
+
+    if logger.has_errors() {
+        FatalError::raise();
+    }
+    data.type_table
+}
+
+/// Decode the escape sequences in a string literal, returning the first invalid sequence if there
+/// is one
+pub fn unescape(value: &str) -> Result<String, String> {
+    let mut string = String::new();
+    let mut chars = value.chars();
+    while let Some(next) = chars.next() {
+        if next != '\\' {
+            string.push(next);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('r') => string.push('\r'),
+            Some('t') => string.push('\t'),
+            Some('0') => string.push('\0'),
+            Some(escaped @ ('\\' | '"' | '\'')) => string.push(escaped),
+            Some(invalid) => return Err(format!("\\{}", invalid)),
+            None => return Err("\\".to_string()),
+        }
+    }
+    Ok(string)
+}
+
+/// Check whether an expression refers to a location in memory, which can be assigned to or
+/// referenced
+fn is_place(expression: &ast::Expression) -> bool {
+    matches!(
+        *expression.expr,
+        ast::VariableExpr(..)
+            | ast::DerefExpr(..)
+            | ast::ArrayIndexExpr(..)
+            | ast::FieldRefExpr(..)
+    )
+}
+
+struct CheckData<'a> {
+    type_table: TypeTable,
+    logger: &'a Logger,
+    /// The functions and global variables of every module
+    global: Scope,
+    modules: Vec<Module>,
+    /// The module containing the code being checked
+    module: usize,
+    /// The return type of the current function
+    rtype: Type,
+    /// The number of loops around the current expression
+    loop_depth: usize,
+}
+
+impl<'a> CheckData<'a> {
+    fn check_function(&mut self, id: usize, function: &mut ast::FunctionDeclaration) {
+        let signature = &self.global.functions[id];
+        let params = signature.params.clone();
+        self.rtype = signature.rtype.clone();
+
+        // Create a local scope for this function, containing its parameters
+        let mut local = Scope::default();
+        for ((name, _), rtype) in function.params.iter().zip(params) {
+            self.check_sized(&rtype, function.span);
+            let variable = Variable { rtype, span: function.span, is_const: false };
+            local.add_var(name.clone(), variable, self.logger);
+        }
+
+        let body_type = self.check_block(&mut local, &mut function.body);
+
+        // The value of the last statement is returned, unless the function doesn't return anything
+        if self.rtype != UNIT_TYPE {
+            let body = &function.body;
+            let span = body.statements.last().map_or(body.span, |x| x.span);
+            self.check_type(&body_type, &self.rtype, span);
+        }
+    }
+
+    /// Find the variable or function that a name refers to, along with the name that it has in the
+    /// checked program. Local variables are found first, followed by the items of the modules.
+    fn lookup<'s>(&'s self, scope: &'s Scope, name: &str) -> Option<(Ident<'s>, String)> {
+        if let Some(ident) = scope.get_ident(name) {
+            return Some((ident, name.to_string()));
+        }
+        let path = resolve_path(&self.modules, self.module, name)?;
+        let ident = self.global.get_ident(&path)?;
+        Some((ident, mangle(&path)))
+    }
+
+    fn check_block(&mut self, scope: &mut Scope, block: &mut ast::Block) -> Type {
+        let mut rtype = UNIT_TYPE;
+        for statement in &mut block.statements {
+            rtype = self.check_expression(scope, statement);
+        }
+        rtype
+    }
+
+    /// Check an expression, recording its type in the expression
+    fn check_expression(&mut self, scope: &mut Scope, expression: &mut ast::Expression) -> Type {
+        let rtype = self.expression_type(scope, expression);
+        expression.rtype = Some(rtype.clone());
+        rtype
+    }
+
+    fn expression_type(&mut self, scope: &mut Scope, expression: &mut ast::Expression) -> Type {
+        let span = expression.span;
+        match *expression.expr {
+            ast::RefExpr(ref mut inner) => {
+                let inner_type = self.check_expression(scope, inner);
+                if !is_place(inner) {
+                    let diagnostic = Diagnostic::error(
+                        "cannot take a reference to this expression".to_string(),
+                        inner.span,
+                    )
+                    .code("E0503")
+                    .help(
+                        "only variables, dereferences, array elements and fields can be referenced"
+                            .to_string(),
+                    );
+                    self.logger.report(diagnostic);
+                    return types::Error;
+                }
+                if let ast::FieldRefExpr(ref field_ref) = *inner.expr {
+                    // Words are read from packed structs a byte at a time, which isn't possible
+                    // through a pointer
+                    let packed = self.type_table.is_packed(field_ref.target.rtype());
+                    if packed
+                        && inner_type != types::Error
+                        && self.type_table.align_of(&inner_type) > 1
+                    {
+                        let diagnostic = Diagnostic::error(
+                            format!(
+                                "cannot take a reference to field `{}` of a packed struct",
+                                field_ref.field
+                            ),
+                            inner.span,
+                        )
+                        .code("E0512")
+                        .label("this field may not be aligned".to_string())
+                        .help(
+                            "copy the field to a variable and reference that instead".to_string(),
+                        );
+                        self.logger.report(diagnostic);
+                    }
+                }
+                types::Pointer(Box::new(inner_type))
+            }
+            ast::DerefExpr(ref mut inner) => match self.check_expression(scope, inner) {
+                types::Pointer(inner) | types::StaticArray(inner, _) => *inner,
+                types::Error => types::Error,
+                invalid => self.type_error(TypeError::CannotDeref(invalid), span),
+            },
+            ast::FieldRefExpr(ref mut inner) => self.check_field_ref(scope, inner),
+            ast::ArrayIndexExpr(ref mut inner) => self.check_array_index(scope, inner),
+            ast::IfExpr(ref mut inner) => self.check_if(scope, inner),
+            ast::ForLoopExpr(ref mut inner) => self.check_for(scope, inner),
+            ast::LoopExpr(ref mut inner) => {
+                let body_type = self.check_loop_body(scope, &mut inner.body);
+                self.check_type(&body_type, &UNIT_TYPE, inner.span);
+                types::Bottom
+            }
+            ast::CallExpr(ref mut inner) => self.check_call(scope, inner),
+            ast::Break => {
+                if self.loop_depth == 0 {
+                    let diagnostic =
+                        Diagnostic::error("`break` outside of a loop".to_string(), span)
+                            .code("E0505")
+                            .label("cannot `break` outside of a loop".to_string());
+                    self.logger.report(diagnostic);
+                }
+                types::Bottom
+            }
+            ast::Return(ref mut inner) => {
+                let value_type = self.check_expression(scope, inner);
+                self.check_type(&value_type, &self.rtype, inner.span);
+                types::Bottom
+            }
+            ast::LetExpr(ref mut inner) => {
+                self.check_let(scope, inner);
+                UNIT_TYPE
+            }
+            ast::AssignExpr(ref mut inner) => {
+                self.check_assign(scope, inner);
+                UNIT_TYPE
+            }
+            ast::VariableExpr(ref mut name) => match self.lookup(scope, name) {
+                Some((VarIdent(var), label)) => {
+                    let rtype = var.rtype.clone();
+                    *name = label;
+                    rtype
+                }
+                Some((FnIdent(func), _)) => {
+                    let diagnostic = Diagnostic::error(
+                        format!("expected variable, found function `{}`", name),
+                        span,
+                    )
+                    .code("E0303")
+                    .label("not a variable".to_string())
+                    .secondary(func.span, format!("`{}` is defined here", name));
+                    self.logger.report(diagnostic);
+                    types::Error
+                }
+                None => self.type_error(TypeError::VariableNotFound(name.clone()), span),
+            },
+            ast::StaticArrayExpr(ref mut inner) => self.check_static_array(scope, inner),
+            ast::LitStringExpr(ref inner) => match unescape(inner) {
+                Ok(string) => match self.type_table.array_type(CHAR_TYPE, string.chars().count()) {
+                    Ok(type_) => type_,
+                    Err(error) => self.type_error(error, span),
+                },
+                Err(sequence) => {
+                    let diagnostic = Diagnostic::error(
+                        format!("invalid escape sequence `{}`", sequence),
+                        span,
+                    )
+                    .code("E0105")
+                    .note("supported escape sequences are `\\n`, `\\r`, `\\t`, `\\0`, `\\\\`, `\\\"` and `\\'`".to_string());
+                    self.logger.report(diagnostic);
+                    types::Error
+                }
+            },
+            ast::StructInitExpr(ref mut inner) => self.check_struct_init(scope, inner),
+            ast::LitNumExpr(..) => INT_TYPE,
+            ast::LitCharExpr(..) => CHAR_TYPE,
+            ast::LitBoolExpr(..) => BOOL_TYPE,
+            ast::NullExpr => types::Pointer(Box::new(types::Any)),
+            // The value produced by inline assembly can be used as any type
+            ast::AsmOpExpr(ref mut inner) => {
+                self.check_asm(scope, inner);
+                types::Any
+            }
+            ast::CastExpr(ref mut inner, ref mut target_type) => {
+                self.check_expression(scope, inner);
+                self.resolve_type(target_type, span)
+            }
+            ast::NewExpr(ref mut allocated_type) => {
+                let allocated_type = self.resolve_type(allocated_type, span);
+                self.check_sized(&allocated_type, span);
+                if !matches!(self.global.get_ident(ALLOC_FN), Some(FnIdent(..))) {
+                    let diagnostic = Diagnostic::error(
+                        format!("`new` requires the function `{}`", ALLOC_FN),
+                        span,
+                    )
+                    .code("E0511")
+                    .label("memory can't be allocated".to_string())
+                    .note(format!(
+                        "the standard library is not available because the program defines `{}`",
+                        STD_MODULE
+                    ));
+                    self.logger.report(diagnostic);
+                }
+                types::Pointer(Box::new(allocated_type))
+            }
+            ast::BinaryExpr(ref mut inner) => self.check_binary(scope, inner),
+            ast::UnaryExpr(ref mut inner) => {
+                let operand_type = self.check_expression(scope, &mut inner.operand);
+                let rtype = match inner.op {
+                    ast::UnaryOp::Neg => INT_TYPE,
+                    ast::UnaryOp::Not => BOOL_TYPE,
+                };
+                self.check_type(&operand_type, &rtype, inner.span);
+                rtype
+            }
+            ast::EmptyExpr => UNIT_TYPE,
+            ast::ErrorExpr => types::Error,
+        }
+    }
+
+    /// Check the operands and clobbers of a block of inline assembly, replacing the names of the
+    /// operands with the names of the variables in the checked program
+    fn check_asm(&mut self, scope: &Scope, block: &mut ast::AsmBlock) {
+        let clobbers = self.check_clobbers(&block.clobbers);
+        for (line, span) in &mut block.lines {
+            // Columns in the line are offset by the opening quote of the string
+            let parts = match ast::asm_parts(line) {
+                Ok(parts) => parts,
+                Err(column) => {
+                    let diagnostic = Diagnostic::error(
+                        "unclosed operand in inline assembly".to_string(),
+                        span.columns(column + 1, column + 2),
+                    )
+                    .code("E0508")
+                    .label("operand starts here".to_string())
+                    .help("operands are written as `{name}`".to_string());
+                    self.logger.report(diagnostic);
+                    continue;
+                }
+            };
+
+            let mut checked = String::new();
+            for part in parts {
+                let (name, column) = match part {
+                    ast::AsmPart::Text(text) => {
+                        checked.push_str(text);
+                        continue;
+                    }
+                    ast::AsmPart::Operand(name, column) => (name, column),
+                };
+                let operand_span = span.columns(column + 1, column + name.chars().count() + 3);
+                let label = match self.lookup(scope, name) {
+                    // The result register is named `result`, even if there is a variable with the
+                    // same name
+                    _ if name == "result" => name.to_string(),
+                    Some((VarIdent(..), label)) => label,
+                    Some((FnIdent(func), _)) => {
+                        let diagnostic = Diagnostic::error(
+                            format!("expected variable, found function `{}`", name),
+                            operand_span,
+                        )
+                        .code("E0303")
+                        .label("not a variable".to_string())
+                        .secondary(func.span, format!("`{}` is defined here", name))
+                        .note(
+                            "only variables can be used as operands of inline assembly".to_string(),
+                        );
+                        self.logger.report(diagnostic);
+                        name.to_string()
+                    }
+                    None if name.is_empty() => {
+                        let diagnostic = Diagnostic::error(
+                            "empty operand in inline assembly".to_string(),
+                            operand_span,
+                        )
+                        .code("E0508")
+                        .help("operands are written as `{name}`".to_string());
+                        self.logger.report(diagnostic);
+                        name.to_string()
+                    }
+                    None => {
+                        self.type_error(
+                            TypeError::VariableNotFound(name.to_string()),
+                            operand_span,
+                        );
+                        name.to_string()
+                    }
+                };
+                checked.push('{');
+                checked.push_str(&label);
+                checked.push('}');
+            }
+            self.check_asm_line(scope, line, *span, &clobbers);
+            *line = checked;
+        }
+    }
+
+    /// Check that a line of inline assembly is a valid instruction or directive
+    fn check_asm_line(&self, scope: &Scope, line: &str, span: InputSpan, clobbers: &[RegId]) {
+        // Columns in the line are offset by the opening quote of the string
+        let error_span = |columns: Range<usize>| {
+            let start = line[..columns.start].chars().count() + 1;
+            span.columns(start, start + line[columns].chars().count())
+        };
+        let report = |error: SyntaxError| {
+            let diagnostic =
+                Diagnostic::error(error.message, error_span(error.columns)).code("E0508");
+            self.logger.report(diagnostic);
+        };
+
+        let mut operation = match parse_line(line) {
+            Ok(Line { operation: Some(operation), .. }) => operation,
+            Ok(..) => return,
+            Err(error) => return report(error),
+        };
+
+        // Variables are replaced by operands of the same kind as their locations, which aren't
+        // known until code is generated
+        for operand in &mut operation.operands {
+            if let Operand::Variable(name) = operand {
+                *operand = match scope.get_ident(name) {
+                    _ if name == "result" => Operand::Register(RESULT_REG),
+                    Some(..) => Operand::Memory(Expr::Number(0), FRAME_POINTER),
+                    None => Operand::Immediate(Expr::Symbol(name.clone(), 0)),
+                };
+            }
+        }
+
+        let mut instruction = match Instruction::from_operation(&operation) {
+            Ok(Some(instruction)) => instruction,
+            Ok(None) => return,
+            Err(error) => return report(error),
+        };
+
+        // Registers that may hold the values of the function must be saved before they are changed
+        if let (Some(&mut reg), _) = instruction.registers_mut() {
+            if regalloc::is_callee_saved(reg) && !clobbers.contains(&reg) {
+                let diagnostic = Diagnostic::error(
+                    format!("inline assembly modifies `r{}` without declaring it", reg),
+                    error_span(operation.operand_columns),
+                )
+                .code("E0507")
+                .label(format!("modifies `r{}`", reg))
+                .note(
+                    "only `r1`-`r5` can be modified without being listed in `clobbers`".to_string(),
+                )
+                .help(format!("add `clobbers(r{})` before the block", reg));
+                self.logger.report(diagnostic);
+            }
+        }
+    }
+
+    /// Check the registers listed as clobbered by inline assembly, returning the valid ones
+    fn check_clobbers(&self, clobbers: &[(String, InputSpan)]) -> Vec<RegId> {
+        let mut registers = vec![];
+        for (name, span) in clobbers {
+            let reason = match parse_register(name) {
+                Some(0) => "`r0` is always zero",
+                Some(STACK_POINTER) => "`r14` is the stack pointer",
+                Some(HEAP_POINTER) => "`r15` is the heap pointer",
+                Some(FRAME_POINTER) => "`r30` is the frame pointer",
+                Some(reg) => {
+                    registers.push(reg);
+                    continue;
+                }
+                None => {
+                    let diagnostic =
+                        Diagnostic::error(format!("`{}` is not a register", name), *span)
+                            .code("E0507")
+                            .label("invalid clobber".to_string())
+                            .note("registers are named `r0` to `r31`".to_string());
+                    self.logger.report(diagnostic);
+                    continue;
+                }
+            };
+            let diagnostic = Diagnostic::error(format!("cannot clobber `{}`", name), *span)
+                .code("E0507")
+                .label("reserved register".to_string())
+                .note(reason.to_string())
+                .help(
+                    "clobbers list the registers that the compiler must save and restore"
+                        .to_string(),
+                );
+            self.logger.report(diagnostic);
+        }
+        registers
+    }
+
+    fn check_field_ref(&mut self, scope: &mut Scope, field_ref: &mut ast::FieldRef) -> Type {
+        let target_type = self.check_expression(scope, &mut field_ref.target);
+        if target_type == types::Error {
+            return types::Error;
+        }
+
+        // Fields can be accessed through a pointer to a struct
+        let field = match self.type_table.base_type(&target_type) {
+            Some(types::Composite(inner)) => inner.field(&field_ref.field),
+            _ => None,
+        };
+        match field {
+            Some(field) => field.field_type.clone(),
+            None => self.type_error(
+                TypeError::NoField(target_type, field_ref.field.clone()),
+                field_ref.span,
+            ),
+        }
+    }
+
+    fn check_array_index(&mut self, scope: &mut Scope, index_expr: &mut ast::ArrayIndex) -> Type {
+        let target_type = self.check_expression(scope, &mut index_expr.target);
+
+        // Check that we are indexing with the correct type
+        let index_type = self.check_expression(scope, &mut index_expr.index);
+        self.check_type(&index_type, &INT_TYPE, index_expr.index.span);
+
+        // Check that the type that we are indexing can be indexed
+        match target_type {
+            types::Pointer(ref inner) | types::StaticArray(ref inner, _)
+                if matches!(**inner, types::Any | types::Bottom) =>
+            {
+                // The elements must have a known size to find their address
+                let diagnostic = Diagnostic::error(
+                    format!("type `{}` cannot be indexed", self.type_name(&target_type)),
+                    index_expr.span,
+                )
+                .code("E0404")
+                .help("cast the pointer to a pointer to a sized type first".to_string());
+                self.logger.report(diagnostic);
+                types::Error
+            }
+            types::Pointer(inner) | types::StaticArray(inner, _) => *inner,
+            types::Error => types::Error,
+            invalid => {
+                let diagnostic = Diagnostic::error(
+                    format!("type `{}` cannot be indexed", self.type_name(&invalid)),
+                    index_expr.target.span,
+                )
+                .code("E0404")
+                .note("only arrays and pointers can be indexed".to_string());
+                self.logger.report(diagnostic);
+                types::Error
+            }
+        }
+    }
+
+    fn check_if(&mut self, scope: &mut Scope, if_statement: &mut ast::IfStatement) -> Type {
+        // Check that the condition is a boolean
+        let cond_type = self.check_expression(scope, &mut if_statement.condition);
+        self.check_type(&cond_type, &BOOL_TYPE, if_statement.span);
+
+        let then_type = self.check_block(scope, &mut if_statement.body);
+        match if_statement.else_block {
+            Some(ref mut block) => {
+                // Check that both sides return the same type
+                let else_type = self.check_block(scope, block);
+                self.check_type(&else_type, &then_type, block.span);
+
+                // If the first branch never finishes, the value always comes from the second
+                if then_type == types::Bottom {
+                    else_type
+                }
+                else {
+                    then_type
+                }
+            }
+            None => {
+                // If the else block was left unspecified, then the if statement must return the
+                // unit type
+                self.check_type(&then_type, &UNIT_TYPE, if_statement.span);
+                UNIT_TYPE
+            }
+        }
+    }
+
+    fn check_for(&mut self, scope: &mut Scope, for_statement: &mut ast::ForLoopStatement) -> Type {
+        let span = for_statement.span;
+        let variable = Variable { rtype: INT_TYPE, span, is_const: false };
+        scope.add_var(for_statement.loop_var.clone(), variable, self.logger);
+
+        // Warn about loops over a constant range that is empty
+        if let (ast::LitNumExpr(start), ast::LitNumExpr(end)) =
+            (&*for_statement.start.expr, &*for_statement.end.expr)
+        {
+            if start >= end {
+                let diagnostic =
+                    Diagnostic::warning("loop body will never be executed".to_string(), span)
+                        .code("W0001")
+                        .note(format!("the range `{}..{}` is empty", start, end));
+                self.logger.report(diagnostic);
+            }
+        }
+
+        // Check that the range has the correct type
+        let end_type = self.check_expression(scope, &mut for_statement.end);
+        self.check_type(&end_type, &INT_TYPE, for_statement.end.span);
+        let start_type = self.check_expression(scope, &mut for_statement.start);
+        self.check_type(&start_type, &INT_TYPE, for_statement.start.span);
+
+        let body_type = self.check_loop_body(scope, &mut for_statement.body);
+        self.check_type(&body_type, &UNIT_TYPE, span);
+        UNIT_TYPE
+    }
+
+    /// Check the body of a loop, which is allowed to contain `break`
+    fn check_loop_body(&mut self, scope: &mut Scope, body: &mut ast::Block) -> Type {
+        self.loop_depth += 1;
+        let body_type = self.check_block(scope, body);
+        self.loop_depth -= 1;
+        body_type
+    }
+
+    fn check_call(&mut self, scope: &mut Scope, call: &mut ast::FunctionCall) -> Type {
+        let mut call_args = vec![];
+        for arg in &mut call.args {
+            call_args.push(self.check_expression(scope, arg));
+        }
+
+        // Get the function corresponding to the call
+        let function = match self.lookup(scope, &call.name) {
+            Some((FnIdent(function), label)) => {
+                call.name = label;
+                function
+            }
+            Some((VarIdent(var), _)) => {
+                let diagnostic = Diagnostic::error(
+                    format!("expected function, found variable `{}`", call.name),
+                    call.span,
+                )
+                .code("E0304")
+                .label("not a function".to_string())
+                .secondary(var.span, format!("`{}` is defined here", call.name));
+                self.logger.report(diagnostic);
+                return types::Error;
+            }
+            None => {
+                return self.type_error(TypeError::VariableNotFound(call.name.clone()), call.span)
+            }
+        };
+
+        // Check that the call args match the function args
+        if call_args.len() != function.params.len() {
+            let plural = |n: usize| if n == 1 { "" } else { "s" };
+            let expected = function.params.len();
+            let diagnostic = Diagnostic::error(
+                format!(
+                    "this function takes {} argument{} but {} argument{} supplied",
+                    expected,
+                    plural(expected),
+                    call_args.len(),
+                    if call_args.len() == 1 { " was" } else { "s were" },
+                ),
+                call.span,
+            )
+            .code("E0402")
+            .secondary(function.span, "function defined here".to_string());
+            self.logger.report(diagnostic);
+        }
+        for (call_arg, fn_arg) in call_args.iter().zip(&function.params) {
+            self.check_type(call_arg, fn_arg, call.span);
+        }
+
+        function.rtype.clone()
+    }
+
+    fn check_binary(&mut self, scope: &mut Scope, binary_expr: &mut ast::BinaryExpression) -> Type {
+        use crate::ast::BinaryOp::*;
+
+        let lhs = self.check_expression(scope, &mut binary_expr.lhs);
+        let rhs = self.check_expression(scope, &mut binary_expr.rhs);
+        let span = binary_expr.span;
+
+        match binary_expr.op {
+            // Arithmetic is only defined for integers
+            Add | Sub | Mul | Div | Rem => {
+                self.check_type(&lhs, &INT_TYPE, span);
+                self.check_type(&rhs, &INT_TYPE, span);
+                return INT_TYPE;
+            }
+
+            // Any two values that fit in a register can be compared for equality. Pointers can be
+            // compared with pointers of any type.
+            Eq | NotEq => {
+                match (&lhs, &rhs) {
+                    (types::Pointer(..), types::Pointer(..)) => {}
+                    _ => self.check_type(&rhs, &lhs, span),
+                }
+                match lhs {
+                    INT_TYPE | CHAR_TYPE | BOOL_TYPE | types::Pointer(..) => {}
+                    types::Any | types::Bottom | types::Error => {}
+                    ref invalid => {
+                        let diagnostic = Diagnostic::error(
+                            format!(
+                                "type `{}` cannot be compared for equality",
+                                self.type_name(invalid)
+                            ),
+                            span,
+                        )
+                        .code("E0405")
+                        .note("only values that fit in a register can be compared".to_string());
+                        self.logger.report(diagnostic);
+                    }
+                }
+            }
+
+            // Ordering is defined for integers and characters
+            Lt | LtEq | Gt | GtEq => {
+                self.check_type(&rhs, &lhs, span);
+                match lhs {
+                    INT_TYPE | CHAR_TYPE | types::Any | types::Bottom | types::Error => {}
+                    ref invalid => {
+                        let diagnostic = Diagnostic::error(
+                            format!("type `{}` cannot be ordered", self.type_name(invalid)),
+                            span,
+                        )
+                        .code("E0406")
+                        .note("ordering is only defined for `int` and `char`".to_string());
+                        self.logger.report(diagnostic);
+                    }
+                }
+            }
+
+            And | Or => {
+                self.check_type(&lhs, &BOOL_TYPE, binary_expr.lhs.span);
+                self.check_type(&rhs, &BOOL_TYPE, binary_expr.rhs.span);
+            }
+        }
+        BOOL_TYPE
+    }
+
+    fn check_let(&mut self, scope: &mut Scope, let_statement: &mut ast::LetStatement) {
+        let rtype = self.check_let_type(scope, let_statement);
+        let variable =
+            Variable { rtype, span: let_statement.span, is_const: let_statement.is_const };
+        scope.add_var(let_statement.name.clone(), variable, self.logger);
+    }
+
+    /// Check the value assigned by a let statement, returning the type of the variable
+    fn check_let_type(&mut self, scope: &mut Scope, let_statement: &mut ast::LetStatement) -> Type {
+        let span = let_statement.span;
+        let var_type = let_statement.var_type.as_mut().map(|x| self.resolve_type(x, span));
+
+        // Variables without a type have the type of the value assigned to them
+        let rtype = match let_statement.assignment {
+            Some(ref mut assignment) => {
+                let value_type = self.check_expression(scope, &mut assignment.rhs);
+                let rtype = match var_type {
+                    Some(var_type) => {
+                        self.check_type(&value_type, &var_type, assignment.span);
+                        var_type
+                    }
+                    None => value_type,
+                };
+                assignment.target.rtype = Some(rtype.clone());
+                rtype
+            }
+            None => var_type.expect("ICE: variable without a type or a value"),
+        };
+
+        self.check_sized(&rtype, span);
+        rtype
+    }
+
+    fn check_assign(&mut self, scope: &mut Scope, assignment: &mut ast::Assignment) {
+        // Constants are looked up before the target is checked, which replaces its name with a label
+        if let ast::VariableExpr(ref name) = *assignment.target.expr {
+            if let Some((VarIdent(var @ Variable { is_const: true, .. }), _)) =
+                self.lookup(scope, name)
+            {
+                let diagnostic = Diagnostic::error(
+                    format!("cannot assign to constant `{}`", name),
+                    assignment.span,
+                )
+                .code("E0509")
+                .label("cannot assign to a constant".to_string())
+                .secondary(var.span, format!("`{}` is declared as a constant here", name))
+                .help("declare it with `let` to make it a variable".to_string());
+                self.logger.report(diagnostic);
+            }
+        }
+
+        let value_type = self.check_expression(scope, &mut assignment.rhs);
+        let target_type = self.check_expression(scope, &mut assignment.target);
+
+        if !is_place(&assignment.target) {
+            let diagnostic = Diagnostic::error(
+                "invalid left-hand side of assignment".to_string(),
+                assignment.target.span,
+            )
+            .code("E0504")
+            .label("cannot assign to this expression".to_string());
+            self.logger.report(diagnostic);
+            return;
+        }
+        self.check_type(&value_type, &target_type, assignment.span);
+    }
+
+    fn check_struct_init(&mut self, scope: &mut Scope, struct_init: &mut ast::StructInit) -> Type {
+        let span = struct_init.span;
+        let mut ast_type = ast::UserType(struct_init.type_name.clone());
+        let struct_type = self.resolve_type(&mut ast_type, span);
+        let struct_def = match self.type_table.base_type(&struct_type) {
+            Some(types::Composite(inner)) => Some(inner.clone()),
+            _ => None,
+        };
+
+        for (field_name, expression) in &mut struct_init.field_init {
+            let value_type = self.check_expression(scope, expression);
+            match struct_def.as_ref().and_then(|x| x.field(field_name)) {
+                Some(field) => self.check_type(&value_type, &field.field_type, span),
+                None if struct_type == types::Error => {}
+                None => {
+                    self.type_error(
+                        TypeError::NoField(struct_type.clone(), field_name.clone()),
+                        span,
+                    );
+                }
+            }
+        }
+        struct_type
+    }
+
+    fn check_static_array(&mut self, scope: &mut Scope, array: &mut ast::StaticArray) -> Type {
+        // Check that all elements of the array have the same type
+        let mut element_type = None;
+        for element in &mut array.elements {
+            let rtype = self.check_expression(scope, element);
+            match element_type {
+                Some(ref element_type) => self.check_type(&rtype, element_type, array.span),
+                None => element_type = Some(rtype),
+            }
+        }
+
+        let element_type = element_type.expect("ICE: array literal without any elements");
+        match self.type_table.array_type(element_type, array.elements.len()) {
+            Ok(type_) => type_,
+            Err(error) => self.type_error(error, array.span),
+        }
+    }
+
+    /// Check that a type is the same as the expected type. Values that never exist (such as the
+    /// result of `return`), values produced by inline assembly, and expressions that already had
+    /// an error are accepted as any type.
+    fn check_type(&self, input: &Type, expected: &Type, span: InputSpan) {
+        let matches_any = |x: &Type| matches!(x, types::Any | types::Bottom | types::Error);
+        // `*any` converts to and from pointers of any other type, so that memory from the allocator
+        // and `null` can be used without a cast
+        let is_any_pointer = |x: &Type| matches!(x, types::Pointer(inner) if **inner == types::Any);
+        let pointers = matches!((input, expected), (types::Pointer(..), types::Pointer(..)));
+        let converts = pointers && (is_any_pointer(input) || is_any_pointer(expected));
+        if input != expected && !matches_any(input) && !matches_any(expected) && !converts {
+            let diagnostic = Diagnostic::error("mismatched types".to_string(), span)
+                .code("E0401")
+                .label(format!(
+                    "expected `{}`, found `{}`",
+                    self.type_name(expected),
+                    self.type_name(input)
+                ));
+            self.logger.report(diagnostic);
+        }
+    }
+
+    /// Check that a variable has a type with a known size
+    fn check_sized(&self, type_: &Type, span: InputSpan) {
+        if let types::Any | types::Bottom = *type_ {
+            let diagnostic = Diagnostic::error(
+                format!("variables cannot have type `{}`", self.type_name(type_)),
+                span,
+            )
+            .code("E0407")
+            .help("use a pointer type such as `*any` instead".to_string());
+            self.logger.report(diagnostic);
+        }
+    }
+
+    /// Report a type error, returning the type given to the expression that caused it
+    fn type_error(&self, error: TypeError, span: InputSpan) -> Type {
+        self.logger.report(error.to_diagnostic(&self.type_table, span));
+        types::Error
+    }
+
+    fn type_name(&self, type_: &Type) -> String {
+        self.type_table.type_name(type_)
+    }
+
+    /// Resolve a type used in the current module, replacing the names of structs with their full
+    /// paths
+    fn resolve_type(&self, ast_type: &mut ast::Type, span: InputSpan) -> Type {
+        qualify_type(&self.modules, self.module, ast_type);
+        match self.type_table.resolve_type(ast_type) {
+            Ok(type_) => type_,
+            Err(error) => self.type_error(error, span),
+        }
+    }
+}
@@ -0,0 +1,657 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    ast,
+    error::{Diagnostic, FatalError, InputSpan, Logger},
+    module::STD_MODULE,
+};
+
+pub use self::BaseType::*;
+pub use self::Type::*;
+
+type TypeId = usize;
+
+// The primitive types are always the first entries in the type table
+pub const UNIT_TYPE: Type = Normal(0);
+pub const INT_TYPE: Type = Normal(1);
+pub const CHAR_TYPE: Type = Normal(2);
+pub const BOOL_TYPE: Type = Normal(3);
+
+/// A field of a struct
+#[derive(Clone)]
+pub struct Field {
+    pub name: String,
+    /// The distance in bytes from the start of the struct to the field
+    pub offset: u16,
+    pub field_type: Type,
+}
+
+/// A struct type. Its fields are kept in the order they were declared, which is also the order of
+/// their offsets, so the layout of a struct only changes when its declaration does.
+#[derive(Clone)]
+pub struct CompositeType {
+    pub name: String,
+    pub fields: Vec<Field>,
+    pub size: u16,
+    /// The alignment of the struct, which is the largest alignment of its fields
+    pub align: u16,
+    /// Whether the fields are laid out without padding, set with `#[packed]`
+    pub packed: bool,
+}
+
+impl CompositeType {
+    /// Create blank type with only a name.
+    /// This is useful for referencing an incomplete type with pointers.
+    fn blank_type(name: String) -> CompositeType {
+        CompositeType { name, fields: vec![], size: 0, align: 1, packed: false }
+    }
+
+    /// Find a field of the struct by its name
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
+
+impl PartialEq for CompositeType {
+    fn eq(&self, other: &CompositeType) -> bool {
+        // No two unique types can have the same name, so it is sufficient just to compare the
+        // names of the types to see if they are the same
+        self.name == other.name
+    }
+}
+
+impl fmt::Debug for CompositeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BaseType {
+    Bool,
+    Int,
+    Char,
+    Unit,
+    Composite(Box<CompositeType>),
+}
+
+impl BaseType {
+    /// Returns the size of the type, which is always a multiple of its alignment
+    pub fn size(&self) -> u16 {
+        match *self {
+            Bool => 4,
+            Int => 4,
+            Char => 1,
+            Unit => 0,
+            Composite(ref tp) => tp.size,
+        }
+    }
+
+    /// Returns the alignment of the type. Values of the type are stored at addresses that are a
+    /// multiple of it, unless they are fields of a packed struct.
+    pub fn align(&self) -> u16 {
+        match *self {
+            Bool => 4,
+            Int => 4,
+            Char => 1,
+            Unit => 1,
+            Composite(ref tp) => tp.align,
+        }
+    }
+}
+
+/// A resolved type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Normal(TypeId),
+    StaticArray(Box<Type>, u16),
+    Pointer(Box<Type>),
+    Any,
+    Bottom,
+    /// The type of an expression that contains an error. It is accepted wherever a type is
+    /// expected, so that one mistake doesn't cause more errors to be reported.
+    Error,
+}
+
+impl Type {
+    /// Get the type that a pointer points to, or the type of the elements of an array
+    pub fn deref_type(&self) -> &Type {
+        match self {
+            StaticArray(inner, _) => inner,
+            Pointer(inner) => inner,
+            invalid => panic!("ICE: Attempted to dereference `{:?}`", invalid),
+        }
+    }
+}
+
+/// An error in the names or types used by a program
+pub enum TypeError {
+    /// A variable or function that could not be found
+    VariableNotFound(String),
+    /// A type name that has not been declared
+    TypeNotFound(String),
+    CannotDeref(Type),
+    /// A field that does not exist in a type
+    NoField(Type, String),
+    /// An array whose size is larger than `MAX_TYPE_SIZE`, given by its element type and length
+    TooLarge(Type, usize),
+}
+
+impl TypeError {
+    pub fn to_diagnostic(&self, type_table: &TypeTable, span: InputSpan) -> Diagnostic {
+        match self {
+            TypeError::VariableNotFound(name) => {
+                Diagnostic::error(format!("cannot find `{}` in this scope", name), span)
+                    .code("E0301")
+                    .label("not found in this scope".to_string())
+            }
+            TypeError::TypeNotFound(name) => {
+                Diagnostic::error(format!("cannot find type `{}`", name), span)
+                    .code("E0305")
+                    .help(format!("declare it with `struct {} {{ ... }}`", name))
+            }
+            TypeError::CannotDeref(invalid) => Diagnostic::error(
+                format!("type `{}` cannot be dereferenced", type_table.type_name(invalid)),
+                span,
+            )
+            .code("E0404"),
+            TypeError::NoField(invalid, field) => Diagnostic::error(
+                format!("type `{}` has no field `{}`", type_table.type_name(invalid), field),
+                span,
+            )
+            .code("E0403")
+            .label(format!("unknown field `{}`", field)),
+            TypeError::TooLarge(element, length) => Diagnostic::error(
+                format!("type `[{}, ..{}]` is too large", type_table.type_name(element), length),
+                span,
+            )
+            .code("E0410")
+            .note(format!("types can take up at most {} bytes", MAX_TYPE_SIZE)),
+        }
+    }
+}
+
+pub struct TypeTable {
+    type_map: HashMap<ast::Type, usize>,
+    types: Vec<BaseType>,
+}
+
+impl TypeTable {
+    pub fn resolve_type(&self, ast_type: &ast::Type) -> Result<Type, TypeError> {
+        let type_ = match ast_type {
+            ast::Pointer(inner) => Pointer(Box::new(self.resolve_type(inner)?)),
+            ast::StaticArrayType(inner, size) => {
+                self.array_type(self.resolve_type(inner)?, *size as usize)?
+            }
+            ast::Primitive(ast::AnyType) => Any,
+
+            ast::UserType(name) => match self.type_map.get(ast_type) {
+                Some(&id) => Normal(id),
+                None => return Err(TypeError::TypeNotFound(name.clone())),
+            },
+
+            // Otherwise this is a primitive type, which are always in the type map
+            ast::Primitive(..) => Normal(self.type_map[ast_type]),
+        };
+        Ok(type_)
+    }
+
+    /// Get the type of an array with `length` elements, checking that its size can be represented
+    pub fn array_type(&self, element: Type, length: usize) -> Result<Type, TypeError> {
+        let fits = match u16::try_from(length) {
+            // The sizes of unsized elements are reported elsewhere
+            Ok(_) if matches!(element, Any | Bottom | Error) => true,
+            Ok(length) => {
+                let size = self.unaligned_size_of(&element).checked_mul(length);
+                matches!(size, Some(size) if size <= MAX_TYPE_SIZE)
+            }
+            Err(_) => false,
+        };
+        if fits {
+            Ok(StaticArray(Box::new(element), length as u16))
+        }
+        else {
+            Err(TypeError::TooLarge(element, length))
+        }
+    }
+
+    /// Get the base type of a type, automatically dereferencing pointers
+    pub fn base_type(&self, type_: &Type) -> Option<&BaseType> {
+        match type_ {
+            Normal(id) => Some(&self.types[*id]),
+            Pointer(inner) => self.base_type(inner),
+            _ => None,
+        }
+    }
+
+    /// Get the name of a type as it would be written in a program
+    pub fn type_name(&self, type_: &Type) -> String {
+        match type_ {
+            Normal(id) => match &self.types[*id] {
+                Bool => "bool".to_string(),
+                Int => "int".to_string(),
+                Char => "char".to_string(),
+                Unit => "()".to_string(),
+                Composite(inner) => inner.name.clone(),
+            },
+            StaticArray(inner, size) => format!("[{}, ..{}]", self.type_name(inner), size),
+            Pointer(inner) => format!("*{}", self.type_name(inner)),
+            Any => "any".to_string(),
+            Bottom => "!".to_string(),
+            Error => "{error}".to_string(),
+        }
+    }
+
+    /// Get the space taken up by a variable of a type, which is rounded up to a whole number of
+    /// words
+    pub fn size_of(&self, type_: &Type) -> u16 {
+        align(self.unaligned_size_of(type_))
+    }
+
+    /// Get the size of a type without rounding it up to a whole number of words. This is the
+    /// distance between the elements of an array of the type.
+    pub fn unaligned_size_of(&self, type_: &Type) -> u16 {
+        match type_ {
+            Normal(id) => self.types[*id].size(),
+            StaticArray(inner, size) => self.unaligned_size_of(inner) * size,
+            Pointer(..) => 4,
+            Bottom => panic!("ICE: Attempted to determine size of bottom type"),
+            Any => panic!("ICE: Attempted to determine size of any type"),
+            Error => panic!("ICE: Attempted to determine size of a type with errors"),
+        }
+    }
+
+    pub fn align_of(&self, type_: &Type) -> u16 {
+        match type_ {
+            Normal(id) => self.types[*id].align(),
+            StaticArray(inner, _) => self.align_of(inner),
+            Pointer(..) => 4,
+            Bottom => panic!("ICE: Attempted to determine alignment of bottom type"),
+            Any => panic!("ICE: Attempted to determine alignment of any type"),
+            Error => panic!("ICE: Attempted to determine alignment of a type with errors"),
+        }
+    }
+
+    /// Check whether a type is a packed struct or a pointer to one, whose fields may not be aligned
+    pub fn is_packed(&self, struct_type: &Type) -> bool {
+        matches!(self.base_type(struct_type), Some(Composite(inner)) if inner.packed)
+    }
+
+    /// Describe the layout of every struct in the order they were declared, giving the offset and
+    /// size of each field along with any padding, e.g. for writing assembly that uses the fields.
+    /// The structs of the standard library are left out, so that only the program is described.
+    pub fn layout_report(&self) -> String {
+        let std_prefix = format!("{}::", STD_MODULE);
+        let mut report = String::new();
+        for base_type in &self.types {
+            let struct_def = match base_type {
+                Composite(inner) if !inner.name.starts_with(&std_prefix) => inner,
+                _ => continue,
+            };
+            if !report.is_empty() {
+                report.push('\n');
+            }
+            let attribute = if struct_def.packed { "#[packed] " } else { "" };
+            report += &format!(
+                "{}struct {} (size {}, align {})\n",
+                attribute, struct_def.name, struct_def.size, struct_def.align
+            );
+
+            let mut end = 0;
+            for field in &struct_def.fields {
+                if field.offset > end {
+                    report += &layout_line(end, "padding", field.offset - end);
+                }
+                let size = self.unaligned_size_of(&field.field_type);
+                let name = format!("{}: {}", field.name, self.type_name(&field.field_type));
+                report += &layout_line(field.offset, &name, size);
+                end = field.offset + size;
+            }
+            if struct_def.size > end {
+                report += &layout_line(end, "padding", struct_def.size - end);
+            }
+        }
+        report
+    }
+
+    fn add_mapping(&mut self, ast_type: ast::Type, resolved_type: BaseType) {
+        let index = self.types.len();
+        self.type_map.insert(ast_type, index);
+        self.types.push(resolved_type);
+    }
+}
+
+/// Format a line of a layout report for a field or padding at an offset
+fn layout_line(offset: u16, description: &str, size: u16) -> String {
+    let unit = if size == 1 { "byte" } else { "bytes" };
+    format!("    offset {:<4} {} ({} {})\n", offset, description, size, unit)
+}
+
+/// The largest size of a type, which still fits in a `u16` once it is rounded up to a whole number
+/// of words
+pub const MAX_TYPE_SIZE: u16 = 0xfffc;
+
+// Aligns types to words
+pub fn align(size: u16) -> u16 {
+    let padding = size % 4;
+    if padding != 0 { size + (4 - padding) } else { size }
+}
+
+/// Round an offset up to the next multiple of an alignment
+pub fn align_to(offset: u16, align: u16) -> u16 {
+    offset.div_ceil(align) * align
+}
+
+struct TypeGenData<'a> {
+    unresolved_map: HashMap<String, (usize, ast::StructDeclaration)>,
+    type_table: TypeTable,
+    logger: &'a Logger,
+    /// The structs whose fields are being generated, in the order that they were reached. A
+    /// struct that contains one of these by value has an infinite size.
+    in_progress: Vec<String>,
+    /// Whether any field has been given the error type
+    has_invalid_fields: bool,
+}
+
+impl<'a> TypeGenData<'a> {
+    fn generate_type_table(mut self, struct_list: Vec<String>) -> TypeTable {
+        // Iterate through the list of remaining types an resolve them. Some types depend on other
+        // types, so we recursively resolve dependencies.
+        for current_type in &struct_list {
+            let (id, struct_decl) = match self.unresolved_map.get(current_type) {
+                // This type is in still in unresolved list so we need to resolve it
+                Some(&(id, ref struct_decl)) => (id, struct_decl.clone()),
+
+                // Already resolved this type due to a dependency.
+                None => continue,
+            };
+            let resolved = Composite(Box::new(self.gen_struct_type(&struct_decl)));
+            self.type_table.types[id] = resolved;
+        }
+
+        // The sizes of all of the structs are needed to check the rest of the program
+        if self.has_invalid_fields {
+            FatalError::raise();
+        }
+        self.type_table
+    }
+
+    fn gen_struct_type(&mut self, struct_decl: &ast::StructDeclaration) -> CompositeType {
+        self.in_progress.push(struct_decl.name.clone());
+        let mut new_type = CompositeType::blank_type(struct_decl.name.clone());
+        new_type.packed = struct_decl.packed;
+        let mut field_spans: HashMap<&String, InputSpan> = HashMap::new();
+        let mut next_offset = 0;
+        // Loop though all the fields of the struct and resolve their types and offsets. Each field
+        // is placed at the next multiple of its alignment, unless the struct is packed.
+        for (field_name, field_type, field_span) in &struct_decl.fields {
+            if let Some(&previous) = field_spans.get(field_name) {
+                let diagnostic = Diagnostic::error(
+                    format!(
+                        "field `{}` is defined multiple times in struct `{}`",
+                        field_name, struct_decl.name
+                    ),
+                    *field_span,
+                )
+                .code("E0302")
+                .secondary(previous, format!("previous definition of `{}` here", field_name));
+                self.logger.report(diagnostic);
+                continue;
+            }
+            field_spans.insert(field_name, *field_span);
+
+            let mut resolved_type = self.gen_type(field_type, *field_span);
+            if let Any | Bottom = resolved_type {
+                let diagnostic = Diagnostic::error(
+                    format!("field `{}` has an unsized type", field_name),
+                    *field_span,
+                )
+                .code("E0407")
+                .help("use a pointer type such as `*any` instead".to_string());
+                self.logger.report(diagnostic);
+                resolved_type = Error;
+            }
+            if resolved_type == Error {
+                // The error has already been reported, so the field is kept without taking up any
+                // space to avoid further errors about it
+                self.has_invalid_fields = true;
+                new_type.fields.push(Field {
+                    name: field_name.clone(),
+                    offset: next_offset,
+                    field_type: Error,
+                });
+                continue;
+            }
+
+            let field_align = self.type_table.align_of(&resolved_type);
+            if struct_decl.packed && field_align > 1 && !self.is_word(&resolved_type) {
+                // Words in packed structs are read a byte at a time, but structs and arrays are
+                // copied assuming that they are aligned
+                let type_name = self.type_table.type_name(&resolved_type);
+                let mut diagnostic = Diagnostic::error(
+                    format!(
+                        "field `{}` of packed struct `{}` has a type that must be aligned",
+                        field_name, struct_decl.name
+                    ),
+                    *field_span,
+                )
+                .code("E0408")
+                .note(format!(
+                    "`{}` has an alignment of {}, but fields of packed structs may not be aligned",
+                    type_name, field_align
+                ));
+                if let Normal(..) = resolved_type {
+                    diagnostic =
+                        diagnostic.help(format!("mark `{}` as `#[packed]` too", type_name));
+                }
+                self.logger.report(diagnostic);
+            }
+
+            let field_offset = if struct_decl.packed {
+                next_offset
+            }
+            else {
+                new_type.align = new_type.align.max(field_align);
+                align_to(next_offset, field_align)
+            };
+            let field_size = self.type_table.unaligned_size_of(&resolved_type);
+            next_offset = match field_offset.checked_add(field_size) {
+                Some(end) if end <= MAX_TYPE_SIZE => end,
+                _ => {
+                    let diagnostic = Diagnostic::error(
+                        format!("struct `{}` is too large", struct_decl.name),
+                        *field_span,
+                    )
+                    .code("E0410")
+                    .label(format!("`{}` goes past the largest size", field_name))
+                    .note(format!("types can take up at most {} bytes", MAX_TYPE_SIZE));
+                    self.logger.report(diagnostic);
+                    self.has_invalid_fields = true;
+                    break;
+                }
+            };
+
+            new_type.fields.push(Field {
+                name: field_name.clone(),
+                offset: field_offset,
+                field_type: resolved_type,
+            });
+        }
+
+        // Padding is added to the end of the struct so that the elements of arrays are aligned
+        new_type.size = align_to(next_offset, new_type.align);
+
+        // Now that we have resolved this we can remove it from the list of unresolved
+        // types.
+        self.unresolved_map.remove(&struct_decl.name);
+        self.in_progress.pop();
+        new_type
+    }
+
+    fn full_resolve_type(&mut self, name: &String, span: InputSpan) -> Type {
+        // A struct that is still being generated contains itself
+        if let Some(start) = self.in_progress.iter().position(|x| x == name) {
+            self.report_cycle(start, span);
+            return Error;
+        }
+
+        let (id, struct_decl) = match self.unresolved_map.get(name) {
+            // This type is in still in unresolved list so we need to resolve it
+            Some(&(id, ref struct_decl)) => (id, struct_decl.clone()),
+
+            // Already resolved this type due to a dependency.
+            None => return self.resolve_type(&ast::UserType(name.clone()), span),
+        };
+        let resolved = Composite(Box::new(self.gen_struct_type(&struct_decl)));
+        self.type_table.types[id] = resolved;
+        Normal(id)
+    }
+
+    /// Report a field that contains a struct that is still being generated, which is
+    /// `in_progress[start]`. The field belongs to the struct at the end of `in_progress`.
+    fn report_cycle(&self, start: usize, span: InputSpan) {
+        let cycle = &self.in_progress[start..];
+        let outer = cycle.last().unwrap();
+
+        // Describe the cycle starting from the struct that the field belongs to, e.g. "`B`
+        // contains `A`, which contains `B`"
+        let mut description = format!("`{}` contains ", outer);
+        if cycle.len() == 1 {
+            description += "itself";
+        }
+        else {
+            for name in &cycle[..cycle.len() - 1] {
+                description += &format!("`{}`, which contains ", name);
+            }
+            description += &format!("`{}`", outer);
+        }
+
+        let diagnostic =
+            Diagnostic::error(format!("recursive type `{}` has infinite size", outer), span)
+                .code("E0409")
+                .label("recursive without a pointer".to_string())
+                .note(description)
+                .help(format!("use a pointer such as `*{}` to break the cycle", cycle[0]));
+        self.logger.report(diagnostic);
+    }
+
+    fn gen_type(&mut self, ast_type: &ast::Type, span: InputSpan) -> Type {
+        match ast_type {
+            // If this type is a pointer, we don't care if it hasn't been defined yet
+            ast::Pointer(inner) => Pointer(Box::new(self.resolve_type(inner, span))),
+
+            // If the type is a user defined type, then it must be fully resolved before we can
+            // continue
+            ast::UserType(name) => self.full_resolve_type(name, span),
+
+            ast::StaticArrayType(inner, size) => match self.gen_type(inner, span) {
+                Error => Error,
+                inner => match self.type_table.array_type(inner, *size as usize) {
+                    Ok(type_) => type_,
+                    Err(error) => self.type_error(error, span),
+                },
+            },
+
+            // Primitive types should already be resolved
+            primitive @ ast::Primitive(..) => self.resolve_type(primitive, span),
+        }
+    }
+
+    /// Check whether a field type is held in a word, which can be read from any address
+    fn is_word(&self, type_: &Type) -> bool {
+        match type_ {
+            StaticArray(..) => false,
+            Pointer(..) => true,
+            other => !matches!(self.type_table.base_type(other), Some(Composite(..))),
+        }
+    }
+
+    /// Resolve a type that has already been generated, reporting an error if it doesn't exist
+    fn resolve_type(&mut self, ast_type: &ast::Type, span: InputSpan) -> Type {
+        match self.type_table.resolve_type(ast_type) {
+            Ok(type_) => type_,
+            Err(error) => self.type_error(error, span),
+        }
+    }
+
+    fn type_error(&mut self, error: TypeError, span: InputSpan) -> Type {
+        self.logger.report(error.to_diagnostic(&self.type_table, span));
+        self.has_invalid_fields = true;
+        Error
+    }
+}
+
+pub fn typegen(program: &ast::Program, logger: &Logger) -> TypeTable {
+    let mut data = TypeGenData {
+        unresolved_map: HashMap::new(),
+        type_table: TypeTable { type_map: HashMap::new(), types: vec![] },
+        logger,
+        in_progress: vec![],
+        has_invalid_fields: false,
+    };
+
+    // Insert primitive types into the type map
+    data.type_table.add_mapping(ast::Primitive(ast::UnitType), Unit);
+    data.type_table.add_mapping(ast::Primitive(ast::IntType), Int);
+    data.type_table.add_mapping(ast::Primitive(ast::CharType), Char);
+    data.type_table.add_mapping(ast::Primitive(ast::BoolType), Bool);
+
+    let mut struct_list = vec![];
+
+    for item in &program.items {
+        if let ast::StructItem(ref struct_item) = *item {
+            let name = struct_item.name.clone();
+            let id = data.type_table.types.len();
+            data.unresolved_map.insert(name.clone(), (id, struct_item.clone()));
+            data.type_table.type_map.insert(ast::UserType(name.clone()), id);
+            data.type_table
+                .types
+                .push(Composite(Box::new(CompositeType::blank_type(name.clone()))));
+
+            struct_list.push(name);
+        }
+    }
+
+    data.generate_type_table(struct_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::parse};
+
+    /// Generate the types declared in `source`, and look up the struct called `name`
+    fn struct_type(source: &str, name: &str) -> (TypeTable, Type) {
+        let logger = Logger::new(true);
+        let program =
+            parse(Lexer::new(source, logger.add_file("main.pcp", source), &logger), &logger);
+        let type_table = typegen(&program, &logger);
+        assert!(!logger.has_errors());
+        let type_ = type_table.resolve_type(&ast::UserType(name.to_string())).ok().unwrap();
+        (type_table, type_)
+    }
+
+    #[test]
+    fn aligns_struct_fields_unless_packed() {
+        let source = "struct A { c: char, x: int }\n#[packed]\nstruct B { c: char, x: int }";
+        let size = |name: &str| {
+            let (type_table, type_) = struct_type(source, name);
+            type_table.unaligned_size_of(&type_)
+        };
+        assert_eq!(size("A"), 8);
+        assert_eq!(size("B"), 5);
+    }
+
+    #[test]
+    fn keeps_fields_in_declaration_order() {
+        let source = "struct A { z: char, y: int, x: char, w: [char, ..2] }";
+        let (type_table, type_) = struct_type(source, "A");
+        let fields = match type_table.base_type(&type_) {
+            Some(Composite(inner)) => inner.fields.clone(),
+            _ => unreachable!(),
+        };
+        let layout: Vec<_> = fields.iter().map(|x| (x.name.as_str(), x.offset)).collect();
+        assert_eq!(layout, vec![("z", 0), ("y", 4), ("x", 8), ("w", 9)]);
+        assert!(type_table.layout_report().contains("    offset 11   padding (1 byte)\n"));
+    }
+}